@@ -0,0 +1,237 @@
+//! A cgroup of its own for each command, for kernel-enforced limits and
+//! exact accounting.
+//!
+//! With a parent cgroup (v2) configured, every command is moved into a
+//! fresh cgroup under it between fork and exec. Its memory limit goes in as
+//! `memory.max` instead of `RLIMIT_AS`, and a CPU quota as `cpu.max`. On
+//! exit, `cpu.stat` and `memory.peak` give usage for everything the command
+//! started, including descendants it never waited for. A controller the
+//! parent cannot hand down leaves its limit to the rlimit, when there is
+//! one, and its usage to `wait4`.
+//!
+//! Only Linux has cgroups; elsewhere [`Parent::open`] fails and commands
+//! run without them.
+
+use anyhow::{Context, Result};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Period `cpu.max` quotas are expressed over, in microseconds; the
+/// kernel's own default
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Smallest quota the kernel accepts, in microseconds
+const MIN_CPU_QUOTA_US: u64 = 1000;
+
+/// How long processes left behind get to die once the cgroup is killed
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// A cgroup v2 directory that commands get their cgroups under.
+#[derive(Debug)]
+pub struct Parent {
+    dir: PathBuf,
+    /// Cgroups created so far, keeping names unique when exec ids repeat
+    created: AtomicU64,
+}
+
+impl Parent {
+    /// Use `dir`, creating it if needed, and hand the memory and cpu
+    /// controllers down to its children where the kernel allows.
+    ///
+    /// Fails if `dir` is not in a cgroup v2 hierarchy.
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        if !cfg!(target_os = "linux") {
+            anyhow::bail!("cgroups are only supported on Linux");
+        }
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create cgroup {}", dir.display()))?;
+        if !dir.join("cgroup.subtree_control").is_file() {
+            anyhow::bail!("{} is not a cgroup v2 directory", dir.display());
+        }
+
+        // One at a time, so a controller that cannot be enabled leaves the other
+        let available = std::fs::read_to_string(dir.join("cgroup.controllers")).unwrap_or_default();
+        for controller in ["memory", "cpu"] {
+            if !available.split_whitespace().any(|name| name == controller) {
+                warn!(controller, cgroup = %dir.display(), "Cgroup controller is not available");
+                continue;
+            }
+            if let Err(e) = std::fs::write(dir.join("cgroup.subtree_control"), format!("+{}", controller)) {
+                warn!(controller, cgroup = %dir.display(), error = %e, "Failed to enable cgroup controller");
+            }
+        }
+        Ok(Self {
+            dir,
+            created: AtomicU64::new(0),
+        })
+    }
+
+    /// Create the cgroup for one command, enforcing whichever of `limits`
+    /// its controllers allow.
+    pub fn create(&self, exec_id: &str, limits: Limits) -> Result<Cgroup> {
+        let name: String = exec_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let dir = self.dir.join(format!("boxed-{}-{}", name, self.created.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir(&dir).with_context(|| format!("Failed to create cgroup {}", dir.display()))?;
+
+        // From here on, dropping the cgroup removes the directory again
+        let procs = match std::fs::OpenOptions::new().write(true).open(dir.join("cgroup.procs")) {
+            Ok(procs) => procs,
+            Err(e) => {
+                let _ = std::fs::remove_dir(&dir);
+                return Err(anyhow::Error::new(e).context(format!("Failed to open {}/cgroup.procs", dir.display())));
+            }
+        };
+        let mut cgroup = Cgroup {
+            dir,
+            procs,
+            limits_memory: false,
+            limits_cpu: false,
+        };
+        if let Some(bytes) = limits.memory_bytes {
+            cgroup.limits_memory = cgroup.set("memory.max", &bytes.to_string())?;
+        }
+        if let Some(millicores) = limits.cpu_millicores {
+            let quota = (u64::from(millicores) * CPU_PERIOD_US / 1000).max(MIN_CPU_QUOTA_US);
+            cgroup.limits_cpu = cgroup.set("cpu.max", &format!("{} {}", quota, CPU_PERIOD_US))?;
+        }
+        Ok(cgroup)
+    }
+}
+
+/// Limits to enforce in a command's cgroup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Memory the command's processes may use together, in bytes
+    pub memory_bytes: Option<u64>,
+    /// CPU bandwidth in thousandths of a CPU, e.g. 500 for half of one
+    pub cpu_millicores: Option<u32>,
+}
+
+/// What a command's cgroup used. A field is `None` when its controller is
+/// not enabled, or, for `memory_peak_bytes`, on kernels before 5.19.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub cpu_user_ms: Option<u64>,
+    pub cpu_sys_ms: Option<u64>,
+    /// Peak memory charged to the cgroup in bytes, page cache included
+    pub memory_peak_bytes: Option<u64>,
+    /// Processes the kernel killed for going over `memory.max`
+    pub oom_kills: u64,
+}
+
+/// One command's cgroup, removed when dropped.
+#[derive(Debug)]
+pub struct Cgroup {
+    dir: PathBuf,
+    /// Its `cgroup.procs`, opened before the fork
+    procs: std::fs::File,
+    limits_memory: bool,
+    limits_cpu: bool,
+}
+
+impl Cgroup {
+    /// Write `value` to the control file `name`, if the cgroup has it.
+    fn set(&self, name: &str, value: &str) -> Result<bool> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            debug!(cgroup = %self.dir.display(), file = name, "Cgroup has no such control file");
+            return Ok(false);
+        }
+        std::fs::write(&path, value).with_context(|| format!("Failed to write '{}' to {}", value, path.display()))?;
+        Ok(true)
+    }
+
+    /// The open `cgroup.procs`. Writing `0` to it moves the writing
+    /// process in, which is all a forked child may safely do before exec.
+    pub fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+
+    /// Whether `memory.max` holds the memory limit, making the rlimit
+    /// unnecessary.
+    pub fn limits_memory(&self) -> bool {
+        self.limits_memory
+    }
+
+    /// Whether `cpu.max` holds the CPU quota.
+    pub fn limits_cpu(&self) -> bool {
+        self.limits_cpu
+    }
+
+    /// What the cgroup's processes have used so far, exited ones included.
+    pub fn usage(&self) -> Usage {
+        let read = |name: &str| std::fs::read_to_string(self.dir.join(name)).ok();
+        let cpu = read("cpu.stat");
+        let cpu_ms = |key: &str| cpu.as_deref().and_then(|stat| keyed_value(stat, key)).map(|usec| usec / 1000);
+        Usage {
+            cpu_user_ms: cpu_ms("user_usec"),
+            cpu_sys_ms: cpu_ms("system_usec"),
+            memory_peak_bytes: read("memory.peak").and_then(|peak| peak.trim().parse().ok()),
+            oom_kills: read("memory.events")
+                .and_then(|events| keyed_value(&events, "oom_kill"))
+                .unwrap_or(0),
+        }
+    }
+
+    /// Kill whatever the command left running in the cgroup and remove it.
+    pub async fn remove(self) {
+        if !self.is_populated() {
+            return;
+        }
+        debug!(cgroup = %self.dir.display(), "Killing processes left in cgroup");
+        if let Err(e) = std::fs::write(self.dir.join("cgroup.kill"), "1") {
+            // cgroup.kill needs Linux 5.14
+            warn!(cgroup = %self.dir.display(), error = %e, "Failed to kill processes left in cgroup");
+            return;
+        }
+        let deadline = Instant::now() + KILL_GRACE;
+        while self.is_populated() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Whether any process is still in the cgroup.
+    fn is_populated(&self) -> bool {
+        std::fs::read_to_string(self.dir.join("cgroup.events"))
+            .ok()
+            .and_then(|events| keyed_value(&events, "populated"))
+            .is_some_and(|populated| populated != 0)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir(&self.dir) {
+            warn!(cgroup = %self.dir.display(), error = %e, "Failed to remove cgroup");
+        }
+    }
+}
+
+/// The value of `key` in a flat-keyed file such as `cpu.stat`, one
+/// `key value` pair per line.
+fn keyed_value(text: &str, key: &str) -> Option<u64> {
+    text.lines().find_map(|line| {
+        let (name, value) = line.split_once(' ')?;
+        (name == key).then(|| value.trim().parse().ok())?
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_flat_keyed_files() {
+        let stat = "usage_usec 15321\nuser_usec 12000\nsystem_usec 3321\nnr_periods 0\n";
+        assert_eq!(keyed_value(stat, "user_usec"), Some(12000));
+        assert_eq!(keyed_value(stat, "system_usec"), Some(3321));
+        assert_eq!(keyed_value(stat, "usec"), None);
+        assert_eq!(keyed_value("low 0\noom 1\noom_kill 1\noom_group_kill 0\n", "oom_kill"), Some(1));
+        assert_eq!(keyed_value("populated x\n", "populated"), None);
+    }
+}
@@ -0,0 +1,271 @@
+//! Typed client for the agent protocol.
+//!
+//! A [`Client`] owns the write half of a connection and a background task
+//! reading the other half. Responses are matched to their requests by id,
+//! and every notification tagged with an exec id is routed to the stream
+//! returned by [`Client::exec`]; the rest (artifacts and untargeted errors)
+//! arrive on the receiver handed out by [`Client::new`].
+//!
+//! Messages are newline-delimited, the agent's default framing.
+
+use base64::Engine;
+use futures::Stream;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::AbortHandle;
+
+use crate::rpc::{ExecParams, InputEncoding, ReplInputParams, ReplInputResult, Request, Response, RpcError, StreamEvent};
+
+/// Why a call did not return a result.
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// The agent answered with a JSON-RPC error
+    #[error("{} (code {})", .0.message, .0.code)]
+    Rpc(RpcError),
+
+    /// The connection closed before the response arrived
+    #[error("Connection to the agent closed")]
+    Closed,
+
+    #[error("Failed to write to the agent: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Unexpected message from the agent: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Requests awaiting a response, by id; `None` once the connection closed.
+type Pending = Mutex<Option<HashMap<u64, oneshot::Sender<Response>>>>;
+
+/// Open exec streams, by exec id.
+type Routes = Mutex<HashMap<String, mpsc::UnboundedSender<StreamEvent>>>;
+
+/// A connection to one agent.
+pub struct Client<W> {
+    writer: tokio::sync::Mutex<W>,
+    pending: Arc<Pending>,
+    routes: Arc<Routes>,
+    next_id: AtomicU64,
+    reader: AbortHandle,
+}
+
+impl<W: AsyncWrite + Unpin> Client<W> {
+    /// Talk to an agent reading from `reader` and writing to `writer`.
+    ///
+    /// Also returns the receiver for notifications not tied to a command.
+    pub fn new<R>(reader: R, writer: W) -> (Self, mpsc::UnboundedReceiver<StreamEvent>)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let routes = Arc::new(Routes::default());
+        let (other_tx, other_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(read_messages(reader, pending.clone(), routes.clone(), other_tx));
+        let client = Self {
+            writer: tokio::sync::Mutex::new(writer),
+            pending,
+            routes,
+            next_id: AtomicU64::new(1),
+            reader: task.abort_handle(),
+        };
+        (client, other_rx)
+    }
+
+    /// Call `method` and wait for its result.
+    pub async fn call<P: Serialize, T: DeserializeOwned>(&self, method: &str, params: P) -> Result<T, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().as_mut().ok_or(ClientError::Closed)?.insert(id, tx);
+
+        let request = Request {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params: serde_json::to_value(params)?,
+            id: Some(id.into()),
+        };
+        if let Err(e) = self.send(&request).await {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+            return Err(e);
+        }
+
+        let response = rx.await.map_err(|_| ClientError::Closed)?;
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(ClientError::Rpc(error)),
+            (result, None) => Ok(serde_json::from_value(result.unwrap_or_default())?),
+        }
+    }
+
+    /// Start a command and stream its events, ending with its exit.
+    ///
+    /// An exec id is chosen when `params` has none, so events that race
+    /// ahead of the response are still routed.
+    pub async fn exec(&self, mut params: ExecParams) -> Result<impl Stream<Item = StreamEvent>, ClientError> {
+        let exec_id = params
+            .spawn
+            .exec_id
+            .get_or_insert_with(|| format!("client-{}", self.next_id.fetch_add(1, Ordering::Relaxed)))
+            .clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.routes.lock().unwrap().insert(exec_id.clone(), tx);
+
+        if let Err(e) = self.call::<_, serde_json::Value>("exec", params).await {
+            self.routes.lock().unwrap().remove(&exec_id);
+            return Err(e);
+        }
+        Ok(futures::stream::unfold(rx, |mut rx| async move {
+            let event = rx.recv().await?;
+            Some((event, rx))
+        }))
+    }
+
+    /// Write `data` to a command's stdin, or the most recent one's,
+    /// returning how many bytes it took.
+    pub async fn repl_input(&self, exec_id: Option<&str>, data: &[u8]) -> Result<usize, ClientError> {
+        let params = ReplInputParams {
+            exec_id: exec_id.map(str::to_string),
+            data: base64::engine::general_purpose::STANDARD.encode(data),
+            encoding: InputEncoding::Base64,
+            append_newline: false,
+        };
+        let result: ReplInputResult = self.call("repl.input", params).await?;
+        Ok(result.bytes_written)
+    }
+
+    async fn send(&self, request: &Request) -> Result<(), ClientError> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&line).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+impl<W> Drop for Client<W> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Dispatch every message from the agent until the connection closes.
+///
+/// Dropping the pending senders and routes on return fails outstanding
+/// calls with [`ClientError::Closed`] and ends open streams.
+async fn read_messages<R: AsyncRead + Unpin>(
+    reader: R,
+    pending: Arc<Pending>,
+    routes: Arc<Routes>,
+    other_tx: mpsc::UnboundedSender<StreamEvent>,
+) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Ok(message) = serde_json::from_str::<serde_json::Value>(&line) else {
+            continue;
+        };
+        if message.get("method").is_some() {
+            // Notifications share StreamEvent's `{ method, params }` shape
+            let Ok(event) = serde_json::from_value::<StreamEvent>(message) else {
+                continue;
+            };
+            let exited = matches!(event, StreamEvent::Exit { .. });
+            let mut routes = routes.lock().unwrap();
+            match event.exec_id().and_then(|id| routes.get(id)).cloned() {
+                Some(route) => {
+                    if exited {
+                        routes.remove(event.exec_id().unwrap_or_default());
+                    }
+                    let _ = route.send(event);
+                }
+                None => {
+                    let _ = other_tx.send(event);
+                }
+            }
+        } else if let Ok(response) = serde_json::from_value::<Response>(message) {
+            let waiter = response
+                .id
+                .as_u64()
+                .and_then(|id| pending.lock().unwrap().as_mut()?.remove(&id));
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(response);
+            }
+        }
+    }
+    pending.lock().unwrap().take();
+    routes.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{RpcHandler, SpawnParams, INTERNAL_ERROR};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_routes_responses_and_events() {
+        let (client_io, agent_io) = tokio::io::duplex(64 * 1024);
+        let (client_read, client_write) = tokio::io::split(client_io);
+        let (agent_read, agent_write) = tokio::io::split(agent_io);
+        let (client, mut other) = Client::new(client_read, client_write);
+
+        // A scripted agent using the real server-side framing
+        let agent = tokio::spawn(async move {
+            let mut rpc = RpcHandler::new(agent_read, agent_write);
+            let exec = rpc.read_request().await.unwrap().unwrap();
+            assert_eq!(exec.method, "exec");
+            assert_eq!(exec.params["cmd"], "echo");
+            let exec_id = exec.params["exec_id"].as_str().unwrap().to_string();
+            // Output can race ahead of the response
+            rpc.send_event(StreamEvent::Stdout { exec_id: exec_id.clone(), seq: 1, chunk: "hi\n".into() }).await.unwrap();
+            rpc.send_response(Response::success(exec.id.unwrap(), serde_json::json!({ "exec_id": exec_id })))
+                .await
+                .unwrap();
+            rpc.send_event(StreamEvent::ArtifactRemoved { root: "/output".into(), path: "old.png".into() }).await.unwrap();
+            rpc.send_event(StreamEvent::Exit {
+                exec_id,
+                code: 0,
+                signal: None,
+                stdout_bytes: Some(3),
+                stderr_bytes: None,
+                truncated: false,
+                cpu_user_ms: None,
+                cpu_sys_ms: None,
+                max_rss_kb: None,
+                spawn_latency_ms: None,
+                time_to_first_output_ms: None,
+            })
+            .await
+            .unwrap();
+
+            let input = rpc.read_request().await.unwrap().unwrap();
+            assert_eq!(input.parse_params::<ReplInputParams>().unwrap().bytes().unwrap(), b"\x00\xffdata");
+            rpc.send_response(Response::error(input.id.unwrap(), INTERNAL_ERROR, "No such process"))
+                .await
+                .unwrap();
+        });
+
+        let params = ExecParams {
+            spawn: SpawnParams { cmd: "echo".into(), args: vec!["hi".into()], ..Default::default() },
+            ..Default::default()
+        };
+        let events: Vec<StreamEvent> = client.exec(params).await.unwrap().collect().await;
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], StreamEvent::Stdout { chunk, .. } if chunk == "hi\n"));
+        assert!(matches!(&events[1], StreamEvent::Exit { code: 0, .. }));
+        assert!(matches!(other.recv().await, Some(StreamEvent::ArtifactRemoved { path, .. }) if path == "old.png"));
+
+        let err = client.repl_input(None, b"\x00\xffdata").await.unwrap_err();
+        assert!(matches!(&err, ClientError::Rpc(e) if e.code == INTERNAL_ERROR), "{}", err);
+        agent.await.unwrap();
+
+        // Calls fail rather than hang once the agent is gone
+        let err = client.call::<_, serde_json::Value>("ping", serde_json::json!({})).await.unwrap_err();
+        assert!(matches!(err, ClientError::Closed | ClientError::Io(_)), "{}", err);
+    }
+}
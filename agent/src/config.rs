@@ -0,0 +1,907 @@
+//! Agent startup configuration.
+//!
+//! Settings come from command-line flags, falling back to `BOXED_*`
+//! environment variables and then to defaults suited to a sandbox.
+
+use crate::executor::{DEFAULT_MAX_CHUNK_BYTES, DEFAULT_OUTPUT_WINDOW, DEFAULT_STDIN_TIMEOUT};
+use crate::fs_watcher::{self, StreamMode, WatchOptions, WatcherPolicy};
+use crate::ignore::IgnoreSet;
+use crate::logs;
+use crate::rpc::Framing;
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Directory watched for artifacts when nothing else is configured.
+const DEFAULT_OUTPUT_DIR: &str = "/output";
+
+/// Shell used for `exec` with `shell` set when nothing else is configured.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Directory the Control Plane may always inspect, alongside the output dirs.
+const DEFAULT_FS_ROOT: &str = "/workspace";
+
+/// Where the artifact manifest is kept when nothing else is configured;
+/// outside the output dirs, so it is never reported as an artifact itself.
+const DEFAULT_ARTIFACT_MANIFEST: &str = "/var/lib/boxed/artifact-manifest.json";
+
+/// Configuration resolved once at agent startup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentConfig {
+    /// Directories watched for artifacts
+    pub output_dirs: Vec<PathBuf>,
+    /// Settings for particular output dirs, replacing the shared ones
+    pub output_policies: Vec<OutputPolicy>,
+    /// Message framing used until the client negotiates another one
+    pub framing: Framing,
+    /// Gitignore-style patterns for paths the watcher should skip
+    pub ignore_patterns: Vec<String>,
+    /// Size from which artifacts are gzipped; unset disables compression
+    pub compress_threshold: Option<u64>,
+    /// Largest artifact sent in a single message
+    pub max_inline_bytes: u64,
+    /// Largest artifact reported at all; bigger files are announced as
+    /// skipped. `None` (the default) reports files of any size
+    pub max_artifact_bytes: Option<u64>,
+    /// Most distinct artifacts streamed in a session; later new files are
+    /// counted but not sent. `None` (the default) streams them all
+    pub max_artifacts: Option<usize>,
+    /// Read symlinks whose targets stay inside the watch directory
+    pub follow_symlinks: bool,
+    /// Directories `fs.list` may inspect; paths outside them are rejected
+    pub fs_roots: Vec<PathBuf>,
+    /// `(pattern, mime)` pairs forcing the MIME type of matching artifacts
+    pub mime_overrides: Vec<(String, String)>,
+    /// Patterns an artifact must match to be reported; empty allows all
+    pub allow_patterns: Vec<String>,
+    /// Largest piece of process output forwarded at once; longer lines are
+    /// split across several events
+    pub max_chunk_bytes: usize,
+    /// How long process output is gathered into one event; zero sends each
+    /// read as it happens
+    pub output_window: Duration,
+    /// How long a write to a process's stdin may wait for the process to
+    /// read it before the request fails
+    pub stdin_timeout: Duration,
+    /// Prepended to every artifact path, normalized to `/`-separated segments
+    pub path_prefix: Option<String>,
+    /// Most artifacts the watcher reads at the same time
+    pub max_concurrent_reads: usize,
+    /// Shell that runs commands sent with `shell` set, invoked as `-lc`
+    pub shell: String,
+    /// How often an idle-proof `keepalive` notification is sent; `None`
+    /// (the default, or 0 seconds) sends none
+    pub keepalive: Option<Duration>,
+    /// File recording streamed artifacts across restarts; `None` streams
+    /// everything found at startup, for stateless sessions
+    pub artifact_manifest: Option<PathBuf>,
+    /// How long the agent may sit with no requests and nothing running
+    /// before it shuts itself down; `None` (the default) never does
+    pub idle_timeout: Option<Duration>,
+    /// Most processes running at once; further commands wait their turn.
+    /// `None` (the default) runs everything immediately
+    pub max_concurrent_processes: Option<usize>,
+    /// Most of the agent's own log records kept for `logs.tail`
+    pub log_buffer_lines: usize,
+    /// Cgroup (v2) directory each command gets a cgroup of its own under,
+    /// for kernel-enforced limits and accounting; `None` (the default)
+    /// relies on rlimits
+    pub cgroup_parent: Option<PathBuf>,
+}
+
+/// Artifact settings for one output dir, from `--output-policy`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputPolicy {
+    pub dir: PathBuf,
+    /// How file contents are sent
+    pub mode: StreamMode,
+    /// Largest artifact reported at all, in place of `--max-artifact-size`
+    pub max_artifact_bytes: Option<u64>,
+    /// Largest artifact sent in a single message, in place of `--max-inline-size`
+    pub max_inline_bytes: Option<u64>,
+    /// Ignore patterns applied after the shared ones
+    pub ignore_patterns: Vec<String>,
+}
+
+impl AgentConfig {
+    /// Load configuration from the process arguments and environment.
+    pub fn load() -> Result<Self> {
+        Self::parse(std::env::args().skip(1), |key| std::env::var(key).ok())
+    }
+
+    /// Build configuration from explicit arguments and an environment lookup.
+    pub(crate) fn parse(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
+        let mut output_dirs = Vec::new();
+        let mut output_policies = Vec::new();
+        let mut framing = None;
+        let mut ignore_patterns = Vec::new();
+        let mut compress_threshold = None;
+        let mut max_inline_bytes = None;
+        let mut max_artifact_bytes = None;
+        let mut max_artifacts = None;
+        let mut follow_symlinks = false;
+        let mut fs_roots = Vec::new();
+        let mut mime_overrides = Vec::new();
+        let mut allow_patterns = Vec::new();
+        let mut max_chunk_bytes = None;
+        let mut output_window = None;
+        let mut stdin_timeout = None;
+        let mut path_prefix = None;
+        let mut max_concurrent_reads = None;
+        let mut shell = None;
+        let mut keepalive = None;
+        let mut artifact_manifest = None;
+        let mut idle_timeout = None;
+        let mut max_concurrent_processes = None;
+        let mut log_buffer_lines = None;
+        let mut cgroup_parent = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--output-dir" => {
+                    let dir = args.next().context("--output-dir requires a path")?;
+                    output_dirs.push(PathBuf::from(dir));
+                }
+                "--output-policy" => {
+                    let value = args.next().context("--output-policy requires DIR:KEY=VALUE,...")?;
+                    output_policies.push(parse_output_policy(&value)?);
+                }
+                "--ignore" => {
+                    ignore_patterns.push(args.next().context("--ignore requires a pattern")?);
+                }
+                "--ignore-file" => {
+                    let path = args.next().context("--ignore-file requires a path")?;
+                    let contents = std::fs::read_to_string(&path)
+                        .with_context(|| format!("Failed to read ignore file {}", path))?;
+                    ignore_patterns.extend(contents.lines().map(str::to_string));
+                }
+                "--framing" => {
+                    let value = args.next().context("--framing requires a value")?;
+                    framing = Some(value.parse()?);
+                }
+                "--compress-threshold" => {
+                    let value = args.next().context("--compress-threshold requires a size in bytes")?;
+                    compress_threshold = Some(parse_size(&value)?);
+                }
+                "--max-inline-size" => {
+                    let value = args.next().context("--max-inline-size requires a size in bytes")?;
+                    max_inline_bytes = Some(parse_size(&value)?);
+                }
+                "--max-artifact-size" => {
+                    let value = args.next().context("--max-artifact-size requires a size in bytes")?;
+                    max_artifact_bytes = Some(parse_size(&value)?);
+                }
+                "--max-artifacts" => {
+                    let value = args.next().context("--max-artifacts requires a number")?;
+                    max_artifacts = Some(parse_count(&value)?);
+                }
+                "--follow-symlinks" => follow_symlinks = true,
+                "--max-chunk-size" => {
+                    let value = args.next().context("--max-chunk-size requires a size in bytes")?;
+                    max_chunk_bytes = Some(parse_chunk_size(&value)?);
+                }
+                "--output-window-ms" => {
+                    let value = args.next().context("--output-window-ms requires milliseconds")?;
+                    output_window = Some(parse_millis(&value)?);
+                }
+                "--stdin-timeout-ms" => {
+                    let value = args.next().context("--stdin-timeout-ms requires milliseconds")?;
+                    stdin_timeout = Some(parse_millis(&value)?);
+                }
+                "--mime" => {
+                    let mapping = args.next().context("--mime requires PATTERN=TYPE")?;
+                    mime_overrides.push(parse_mime_override(&mapping)?);
+                }
+                "--allow" => {
+                    allow_patterns.push(args.next().context("--allow requires a pattern")?);
+                }
+                "--max-concurrent-reads" => {
+                    let value = args.next().context("--max-concurrent-reads requires a number")?;
+                    max_concurrent_reads = Some(parse_count(&value)?);
+                }
+                "--max-concurrent-processes" => {
+                    let value = args.next().context("--max-concurrent-processes requires a number")?;
+                    max_concurrent_processes = Some(parse_count(&value)?);
+                }
+                "--log-buffer-lines" => {
+                    let value = args.next().context("--log-buffer-lines requires a number")?;
+                    log_buffer_lines = Some(parse_count(&value)?);
+                }
+                "--cgroup-parent" => {
+                    cgroup_parent = Some(PathBuf::from(args.next().context("--cgroup-parent requires a path")?));
+                }
+                "--keepalive-secs" => {
+                    let value = args.next().context("--keepalive-secs requires a number of seconds")?;
+                    keepalive = Some(parse_interval(&value)?);
+                }
+                "--idle-timeout-secs" => {
+                    let value = args.next().context("--idle-timeout-secs requires a number of seconds")?;
+                    idle_timeout = Some(parse_interval(&value)?);
+                }
+                "--shell" => {
+                    shell = Some(args.next().context("--shell requires a path")?);
+                }
+                "--path-prefix" => {
+                    path_prefix = Some(args.next().context("--path-prefix requires a prefix")?);
+                }
+                "--artifact-manifest" => {
+                    let path = args.next().context("--artifact-manifest requires a path")?;
+                    artifact_manifest = Some(Some(PathBuf::from(path)));
+                }
+                "--no-artifact-manifest" => artifact_manifest = Some(None),
+                "--fs-root" => {
+                    fs_roots.push(PathBuf::from(args.next().context("--fs-root requires a path")?));
+                }
+                _ => anyhow::bail!("Unknown argument '{}'", arg),
+            }
+        }
+
+        // Flags win over the environment; BOXED_OUTPUT_DIR is PATH-style
+        if output_dirs.is_empty() {
+            if let Some(dirs) = env("BOXED_OUTPUT_DIR") {
+                output_dirs = std::env::split_paths(&dirs)
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .collect();
+            }
+        }
+        if output_dirs.is_empty() {
+            output_dirs.push(PathBuf::from(DEFAULT_OUTPUT_DIR));
+        }
+
+        // BOXED_OUTPUT_POLICY is semicolon-separated and adds to any flags
+        for policy in env("BOXED_OUTPUT_POLICY").iter().flat_map(|v| v.split(';')) {
+            if !policy.trim().is_empty() {
+                output_policies.push(parse_output_policy(policy)?);
+            }
+        }
+        for (index, policy) in output_policies.iter().enumerate() {
+            if !output_dirs.contains(&policy.dir) {
+                anyhow::bail!("Output policy for {} names a directory that is not watched", policy.dir.display());
+            }
+            if output_policies[..index].iter().any(|earlier| earlier.dir == policy.dir) {
+                anyhow::bail!("Output policy for {} is given more than once", policy.dir.display());
+            }
+        }
+
+        let framing = match framing {
+            Some(framing) => framing,
+            None => match env("BOXED_FRAMING") {
+                Some(value) => value.parse()?,
+                None => Framing::default(),
+            },
+        };
+
+        // BOXED_IGNORE is comma-separated and adds to any flags
+        if let Some(patterns) = env("BOXED_IGNORE") {
+            ignore_patterns.extend(split_list(&patterns));
+        }
+
+        let compress_threshold = match compress_threshold {
+            Some(threshold) => Some(threshold),
+            None => env("BOXED_COMPRESS_THRESHOLD").map(|value| parse_size(&value)).transpose()?,
+        };
+
+        let max_artifact_bytes = match max_artifact_bytes {
+            Some(size) => Some(size),
+            None => env("BOXED_MAX_ARTIFACT_SIZE").map(|value| parse_size(&value)).transpose()?,
+        };
+
+        let max_artifacts = match max_artifacts {
+            Some(count) => Some(count),
+            None => env("BOXED_MAX_ARTIFACTS").map(|value| parse_count(&value)).transpose()?,
+        };
+
+        // BOXED_FS_ROOT is PATH-style; by default the workspace and every
+        // watched directory are inspectable
+        if fs_roots.is_empty() {
+            if let Some(roots) = env("BOXED_FS_ROOT") {
+                fs_roots = std::env::split_paths(&roots)
+                    .filter(|root| !root.as_os_str().is_empty())
+                    .collect();
+            }
+        }
+        if fs_roots.is_empty() {
+            fs_roots.push(PathBuf::from(DEFAULT_FS_ROOT));
+            fs_roots.extend(output_dirs.iter().cloned());
+        }
+
+        // BOXED_MIME and BOXED_ALLOW are comma-separated and add to any flags
+        for mapping in env("BOXED_MIME").iter().flat_map(|v| split_list(v)) {
+            mime_overrides.push(parse_mime_override(&mapping)?);
+        }
+        allow_patterns.extend(env("BOXED_ALLOW").iter().flat_map(|v| split_list(v)));
+
+        let max_chunk_bytes = match max_chunk_bytes {
+            Some(size) => size,
+            None => env("BOXED_MAX_CHUNK_SIZE")
+                .map(|value| parse_chunk_size(&value))
+                .transpose()?
+                .unwrap_or(DEFAULT_MAX_CHUNK_BYTES),
+        };
+
+        let max_inline_bytes = match max_inline_bytes {
+            Some(size) => size,
+            None => env("BOXED_MAX_INLINE_SIZE")
+                .map(|value| parse_size(&value))
+                .transpose()?
+                .unwrap_or(fs_watcher::DEFAULT_MAX_INLINE_BYTES),
+        };
+
+        let output_window = match output_window {
+            Some(window) => window,
+            None => env("BOXED_OUTPUT_WINDOW_MS")
+                .map(|value| parse_millis(&value))
+                .transpose()?
+                .unwrap_or(DEFAULT_OUTPUT_WINDOW),
+        };
+
+        let stdin_timeout = match stdin_timeout {
+            Some(timeout) => timeout,
+            None => env("BOXED_STDIN_TIMEOUT_MS")
+                .map(|value| parse_millis(&value))
+                .transpose()?
+                .unwrap_or(DEFAULT_STDIN_TIMEOUT),
+        };
+
+        let max_concurrent_reads = match max_concurrent_reads {
+            Some(count) => count,
+            None => env("BOXED_MAX_CONCURRENT_READS")
+                .map(|value| parse_count(&value))
+                .transpose()?
+                .unwrap_or(fs_watcher::DEFAULT_CONCURRENT_READS),
+        };
+
+        let max_concurrent_processes = match max_concurrent_processes {
+            Some(count) => Some(count),
+            None => env("BOXED_MAX_CONCURRENT_PROCESSES").map(|value| parse_count(&value)).transpose()?,
+        };
+
+        let log_buffer_lines = match log_buffer_lines {
+            Some(count) => count,
+            None => env("BOXED_LOG_BUFFER_LINES")
+                .map(|value| parse_count(&value))
+                .transpose()?
+                .unwrap_or(logs::DEFAULT_LOG_BUFFER_LINES),
+        };
+
+        let keepalive = match keepalive {
+            Some(interval) => interval,
+            None => env("BOXED_KEEPALIVE_SECS").map(|value| parse_interval(&value)).transpose()?.flatten(),
+        };
+
+        let idle_timeout = match idle_timeout {
+            Some(timeout) => timeout,
+            None => env("BOXED_IDLE_TIMEOUT_SECS").map(|value| parse_interval(&value)).transpose()?.flatten(),
+        };
+
+        // An empty BOXED_ARTIFACT_MANIFEST turns the manifest off
+        let artifact_manifest = match artifact_manifest {
+            Some(path) => path,
+            None => match env("BOXED_ARTIFACT_MANIFEST") {
+                Some(path) => (!path.is_empty()).then(|| PathBuf::from(path)),
+                None => Some(PathBuf::from(DEFAULT_ARTIFACT_MANIFEST)),
+            },
+        };
+
+        let cgroup_parent = cgroup_parent.or_else(|| env("BOXED_CGROUP_PARENT").map(PathBuf::from));
+
+        let shell = shell
+            .or_else(|| env("BOXED_SHELL"))
+            .unwrap_or_else(|| DEFAULT_SHELL.to_string());
+
+        let path_prefix = match path_prefix.or_else(|| env("BOXED_PATH_PREFIX")) {
+            Some(prefix) => fs_watcher::normalize_prefix(&prefix)?,
+            None => None,
+        };
+
+        if !follow_symlinks {
+            follow_symlinks = matches!(env("BOXED_FOLLOW_SYMLINKS").as_deref(), Some("1" | "true"));
+        }
+
+        Ok(Self {
+            output_dirs,
+            output_policies,
+            framing,
+            ignore_patterns,
+            compress_threshold,
+            max_inline_bytes,
+            max_artifact_bytes,
+            max_artifacts,
+            follow_symlinks,
+            fs_roots,
+            mime_overrides,
+            allow_patterns,
+            max_chunk_bytes,
+            output_window,
+            stdin_timeout,
+            path_prefix,
+            max_concurrent_reads,
+            shell,
+            keepalive,
+            artifact_manifest,
+            idle_timeout,
+            max_concurrent_processes,
+            log_buffer_lines,
+            cgroup_parent,
+        })
+    }
+
+    /// The watcher's ignore rules.
+    pub fn ignore_set(&self) -> IgnoreSet {
+        IgnoreSet::new(&self.ignore_patterns)
+    }
+
+    /// Everything the artifact watcher needs besides its directories.
+    pub fn watch_options(&self) -> WatchOptions {
+        WatchOptions {
+            ignore: self.ignore_set(),
+            compress_threshold: self.compress_threshold,
+            follow_symlinks: self.follow_symlinks,
+            mime_overrides: self
+                .mime_overrides
+                .iter()
+                .map(|(pattern, mime)| (IgnoreSet::new([pattern]), mime.clone()))
+                .collect(),
+            allow: (!self.allow_patterns.is_empty()).then(|| IgnoreSet::new(&self.allow_patterns)),
+            path_prefix: self.path_prefix.clone(),
+            max_concurrent_reads: self.max_concurrent_reads,
+            max_inline_bytes: self.max_inline_bytes,
+            max_artifact_bytes: self.max_artifact_bytes,
+            mode: StreamMode::Auto,
+            max_artifacts: self.max_artifacts,
+            manifest: self.artifact_manifest.clone(),
+        }
+    }
+
+    /// Every output dir with the policy configured for it, if any.
+    pub fn watch_roots(&self) -> Vec<(PathBuf, WatcherPolicy)> {
+        self.output_dirs
+            .iter()
+            .map(|dir| {
+                let policy = match self.output_policies.iter().find(|policy| &policy.dir == dir) {
+                    Some(policy) => WatcherPolicy {
+                        max_artifact_bytes: policy.max_artifact_bytes,
+                        max_inline_bytes: policy.max_inline_bytes,
+                        ignore: IgnoreSet::new(&policy.ignore_patterns),
+                        mode: policy.mode,
+                    },
+                    None => WatcherPolicy::default(),
+                };
+                (dir.clone(), policy)
+            })
+            .collect()
+    }
+}
+
+/// Split a comma-separated setting, dropping blank items.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parse a `PATTERN=TYPE` MIME override such as `*.parquet=application/vnd.apache.parquet`.
+fn parse_mime_override(value: &str) -> Result<(String, String)> {
+    match value.split_once('=') {
+        Some((pattern, mime)) if !pattern.trim().is_empty() && mime.contains('/') => {
+            Ok((pattern.trim().to_string(), mime.trim().to_string()))
+        }
+        _ => anyhow::bail!("Invalid MIME override '{}': expected PATTERN=TYPE", value),
+    }
+}
+
+/// Parse a `DIR:KEY=VALUE,...` output policy such as
+/// `/output/models:mode=upload,max-inline-size=0`; `ignore` may be repeated.
+fn parse_output_policy(value: &str) -> Result<OutputPolicy> {
+    let Some((dir, settings)) = value.split_once(':').filter(|(dir, _)| !dir.trim().is_empty()) else {
+        anyhow::bail!("Invalid output policy '{}': expected DIR:KEY=VALUE,...", value);
+    };
+    let mut policy = OutputPolicy {
+        dir: PathBuf::from(dir.trim()),
+        ..Default::default()
+    };
+    for setting in split_list(settings) {
+        let (key, value) = setting
+            .split_once('=')
+            .with_context(|| format!("Invalid output policy setting '{}': expected KEY=VALUE", setting))?;
+        let value = value.trim();
+        match key.trim() {
+            "mode" => policy.mode = StreamMode::for_name(value)?,
+            "max-artifact-size" => policy.max_artifact_bytes = Some(parse_size(value)?),
+            "max-inline-size" => policy.max_inline_bytes = Some(parse_size(value)?),
+            "ignore" => policy.ignore_patterns.push(value.to_string()),
+            key => anyhow::bail!("Unknown output policy setting '{}'", key),
+        }
+    }
+    Ok(policy)
+}
+
+fn parse_size(value: &str) -> Result<u64> {
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid size '{}': expected a number of bytes", value))
+}
+
+/// Parse a count limit, which must allow at least one.
+fn parse_count(value: &str) -> Result<usize> {
+    match value.trim().parse() {
+        Ok(0) | Err(_) => anyhow::bail!("Invalid count '{}': expected a positive number", value),
+        Ok(count) => Ok(count),
+    }
+}
+
+/// Parse a duration given in whole milliseconds.
+fn parse_millis(value: &str) -> Result<Duration> {
+    let millis = value
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid duration '{}': expected milliseconds", value))?;
+    Ok(Duration::from_millis(millis))
+}
+
+/// Parse an interval in whole seconds, where 0 turns the feature off.
+fn parse_interval(value: &str) -> Result<Option<Duration>> {
+    let seconds: u64 = value
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid interval '{}': expected seconds", value))?;
+    Ok((seconds > 0).then(|| Duration::from_secs(seconds)))
+}
+
+/// Parse an output chunk size, which must leave room for at least one byte.
+fn parse_chunk_size(value: &str) -> Result<usize> {
+    match parse_size(value)? {
+        0 => anyhow::bail!("Invalid chunk size '{}': must be at least 1 byte", value),
+        size => Ok(size as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_defaults_to_output() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.output_dirs, vec![PathBuf::from("/output")]);
+    }
+
+    #[test]
+    fn test_output_dirs_from_env() {
+        let config = AgentConfig::parse(args(&[]), |key| {
+            (key == "BOXED_OUTPUT_DIR").then(|| "/output:/workspace/dist".to_string())
+        })
+        .unwrap();
+        assert_eq!(
+            config.output_dirs,
+            vec![PathBuf::from("/output"), PathBuf::from("/workspace/dist")]
+        );
+    }
+
+    #[test]
+    fn test_flags_override_env() {
+        let config = AgentConfig::parse(args(&["--output-dir", "/tmp/a", "--output-dir", "/tmp/b"]), |key| {
+            (key == "BOXED_OUTPUT_DIR").then(|| "/ignored".to_string())
+        })
+        .unwrap();
+        assert_eq!(
+            config.output_dirs,
+            vec![PathBuf::from("/tmp/a"), PathBuf::from("/tmp/b")]
+        );
+    }
+
+    #[test]
+    fn test_framing_from_flag_or_env() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.framing, Framing::Line);
+
+        let config = AgentConfig::parse(args(&[]), |key| {
+            (key == "BOXED_FRAMING").then(|| "content-length".to_string())
+        })
+        .unwrap();
+        assert_eq!(config.framing, Framing::ContentLength);
+
+        let config = AgentConfig::parse(args(&["--framing", "line"]), |key| {
+            (key == "BOXED_FRAMING").then(|| "content-length".to_string())
+        })
+        .unwrap();
+        assert_eq!(config.framing, Framing::Line);
+
+        assert!(AgentConfig::parse(args(&["--framing", "xml"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_ignore_patterns_from_flags_and_env() {
+        let config = AgentConfig::parse(args(&["--ignore", "*.tmp"]), |key| {
+            (key == "BOXED_IGNORE").then(|| "node_modules, **/cache/**".to_string())
+        })
+        .unwrap();
+        assert_eq!(config.ignore_patterns, vec!["*.tmp", "node_modules", "**/cache/**"]);
+        assert!(config.ignore_set().is_ignored(std::path::Path::new("a/cache/b")));
+    }
+
+    #[test]
+    fn test_compress_threshold_from_flag_or_env() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.compress_threshold, None);
+
+        let config = AgentConfig::parse(args(&[]), |key| {
+            (key == "BOXED_COMPRESS_THRESHOLD").then(|| "65536".to_string())
+        })
+        .unwrap();
+        assert_eq!(config.watch_options().compress_threshold, Some(65536));
+
+        let config = AgentConfig::parse(args(&["--compress-threshold", "0"]), |key| {
+            (key == "BOXED_COMPRESS_THRESHOLD").then(|| "65536".to_string())
+        });
+        assert_eq!(config.unwrap().compress_threshold, Some(0));
+
+        assert!(AgentConfig::parse(args(&["--compress-threshold", "1MB"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_max_chunk_size_from_flag_or_env() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.max_chunk_bytes, DEFAULT_MAX_CHUNK_BYTES);
+
+        let env = |key: &str| (key == "BOXED_MAX_CHUNK_SIZE").then(|| "4096".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().max_chunk_bytes, 4096);
+        let config = AgentConfig::parse(args(&["--max-chunk-size", "1024"]), env).unwrap();
+        assert_eq!(config.max_chunk_bytes, 1024);
+
+        assert!(AgentConfig::parse(args(&["--max-chunk-size", "0"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_max_inline_size_from_flag_or_env() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.watch_options().max_inline_bytes, fs_watcher::DEFAULT_MAX_INLINE_BYTES);
+
+        let env = |key: &str| (key == "BOXED_MAX_INLINE_SIZE").then(|| "65536".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().max_inline_bytes, 65536);
+        let config = AgentConfig::parse(args(&["--max-inline-size", "1024"]), env).unwrap();
+        assert_eq!(config.watch_options().max_inline_bytes, 1024);
+
+        assert!(AgentConfig::parse(args(&["--max-inline-size", "10MB"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_max_artifact_size_from_flag_or_env() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.watch_options().max_artifact_bytes, None);
+
+        let env = |key: &str| (key == "BOXED_MAX_ARTIFACT_SIZE").then(|| "1048576".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().max_artifact_bytes, Some(1048576));
+        let config = AgentConfig::parse(args(&["--max-artifact-size", "4096"]), env).unwrap();
+        assert_eq!(config.watch_options().max_artifact_bytes, Some(4096));
+    }
+
+    #[test]
+    fn test_max_artifacts_from_flag_or_env() {
+        assert_eq!(AgentConfig::parse(args(&[]), |_| None).unwrap().watch_options().max_artifacts, None);
+
+        let env = |key: &str| (key == "BOXED_MAX_ARTIFACTS").then(|| "500".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().max_artifacts, Some(500));
+        let config = AgentConfig::parse(args(&["--max-artifacts", "10"]), env).unwrap();
+        assert_eq!(config.watch_options().max_artifacts, Some(10));
+        assert!(AgentConfig::parse(args(&["--max-artifacts", "0"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_output_window_from_flag_or_env() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.output_window, DEFAULT_OUTPUT_WINDOW);
+
+        let env = |key: &str| (key == "BOXED_OUTPUT_WINDOW_MS").then(|| "10".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().output_window, Duration::from_millis(10));
+        let config = AgentConfig::parse(args(&["--output-window-ms", "0"]), env).unwrap();
+        assert_eq!(config.output_window, Duration::ZERO);
+
+        assert!(AgentConfig::parse(args(&["--output-window-ms", "soon"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_stdin_timeout_from_flag_or_env() {
+        assert_eq!(AgentConfig::parse(args(&[]), |_| None).unwrap().stdin_timeout, DEFAULT_STDIN_TIMEOUT);
+
+        let env = |key: &str| (key == "BOXED_STDIN_TIMEOUT_MS").then(|| "30000".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().stdin_timeout, Duration::from_secs(30));
+        let config = AgentConfig::parse(args(&["--stdin-timeout-ms", "250"]), env).unwrap();
+        assert_eq!(config.stdin_timeout, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_max_concurrent_reads_from_flag_or_env() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.watch_options().max_concurrent_reads, fs_watcher::DEFAULT_CONCURRENT_READS);
+
+        let env = |key: &str| (key == "BOXED_MAX_CONCURRENT_READS").then(|| "8".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().max_concurrent_reads, 8);
+        let config = AgentConfig::parse(args(&["--max-concurrent-reads", "2"]), env).unwrap();
+        assert_eq!(config.max_concurrent_reads, 2);
+
+        assert!(AgentConfig::parse(args(&["--max-concurrent-reads", "0"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_log_buffer_lines_from_flag_or_env() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.log_buffer_lines, logs::DEFAULT_LOG_BUFFER_LINES);
+        let env = |key: &str| (key == "BOXED_LOG_BUFFER_LINES").then(|| "200".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().log_buffer_lines, 200);
+        let config = AgentConfig::parse(args(&["--log-buffer-lines", "50"]), env).unwrap();
+        assert_eq!(config.log_buffer_lines, 50);
+        assert!(AgentConfig::parse(args(&["--log-buffer-lines", "0"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_keepalive_is_off_unless_configured() {
+        assert_eq!(AgentConfig::parse(args(&[]), |_| None).unwrap().keepalive, None);
+
+        let env = |key: &str| (key == "BOXED_KEEPALIVE_SECS").then(|| "30".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().keepalive, Some(Duration::from_secs(30)));
+        // An explicit 0 on the command line overrides the environment
+        assert_eq!(AgentConfig::parse(args(&["--keepalive-secs", "0"]), env).unwrap().keepalive, None);
+
+        assert!(AgentConfig::parse(args(&["--keepalive-secs", "-1"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_artifact_manifest_can_be_moved_or_disabled() {
+        let config = AgentConfig::parse(args(&[]), |_| None).unwrap();
+        assert_eq!(config.watch_options().manifest, Some(PathBuf::from(DEFAULT_ARTIFACT_MANIFEST)));
+
+        let env = |key: &str| (key == "BOXED_ARTIFACT_MANIFEST").then(|| "/state/manifest.json".to_string());
+        let config = AgentConfig::parse(args(&[]), env).unwrap();
+        assert_eq!(config.artifact_manifest, Some(PathBuf::from("/state/manifest.json")));
+        assert_eq!(AgentConfig::parse(args(&["--no-artifact-manifest"]), env).unwrap().artifact_manifest, None);
+        let config = AgentConfig::parse(args(&["--artifact-manifest", "/tmp/m.json"]), env).unwrap();
+        assert_eq!(config.artifact_manifest, Some(PathBuf::from("/tmp/m.json")));
+
+        let env = |key: &str| (key == "BOXED_ARTIFACT_MANIFEST").then(String::new);
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().artifact_manifest, None);
+    }
+
+    #[test]
+    fn test_max_concurrent_processes_is_unlimited_unless_configured() {
+        assert_eq!(AgentConfig::parse(args(&[]), |_| None).unwrap().max_concurrent_processes, None);
+
+        let env = |key: &str| (key == "BOXED_MAX_CONCURRENT_PROCESSES").then(|| "4".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().max_concurrent_processes, Some(4));
+        let config = AgentConfig::parse(args(&["--max-concurrent-processes", "2"]), env).unwrap();
+        assert_eq!(config.max_concurrent_processes, Some(2));
+        assert!(AgentConfig::parse(args(&["--max-concurrent-processes", "0"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_idle_timeout_is_off_unless_configured() {
+        assert_eq!(AgentConfig::parse(args(&[]), |_| None).unwrap().idle_timeout, None);
+
+        let env = |key: &str| (key == "BOXED_IDLE_TIMEOUT_SECS").then(|| "600".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().idle_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(AgentConfig::parse(args(&["--idle-timeout-secs", "0"]), env).unwrap().idle_timeout, None);
+    }
+
+    #[test]
+    fn test_cgroup_parent_from_flag_or_env() {
+        assert_eq!(AgentConfig::parse(args(&[]), |_| None).unwrap().cgroup_parent, None);
+
+        let env = |key: &str| (key == "BOXED_CGROUP_PARENT").then(|| "/sys/fs/cgroup/boxed".to_string());
+        let config = AgentConfig::parse(args(&[]), env).unwrap();
+        assert_eq!(config.cgroup_parent, Some(PathBuf::from("/sys/fs/cgroup/boxed")));
+        let config = AgentConfig::parse(args(&["--cgroup-parent", "/sys/fs/cgroup/agent"]), env).unwrap();
+        assert_eq!(config.cgroup_parent, Some(PathBuf::from("/sys/fs/cgroup/agent")));
+    }
+
+    #[test]
+    fn test_shell_from_flag_or_env() {
+        assert_eq!(AgentConfig::parse(args(&[]), |_| None).unwrap().shell, "/bin/sh");
+        let env = |key: &str| (key == "BOXED_SHELL").then(|| "/bin/bash".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().shell, "/bin/bash");
+        let config = AgentConfig::parse(args(&["--shell", "/bin/zsh"]), env).unwrap();
+        assert_eq!(config.shell, "/bin/zsh");
+    }
+
+    #[test]
+    fn test_path_prefix_is_normalized() {
+        assert_eq!(AgentConfig::parse(args(&[]), |_| None).unwrap().path_prefix, None);
+
+        let env = |key: &str| (key == "BOXED_PATH_PREFIX").then(|| "/sessions//abc/".to_string());
+        assert_eq!(AgentConfig::parse(args(&[]), env).unwrap().path_prefix.as_deref(), Some("sessions/abc"));
+        let config = AgentConfig::parse(args(&["--path-prefix", r"workspace\out"]), env).unwrap();
+        assert_eq!(config.watch_options().path_prefix.as_deref(), Some("workspace/out"));
+
+        assert!(AgentConfig::parse(args(&["--path-prefix", "a/../../etc"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_follow_symlinks_is_opt_in() {
+        assert!(!AgentConfig::parse(args(&[]), |_| None).unwrap().follow_symlinks);
+        assert!(AgentConfig::parse(args(&["--follow-symlinks"]), |_| None).unwrap().follow_symlinks);
+        let config = AgentConfig::parse(args(&[]), |key| {
+            (key == "BOXED_FOLLOW_SYMLINKS").then(|| "true".to_string())
+        })
+        .unwrap();
+        assert!(config.watch_options().follow_symlinks);
+    }
+
+    #[test]
+    fn test_fs_roots_default_to_workspace_and_outputs() {
+        let config = AgentConfig::parse(args(&["--output-dir", "/tmp/out"]), |_| None).unwrap();
+        assert_eq!(config.fs_roots, vec![PathBuf::from("/workspace"), PathBuf::from("/tmp/out")]);
+
+        let config = AgentConfig::parse(args(&["--fs-root", "/srv"]), |key| {
+            (key == "BOXED_FS_ROOT").then(|| "/ignored".to_string())
+        })
+        .unwrap();
+        assert_eq!(config.fs_roots, vec![PathBuf::from("/srv")]);
+    }
+
+    #[test]
+    fn test_mime_overrides_and_allowlist() {
+        let config = AgentConfig::parse(
+            args(&["--mime", "*.parquet=application/vnd.apache.parquet", "--allow", "*.csv"]),
+            |key| (key == "BOXED_ALLOW").then(|| "*.parquet, plots/**".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            config.mime_overrides,
+            vec![("*.parquet".to_string(), "application/vnd.apache.parquet".to_string())]
+        );
+        assert_eq!(config.allow_patterns, vec!["*.csv", "*.parquet", "plots/**"]);
+        assert!(config.watch_options().allow.is_some());
+        assert!(AgentConfig::parse(args(&[]), |_| None).unwrap().watch_options().allow.is_none());
+
+        assert!(AgentConfig::parse(args(&["--mime", "*.parquet"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_output_policies_apply_to_their_dirs() {
+        let config = AgentConfig::parse(
+            args(&[
+                "--output-dir",
+                "/output",
+                "--output-dir",
+                "/models",
+                "--output-policy",
+                "/models:mode=upload, max-artifact-size=1073741824,ignore=*.ckpt,ignore=tmp/",
+            ]),
+            |key| (key == "BOXED_OUTPUT_POLICY").then(|| "/output:max-inline-size=0;".to_string()),
+        )
+        .unwrap();
+        let roots = config.watch_roots();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].1.max_inline_bytes, Some(0));
+        assert_eq!(roots[0].1.mode, StreamMode::Auto);
+        let (dir, models) = &roots[1];
+        assert_eq!(dir, &PathBuf::from("/models"));
+        assert_eq!(models.mode, StreamMode::Upload);
+        assert_eq!(models.max_artifact_bytes, Some(1073741824));
+        assert!(models.ignore.is_ignored(std::path::Path::new("tmp/x.bin")));
+        assert!(models.ignore.is_ignored(std::path::Path::new("last.ckpt")));
+
+        for policy in ["/elsewhere:mode=upload", "/output:mode=fast", "/output:colour=red", "mode=upload"] {
+            assert!(AgentConfig::parse(args(&["--output-policy", policy]), |_| None).is_err(), "{}", policy);
+        }
+        let twice = args(&["--output-policy", "/output:mode=upload", "--output-policy", "/output:mode=chunked"]);
+        assert!(AgentConfig::parse(twice, |_| None).is_err());
+    }
+
+    #[test]
+    fn test_rejects_unknown_flags() {
+        assert!(AgentConfig::parse(args(&["--bogus"]), |_| None).is_err());
+        assert!(AgentConfig::parse(args(&["--output-dir"]), |_| None).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Finding the core file a crashed command left behind.
+//!
+//! Where the kernel writes a core is decided machine-wide by
+//! `/proc/sys/kernel/core_pattern`, which an unprivileged agent cannot
+//! change for one process. What is supported is the plain-file form of the
+//! pattern: relative patterns such as the default `core` land in the
+//! command's working directory, absolute ones in their own directory. When
+//! the pattern pipes cores to a helper instead (`|/usr/lib/systemd/...`,
+//! apport and the like), no file appears and nothing can be captured. The
+//! image has to keep a file pattern for core capture to work.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// The kernel's template for core file names
+const CORE_PATTERN: &str = "/proc/sys/kernel/core_pattern";
+
+/// Whether `.PID` is appended to patterns that do not contain the pid
+const CORE_USES_PID: &str = "/proc/sys/kernel/core_uses_pid";
+
+/// Longest command name the kernel substitutes for `%e`
+const COMM_LEN: usize = 15;
+
+/// How far behind the system clock a file's modification time may lag
+const MTIME_SLACK: Duration = Duration::from_millis(100);
+
+/// Look for the core `pid` dumped after being started in `cwd` as `cmd`.
+///
+/// Only files modified since `since` are considered, so a stale core from an
+/// earlier run with the same pid is never picked up. File times come from a
+/// coarser clock, so a core written right away can look slightly older.
+pub async fn locate(cwd: &Path, cmd: &str, pid: u32, since: SystemTime) -> Option<PathBuf> {
+    let since = since.checked_sub(MTIME_SLACK).unwrap_or(since);
+    let pattern = tokio::fs::read_to_string(CORE_PATTERN).await.ok()?;
+    let uses_pid = tokio::fs::read_to_string(CORE_USES_PID).await.is_ok_and(|value| value.trim() == "1");
+    let (dir, name) = core_path(pattern.trim_end_matches('\n'), uses_pid, cwd, cmd, pid)?;
+
+    let mut entries = tokio::fs::read_dir(&dir).await.ok()?;
+    let mut newest: Option<(SystemTime, PathBuf)> = None;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if !name.matches(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata().await else { continue };
+        let Ok(modified) = metadata.modified() else { continue };
+        if metadata.is_file() && modified >= since && newest.as_ref().is_none_or(|(time, _)| modified > *time) {
+            newest = Some((modified, entry.path()));
+        }
+    }
+    newest.map(|(_, path)| path)
+}
+
+/// Move a core file into `dir`, named after the command it came from.
+///
+/// Falls back to copying when the core is on another filesystem.
+pub async fn collect(core: &Path, dir: &Path, exec_id: &str, pid: u32) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+    let dest = dir.join(format!("core.{}.{}", exec_id.replace('/', "_"), pid));
+    if tokio::fs::rename(core, &dest).await.is_err() {
+        tokio::fs::copy(core, &dest)
+            .await
+            .with_context(|| format!("Failed to copy core '{}'", core.display()))?;
+        let _ = tokio::fs::remove_file(core).await;
+    }
+    Ok(dest)
+}
+
+/// The directory a core is written to and a matcher for its file name, or
+/// `None` when cores are piped to a helper.
+fn core_path(pattern: &str, uses_pid: bool, cwd: &Path, cmd: &str, pid: u32) -> Option<(PathBuf, NamePattern)> {
+    if pattern.is_empty() || pattern.starts_with('|') {
+        return None;
+    }
+    let path = Path::new(pattern);
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => cwd.join(parent),
+        _ => cwd.to_path_buf(),
+    };
+    let template = path.file_name()?.to_string_lossy();
+
+    let comm: String = Path::new(cmd)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+        .chars()
+        .take(COMM_LEN)
+        .collect();
+    let mut name = NamePattern::default();
+    let mut has_pid = false;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            name.push_literal(c.encode_utf8(&mut [0; 4]));
+            continue;
+        }
+        match chars.next() {
+            Some('%') => name.push_literal("%"),
+            Some('p' | 'P' | 'i' | 'I') => {
+                has_pid = true;
+                name.push_literal(&pid.to_string());
+            }
+            Some('e') => name.push_literal(&comm),
+            // Time, signal, uid, hostname and the rest are not known here
+            Some(_) => name.push_wildcard(),
+            None => {}
+        }
+    }
+    if uses_pid && !has_pid {
+        name.push_literal(&format!(".{}", pid));
+    }
+    Some((dir, name))
+}
+
+/// A file name with some parts unknown, each matching any text.
+#[derive(Debug, Default)]
+struct NamePattern {
+    /// Literal text between wildcards; a wildcard sits between each pair
+    parts: Vec<String>,
+}
+
+impl NamePattern {
+    fn push_literal(&mut self, text: &str) {
+        match self.parts.last_mut() {
+            Some(last) => last.push_str(text),
+            None => self.parts.push(text.to_string()),
+        }
+    }
+
+    fn push_wildcard(&mut self) {
+        if self.parts.is_empty() {
+            self.parts.push(String::new());
+        }
+        self.parts.push(String::new());
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let Some((first, rest)) = self.parts.split_first() else {
+            return name.is_empty();
+        };
+        let Some(mut remaining) = name.strip_prefix(first.as_str()) else {
+            return false;
+        };
+        let Some((last, middle)) = rest.split_last() else {
+            return remaining.is_empty();
+        };
+        for part in middle {
+            match remaining.find(part.as_str()) {
+                Some(at) => remaining = &remaining[at + part.len()..],
+                None => return false,
+            }
+        }
+        remaining.ends_with(last.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_core_paths_follow_the_pattern() {
+        let cwd = Path::new("/workspace");
+        let (dir, name) = core_path("core", false, cwd, "/usr/bin/python3", 42).unwrap();
+        assert_eq!(dir, cwd);
+        assert!(name.matches("core") && !name.matches("core.42"));
+
+        let (_, name) = core_path("core", true, cwd, "python3", 42).unwrap();
+        assert!(name.matches("core.42") && !name.matches("core"));
+
+        let (dir, name) = core_path("/var/crash/core.%e.%p.%t", true, cwd, "a-very-long-command-name", 7).unwrap();
+        assert_eq!(dir, Path::new("/var/crash"));
+        assert!(name.matches("core.a-very-long-com.7.1760000000"));
+        assert!(!name.matches("core.a-very-long-com.8.1760000000"));
+
+        let (_, name) = core_path("100%%-%s-core", false, cwd, "sh", 1).unwrap();
+        assert!(name.matches("100%-11-core") && !name.matches("100%-11-core.1"));
+
+        assert!(core_path("|/usr/lib/systemd/systemd-coredump %P %u", false, cwd, "sh", 1).is_none());
+    }
+}
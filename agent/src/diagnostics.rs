@@ -0,0 +1,176 @@
+//! Structured diagnostics parsed out of a toolchain's stderr.
+//!
+//! With a format chosen for a command, each complete line of its stderr is
+//! run through that format's parser, and every diagnostic recognized is
+//! sent after the raw chunk that held it. The raw output is delivered
+//! unchanged either way; lines that are not diagnostics are left alone.
+
+use serde::Deserialize;
+
+/// Longest line kept while waiting for its newline; the rest of a longer
+/// one is skipped rather than buffered without bound
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+/// Toolchain output that can be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// rustc's `--error-format=json`: one JSON object per line
+    Rustc,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "rustc" => Ok(Format::Rustc),
+            other => anyhow::bail!("Unknown diagnostics format '{}': expected rustc", other),
+        }
+    }
+}
+
+/// One problem a toolchain reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Where the primary span starts; all `None` for diagnostics about
+    /// no code in particular, such as rustc's "aborting due to ..."
+    pub file: Option<String>,
+    pub line: Option<u64>,
+    pub col: Option<u64>,
+    /// As the toolchain puts it, e.g. "error", "warning" or "note"
+    pub severity: String,
+    pub message: String,
+}
+
+/// Splits a stream into lines and parses each as it completes.
+#[derive(Debug)]
+pub struct Parser {
+    format: Format,
+    /// The line still waiting for its newline
+    partial: String,
+    /// Dropping the rest of a line that outgrew `MAX_LINE_BYTES`
+    skipping: bool,
+}
+
+impl Parser {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            partial: String::new(),
+            skipping: false,
+        }
+    }
+
+    /// Parse every line `text` completes.
+    pub fn feed(&mut self, text: &str) -> Vec<Diagnostic> {
+        let mut found = Vec::new();
+        let mut rest = text;
+        while let Some(end) = rest.find('\n') {
+            if !self.skipping {
+                self.partial.push_str(&rest[..end]);
+                found.extend(self.parse(&self.partial));
+            }
+            self.partial.clear();
+            self.skipping = false;
+            rest = &rest[end + 1..];
+        }
+        if !self.skipping {
+            self.partial.push_str(rest);
+            if self.partial.len() > MAX_LINE_BYTES {
+                self.partial.clear();
+                self.skipping = true;
+            }
+        }
+        found
+    }
+
+    /// Parse the last line once the stream ends without a newline.
+    pub fn finish(&mut self) -> Option<Diagnostic> {
+        let line = std::mem::take(&mut self.partial);
+        self.parse(&line)
+    }
+
+    fn parse(&self, line: &str) -> Option<Diagnostic> {
+        // Terminals end lines with \r\n
+        let line = line.trim();
+        match self.format {
+            Format::Rustc => parse_rustc(line),
+        }
+    }
+}
+
+/// A line of rustc's JSON output, as far as diagnostics need it.
+#[derive(Deserialize)]
+struct RustcMessage {
+    /// Absent before Rust 1.49; "artifact" and the like are not diagnostics
+    #[serde(rename = "$message_type")]
+    message_type: Option<String>,
+    message: String,
+    level: String,
+    #[serde(default)]
+    spans: Vec<RustcSpan>,
+}
+
+#[derive(Deserialize)]
+struct RustcSpan {
+    file_name: String,
+    line_start: u64,
+    column_start: u64,
+    #[serde(default)]
+    is_primary: bool,
+}
+
+fn parse_rustc(line: &str) -> Option<Diagnostic> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let message: RustcMessage = serde_json::from_str(line).ok()?;
+    if message.message_type.as_deref().is_some_and(|kind| kind != "diagnostic") {
+        return None;
+    }
+    let span = message.spans.iter().find(|span| span.is_primary).or(message.spans.first());
+    Some(Diagnostic {
+        file: span.map(|span| span.file_name.clone()),
+        line: span.map(|span| span.line_start),
+        col: span.map(|span| span.column_start),
+        severity: message.level,
+        message: message.message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What rustc 1.95 writes for `let x: i32 = "no";`, explanation trimmed
+    const RUSTC_LINE: &str = r#"{"$message_type":"diagnostic","message":"mismatched types","code":{"code":"E0308","explanation":"Expected type did not match the received type.\n"},"level":"error","spans":[{"file_name":"src/main.rs","byte_start":23,"byte_end":26,"line_start":2,"line_end":2,"column_start":12,"column_end":15,"is_primary":false,"text":[],"label":"expected due to this","suggested_replacement":null,"suggestion_applicability":null,"expansion":null},{"file_name":"src/main.rs","byte_start":29,"byte_end":33,"line_start":2,"line_end":2,"column_start":18,"column_end":22,"is_primary":true,"text":[],"label":"expected `i32`, found `&str`","suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":"error[E0308]: mismatched types\n --> src/main.rs:2:18\n"}"#;
+
+    #[test]
+    fn test_parses_rustc_json_diagnostics() {
+        let mut parser = Parser::new(Format::Rustc);
+        // Split mid-line, with other output around it
+        let (head, tail) = RUSTC_LINE.split_at(100);
+        assert!(parser.feed(&format!("   Compiling demo v0.1.0\n{}", head)).is_empty());
+        let found = parser.feed(&format!("{}\r\n", tail));
+        assert_eq!(
+            found,
+            [Diagnostic {
+                file: Some("src/main.rs".to_string()),
+                line: Some(2),
+                col: Some(18),
+                severity: "error".to_string(),
+                message: "mismatched types".to_string(),
+            }]
+        );
+
+        let summary = r#"{"$message_type":"diagnostic","message":"aborting due to 1 previous error","code":null,"level":"error","spans":[],"children":[],"rendered":"error: aborting due to 1 previous error\n\n"}"#;
+        let artifact = r#"{"$message_type":"artifact","artifact":"demo.rmeta","emit":"metadata"}"#;
+        assert!(parser.feed(&format!("{}\n", artifact)).is_empty());
+        assert!(parser.feed(summary).is_empty());
+        let summary = parser.finish().unwrap();
+        assert_eq!((summary.file, summary.line, summary.severity.as_str()), (None, None, "error"));
+        assert_eq!(parser.finish(), None);
+
+        assert!("gcc".parse::<Format>().is_err());
+    }
+}
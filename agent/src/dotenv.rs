@@ -0,0 +1,196 @@
+//! Parser for `.env` files passed as `env_file` to `exec`.
+//!
+//! The format follows what dotenv tools commonly accept: `KEY=value` lines,
+//! an optional `export ` prefix, `#` comments, and single- or double-quoted
+//! values that may span lines. Single quotes are literal; double quotes
+//! understand `\n`, `\t`, `\\`, `\"` and `\$`. Values are never expanded, so
+//! `$HOME` stays as written. Nothing is applied to the agent's own
+//! environment.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::files;
+
+/// Largest env file read; anything bigger is almost certainly not one
+const MAX_ENV_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Read the env file at `path` into `env`, keeping entries already present.
+///
+/// `env` holds the command's own variables, which take precedence over the
+/// file. The path must stay inside `roots`.
+pub async fn load_into(path: &Path, roots: &[PathBuf], env: &mut HashMap<String, String>) -> Result<()> {
+    let data = files::read(path, roots, MAX_ENV_FILE_BYTES)
+        .await
+        .with_context(|| format!("Failed to read env file '{}'", path.display()))?;
+    let contents = String::from_utf8(data)
+        .map_err(|_| anyhow::anyhow!("Env file '{}' is not valid UTF-8", path.display()))?;
+    let vars = parse(&contents).with_context(|| format!("Invalid env file '{}'", path.display()))?;
+    for (key, value) in vars {
+        env.entry(key).or_insert(value);
+    }
+    Ok(())
+}
+
+/// Parse dotenv contents into variables, in file order.
+///
+/// A key set twice keeps its last value, as if the lines were run by a shell.
+pub fn parse(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+    let mut lines = contents.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let number = index + 1;
+        // Trailing space is only dropped once it is known to be outside quotes
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let (key, rest) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: expected KEY=VALUE", number))?;
+        let key = key.trim_end();
+        if !valid_key(key) {
+            anyhow::bail!("line {}: invalid variable name '{}'", number, key);
+        }
+
+        let rest = rest.trim_start();
+        let value = match rest.chars().next() {
+            Some(quote @ ('\'' | '"')) => {
+                let mut text = rest[1..].to_string();
+                // Keep taking lines until the closing quote turns up
+                loop {
+                    if let Some((value, tail)) = closing_quote(&text, quote) {
+                        let tail = tail.trim_start();
+                        if !tail.is_empty() && !tail.starts_with('#') {
+                            anyhow::bail!("line {}: unexpected text after closing quote", number);
+                        }
+                        break if quote == '"' { unescape(value) } else { value.to_string() };
+                    }
+                    match lines.next() {
+                        Some((_, next)) => {
+                            text.push('\n');
+                            text.push_str(next);
+                        }
+                        None => anyhow::bail!("line {}: unterminated {} quote", number, quote),
+                    }
+                }
+            }
+            // An unquoted value ends at a comment that follows whitespace
+            _ => match rest.find(" #").or_else(|| rest.find("\t#")) {
+                Some(comment) => rest[..comment].trim_end().to_string(),
+                None => rest.trim_end().to_string(),
+            },
+        };
+
+        vars.retain(|(existing, _)| existing != key);
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+/// Names as a shell would accept them, plus `.` which some tools use.
+fn valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Split quoted text at its closing quote, skipping escaped ones in `"`.
+fn closing_quote(text: &str, quote: char) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\\' if quote == '"' && !escaped => escaped = true,
+            c if c == quote && !escaped => return Some((&text[..i], &text[i + 1..])),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// Resolve the escapes double-quoted values support; others are kept as is.
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c @ ('\\' | '"' | '$')) => out.push(c),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parses_common_dotenv_syntax() {
+        let contents = r#"
+# database settings
+export DB_HOST=localhost
+DB_PORT = 5432 # default port
+PASSWORD='p@ss #1 $HOME'
+GREETING="hello\n\"world\""
+CERT="-----BEGIN-----
+abc
+-----END-----"
+EMPTY=
+DB_PORT=6543
+"#;
+        let vars = parse(contents).unwrap();
+        let get = |key: &str| vars.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        assert_eq!(get("DB_HOST"), Some("localhost"));
+        assert_eq!(get("PASSWORD"), Some("p@ss #1 $HOME"));
+        assert_eq!(get("GREETING"), Some("hello\n\"world\""));
+        assert_eq!(get("CERT"), Some("-----BEGIN-----\nabc\n-----END-----"));
+        assert_eq!(get("EMPTY"), Some(""));
+        // The later assignment wins
+        assert_eq!(get("DB_PORT"), Some("6543"));
+        assert_eq!(vars.len(), 6);
+    }
+
+    #[test]
+    fn test_malformed_lines_are_rejected_with_their_number() {
+        for (contents, expected) in [
+            ("A=1\nnot a pair\n", "line 2: expected KEY=VALUE"),
+            ("1ABC=x", "line 1: invalid variable name '1ABC'"),
+            ("A=1\nB=\"open\nstill open", "line 2: unterminated \" quote"),
+            ("A='x' trailing", "line 1: unexpected text after closing quote"),
+        ] {
+            let err = parse(contents).unwrap_err();
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_env_takes_precedence_over_file() {
+        let dir = tempdir().unwrap();
+        let roots = vec![dir.path().to_path_buf()];
+        let path = dir.path().join(".env");
+        std::fs::write(&path, "MODE=file\nONLY_IN_FILE=1\n").unwrap();
+
+        let mut env = HashMap::from([("MODE".to_string(), "command".to_string())]);
+        load_into(&path, &roots, &mut env).await.unwrap();
+        assert_eq!(env["MODE"], "command");
+        assert_eq!(env["ONLY_IN_FILE"], "1");
+
+        let err = load_into(&dir.path().join("missing.env"), &roots, &mut env).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to read env file"), "{:#}", err);
+        assert!(load_into(Path::new("/etc/hostname"), &roots, &mut env).await.is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Character encodings a process may declare for its output.
+//!
+//! Output is forwarded as JSON strings, so whatever a process writes is
+//! turned into UTF-8 first. Tools emitting a legacy single-byte encoding
+//! would otherwise have every non-ASCII byte replaced with U+FFFD. Only the
+//! encodings below are kept in-tree; multi-byte legacy encodings such as
+//! Shift-JIS need mapping tables too large to be worth carrying.
+
+use anyhow::Result;
+
+use crate::executor::Utf8Decoder;
+
+/// Labels accepted for each encoding, compared case-insensitively
+const LABELS: &[(&str, Encoding)] = &[
+    ("utf-8", Encoding::Utf8),
+    ("utf8", Encoding::Utf8),
+    ("iso-8859-1", Encoding::Latin1),
+    ("latin1", Encoding::Latin1),
+    ("latin-1", Encoding::Latin1),
+    ("windows-1252", Encoding::Windows1252),
+    ("cp1252", Encoding::Windows1252),
+];
+
+/// What Windows-1252 puts at 0x80..=0x9F, where ISO-8859-1 has control
+/// characters. The five unassigned bytes keep their ISO-8859-1 meaning.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+/// An encoding process output can be decoded from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Passed through, with invalid sequences replaced by U+FFFD
+    #[default]
+    Utf8,
+    /// ISO-8859-1: every byte is the code point of the same value
+    Latin1,
+    /// The Western Windows code page, a superset of ISO-8859-1's printable characters
+    Windows1252,
+}
+
+impl Encoding {
+    /// Look up an encoding by one of its common names.
+    pub fn for_label(label: &str) -> Result<Self> {
+        LABELS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(label.trim()))
+            .map(|(_, encoding)| *encoding)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unsupported output encoding '{}': expected utf-8, iso-8859-1 or windows-1252",
+                    label
+                )
+            })
+    }
+
+    fn decode_byte(self, byte: u8) -> char {
+        match (self, byte) {
+            (Self::Windows1252, 0x80..=0x9F) => WINDOWS_1252_HIGH[usize::from(byte - 0x80)],
+            _ => char::from(byte),
+        }
+    }
+}
+
+/// Incremental decoder from an [`Encoding`] to UTF-8 text.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    encoding: Encoding,
+    utf8: Utf8Decoder,
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            utf8: Utf8Decoder::default(),
+        }
+    }
+
+    /// Decode the next bytes read; a character split across reads is
+    /// completed by the next call.
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        match self.encoding {
+            Encoding::Utf8 => self.utf8.decode(bytes),
+            single_byte => bytes.iter().map(|&byte| single_byte.decode_byte(byte)).collect(),
+        }
+    }
+
+    /// Decode whatever is still buffered once the stream has ended.
+    pub fn finish(&mut self) -> String {
+        self.utf8.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(Encoding::for_label("UTF-8").unwrap(), Encoding::Utf8);
+        assert_eq!(Encoding::for_label("Latin1").unwrap(), Encoding::Latin1);
+        assert_eq!(Encoding::for_label("CP1252").unwrap(), Encoding::Windows1252);
+        let err = Encoding::for_label("shift_jis").unwrap_err();
+        assert!(err.to_string().contains("shift_jis"), "{}", err);
+    }
+
+    #[test]
+    fn test_single_byte_encodings_differ_only_in_the_c1_range() {
+        let bytes = b"caf\xe9 \x80\x93 \x81!";
+        assert_eq!(Decoder::new(Encoding::Latin1).decode(bytes), "café \u{80}\u{93} \u{81}!");
+        assert_eq!(Decoder::new(Encoding::Windows1252).decode(bytes), "café €“ \u{81}!");
+        assert_eq!(Decoder::new(Encoding::Utf8).decode(bytes), "caf\u{FFFD} \u{FFFD}\u{FFFD} \u{FFFD}!");
+    }
+}
@@ -3,27 +3,211 @@
 //! This module handles spawning user code as child processes, capturing their
 //! output, and managing their lifecycle.
 
+use crate::cgroup;
+use crate::coredump;
+use crate::diagnostics::{self, Diagnostic};
+use crate::encoding::{self, Encoding};
+use crate::procfs;
+use crate::pty::{self, WindowSize};
 use anyhow::{Context, Result};
-use std::collections::HashMap;
-use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::{Child, Command};
-use tokio::sync::mpsc;
+use std::collections::{HashMap, VecDeque};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot, watch, Notify, OwnedSemaphorePermit, Semaphore};
 use tracing::{debug, error, info, warn};
 
 /// Output event from a running process.
 #[derive(Debug, Clone)]
 pub enum ProcessOutput {
-    /// A line from stdout
-    Stdout(String),
-    /// A line from stderr  
-    Stderr(String),
-    /// Process exited with the given code
-    Exit(i32),
+    /// A chunk of stdout, forwarded as soon as it is read. `seq` numbers
+    /// the command's stdout and stderr chunks together, from 1, so a
+    /// `since_seq` of 0 replays everything
+    Stdout { seq: u64, chunk: String },
+    /// A chunk of stderr, forwarded as soon as it is read
+    Stderr { seq: u64, chunk: String },
+    /// A diagnostic parsed from stderr, sent after the chunk that held it
+    Diagnostic(Diagnostic),
+    /// Process exited with the given code after writing this many bytes
+    Exit {
+        code: i32,
+        /// Signal that terminated the process, if it did not exit on its own
+        signal: Option<i32>,
+        stdout_bytes: u64,
+        stderr_bytes: u64,
+        /// Output hit `max_output_bytes` and the process was killed
+        truncated: bool,
+        /// CPU time and peak memory, where the platform reports them
+        usage: Option<ResourceUsage>,
+        /// How long the process took to start and to say something
+        timing: Timing,
+    },
+    /// Process exceeded its wall-clock limit and was killed
+    Timeout(Duration),
+    /// Process was terminated after hitting a resource limit
+    LimitExceeded(ResourceLimit),
     /// Error occurred during execution
     Error(String),
+    /// Every slot is taken; the command waits at this place in line, from 1
+    Queued(usize),
+    /// A queued command got a slot and was spawned
+    Started,
 }
 
+/// Where the time went before a process produced output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timing {
+    /// From the request (or the `exec` call) until `spawn` returned
+    pub spawn_latency: Duration,
+    /// From spawning until the first byte of output; `None` if it wrote none
+    pub first_output: Option<Duration>,
+}
+
+/// Resources a process consumed, from `wait4`.
+///
+/// Covers the process and any descendants it waited for, but not those of
+/// other commands running at the same time. In a cgroup, the cgroup's own
+/// figures are used instead, which cover every descendant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub cpu_user_ms: u64,
+    pub cpu_sys_ms: u64,
+    /// Peak resident set size in KiB
+    pub max_rss_kb: u64,
+}
+
+impl ResourceUsage {
+    fn from_rusage(usage: &libc::rusage) -> Self {
+        let millis = |tv: libc::timeval| tv.tv_sec as u64 * 1000 + tv.tv_usec as u64 / 1000;
+        // Linux reports ru_maxrss in KiB, macOS in bytes
+        let max_rss_kb = if cfg!(target_os = "macos") {
+            usage.ru_maxrss as u64 / 1024
+        } else {
+            usage.ru_maxrss as u64
+        };
+        Self {
+            cpu_user_ms: millis(usage.ru_utime),
+            cpu_sys_ms: millis(usage.ru_stime),
+            max_rss_kb,
+        }
+    }
+
+    /// Take what a cgroup measured over what `wait4` saw. Its peak is of
+    /// all the memory charged to the command, page cache included.
+    fn with_cgroup(self, usage: &cgroup::Usage) -> Self {
+        Self {
+            cpu_user_ms: usage.cpu_user_ms.unwrap_or(self.cpu_user_ms),
+            cpu_sys_ms: usage.cpu_sys_ms.unwrap_or(self.cpu_sys_ms),
+            max_rss_kb: usage.memory_peak_bytes.map_or(self.max_rss_kb, |bytes| bytes / 1024),
+        }
+    }
+}
+
+/// A kernel-enforced limit that terminated a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceLimit {
+    /// Address-space limit in bytes
+    Memory(u64),
+    /// CPU time limit in seconds
+    Cpu(u64),
+}
+
+impl ResourceLimit {
+    /// Name of the limited resource as reported to the Control Plane.
+    pub fn resource(&self) -> &'static str {
+        match self {
+            ResourceLimit::Memory(_) => "memory",
+            ResourceLimit::Cpu(_) => "cpu",
+        }
+    }
+
+    /// The configured limit value.
+    pub fn value(&self) -> u64 {
+        match self {
+            ResourceLimit::Memory(bytes) => *bytes,
+            ResourceLimit::Cpu(seconds) => *seconds,
+        }
+    }
+}
+
+/// Why a command could not be started, for failures callers can act on.
+#[derive(Debug, thiserror::Error)]
+pub enum SpawnError {
+    /// The executable does not exist or is not on `PATH`
+    #[error("Command not found: {cmd}")]
+    CommandNotFound { cmd: String, source: std::io::Error },
+    /// The executable exists but may not be executed
+    #[error("Permission denied: {cmd}")]
+    PermissionDenied { cmd: String, source: std::io::Error },
+    /// The requested working directory is missing or not a directory
+    #[error("Working directory '{cwd}' {}", if *.exists { "is not a directory" } else { "does not exist" })]
+    InvalidCwd { cwd: String, exists: bool },
+    /// Environment variables whose name is empty or contains `=` or NUL,
+    /// or whose value contains NUL, in name order
+    #[error("Invalid environment variable names or values: {}", quote_keys(.keys))]
+    InvalidEnv { keys: Vec<String> },
+    /// The arguments and environment together exceed what `execve` accepts
+    #[error(
+        "Argument list too long for {cmd} ({args} arguments, {bytes} bytes): write the arguments to a file \
+         and pass them through `xargs`, or the command's own args-file option such as `@file`"
+    )]
+    ArgumentListTooLong {
+        cmd: String,
+        args: usize,
+        /// Total size of the arguments, counting each terminating NUL
+        bytes: usize,
+        source: std::io::Error,
+    },
+    /// `expand_env` was set to reject undefined variables and the command
+    /// refers to these, in order of appearance
+    #[error("Undefined variables in the command: {}", quote_keys(.names))]
+    UndefinedEnv { names: Vec<String> },
+}
+
+/// What `${NAME}` in a command becomes when `NAME` is not set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UndefinedVars {
+    /// Replaced with nothing, as a shell would
+    #[default]
+    Empty,
+    /// The command is rejected with [`SpawnError::UndefinedEnv`]
+    Error,
+}
+
+/// A process did not take up a write to its input in time, after taking
+/// `bytes_written` bytes of it.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Timed out after {}ms writing to stdin, {} bytes in: the process is not reading its input",
+    .limit.as_millis(),
+    .bytes_written
+)]
+pub struct StdinTimeout {
+    pub limit: Duration,
+    pub bytes_written: usize,
+}
+
+/// List variable names for an error message, escaping NUL and the like.
+fn quote_keys(keys: &[String]) -> String {
+    keys.iter().map(|key| format!("{:?}", key)).collect::<Vec<_>>().join(", ")
+}
+
+/// Working directory used when a command does not specify one.
+pub const DEFAULT_CWD: &str = "/workspace";
+
+/// Where commands are searched for when no `PATH` is set, as `execvp` does
+const DEFAULT_PATH: &str = "/usr/bin:/bin";
+
+/// Terminal type announced to tty-mode commands that are not given one
+const DEFAULT_TERM: &str = "xterm-256color";
+
 /// Configuration for process execution.
 #[derive(Debug, Clone)]
 pub struct ExecConfig {
@@ -35,6 +219,104 @@ pub struct ExecConfig {
     pub env: HashMap<String, String>,
     /// Working directory
     pub cwd: String,
+    /// Wall-clock limit after which the process is killed
+    pub timeout: Option<Duration>,
+    /// Address-space limit (RLIMIT_AS) in bytes.
+    ///
+    /// This is a soft guard: allocations beyond the limit fail, and most
+    /// runtimes react by aborting or raising an out-of-memory error rather
+    /// than the kernel killing the process outright. In a cgroup with the
+    /// memory controller it becomes `memory.max` instead, a limit on the
+    /// memory actually used by the command and everything it starts.
+    pub memory_limit_bytes: Option<u64>,
+    /// CPU time limit (RLIMIT_CPU) in seconds; the kernel sends SIGXCPU
+    /// when it is reached
+    pub cpu_seconds: Option<u64>,
+    /// CPU bandwidth cap in thousandths of a CPU, e.g. 500 for half of one.
+    /// Enforced through `cpu.max`, so only in a cgroup with the cpu
+    /// controller; ignored with a warning elsewhere
+    pub cpu_millicores: Option<u32>,
+    /// Scheduling niceness from -20 (highest priority) to 19 (lowest);
+    /// values below 0 require the agent to run as root
+    pub nice: Option<i32>,
+    /// File mode creation mask for the child and everything it creates,
+    /// instead of the agent's own
+    pub umask: Option<u32>,
+    /// Run on a pseudo-terminal of this size instead of plain pipes.
+    ///
+    /// stdout and stderr are merged into the terminal and reported as stdout.
+    pub tty: Option<WindowSize>,
+    /// User id to run as; requires the agent to run as root
+    pub uid: Option<u32>,
+    /// Group id to run as; requires the agent to run as root
+    pub gid: Option<u32>,
+    /// Supplementary groups for the child. When only `uid`/`gid` are given
+    /// the agent's own supplementary groups are dropped.
+    pub supplementary_groups: Option<Vec<u32>>,
+    /// Send stderr down the stdout pipe so the two stay in write order.
+    ///
+    /// Everything is then reported as stdout: ordering is preserved at the
+    /// cost of no longer knowing which stream a chunk came from.
+    pub combine_stderr: bool,
+    /// Cap on stdout and stderr bytes combined. Once reached, the rest of
+    /// the output is discarded and the process is killed.
+    pub max_output_bytes: Option<u64>,
+    /// Read stdout without delivering it; its bytes still count towards
+    /// `max_output_bytes`
+    pub discard_stdout: bool,
+    /// Read stderr without delivering it, like `discard_stdout`
+    pub discard_stderr: bool,
+    /// Encoding the process writes its output in, such as "iso-8859-1";
+    /// output is passed through as UTF-8 when unset
+    pub output_encoding: Option<String>,
+    /// Parse stderr as this toolchain's diagnostics, besides delivering it.
+    /// Where stderr is merged into stdout, the merged stream is parsed
+    pub diagnostics: Option<diagnostics::Format>,
+    /// Start from an empty environment so only `env` is visible
+    pub clear_env: bool,
+    /// Inherited variables to unset before `env` is applied
+    pub env_remove: Vec<String>,
+    /// Directories searched before the `PATH` the command would otherwise see
+    pub path_prepend: Vec<String>,
+    /// Directories searched after the `PATH` the command would otherwise see
+    pub path_append: Vec<String>,
+    /// Substitute `${NAME}` in `cmd` and `args` from the environment the
+    /// command will see, treating unset names as given; `$$` stands for a
+    /// literal `$`. `None` passes both through untouched
+    pub expand_env: Option<UndefinedVars>,
+    /// Let the process dump core when it crashes, by raising its
+    /// `RLIMIT_CORE` to the hard limit. The core is moved into the
+    /// executor's core directory, if it has one; see [`crate::coredump`] for
+    /// where the kernel puts it.
+    pub capture_core: bool,
+    /// File read as stdin instead of a pipe or `/dev/null`. The caller is
+    /// responsible for confining it to the sandbox; ignored on a terminal.
+    pub stdin_file: Option<PathBuf>,
+    /// When the request for this command arrived; spawn latency is measured
+    /// from here, or from the `exec` call when unset
+    pub requested: Option<Instant>,
+    /// Run in the background, e.g. a dev server: spawned straight away
+    /// without taking a concurrency slot, and only targeted by requests
+    /// that name its exec id
+    pub detach: bool,
+}
+
+impl ExecConfig {
+    /// Run `cmd` as a script for `shell -lc` instead of as an executable.
+    ///
+    /// `args` become the script's positional parameters (`$1`, `$2`, ...).
+    /// The shell interprets `cmd` in full (pipes, globs, `$(...)`), so it
+    /// must never contain untrusted input; the default argv spawn has no
+    /// such risk.
+    pub fn through_shell(self, shell: &str) -> Self {
+        let mut args = vec!["-lc".to_string(), self.cmd, shell.to_string()];
+        args.extend(self.args);
+        Self {
+            cmd: shell.to_string(),
+            args,
+            ..self
+        }
+    }
 }
 
 impl Default for ExecConfig {
@@ -43,148 +325,3505 @@ impl Default for ExecConfig {
             cmd: String::new(),
             args: Vec::new(),
             env: HashMap::new(),
-            cwd: "/workspace".to_string(),
+            cwd: DEFAULT_CWD.to_string(),
+            timeout: None,
+            memory_limit_bytes: None,
+            cpu_seconds: None,
+            cpu_millicores: None,
+            nice: None,
+            umask: None,
+            tty: None,
+            uid: None,
+            gid: None,
+            supplementary_groups: None,
+            combine_stderr: false,
+            max_output_bytes: None,
+            discard_stdout: false,
+            discard_stderr: false,
+            output_encoding: None,
+            diagnostics: None,
+            clear_env: false,
+            env_remove: Vec::new(),
+            path_prepend: Vec::new(),
+            path_append: Vec::new(),
+            expand_env: None,
+            capture_core: false,
+            stdin_file: None,
+            requested: None,
+            detach: false,
+        }
+    }
+}
+
+/// How long reader tasks may keep draining after a timed-out process is killed.
+///
+/// Grandchildren can hold the pipes open indefinitely, so the readers are
+/// aborted once this grace period elapses.
+const READER_DRAIN_GRACE: Duration = Duration::from_millis(500);
+
+/// Maximum number of bytes read from a pipe per output chunk, unless the
+/// agent is configured otherwise.
+///
+/// Output is forwarded as it is read rather than split on newlines, so this
+/// also bounds what a single line without a newline can make the agent hold.
+pub const DEFAULT_MAX_CHUNK_BYTES: usize = 8192;
+
+/// How long output is gathered before being forwarded, unless the agent is
+/// configured otherwise.
+///
+/// Programs printing many tiny lines would otherwise cost one event per
+/// write; a chunk is still sent early once it reaches the chunk size.
+pub const DEFAULT_OUTPUT_WINDOW: Duration = Duration::from_millis(50);
+
+/// Output kept per command so a reconnecting client can `replay` what it
+/// missed; older chunks are dropped first.
+pub const REPLAY_BUFFER_BYTES: usize = 64 * 1024;
+
+/// How long a write to a process's input may wait for it to be read, unless
+/// the agent is configured otherwise.
+pub const DEFAULT_STDIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a process gets to exit after SIGTERM before it is sent SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// Shortest interval `sample` accepts, since each sample reads several files
+pub const MIN_SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Writable end of a process's input: a stdin pipe or a PTY master.
+type ProcessStdin = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Why input can no longer be written to a process.
+const STDIN_GONE: &str = "Process has exited: nothing is reading its stdin";
+
+/// Writes waiting for a process's input, each answered once it is done.
+type StdinQueue = mpsc::UnboundedSender<(Vec<u8>, oneshot::Sender<Result<usize>>)>;
+
+/// Exit code, terminating signal and resource usage of a reaped child.
+type Reaped = (i32, Option<i32>, Option<ResourceUsage>);
+
+/// Handle to a process whose child is owned by a supervisor task.
+struct RunningProcess {
+    /// OS process id, used for signalling
+    pid: Option<u32>,
+    /// Writes for the child's stdin, if it was piped; dropping it closes
+    /// stdin once the writes already queued are done
+    stdin: Option<StdinQueue>,
+    /// PTY master, when the process runs on a terminal
+    pty_master: Option<OwnedFd>,
+    /// Set once the supervisor has reaped the child
+    exit_rx: watch::Receiver<Option<Reaped>>,
+    /// Raw bytes read from each pipe so far
+    output_bytes: Arc<OutputBytes>,
+    /// Latest output chunks, kept for `replay`
+    history: Arc<OutputHistory>,
+    /// Reader and supervisor tasks, cancelled when the agent shuts down
+    tasks: Vec<tokio::task::AbortHandle>,
+    /// Program and arguments as spawned
+    command: (String, Vec<String>),
+    started: Instant,
+    /// Started with `detach`
+    detached: bool,
+}
+
+/// A snapshot of one running command, as reported by `status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessStatus {
+    pub exec_id: String,
+    pub pid: Option<u32>,
+    pub cmd: String,
+    pub args: Vec<String>,
+    /// Time since the process was spawned
+    pub elapsed: Duration,
+    /// Bytes written to stdout so far
+    pub stdout_bytes: u64,
+    /// Bytes written to stderr so far
+    pub stderr_bytes: u64,
+    /// Started with `detach`
+    pub detached: bool,
+}
+
+/// Running totals of the bytes a process has written, and the cap on them.
+///
+/// Shared by all reader tasks of a process so the cap covers both streams.
+#[derive(Debug, Default)]
+struct OutputBytes {
+    /// Bytes forwarded from stdout
+    stdout: AtomicU64,
+    /// Bytes forwarded from stderr
+    stderr: AtomicU64,
+    /// Bytes read from either pipe, used against the cap
+    read: AtomicU64,
+    limit: Option<u64>,
+    /// Process group killed once the cap is hit
+    pid: Option<u32>,
+    truncated: AtomicBool,
+    /// When the process was spawned, and how long that took
+    spawned: Option<Instant>,
+    spawn_latency: Duration,
+    /// Time from spawning to the first read with any bytes
+    first_output: OnceLock<Duration>,
+}
+
+impl OutputBytes {
+    fn new(limit: Option<u64>, pid: Option<u32>, requested: Instant, spawned: Instant) -> Self {
+        Self {
+            limit,
+            pid,
+            spawned: Some(spawned),
+            spawn_latency: spawned.duration_since(requested),
+            ..Self::default()
+        }
+    }
+
+    /// Account for `n` bytes just read, returning how many may be forwarded.
+    ///
+    /// The first read to cross the cap kills the process.
+    fn admit(&self, counter: &AtomicU64, n: usize) -> usize {
+        if let Some(spawned) = self.spawned {
+            self.first_output.get_or_init(|| spawned.elapsed());
+        }
+        let n = n as u64;
+        let before = self.read.fetch_add(n, Ordering::Relaxed);
+        let allowed = match self.limit {
+            Some(limit) => n.min(limit.saturating_sub(before)),
+            None => n,
+        };
+        counter.fetch_add(allowed, Ordering::Relaxed);
+
+        if allowed < n && !self.truncated.swap(true, Ordering::Relaxed) {
+            warn!(limit = self.limit, "Output limit reached, killing process");
+            if let Some(pid) = self.pid {
+                if let Err(e) = signal_group(pid, libc::SIGKILL) {
+                    error!(error = %e, "Failed to kill process after output limit");
+                }
+            }
+        }
+        allowed as usize
+    }
+
+    fn is_truncated(&self) -> bool {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    /// The exit event carrying these totals.
+    fn exit(&self, code: i32, signal: Option<i32>, usage: Option<ResourceUsage>) -> ProcessOutput {
+        ProcessOutput::Exit {
+            code,
+            signal,
+            stdout_bytes: self.stdout.load(Ordering::Relaxed),
+            stderr_bytes: self.stderr.load(Ordering::Relaxed),
+            truncated: self.is_truncated(),
+            usage,
+            timing: Timing {
+                spawn_latency: self.spawn_latency,
+                first_output: self.first_output.get().copied(),
+            },
+        }
+    }
+}
+
+/// The latest output chunks of one command, numbered in delivery order.
+///
+/// Every reader of the command delivers through here, one at a time, so
+/// sequence numbers reach the consumer in order across stdout and stderr.
+#[derive(Debug)]
+struct OutputHistory {
+    ring: Mutex<OutputRing>,
+    /// Held from numbering a chunk until it is on the channel
+    delivering: tokio::sync::Mutex<()>,
+}
+
+impl OutputHistory {
+    fn new(limit: usize) -> Self {
+        Self {
+            ring: Mutex::new(OutputRing::new(limit)),
+            delivering: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Number `chunk`, keep a copy, and send it.
+    async fn deliver(
+        &self,
+        tx: &mpsc::Sender<ProcessOutput>,
+        wrap: fn(u64, String) -> ProcessOutput,
+        chunk: String,
+    ) -> Result<(), mpsc::error::SendError<ProcessOutput>> {
+        let _turn = self.delivering.lock().await;
+        let output = self.ring.lock().unwrap().push(wrap, chunk);
+        tx.send(output).await
+    }
+}
+
+/// Output chunks up to a byte budget, evicting the oldest first.
+#[derive(Debug)]
+struct OutputRing {
+    /// Chunks with their sequence numbers, oldest first
+    chunks: VecDeque<(u64, ProcessOutput)>,
+    /// Text held in `chunks`
+    bytes: usize,
+    limit: usize,
+    next_seq: u64,
+}
+
+impl OutputRing {
+    fn new(limit: usize) -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            bytes: 0,
+            limit,
+            // Numbering from 1 leaves 0 to mean "nothing seen yet"
+            next_seq: 1,
+        }
+    }
+
+    /// Number the next chunk and keep a copy of it. The newest chunk is
+    /// kept even when it alone exceeds the budget.
+    fn push(&mut self, wrap: fn(u64, String) -> ProcessOutput, chunk: String) -> ProcessOutput {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.bytes += chunk.len();
+        let output = wrap(seq, chunk);
+        self.chunks.push_back((seq, output.clone()));
+        while self.bytes > self.limit && self.chunks.len() > 1 {
+            if let Some((_, evicted)) = self.chunks.pop_front() {
+                self.bytes -= chunk_len(&evicted);
+            }
+        }
+        output
+    }
+
+    /// Retained chunks numbered after `since`, and whether none of those
+    /// had been evicted yet.
+    fn since(&self, since: u64) -> (Vec<ProcessOutput>, bool) {
+        let oldest = self.chunks.front().map_or(self.next_seq, |(seq, _)| *seq);
+        let chunks = self
+            .chunks
+            .iter()
+            .filter(|(seq, _)| *seq > since)
+            .map(|(_, output)| output.clone())
+            .collect();
+        (chunks, since + 1 >= oldest)
+    }
+}
+
+fn chunk_len(output: &ProcessOutput) -> usize {
+    match output {
+        ProcessOutput::Stdout { chunk, .. } | ProcessOutput::Stderr { chunk, .. } => chunk.len(),
+        _ => 0,
+    }
+}
+
+/// Output re-sent by [`Executor::replay`].
+#[derive(Debug)]
+pub struct Replay {
+    pub exec_id: String,
+    /// Retained stdout and stderr chunks after the requested one
+    pub chunks: Vec<ProcessOutput>,
+    /// False when some of the requested chunks were already dropped
+    pub complete: bool,
+}
+
+/// What is needed to find and keep the core of a `capture_core` command.
+#[derive(Debug)]
+struct CoreCapture {
+    exec_id: String,
+    cwd: PathBuf,
+    cmd: String,
+    /// Just before the spawn; older cores belong to someone else
+    since: SystemTime,
+    dir: Option<PathBuf>,
+}
+
+impl CoreCapture {
+    /// Find the core `pid` dumped and move it to the core directory.
+    async fn collect(&self, pid: u32) {
+        let Some(core) = coredump::locate(&self.cwd, &self.cmd, pid, self.since).await else {
+            warn!(exec_id = %self.exec_id, pid, "Process dumped core, but no core file was found");
+            return;
+        };
+        let Some(dir) = &self.dir else {
+            info!(exec_id = %self.exec_id, path = %core.display(), "Process dumped core");
+            return;
+        };
+        match coredump::collect(&core, dir, &self.exec_id, pid).await {
+            Ok(path) => info!(exec_id = %self.exec_id, path = %path.display(), "Captured core dump"),
+            Err(e) => warn!(exec_id = %self.exec_id, error = %format!("{:#}", e), "Failed to capture core dump"),
         }
     }
 }
 
+/// How a crashed process is respawned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Most restarts allowed within `window` before giving up
+    pub max_restarts: u32,
+    pub window: Duration,
+}
+
+/// Outcome of [`Executor::restart_crashed`].
+#[derive(Debug)]
+pub enum Restart {
+    /// The process was respawned under the same exec id
+    Restarted {
+        output: mpsc::Receiver<ProcessOutput>,
+        /// Restarts so far within the policy window, including this one
+        attempt: u32,
+    },
+    /// The process crashed too often within the window and stays down
+    GaveUp { restarts: u32, window: Duration },
+    /// No restart applies: none was requested, the process exited cleanly,
+    /// or it was stopped on purpose
+    NotNeeded,
+}
+
+/// What is needed to respawn a process, and when it last was.
+struct RestartState {
+    policy: RestartPolicy,
+    config: ExecConfig,
+    pipe_stdin: bool,
+    recent: VecDeque<Instant>,
+}
+
+impl RunningProcess {
+    /// Whether the supervisor has not yet reaped the child.
+    fn is_running(&self) -> bool {
+        self.exit_rx.borrow().is_none()
+    }
+}
+
 /// Process executor that manages child processes.
+///
+/// Several commands may run at once; each is addressed by an exec id chosen
+/// by the caller or generated with [`Executor::next_exec_id`].
 pub struct Executor {
-    /// Currently running process, if any
-    current: Option<Child>,
-    /// Handle to child's stdin
-    stdin: Option<tokio::process::ChildStdin>,
+    /// Processes by exec id, including finished ones not yet pruned
+    processes: HashMap<String, RunningProcess>,
+    /// Most recently started exec id, targeted when a request names none
+    last_id: Option<String>,
+    /// Counter for generated exec ids
+    id_counter: u64,
+    /// Processes to respawn if they crash, by exec id
+    restarts: HashMap<String, RestartState>,
+    /// Largest chunk read from a process's output at once
+    max_chunk_bytes: usize,
+    /// How long output is coalesced before being forwarded
+    output_window: Duration,
+    /// Variables set with `env.set`, inherited by every later command
+    session_env: HashMap<String, String>,
+    /// One permit per process allowed to run at once, when limited
+    slots: Option<Arc<Semaphore>>,
+    /// Commands waiting for a slot, oldest first
+    queue: VecDeque<QueuedCommand>,
+    /// Woken whenever a process gives its slot back
+    slot_freed: Arc<Notify>,
+    /// Where cores of commands run with `capture_core` are moved
+    core_dir: Option<PathBuf>,
+    /// Longest a write to a process's input may wait
+    stdin_timeout: Duration,
+    /// Where each command gets a cgroup of its own, when configured
+    cgroup_parent: Option<cgroup::Parent>,
+}
+
+/// A command accepted while every slot was taken.
+struct QueuedCommand {
+    exec_id: String,
+    config: ExecConfig,
+    pipe_stdin: bool,
+    tx: mpsc::Sender<ProcessOutput>,
 }
 
 impl Executor {
     /// Create a new Executor.
     pub fn new() -> Self {
-        Self { 
-            current: None,
-            stdin: None,
+        Self::with_max_chunk_bytes(DEFAULT_MAX_CHUNK_BYTES)
+    }
+
+    /// Create an Executor that forwards output in chunks of at most
+    /// `max_chunk_bytes` (plus the tail of a split UTF-8 character).
+    pub fn with_max_chunk_bytes(max_chunk_bytes: usize) -> Self {
+        Self {
+            processes: HashMap::new(),
+            last_id: None,
+            id_counter: 0,
+            restarts: HashMap::new(),
+            max_chunk_bytes: max_chunk_bytes.max(1),
+            output_window: DEFAULT_OUTPUT_WINDOW,
+            session_env: HashMap::new(),
+            slots: None,
+            queue: VecDeque::new(),
+            slot_freed: Arc::new(Notify::new()),
+            core_dir: None,
+            stdin_timeout: DEFAULT_STDIN_TIMEOUT,
+            cgroup_parent: None,
+        }
+    }
+
+    /// Coalesce each stream's output for up to `window` before forwarding
+    /// it; zero forwards every read as it happens.
+    pub fn with_output_window(mut self, window: Duration) -> Self {
+        self.output_window = window;
+        self
+    }
+
+    /// Run at most `limit` processes at once, queueing the rest; `None`
+    /// runs every command as soon as it arrives.
+    pub fn with_max_concurrency(mut self, limit: Option<usize>) -> Self {
+        self.slots = limit.map(|limit| Arc::new(Semaphore::new(limit.max(1))));
+        self
+    }
+
+    /// Move the cores of crashed `capture_core` commands into `dir`, e.g.
+    /// inside a watched output directory so they are reported as artifacts.
+    /// Without one they stay wherever the kernel wrote them.
+    pub fn with_core_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.core_dir = dir;
+        self
+    }
+
+    /// Fail writes to a process's input that are not taken up within
+    /// `timeout`, rather than waiting for a process that never reads.
+    pub fn with_stdin_timeout(mut self, timeout: Duration) -> Self {
+        self.stdin_timeout = timeout;
+        self
+    }
+
+    /// Run each command in a cgroup of its own under `parent`, for limits
+    /// and usage that cover everything it starts. Without one, limits are
+    /// rlimits and usage comes from `wait4`.
+    pub fn with_cgroup_parent(mut self, parent: Option<cgroup::Parent>) -> Self {
+        self.cgroup_parent = parent;
+        self
+    }
+
+    /// Notified each time a running process frees its slot, so the owner
+    /// knows to call [`Executor::start_queued`].
+    pub fn slot_freed(&self) -> Arc<Notify> {
+        self.slot_freed.clone()
+    }
+
+    /// Whether any command is waiting for a slot.
+    pub fn has_queued(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Variables every later command inherits, as if exported by a shell.
+    pub fn session_env(&self) -> &HashMap<String, String> {
+        &self.session_env
+    }
+
+    /// Add or overwrite session variables; nothing is set if any is invalid.
+    pub fn set_env(&mut self, vars: HashMap<String, String>) -> Result<()> {
+        check_env(&vars)?;
+        self.session_env.extend(vars);
+        Ok(())
+    }
+
+    /// Remove session variables. Unknown keys are ignored.
+    pub fn unset_env(&mut self, keys: &[String]) {
+        for key in keys {
+            self.session_env.remove(key);
         }
     }
 
+    /// The executable a command named `name` would run if started now, as
+    /// found in the `PATH` it would inherit.
+    pub fn which(&self, name: &str) -> Option<PathBuf> {
+        let config = ExecConfig {
+            cmd: name.to_string(),
+            env: self.session_env.clone(),
+            ..Default::default()
+        };
+        resolve_command(&config).ok()
+    }
+
+    /// Generate a fresh exec id for callers that did not supply one.
+    pub fn next_exec_id(&mut self) -> String {
+        self.id_counter += 1;
+        format!("exec-{}", self.id_counter)
+    }
+
     /// Execute a command and stream its output.
     ///
     /// Returns a channel that receives output events until the process completes.
-    pub async fn exec(&mut self, config: ExecConfig, pipe_stdin: bool) -> Result<mpsc::Receiver<ProcessOutput>> {
-        info!(cmd = %config.cmd, args = ?config.args, "Spawning process");
+    /// The final event is always `ProcessOutput::Exit` with the real exit code.
+    /// Fails if another command with the same `exec_id` is still running or queued.
+    ///
+    /// When every slot is taken the command is checked but not spawned: the
+    /// channel starts with `ProcessOutput::Queued`, and `ProcessOutput::Started`
+    /// follows once [`Executor::start_queued`] gives it a slot.
+    pub async fn exec(
+        &mut self,
+        exec_id: &str,
+        mut config: ExecConfig,
+        pipe_stdin: bool,
+    ) -> Result<mpsc::Receiver<ProcessOutput>> {
+        // Time spent waiting in the queue counts towards spawn latency
+        config.requested.get_or_insert_with(Instant::now);
+        // Forget finished processes so their ids can be reused
+        self.processes.retain(|_, p| p.is_running());
+        // A new command under this id starts with a fresh restart history
+        self.restarts.remove(exec_id);
+        if self.processes.contains_key(exec_id) || self.queue.iter().any(|queued| queued.exec_id == exec_id) {
+            anyhow::bail!("Command '{}' is already running", exec_id);
+        }
+
+        expand_command(&mut config, &self.session_env)?;
+        validate_cwd(&config.cwd).await?;
+        validate_env(&config)?;
+        validate_nice(config.nice)?;
+        if let Some(mask) = config.umask.filter(|mask| *mask > 0o777) {
+            anyhow::bail!("Invalid umask {:#o}: must be at most 0o777", mask);
+        }
+        output_encoding(&config)?;
+        if let Some(dir) = config.path_prepend.iter().chain(&config.path_append).find(|dir| dir.contains([':', '\0'])) {
+            anyhow::bail!("Invalid PATH directory '{}': must not contain ':' or NUL", dir);
+        }
 
         let (tx, rx) = mpsc::channel(100);
+        // Nobody may overtake a command that is already waiting, except a
+        // detached one, which would otherwise hold its slot for good
+        let permit = match &self.slots {
+            Some(slots) if !config.detach => match slots.clone().try_acquire_owned() {
+                Ok(permit) if self.queue.is_empty() => Some(permit),
+                _ => {
+                    let position = self.queue.len() + 1;
+                    info!(exec_id, position, "All process slots taken, queueing command");
+                    let _ = tx.try_send(ProcessOutput::Queued(position));
+                    self.queue.push_back(QueuedCommand {
+                        exec_id: exec_id.to_string(),
+                        config,
+                        pipe_stdin,
+                        tx,
+                    });
+                    return Ok(rx);
+                }
+            },
+            _ => None,
+        };
+        self.spawn(exec_id, config, pipe_stdin, tx, permit)?;
+        Ok(rx)
+    }
+
+    /// Spawn queued commands while slots are free, oldest first.
+    ///
+    /// Their requests were answered when they were queued, so a command
+    /// that fails to spawn now reports an error and an exit of -1 instead.
+    pub fn start_queued(&mut self) {
+        let Some(slots) = self.slots.clone() else {
+            return;
+        };
+        while !self.queue.is_empty() {
+            let Ok(permit) = slots.clone().try_acquire_owned() else {
+                break;
+            };
+            let queued = self.queue.pop_front().expect("queue is not empty");
+            let _ = queued.tx.try_send(ProcessOutput::Started);
+            let tx = queued.tx.clone();
+            if let Err(e) = self.spawn(&queued.exec_id, queued.config, queued.pipe_stdin, queued.tx, Some(permit)) {
+                warn!(exec_id = %queued.exec_id, error = %format!("{:#}", e), "Queued command failed to start");
+                let _ = tx.try_send(ProcessOutput::Error(format!("Failed to spawn process: {:#}", e)));
+                let _ = tx.try_send(never_ran_exit());
+            }
+        }
+    }
+
+    /// Spawn a checked command, holding `permit` (if slots are limited)
+    /// until it has been reaped.
+    fn spawn(
+        &mut self,
+        exec_id: &str,
+        config: ExecConfig,
+        pipe_stdin: bool,
+        tx: mpsc::Sender<ProcessOutput>,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<()> {
+        let requested = config.requested.unwrap_or_else(Instant::now);
+        let detached = config.detach;
+        info!(exec_id, cmd = %config.cmd, args = ?config.args, detached, "Spawning process");
+
+        let pty = match config.tty {
+            Some(size) => Some(pty::Pty::open(size).context("Failed to allocate a pseudo-terminal")?),
+            None => None,
+        };
+
+        // One pipe shared by stdout and stderr, when they are combined
+        let combined = match (&pty, config.combine_stderr) {
+            (None, true) => Some(output_pipe().context("Failed to create output pipe")?),
+            _ => None,
+        };
 
         // Build the command
         let mut cmd = Command::new(&config.cmd);
         cmd.args(&config.args)
             .current_dir(&config.cwd)
-            .stdin(if pipe_stdin { Stdio::piped() } else { Stdio::null() })
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
             .kill_on_drop(true);
+        match &pty {
+            Some(pty) => {
+                cmd.stdin(Stdio::from(pty.slave.try_clone()?))
+                    .stdout(Stdio::from(pty.slave.try_clone()?))
+                    .stderr(Stdio::from(pty.slave.try_clone()?));
+                // A new session (and with it a process group) owning the terminal
+                // SAFETY: the hook only makes async-signal-safe syscalls
+                unsafe {
+                    cmd.pre_exec(pty::attach_controlling_terminal);
+                }
+            }
+            None => {
+                let stdin = match &config.stdin_file {
+                    Some(path) => Stdio::from(
+                        std::fs::File::open(path)
+                            .with_context(|| format!("Failed to open stdin file '{}'", path.display()))?,
+                    ),
+                    None if pipe_stdin => Stdio::piped(),
+                    None => Stdio::null(),
+                };
+                cmd.stdin(stdin)
+                    // Own process group so signals reach the whole process tree
+                    .process_group(0);
+                match &combined {
+                    Some((_, write)) => {
+                        cmd.stdout(Stdio::from(write.try_clone()?))
+                            .stderr(Stdio::from(write.try_clone()?));
+                    }
+                    None => {
+                        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+                    }
+                }
+            }
+        }
 
-        // Set environment variables
+        // Set environment variables. The session's act as inherited ones, so
+        // `clear_env` and `env_remove` apply to them too
+        cmd.envs(&self.session_env);
+        if config.clear_env {
+            cmd.env_clear();
+        }
+        for key in &config.env_remove {
+            cmd.env_remove(key);
+        }
+        if let Some(size) = config.tty {
+            for (key, value) in terminal_env(size) {
+                if !config.env.contains_key(key) && !self.session_env.contains_key(key) {
+                    cmd.env(key, value);
+                }
+            }
+        }
         for (key, value) in &config.env {
             cmd.env(key, value);
         }
+        if let Some(path) = merged_path(&config, &self.session_env) {
+            cmd.env("PATH", path);
+        }
 
-        // Spawn the process
-        let mut child = cmd.spawn().context("Failed to spawn process")?;
-
-        let stdout = child.stdout.take().expect("stdout piped");
-        let stderr = child.stderr.take().expect("stderr piped");
-        
-        // If stdin is piped, take it and store it
-        if pipe_stdin {
-             let stdin = child.stdin.take().expect("stdin piped");
-             self.stdin = Some(stdin);
+        // A cgroup takes over whatever limits its controllers can enforce
+        let cgroup = self.cgroup_parent.as_ref().and_then(|parent| {
+            let limits = cgroup::Limits {
+                memory_bytes: config.memory_limit_bytes,
+                cpu_millicores: config.cpu_millicores,
+            };
+            parent
+                .create(exec_id, limits)
+                .map_err(|e| warn!(exec_id, error = %format!("{:#}", e), "Failed to create cgroup, running without one"))
+                .ok()
+        });
+        if config.cpu_millicores.is_some() && !cgroup.as_ref().is_some_and(cgroup::Cgroup::limits_cpu) {
+            warn!(exec_id, "CPU quota needs a cgroup with the cpu controller, ignoring it");
         }
+        let memory_rlimit = config
+            .memory_limit_bytes
+            .filter(|_| !cgroup.as_ref().is_some_and(cgroup::Cgroup::limits_memory));
+        if let Some(cgroup) = &cgroup {
+            apply_cgroup(&mut cmd, cgroup);
+        }
+        apply_limits(&mut cmd, &config, memory_rlimit);
+        apply_identity(&mut cmd, &config)?;
+
+        // Spawn the process
+        let mut child = cmd.spawn().map_err(|e| spawn_error(e, &config))?;
+        // Release our copies of the PTY slave or shared pipe so readers see EOF on exit
+        drop(cmd);
+        let combined = combined.map(|(read, _write)| read);
 
-        self.current = Some(child);
+        let pid = child.id();
+        let started = Instant::now();
+        let command = (config.cmd.clone(), config.args.clone());
 
         // Spawn tasks to read stdout and stderr
-        let tx_stdout = tx.clone();
-        tokio::spawn(async move {
-            let reader = BufReader::new(stdout);
-            let mut lines = reader.lines();
-            while let Ok(Some(line)) = lines.next_line().await {
-                if tx_stdout.send(ProcessOutput::Stdout(line)).await.is_err() {
-                    break;
-                }
+        let output_bytes = Arc::new(OutputBytes::new(config.max_output_bytes, pid, requested, started));
+        let history = Arc::new(OutputHistory::new(REPLAY_BUFFER_BYTES));
+        let options = ReadOptions {
+            chunk_size: self.max_chunk_bytes,
+            window: self.output_window,
+            encoding: output_encoding(&config)?,
+            diagnostics: config.diagnostics,
+        };
+        let mut readers = Vec::new();
+        let (stdin, pty_master): (Option<ProcessStdin>, _) = match (pty, combined) {
+            (Some(pty), _) => {
+                let reader = tokio::fs::File::from(std::fs::File::from(pty.master.try_clone()?));
+                let writer = tokio::fs::File::from(std::fs::File::from(pty.master.try_clone()?));
+                readers.push(tokio::spawn(read_output(
+                    reader,
+                    (!config.discard_stdout).then(|| tx.clone()),
+                    |seq, chunk| ProcessOutput::Stdout { seq, chunk },
+                    output_bytes.clone(),
+                    |bytes| &bytes.stdout,
+                    options,
+                    history.clone(),
+                )));
+                (Some(Box::new(writer)), Some(pty.master))
             }
-        });
-
-        let tx_stderr = tx.clone();
-        tokio::spawn(async move {
-            let reader = BufReader::new(stderr);
-            let mut lines = reader.lines();
-            while let Ok(Some(line)) = lines.next_line().await {
-                if tx_stderr.send(ProcessOutput::Stderr(line)).await.is_err() {
-                    break;
-                }
+            (None, Some(read)) => {
+                let reader = tokio::net::unix::pipe::Receiver::from_owned_fd(read)?;
+                readers.push(tokio::spawn(read_output(
+                    reader,
+                    (!config.discard_stdout).then(|| tx.clone()),
+                    |seq, chunk| ProcessOutput::Stdout { seq, chunk },
+                    output_bytes.clone(),
+                    |bytes| &bytes.stdout,
+                    options,
+                    history.clone(),
+                )));
+                let stdin = child.stdin.take().map(|stdin| Box::new(stdin) as ProcessStdin);
+                (stdin, None)
             }
-        });
-
-        Ok(rx)
-    }
+            (None, None) => {
+                let stdout = child.stdout.take().expect("stdout piped");
+                let stderr = child.stderr.take().expect("stderr piped");
+                readers.push(tokio::spawn(read_output(
+                    stdout,
+                    (!config.discard_stdout).then(|| tx.clone()),
+                    |seq, chunk| ProcessOutput::Stdout { seq, chunk },
+                    output_bytes.clone(),
+                    |bytes| &bytes.stdout,
+                    ReadOptions { diagnostics: None, ..options },
+                    history.clone(),
+                )));
+                readers.push(tokio::spawn(read_output(
+                    stderr,
+                    (!config.discard_stderr).then(|| tx.clone()),
+                    |seq, chunk| ProcessOutput::Stderr { seq, chunk },
+                    output_bytes.clone(),
+                    |bytes| &bytes.stderr,
+                    options,
+                    history.clone(),
+                )));
+                let stdin = child.stdin.take().map(|stdin| Box::new(stdin) as ProcessStdin);
+                (stdin, None)
+            }
+        };
 
-    /// Write to the stdin of the current process.
-    pub async fn write_stdin(&mut self, data: &str) -> Result<()> {
-        use tokio::io::AsyncWriteExt;
-        if let Some(stdin) = self.stdin.as_mut() {
-            stdin.write_all(data.as_bytes()).await.context("Failed to write to stdin")?;
-            stdin.flush().await.context("Failed to flush stdin")?;
-            Ok(())
-        } else {
-            anyhow::bail!("Process has no persistent stdin")
-        }
-    }
+        let mut tasks: Vec<_> = readers.iter().map(|reader| reader.abort_handle()).collect();
+        let stdin = stdin.map(|stdin| {
+            let (queue, writer) = stdin_writer(stdin, self.stdin_timeout);
+            tasks.push(writer);
+            queue
+        });
 
-    /// Wait for the current process to complete.
-    pub async fn wait_for_completion(&mut self) -> Option<ProcessOutput> {
-        self.stdin = None; // Close stdin to allow process to exit if waiting for it
-        if let Some(mut child) = self.current.take() {
-            match child.wait().await {
+        // Supervise the child: reap it, let the readers drain, then report the exit
+        let (exit_tx, exit_rx) = watch::channel(None);
+        let timeout = config.timeout;
+        let memory_limit = config.memory_limit_bytes;
+        let cpu_limit = config.cpu_seconds;
+        let totals = output_bytes.clone();
+        let slot_freed = self.slot_freed.clone();
+        let core_capture = config.capture_core.then(|| CoreCapture {
+            exec_id: exec_id.to_string(),
+            cwd: PathBuf::from(&config.cwd),
+            cmd: config.cmd.clone(),
+            since: SystemTime::now(),
+            dir: self.core_dir.clone(),
+        });
+        let supervisor = tokio::spawn(async move {
+            let mut timed_out = false;
+            let mut reaped = Box::pin(wait_with_usage(child, pid));
+            let result = match timeout {
+                Some(limit) => match tokio::time::timeout(limit, &mut reaped).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        warn!(timeout_ms = limit.as_millis() as u64, "Process timed out, killing");
+                        timed_out = true;
+                        let _ = tx.send(ProcessOutput::Timeout(limit)).await;
+                        if let Some(Err(e)) = pid.map(|pid| signal_group(pid, libc::SIGKILL)) {
+                            error!(error = %e, "Failed to kill timed out process");
+                        }
+                        reaped.await
+                    }
+                },
+                None => reaped.await,
+            };
+            let (status, usage) = match result {
+                Ok((status, usage)) => (Ok(status), usage),
+                Err(e) => (Err(e), None),
+            };
+            let cgroup_usage = cgroup.as_ref().map(cgroup::Cgroup::usage);
+            let usage = match &cgroup_usage {
+                Some(measured) => usage.map(|usage| usage.with_cgroup(measured)),
+                None => usage,
+            };
+            let core_dumped = status.as_ref().is_ok_and(|status| status.core_dumped());
+            let (code, signal) = match status {
                 Ok(status) => {
-                    let code = status.code().unwrap_or(-1);
-                    debug!(exit_code = code, "Process completed");
-                    Some(ProcessOutput::Exit(code))
+                    let oom_killed = cgroup_usage.is_some_and(|usage| usage.oom_kills > 0);
+                    let limit = match memory_limit {
+                        Some(bytes) if oom_killed => Some(ResourceLimit::Memory(bytes)),
+                        _ => exceeded_limit(status, memory_rlimit, cpu_limit),
+                    };
+                    if let Some(limit) = limit {
+                        warn!(resource = limit.resource(), limit = limit.value(), "Process hit resource limit");
+                        let _ = tx.send(ProcessOutput::LimitExceeded(limit)).await;
+                    }
+                    (exit_code(status), status.signal())
                 }
                 Err(e) => {
                     error!(error = %e, "Failed to wait for process");
-                    Some(ProcessOutput::Error(e.to_string()))
+                    let _ = tx.send(ProcessOutput::Error(e.to_string())).await;
+                    (-1, None)
+                }
+            };
+
+            for mut task in readers {
+                if timed_out {
+                    if tokio::time::timeout(READER_DRAIN_GRACE, &mut task).await.is_err() {
+                        task.abort();
+                    }
+                } else {
+                    let _ = task.await;
                 }
             }
-        } else {
-            None
+
+            if let (true, Some(capture), Some(pid)) = (core_dumped, &core_capture, pid) {
+                capture.collect(pid).await;
+            }
+            if let Some(cgroup) = cgroup {
+                cgroup.remove().await;
+            }
+
+            debug!(exit_code = code, ?signal, ?usage, "Process completed");
+            let _ = exit_tx.send(Some((code, signal, usage)));
+            if permit.is_some() {
+                drop(permit);
+                slot_freed.notify_one();
+            }
+            let _ = tx.send(totals.exit(code, signal, usage)).await;
+        });
+        tasks.push(supervisor.abort_handle());
+
+        self.processes.insert(
+            exec_id.to_string(),
+            RunningProcess {
+                pid,
+                stdin,
+                pty_master,
+                exit_rx,
+                output_bytes,
+                history,
+                tasks,
+                command,
+                started,
+                detached,
+            },
+        );
+        // Requests naming no process are meant for the foreground ones
+        if !detached {
+            self.last_id = Some(exec_id.to_string());
         }
+
+        Ok(())
     }
 
-}
+    /// Every command still running, oldest first.
+    pub fn status(&self) -> Vec<ProcessStatus> {
+        let mut running: Vec<(&String, &RunningProcess)> =
+            self.processes.iter().filter(|(_, process)| process.is_running()).collect();
+        running.sort_by_key(|(_, process)| process.started);
+        running
+            .into_iter()
+            .map(|(exec_id, process)| ProcessStatus {
+                exec_id: exec_id.clone(),
+                pid: process.pid,
+                cmd: process.command.0.clone(),
+                args: process.command.1.clone(),
+                elapsed: process.started.elapsed(),
+                stdout_bytes: process.output_bytes.stdout.load(Ordering::Relaxed),
+                stderr_bytes: process.output_bytes.stderr.load(Ordering::Relaxed),
+                detached: process.detached,
+            })
+            .collect()
+    }
 
-impl Default for Executor {
-    fn default() -> Self {
-        Self::new()
+    /// The OS process id of a running command.
+    pub fn pid(&self, exec_id: &str) -> Option<u32> {
+        self.processes.get(exec_id).filter(|process| process.is_running())?.pid
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Look up a process by id, defaulting to the most recently started one.
+    fn process_mut(&mut self, exec_id: Option<&str>) -> Result<&mut RunningProcess> {
+        let id = exec_id
+            .or(self.last_id.as_deref())
+            .context("No process is running")?;
+        self.processes
+            .get_mut(id)
+            .with_context(|| format!("Unknown exec id '{}'", id))
+    }
 
-    #[tokio::test]
-    async fn test_exec_echo() {
-        let mut executor = Executor::new();
-        let config = ExecConfig {
-            cmd: "echo".to_string(),
-            args: vec!["hello".to_string()],
-            ..Default::default()
+    /// Write to the stdin of a process, returning how many bytes it took.
+    #[cfg(test)]
+    pub async fn write_stdin(&mut self, exec_id: Option<&str>, data: &[u8]) -> Result<usize> {
+        self.queue_stdin(exec_id, data.to_vec())?.await
+    }
+
+    /// Queue a write to the stdin of a process, returning a future that
+    /// resolves to the number of bytes written once it is done, which is
+    /// all of them unless it fails.
+    ///
+    /// Writes to one process happen in the order they were queued. The
+    /// future does not borrow the executor, so a process slow to read its
+    /// input holds up only whoever awaits it; after the stdin timeout it
+    /// fails with [`StdinTimeout`].
+    pub fn queue_stdin(
+        &mut self,
+        exec_id: Option<&str>,
+        data: Vec<u8>,
+    ) -> Result<impl std::future::Future<Output = Result<usize>> + Send + 'static> {
+        let process = self.process_mut(exec_id)?;
+        // The writer stops at a broken pipe; drop it so this fails up front
+        if !process.is_running() || process.stdin.as_ref().is_some_and(|stdin| stdin.is_closed()) {
+            process.stdin = None;
+            anyhow::bail!(STDIN_GONE)
+        }
+        let Some(stdin) = process.stdin.as_ref() else {
+            anyhow::bail!("Process has no persistent stdin")
         };
+        let (done_tx, done_rx) = oneshot::channel();
+        stdin
+            .send((data, done_tx))
+            .map_err(|_| anyhow::anyhow!(STDIN_GONE))?;
+        Ok(async move { done_rx.await.unwrap_or_else(|_| Err(anyhow::anyhow!(STDIN_GONE))) })
+    }
 
-        let mut rx = executor.exec(config).await.unwrap();
-        
-        // Should receive stdout
-        if let Some(ProcessOutput::Stdout(line)) = rx.recv().await {
-            assert_eq!(line, "hello");
+    /// Close a process's stdin so it sees EOF.
+    ///
+    /// Succeeds even if stdin was never piped or is already closed.
+    /// On a terminal this sends the EOF character instead, since the master
+    /// must stay open for output.
+    pub fn close_stdin(&mut self, exec_id: Option<&str>) -> Result<()> {
+        let process = self.process_mut(exec_id)?;
+        if let Some(master) = &process.pty_master {
+            let eot = [0x04u8];
+            // SAFETY: writes one byte from a valid buffer to an fd we own
+            if unsafe { libc::write(master.as_raw_fd(), eot.as_ptr().cast(), 1) } < 0 {
+                return Err(std::io::Error::last_os_error()).context("Failed to send EOF");
+            }
+            return Ok(());
+        }
+        process.stdin = None;
+        Ok(())
+    }
+
+    /// Change the terminal size of a process started with a PTY.
+    pub fn resize(&mut self, exec_id: Option<&str>, size: WindowSize) -> Result<()> {
+        let process = self.process_mut(exec_id)?;
+        let master = process.pty_master.as_ref().context("Process has no terminal")?;
+        pty::resize(master, size).context("Failed to resize terminal")
+    }
+
+    /// Terminate a process and everything it spawned.
+    ///
+    /// Sends SIGTERM to the process group immediately and escalates to SIGKILL
+    /// in the background if the process is still alive after `KILL_GRACE`.
+    pub fn kill(&mut self, exec_id: Option<&str>) -> Result<()> {
+        // Stopping a process on purpose is not a crash
+        if let Some(id) = exec_id.or(self.last_id.as_deref()) {
+            self.restarts.remove(id);
+        }
+        // A command still waiting for a slot is simply never started
+        if let Some(position) = self.queue.iter().position(|queued| Some(queued.exec_id.as_str()) == exec_id) {
+            let queued = self.queue.remove(position).expect("position is in the queue");
+            info!(exec_id = %queued.exec_id, "Cancelled queued command");
+            let _ = queued.tx.try_send(never_ran_exit());
+            return Ok(());
+        }
+        let process = self.process_mut(exec_id)?;
+        if !process.is_running() {
+            anyhow::bail!("No process is running");
+        }
+        let pid = process.pid.context("Process has no pid")?;
+
+        info!(pid, "Sending SIGTERM");
+        signal_group(pid, libc::SIGTERM)?;
+
+        let mut exit_rx = process.exit_rx.clone();
+        tokio::spawn(async move {
+            let exited = tokio::time::timeout(KILL_GRACE, exit_rx.wait_for(Option::is_some)).await;
+            if exited.is_err() {
+                warn!(pid, "Process ignored SIGTERM, sending SIGKILL");
+                if let Err(e) = signal_group(pid, libc::SIGKILL) {
+                    error!(pid, error = %e, "Failed to kill process");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// The retained output of a command after chunk `since`, so a client
+    /// that lost some of it can catch up.
+    ///
+    /// Works until the command's id is reused or finished commands are
+    /// pruned by the next `exec`.
+    pub fn replay(&mut self, exec_id: Option<&str>, since: u64) -> Result<Replay> {
+        let id = exec_id.or(self.last_id.as_deref()).unwrap_or_default().to_string();
+        let process = self.process_mut(exec_id)?;
+        let (chunks, complete) = process.history.ring.lock().unwrap().since(since);
+        Ok(Replay { exec_id: id, chunks, complete })
+    }
+
+    /// Sample a running process's memory, open files and threads every
+    /// `interval` for up to `duration`.
+    ///
+    /// Returns the exec id being sampled and a channel of samples, each with
+    /// the time since sampling began, that closes when sampling ends or the
+    /// process exits. Only the process itself is sampled, not its children.
+    pub fn sample(
+        &mut self,
+        exec_id: Option<&str>,
+        interval: Duration,
+        duration: Duration,
+    ) -> Result<(String, mpsc::Receiver<(Duration, procfs::Sample)>)> {
+        if !procfs::SUPPORTED {
+            anyhow::bail!("Process sampling is not supported on this platform");
+        }
+        if interval < MIN_SAMPLE_INTERVAL {
+            anyhow::bail!("Sample interval must be at least {}ms", MIN_SAMPLE_INTERVAL.as_millis());
+        }
+        let id = exec_id.or(self.last_id.as_deref()).unwrap_or_default().to_string();
+        let process = self.process_mut(exec_id)?;
+        if !process.is_running() {
+            anyhow::bail!("No process is running");
+        }
+        let pid = process.pid.context("Process has no pid")?;
+        let mut exit_rx = process.exit_rx.clone();
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    _ = exit_rx.wait_for(Option::is_some) => break,
+                }
+                if started.elapsed() > duration {
+                    break;
+                }
+                let sample = procfs::sample(pid).await;
+                // Once reaped the pid may already belong to someone else
+                if sample.is_empty() || exit_rx.borrow().is_some() {
+                    break;
+                }
+                if tx.send((started.elapsed(), sample)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok((id, rx))
+    }
+
+    /// Respawn a process with its original config if it exits non-zero.
+    ///
+    /// `config` is what the process was started with; call this right after
+    /// `exec`. Restarts are refused once `policy.max_restarts` have happened
+    /// within `policy.window`, so a crash loop ends instead of spinning.
+    pub fn enable_restart(&mut self, exec_id: &str, policy: RestartPolicy, config: ExecConfig, pipe_stdin: bool) {
+        let state = RestartState {
+            policy,
+            config,
+            pipe_stdin,
+            recent: VecDeque::new(),
+        };
+        self.restarts.insert(exec_id.to_string(), state);
+    }
+
+    /// Handle the exit of a process that may need restarting.
+    pub async fn restart_crashed(&mut self, exec_id: &str, code: i32) -> Result<Restart> {
+        if code == 0 {
+            self.restarts.remove(exec_id);
+            return Ok(Restart::NotNeeded);
+        }
+        let Some(mut state) = self.restarts.remove(exec_id) else {
+            return Ok(Restart::NotNeeded);
+        };
+
+        let now = Instant::now();
+        while state
+            .recent
+            .front()
+            .is_some_and(|&at| now.duration_since(at) > state.policy.window)
+        {
+            state.recent.pop_front();
+        }
+        if state.recent.len() as u32 >= state.policy.max_restarts {
+            warn!(exec_id, restarts = state.recent.len(), "Process keeps crashing, not restarting");
+            return Ok(Restart::GaveUp {
+                restarts: state.recent.len() as u32,
+                window: state.policy.window,
+            });
+        }
+
+        info!(exec_id, code, "Restarting crashed process");
+        // Latency is measured from the restart, not the original request
+        let config = ExecConfig {
+            requested: None,
+            ..state.config.clone()
+        };
+        let output = self.exec(exec_id, config, state.pipe_stdin).await?;
+        state.recent.push_back(now);
+        let attempt = state.recent.len() as u32;
+        self.restarts.insert(exec_id.to_string(), state);
+        Ok(Restart::Restarted { output, attempt })
+    }
+
+    /// Kill every running process, as [`Executor::kill`] does for one.
+    ///
+    /// Returns how many processes were signalled.
+    pub fn kill_all(&mut self) -> usize {
+        for queued in self.queue.drain(..) {
+            let _ = queued.tx.try_send(never_ran_exit());
+        }
+        let running: Vec<String> = self
+            .processes
+            .iter()
+            .filter(|(_, process)| process.is_running())
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut killed = 0;
+        for id in running {
+            match self.kill(Some(&id)) {
+                Ok(()) => killed += 1,
+                Err(e) => warn!(exec_id = %id, error = %e, "Failed to kill process"),
+            }
+        }
+        killed
+    }
+
+    /// Cancel the reader and supervisor tasks of every process and forget them.
+    ///
+    /// For shutdown, once processes have had their chance to exit: output
+    /// channels close even if a grandchild still holds a pipe open. Nothing
+    /// is signalled, so call [`Executor::kill_all`] first.
+    pub fn abort_all(&mut self) {
+        self.restarts.clear();
+        self.queue.clear();
+        for (_, process) in self.processes.drain() {
+            for task in process.tasks {
+                task.abort();
+            }
+        }
+    }
+
+    /// Deliver a signal to a process (not its whole group).
+    pub fn signal(&mut self, exec_id: Option<&str>, signal: i32) -> Result<()> {
+        let process = self.process_mut(exec_id)?;
+        if !process.is_running() {
+            anyhow::bail!("No process is running");
+        }
+        let pid = process.pid.context("Process has no pid")?;
+
+        info!(pid, signal, "Sending signal");
+        // SAFETY: kill(2) has no memory-safety preconditions
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to signal process");
+        }
+        Ok(())
+    }
+
+    /// Wait for a process to complete.
+    #[allow(dead_code)]
+    pub async fn wait_for_completion(&mut self, exec_id: Option<&str>) -> Option<ProcessOutput> {
+        let id = exec_id.or(self.last_id.as_deref())?.to_string();
+        let mut process = self.processes.remove(&id)?;
+        process.stdin = None; // Close stdin to allow process to exit if waiting for it
+        let reaped = process.exit_rx.wait_for(Option::is_some).await.map(|reaped| *reaped);
+        match reaped {
+            Ok(Some((code, signal, usage))) => Some(process.output_bytes.exit(code, signal, usage)),
+            Ok(None) => Some(process.output_bytes.exit(-1, None, None)),
+            Err(_) => Some(ProcessOutput::Error(
+                "Process supervisor exited unexpectedly".to_string(),
+            )),
+        }
+    }
+}
+
+/// How a pipe's output is turned into events.
+#[derive(Debug, Clone, Copy)]
+struct ReadOptions {
+    /// Largest chunk read at once (plus the tail of a split character)
+    chunk_size: usize,
+    /// How long output is coalesced before being forwarded
+    window: Duration,
+    /// What the bytes are decoded from
+    encoding: Encoding,
+    /// Diagnostics to parse out of the text
+    diagnostics: Option<diagnostics::Format>,
+}
+
+/// Forward everything a pipe produces, without waiting for newlines.
+///
+/// Prompts, progress bars and partial lines are sent at most `window` after
+/// the child writes them, together with anything written meanwhile, and
+/// whatever is left when the pipe closes is flushed too.
+/// Every byte forwarded is added to the pipe's counter in `bytes`, and
+/// reading stops once the shared output cap has been reached. Without a
+/// `tx` the output is read and counted the same way, then dropped.
+async fn read_output<R: AsyncRead + Unpin>(
+    mut reader: R,
+    tx: Option<mpsc::Sender<ProcessOutput>>,
+    wrap: fn(u64, String) -> ProcessOutput,
+    bytes: Arc<OutputBytes>,
+    counter: fn(&OutputBytes) -> &AtomicU64,
+    options: ReadOptions,
+    history: Arc<OutputHistory>,
+) {
+    let ReadOptions {
+        chunk_size,
+        window,
+        encoding,
+        diagnostics,
+    } = options;
+    let Some(tx) = tx else {
+        return discard_output(reader, &bytes, counter, chunk_size).await;
+    };
+    let mut buf = vec![0u8; chunk_size];
+    let mut decoder = encoding::Decoder::new(encoding);
+    let mut pending = String::new();
+    let mut parser = diagnostics.map(diagnostics::Parser::new);
+    // Parsed from `pending`, so sent once it has been
+    let mut found = Vec::new();
+    let mut deadline = tokio::time::Instant::now();
+    loop {
+        // Only read what still fits, so a coalesced chunk stays within chunk_size
+        let room = chunk_size.saturating_sub(pending.len()).max(1);
+        let read = if pending.is_empty() {
+            reader.read(&mut buf[..room]).await
+        } else {
+            match tokio::time::timeout_at(deadline, reader.read(&mut buf[..room])).await {
+                Ok(read) => read,
+                Err(_) => {
+                    // The window closed with nothing more to add
+                    if history.deliver(&tx, wrap, std::mem::take(&mut pending)).await.is_err()
+                        || send_diagnostics(&tx, &mut found).await.is_err()
+                    {
+                        return;
+                    }
+                    continue;
+                }
+            }
+        };
+        let n = match read {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let allowed = bytes.admit(counter(&bytes), n);
+        let chunk = decoder.decode(&buf[..allowed]);
+        if pending.is_empty() {
+            deadline = tokio::time::Instant::now() + window;
+        }
+        if let Some(parser) = &mut parser {
+            found.extend(parser.feed(&chunk));
+        }
+        pending.push_str(&chunk);
+        if bytes.is_truncated() {
+            break;
+        }
+        let full = pending.len() >= chunk_size || window.is_zero();
+        if full
+            && !pending.is_empty()
+            && (history.deliver(&tx, wrap, std::mem::take(&mut pending)).await.is_err()
+                || send_diagnostics(&tx, &mut found).await.is_err())
+        {
+            return;
+        }
+    }
+    let rest = decoder.finish();
+    if let Some(parser) = &mut parser {
+        found.extend(parser.feed(&rest));
+        found.extend(parser.finish());
+    }
+    pending.push_str(&rest);
+    if !pending.is_empty() {
+        let _ = history.deliver(&tx, wrap, pending).await;
+    }
+    let _ = send_diagnostics(&tx, &mut found).await;
+}
+
+/// Send the diagnostics parsed from output just delivered.
+async fn send_diagnostics(
+    tx: &mpsc::Sender<ProcessOutput>,
+    found: &mut Vec<Diagnostic>,
+) -> Result<(), mpsc::error::SendError<ProcessOutput>> {
+    for diagnostic in found.drain(..) {
+        tx.send(ProcessOutput::Diagnostic(diagnostic)).await?;
+    }
+    Ok(())
+}
+
+/// Spawn the task feeding a process's input from a queue of writes.
+///
+/// A write not done within `timeout` is answered with [`StdinTimeout`] and
+/// the next one is tried. The task ends, closing stdin, once the queue is
+/// dropped and drained, or as soon as the pipe breaks.
+fn stdin_writer(mut stdin: ProcessStdin, timeout: Duration) -> (StdinQueue, tokio::task::AbortHandle) {
+    use tokio::io::AsyncWriteExt;
+    let (queue, mut writes) = mpsc::unbounded_channel::<(Vec<u8>, oneshot::Sender<Result<usize>>)>();
+    let writer = tokio::spawn(async move {
+        while let Some((data, done)) = writes.recv().await {
+            // Counted as it goes, so a write cut short can say how far it got
+            let mut written = 0;
+            let write = async {
+                while written < data.len() {
+                    match stdin.write(&data[written..]).await? {
+                        0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                        n => written += n,
+                    }
+                }
+                stdin.flush().await
+            };
+            let result = match tokio::time::timeout(timeout, write).await {
+                Ok(Ok(())) => Ok(written),
+                // Nothing reads the pipe any more, so no later write can succeed
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                    let _ = done.send(Err(anyhow::anyhow!(STDIN_GONE)));
+                    break;
+                }
+                Ok(Err(e)) => Err(anyhow::Error::new(e)
+                    .context(format!("Failed to write to stdin after {} of {} bytes", written, data.len()))),
+                Err(_) => Err(StdinTimeout { limit: timeout, bytes_written: written }.into()),
+            };
+            let _ = done.send(result);
+        }
+    });
+    (queue, writer.abort_handle())
+}
+
+/// Drain a pipe nobody subscribed to, so the child never blocks on it.
+async fn discard_output<R: AsyncRead + Unpin>(
+    mut reader: R,
+    bytes: &OutputBytes,
+    counter: fn(&OutputBytes) -> &AtomicU64,
+    chunk_size: usize,
+) {
+    let mut buf = vec![0u8; chunk_size];
+    while let Ok(n @ 1..) = reader.read(&mut buf).await {
+        bytes.admit(counter(bytes), n);
+        if bytes.is_truncated() {
+            break;
+        }
+    }
+}
+
+/// Incremental UTF-8 decoder for chunked pipe (or file) output.
+///
+/// A multi-byte character split across two reads is held back until the
+/// rest of it arrives; genuinely invalid bytes become U+FFFD.
+#[derive(Debug, Default)]
+pub(crate) struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Decode as much of the input as forms complete characters.
+    pub(crate) fn decode(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let split = self.pending.len() - incomplete_tail(&self.pending);
+        let tail = self.pending.split_off(split);
+        let chunk = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending = tail;
+        chunk
+    }
+
+    /// Decode whatever is still buffered once the stream has ended.
+    pub(crate) fn finish(&mut self) -> String {
+        let chunk = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        chunk
+    }
+}
+
+/// Length of an unfinished UTF-8 sequence at the end of `bytes`, if any.
+fn incomplete_tail(bytes: &[u8]) -> usize {
+    for i in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - i];
+        if byte & 0xC0 == 0x80 {
+            continue; // continuation byte, keep looking for the lead byte
+        }
+        let needed = match byte {
+            0xF0.. => 4,
+            0xE0.. => 3,
+            0xC0.. => 2,
+            _ => 1,
+        };
+        return if needed > i { i } else { 0 };
+    }
+    0
+}
+
+/// Resolve a requested working directory against [`DEFAULT_CWD`].
+pub fn resolve_cwd(cwd: Option<&str>) -> String {
+    match cwd {
+        Some(cwd) => Path::new(DEFAULT_CWD).join(cwd).to_string_lossy().into_owned(),
+        None => DEFAULT_CWD.to_string(),
+    }
+}
+
+/// Check that a working directory exists before spawning into it.
+///
+/// Without this the spawn fails with a bare ENOENT that reads as if the
+/// command itself were missing.
+async fn validate_cwd(cwd: &str) -> Result<()> {
+    let exists = match tokio::fs::metadata(cwd).await {
+        Ok(metadata) if metadata.is_dir() => return Ok(()),
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    Err(SpawnError::InvalidCwd {
+        cwd: cwd.to_string(),
+        exists,
+    }
+    .into())
+}
+
+/// The encoding a command declared for its output.
+fn output_encoding(config: &ExecConfig) -> Result<Encoding> {
+    config.output_encoding.as_deref().map_or(Ok(Encoding::Utf8), Encoding::for_label)
+}
+
+/// Check that every variable in a command's `env` can be passed to `execve`.
+fn validate_env(config: &ExecConfig) -> Result<()> {
+    check_env(&config.env)
+}
+
+/// Check that every variable in `env` can be passed to `execve`.
+///
+/// `Command::env` would otherwise drop or mangle such entries, or fail the
+/// spawn with an error that does not say which one was at fault.
+fn check_env(env: &HashMap<String, String>) -> Result<()> {
+    let mut keys: Vec<String> = env
+        .iter()
+        .filter(|(key, value)| key.is_empty() || key.contains(['=', '\0']) || value.contains('\0'))
+        .map(|(key, _)| key.clone())
+        .collect();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    keys.sort();
+    Err(SpawnError::InvalidEnv { keys }.into())
+}
+
+/// Variables describing the terminal a tty-mode command runs on, unless the
+/// caller sets its own.
+///
+/// Whatever `TERM` the agent inherited describes its own stdio, not the
+/// pseudo-terminal, so it is replaced too. `COLUMNS` and `LINES` give the
+/// initial size only; programs that care about resizes read it from the
+/// terminal itself.
+fn terminal_env(size: WindowSize) -> [(&'static str, String); 3] {
+    [
+        ("TERM", DEFAULT_TERM.to_string()),
+        ("COLUMNS", size.cols.to_string()),
+        ("LINES", size.rows.to_string()),
+    ]
+}
+
+/// The `PATH` a command gets once its `path_prepend` and `path_append` are
+/// merged in, or `None` when it has neither.
+///
+/// The directories are added around whatever `PATH` the command would
+/// otherwise see. When it would see none, they are added around the
+/// default search path instead, so standard tools are still found.
+fn merged_path(config: &ExecConfig, session_env: &HashMap<String, String>) -> Option<String> {
+    if config.path_prepend.is_empty() && config.path_append.is_empty() {
+        return None;
+    }
+    let inherited = (!config.clear_env && !config.env_remove.iter().any(|key| key == "PATH"))
+        .then(|| session_env.get("PATH").cloned().or_else(|| std::env::var("PATH").ok()))
+        .flatten();
+    let base = config.env.get("PATH").cloned().or(inherited).unwrap_or_else(|| DEFAULT_PATH.to_string());
+    let dirs: Vec<&str> = config
+        .path_prepend
+        .iter()
+        .map(String::as_str)
+        .chain((!base.is_empty()).then_some(base.as_str()))
+        .chain(config.path_append.iter().map(String::as_str))
+        .collect();
+    Some(dirs.join(":"))
+}
+
+/// Find the executable `cmd` would run, the way `execvp` searches.
+///
+/// Commands containing a `/` are taken as paths; anything else is looked up
+/// in the `PATH` the child will see.
+fn resolve_command(config: &ExecConfig) -> std::result::Result<PathBuf, SpawnError> {
+    let not_found = || SpawnError::CommandNotFound {
+        cmd: config.cmd.clone(),
+        source: std::io::Error::from_raw_os_error(libc::ENOENT),
+    };
+    let candidates: Vec<PathBuf> = if config.cmd.contains('/') {
+        vec![Path::new(&config.cwd).join(&config.cmd)]
+    } else if config.cmd.is_empty() {
+        return Err(not_found());
+    } else {
+        let inherited = (!config.clear_env && !config.env_remove.iter().any(|key| key == "PATH"))
+            .then(|| std::env::var("PATH").ok())
+            .flatten();
+        let path = config.env.get("PATH").cloned().or(inherited).unwrap_or_else(|| DEFAULT_PATH.to_string());
+        path.split(':')
+            .map(|dir| Path::new(if dir.is_empty() { "." } else { dir }).join(&config.cmd))
+            .collect()
+    };
+
+    // Like execvp, a match without execute permission is only reported
+    // once no later directory has a runnable one
+    let mut denied = false;
+    for candidate in candidates {
+        match std::fs::metadata(&candidate) {
+            Ok(metadata) if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 => return Ok(candidate),
+            Ok(_) => denied = true,
+            Err(_) => {}
+        }
+    }
+    if denied {
+        return Err(SpawnError::PermissionDenied {
+            cmd: config.cmd.clone(),
+            source: std::io::Error::from_raw_os_error(libc::EACCES),
+        });
+    }
+    Err(not_found())
+}
+
+/// Run every pre-spawn check on `config` without starting anything.
+///
+/// Unlike [`Executor::exec`], which stops at the first problem, this
+/// collects them all so a client can fix everything in one go. The command
+/// is looked up with any session `PATH` in effect.
+pub async fn validate(config: &ExecConfig, session_env: &HashMap<String, String>) -> Vec<SpawnError> {
+    let mut failures = Vec::new();
+    let mut env: HashMap<String, String> = session_env
+        .iter()
+        .filter(|(key, _)| !config.clear_env && !config.env_remove.contains(key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    env.extend(config.env.clone());
+    if let Some(path) = merged_path(config, session_env) {
+        env.insert("PATH".to_string(), path);
+    }
+    let mut lookup = ExecConfig { env, ..config.clone() };
+    if let Err(err) = expand_command(&mut lookup, session_env) {
+        failures.extend(err.downcast::<SpawnError>().ok());
+    }
+    if let Err(err) = resolve_command(&lookup) {
+        failures.push(err);
+    }
+    for check in [validate_cwd(&config.cwd).await, validate_env(config)] {
+        if let Err(err) = check {
+            failures.extend(err.downcast::<SpawnError>().ok());
+        }
+    }
+    failures
+}
+
+/// Apply `expand_env` to a command's `cmd` and `args`.
+fn expand_command(config: &mut ExecConfig, session_env: &HashMap<String, String>) -> Result<()> {
+    let Some(undefined_vars) = config.expand_env else {
+        return Ok(());
+    };
+    let env = command_env(config, session_env);
+    let mut undefined = Vec::new();
+    config.cmd = expand_vars(&config.cmd, &env, &mut undefined);
+    for arg in &mut config.args {
+        *arg = expand_vars(arg, &env, &mut undefined);
+    }
+    if undefined_vars == UndefinedVars::Error && !undefined.is_empty() {
+        return Err(SpawnError::UndefinedEnv { names: undefined }.into());
+    }
+    config.expand_env = None;
+    Ok(())
+}
+
+/// The environment a command is spawned with, built the way
+/// [`Executor::exec`] builds it.
+fn command_env(config: &ExecConfig, session_env: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut env: HashMap<String, String> = HashMap::new();
+    if !config.clear_env {
+        env.extend(std::env::vars_os().filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?))));
+        env.extend(session_env.iter().map(|(key, value)| (key.clone(), value.clone())));
+    }
+    env.retain(|key, _| !config.env_remove.contains(key));
+    if let Some(size) = config.tty {
+        for (key, value) in terminal_env(size) {
+            if !config.env.contains_key(key) && !session_env.contains_key(key) {
+                env.insert(key.to_string(), value);
+            }
+        }
+    }
+    env.extend(config.env.iter().map(|(key, value)| (key.clone(), value.clone())));
+    if let Some(path) = merged_path(config, session_env) {
+        env.insert("PATH".to_string(), path);
+    }
+    env
+}
+
+/// Substitute `${NAME}` in `text` from `env`, with `$$` for a literal `$`.
+///
+/// Any other `$`, such as `$NAME` without braces, is kept as it is. Names
+/// that are not set become empty and are added to `undefined`, once each.
+fn expand_vars(text: &str, env: &HashMap<String, String>, undefined: &mut Vec<String>) -> String {
+    let is_name = |name: &str| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('$') {
+        expanded.push_str(&rest[..at]);
+        let after = &rest[at + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        match after.strip_prefix('{').and_then(|inner| inner.split_once('}')) {
+            Some((name, after)) if is_name(name) => {
+                match env.get(name) {
+                    Some(value) => expanded.push_str(value),
+                    None if !undefined.iter().any(|seen| seen == name) => undefined.push(name.to_string()),
+                    None => {}
+                }
+                rest = after;
+            }
+            _ => {
+                expanded.push('$');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Classify a spawn failure so the Control Plane can tell a missing binary
+/// from other errors.
+///
+/// The working directory is validated beforehand, so ENOENT here means the
+/// command itself.
+fn spawn_error(err: std::io::Error, config: &ExecConfig) -> anyhow::Error {
+    let cmd = &config.cmd;
+    if err.raw_os_error() == Some(libc::E2BIG) {
+        return SpawnError::ArgumentListTooLong {
+            cmd: cmd.to_string(),
+            args: config.args.len(),
+            bytes: config.args.iter().map(|arg| arg.len() + 1).sum(),
+            source: err,
+        }
+        .into();
+    }
+    match err.kind() {
+        std::io::ErrorKind::NotFound => SpawnError::CommandNotFound {
+            cmd: cmd.to_string(),
+            source: err,
+        }
+        .into(),
+        std::io::ErrorKind::PermissionDenied => SpawnError::PermissionDenied {
+            cmd: cmd.to_string(),
+            source: err,
+        }
+        .into(),
+        _ => anyhow::Error::new(err).context("Failed to spawn process"),
+    }
+}
+
+/// Reap a child, collecting its own resource usage along with the status.
+///
+/// `wait4` has no async form, so it runs on the blocking pool; tokio's
+/// `Child::wait` is only used when the pid is unknown.
+async fn wait_with_usage(
+    mut child: tokio::process::Child,
+    pid: Option<u32>,
+) -> std::io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    let Some(pid) = pid else {
+        return Ok((child.wait().await?, None));
+    };
+    let reaped = tokio::task::spawn_blocking(move || {
+        let mut status = 0;
+        // SAFETY: rusage is plain data, fully written by wait4 on success
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            // SAFETY: both out-pointers are valid for the duration of the call
+            if unsafe { libc::wait4(pid as libc::pid_t, &mut status, 0, &mut usage) } >= 0 {
+                return Ok((ExitStatus::from_raw(status), ResourceUsage::from_rusage(&usage)));
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+    // The pid is reaped behind tokio's back. Dropping the handle would queue
+    // it for reaping again, which could claim another command once the pid is
+    // reused; its stdio is already taken, so forgetting it frees nothing.
+    std::mem::forget(child);
+    Ok((reaped.0, Some(reaped.1)))
+}
+
+/// Create an anonymous pipe, returning its read and write ends.
+fn output_pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe2 writes two fds into the array we pass
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 succeeded, so both fds are open and owned by us
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// Check a requested niceness before anything is spawned.
+///
+/// Raising niceness is always allowed, but lowering it below 0 needs
+/// privileges; checking here gives a clearer error than the EACCES the
+/// child would otherwise fail with.
+fn validate_nice(nice: Option<i32>) -> Result<()> {
+    match nice {
+        Some(nice) if !(-20..=19).contains(&nice) => {
+            anyhow::bail!("Invalid nice value {}: must be between -20 and 19", nice)
+        }
+        // SAFETY: geteuid has no preconditions
+        Some(nice) if nice < 0 && unsafe { libc::geteuid() } != 0 => {
+            anyhow::bail!("Setting nice value {} requires the agent to run as root", nice)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Move the child into its cgroup between fork and exec, so its limits
+/// hold from the first instruction and cover everything it starts.
+fn apply_cgroup(cmd: &mut Command, cgroup: &cgroup::Cgroup) {
+    let procs = cgroup.procs_fd();
+    // SAFETY: the closure runs in the forked child and only makes an
+    // async-signal-safe syscall on an fd the cgroup keeps open until spawned
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::write(procs, b"0".as_ptr().cast(), 1) != 1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Install resource limits, the scheduling priority and the umask that
+/// apply to the child between fork and exec. `memory_limit` is the part of
+/// the config's that is left to `RLIMIT_AS`.
+fn apply_limits(cmd: &mut Command, config: &ExecConfig, memory_limit: Option<u64>) {
+    let cpu_limit = config.cpu_seconds;
+    let nice = config.nice;
+    let umask = config.umask;
+    let capture_core = config.capture_core;
+    if memory_limit.is_none() && cpu_limit.is_none() && nice.is_none() && umask.is_none() && !capture_core {
+        return;
+    }
+
+    // SAFETY: the closure runs in the forked child and only makes
+    // async-signal-safe syscalls
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(bytes) = memory_limit {
+                set_rlimit(libc::RLIMIT_AS, bytes, bytes)?;
+            }
+            if let Some(seconds) = cpu_limit {
+                // The hard limit is a SIGKILL; leave a second of headroom
+                // so the soft limit's SIGXCPU is what ends the process
+                set_rlimit(libc::RLIMIT_CPU, seconds, seconds.saturating_add(1))?;
+            }
+            // Before any uid change, which would forfeit the right to lower it
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(mask) = umask {
+                libc::umask(mask as libc::mode_t);
+            }
+            if capture_core {
+                let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+                if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                limit.rlim_cur = limit.rlim_max;
+                if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Switch the child to the configured user and groups before exec.
+///
+/// Groups are changed before the uid, since an unprivileged process can no
+/// longer change them. Limits are applied earlier for the same reason.
+fn apply_identity(cmd: &mut Command, config: &ExecConfig) -> Result<()> {
+    let (uid, gid) = (config.uid, config.gid);
+    if uid.is_none() && gid.is_none() && config.supplementary_groups.is_none() {
+        return Ok(());
+    }
+
+    // SAFETY: geteuid has no preconditions
+    if unsafe { libc::geteuid() } != 0 {
+        anyhow::bail!("Changing uid or gid requires the agent to run as root");
+    }
+
+    let groups: Vec<libc::gid_t> = config.supplementary_groups.clone().unwrap_or_default();
+    // SAFETY: the closure runs in the forked child and only makes
+    // async-signal-safe syscalls on memory it owns
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if let Some(gid) = gid {
+                if libc::setgid(gid) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(uid) = uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Set the soft and hard value of a resource limit.
+fn set_rlimit(resource: RlimitResource, soft: u64, hard: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    // SAFETY: setrlimit only reads the struct we pass it
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The resource argument type of setrlimit(2) differs between libcs.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type RlimitResource = libc::c_int;
+
+/// Work out whether a process died because of a configured resource limit.
+///
+/// With RLIMIT_AS the kernel never kills the process itself; allocation
+/// failures surface as an abort or a segfault, so those signals are treated
+/// as a memory-limit termination when a limit was set. RLIMIT_CPU is
+/// unambiguous: the kernel delivers SIGXCPU.
+fn exceeded_limit(
+    status: ExitStatus,
+    memory_limit: Option<u64>,
+    cpu_limit: Option<u64>,
+) -> Option<ResourceLimit> {
+    let signal = status.signal()?;
+    if let (Some(seconds), libc::SIGXCPU) = (cpu_limit, signal) {
+        return Some(ResourceLimit::Cpu(seconds));
+    }
+    match memory_limit {
+        Some(bytes) if [libc::SIGABRT, libc::SIGSEGV, libc::SIGBUS].contains(&signal) => {
+            Some(ResourceLimit::Memory(bytes))
+        }
+        _ => None,
+    }
+}
+
+/// Signals that may be sent by name through `exec.signal`.
+const SIGNAL_NAMES: &[(&str, i32)] = &[
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGTERM", libc::SIGTERM),
+    ("SIGCONT", libc::SIGCONT),
+    ("SIGSTOP", libc::SIGSTOP),
+    ("SIGTSTP", libc::SIGTSTP),
+    ("SIGWINCH", libc::SIGWINCH),
+];
+
+/// Resolve a signal name such as "SIGHUP" or "hup" to its number.
+pub fn parse_signal_name(name: &str) -> Result<i32> {
+    let upper = name.trim().to_ascii_uppercase();
+    let full = if upper.starts_with("SIG") {
+        upper
+    } else {
+        format!("SIG{}", upper)
+    };
+    SIGNAL_NAMES
+        .iter()
+        .find(|(candidate, _)| *candidate == full)
+        .map(|(_, number)| *number)
+        .with_context(|| format!("Unrecognized signal '{}'", name))
+}
+
+/// Deliver a signal to the process group led by `pid`.
+fn signal_group(pid: u32, signal: i32) -> Result<()> {
+    // SAFETY: kill(2) has no memory-safety preconditions
+    if unsafe { libc::kill(-(pid as libc::pid_t), signal) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to signal process");
+    }
+    Ok(())
+}
+
+/// The exit reported for a queued command that never got to run.
+fn never_ran_exit() -> ProcessOutput {
+    ProcessOutput::Exit {
+        code: -1,
+        signal: None,
+        stdout_bytes: 0,
+        stderr_bytes: 0,
+        truncated: false,
+        usage: None,
+        timing: Timing::default(),
+    }
+}
+
+/// Map an exit status to a shell-style exit code.
+///
+/// Processes killed by a signal report `128 + signal`, matching bash.
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => -1,
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a config that runs in the temp dir, since /workspace only exists in sandboxes.
+    fn test_config(cmd: &str, args: &[&str]) -> ExecConfig {
+        ExecConfig {
+            cmd: cmd.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            cwd: std::env::temp_dir().to_string_lossy().into_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exec_echo() {
+        let mut executor = Executor::new();
+        let config = test_config("echo", &["hello"]);
+
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+        
+        // Should receive stdout, newline included
+        if let Some(ProcessOutput::Stdout { chunk, .. }) = rx.recv().await {
+            assert_eq!(chunk, "hello\n");
+        }
+    }
+
+    /// Run a command to completion, returning its output and final exit code.
+    async fn run_to_completion(config: ExecConfig) -> (Vec<ProcessOutput>, Option<ProcessOutput>) {
+        let mut executor = Executor::new();
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+        let mut outputs = Vec::new();
+        while let Some(output) = rx.recv().await {
+            outputs.push(output);
+        }
+        let completion = executor.wait_for_completion(None).await;
+        (outputs, completion)
+    }
+
+    #[tokio::test]
+    async fn test_spawn_failures_are_classified() {
+        let mut executor = Executor::new();
+        let err = executor
+            .exec("missing", test_config("definitely-not-a-command", &[]), false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SpawnError::CommandNotFound { cmd, .. }) if cmd == "definitely-not-a-command"));
+
+        // Without any execute bit, even root cannot run the file
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("script.sh");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        let config = test_config(script.to_str().unwrap(), &[]);
+        let err = executor.exec("denied", config, false).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SpawnError::PermissionDenied { .. })));
+
+        let config = ExecConfig {
+            cwd: "/definitely/not/here".to_string(),
+            ..test_config("true", &[])
+        };
+        let err = executor.exec("cwd", config, false).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SpawnError::InvalidCwd { exists: false, .. })));
+        assert_eq!(err.to_string(), "Working directory '/definitely/not/here' does not exist");
+
+        // 16 MiB of arguments is beyond any kernel's limit
+        let huge = vec!["x".repeat(1023); 16 * 1024];
+        let config = ExecConfig {
+            args: huge,
+            ..test_config("true", &[])
+        };
+        let err = executor.exec("huge", config, false).await.unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(SpawnError::ArgumentListTooLong { args: 16384, bytes: 16777216, .. })),
+            "{:#}",
+            err
+        );
+        assert!(err.to_string().contains("xargs"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_validate_reports_every_failure_without_spawning() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let script = format!("touch {}", marker.display());
+        let config = ExecConfig {
+            cwd: dir.path().to_string_lossy().into_owned(),
+            ..test_config("sh", &["-c", &script])
+        };
+        assert!(validate(&config, &HashMap::new()).await.is_empty());
+        assert!(!marker.exists());
+
+        let config = ExecConfig {
+            cwd: "/definitely/not/here".to_string(),
+            env: HashMap::from([("BAD=KEY".to_string(), "1".to_string())]),
+            ..test_config("definitely-not-a-command", &[])
+        };
+        let failures = validate(&config, &HashMap::new()).await;
+        assert!(matches!(failures[..], [
+            SpawnError::CommandNotFound { .. },
+            SpawnError::InvalidCwd { exists: false, .. },
+            SpawnError::InvalidEnv { ref keys },
+        ] if keys == &["BAD=KEY"]), "{:?}", failures);
+
+        // The command is looked up in the PATH the child will get
+        let config = ExecConfig {
+            env: HashMap::from([("PATH".to_string(), dir.path().to_string_lossy().into_owned())]),
+            ..test_config("sh", &[])
+        };
+        assert!(matches!(validate(&config, &HashMap::new()).await[..], [SpawnError::CommandNotFound { .. }]));
+    }
+
+    #[tokio::test]
+    async fn test_path_dirs_are_merged_into_the_inherited_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let shim = dir.path().join("ls");
+        std::fs::write(&shim, "#!/bin/sh\necho shim\n").unwrap();
+        std::fs::set_permissions(&shim, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let shim_dir = dir.path().to_string_lossy().into_owned();
+        let stdout = |outputs: &[ProcessOutput]| -> String {
+            outputs
+                .iter()
+                .filter_map(|output| match output {
+                    ProcessOutput::Stdout { chunk, .. } => Some(chunk.as_str()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Prepended, the shim shadows the real ls
+        let config = ExecConfig {
+            path_prepend: vec![shim_dir.clone()],
+            ..test_config("ls", &[])
+        };
+        assert!(validate(&config, &HashMap::new()).await.is_empty());
+        let (outputs, _) = run_to_completion(config).await;
+        assert_eq!(stdout(&outputs), "shim\n");
+
+        // With no PATH at all, the default search path is kept
+        let config = ExecConfig {
+            clear_env: true,
+            path_append: vec![shim_dir.clone()],
+            ..test_config("sh", &["-c", "ls >/dev/null && echo \"$PATH\""])
+        };
+        let (outputs, _) = run_to_completion(config).await;
+        assert_eq!(stdout(&outputs), format!("{}:{}\n", DEFAULT_PATH, shim_dir));
+
+        let config = ExecConfig {
+            path_prepend: vec!["/opt/a:/opt/b".to_string()],
+            ..test_config("true", &[])
+        };
+        let err = Executor::new().exec("bad", config, false).await.unwrap_err();
+        assert!(err.to_string().contains("Invalid PATH directory '/opt/a:/opt/b'"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_env_is_validated_before_spawning() {
+        let mut executor = Executor::new();
+        let with_env = |env: &[(&str, &str)]| ExecConfig {
+            env: env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..test_config("sh", &["-c", "echo \"$GREETING\""])
+        };
+
+        let mut rx = executor.exec("valid", with_env(&[("GREETING", "a=b")]), false).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(ProcessOutput::Stdout { chunk, .. }) if chunk == "a=b\n"));
+
+        let err = executor
+            .exec("equals", with_env(&[("A=B", "1"), ("OK", "1"), ("", "1")]), false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SpawnError::InvalidEnv { keys }) if keys == &["", "A=B"]));
+        assert_eq!(err.to_string(), "Invalid environment variable names or values: \"\", \"A=B\"");
+
+        let err = executor.exec("nul", with_env(&[("NUL", "a\0b")]), false).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SpawnError::InvalidEnv { keys }) if keys == &["NUL"]));
+    }
+
+    #[tokio::test]
+    async fn test_shell_mode_interprets_the_command() {
+        let stdout = |outputs: Vec<ProcessOutput>| -> String {
+            outputs
+                .into_iter()
+                .filter_map(|output| match output {
+                    ProcessOutput::Stdout { chunk, .. } => Some(chunk),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Without a shell the pipe is just more arguments to echo
+        let (outputs, _) = run_to_completion(test_config("echo", &["hi", "|", "tr", "a-z", "A-Z"])).await;
+        assert_eq!(stdout(outputs), "hi | tr a-z A-Z\n");
+
+        let (outputs, _) = run_to_completion(test_config("echo hi | tr a-z A-Z", &[]).through_shell("/bin/sh")).await;
+        assert_eq!(stdout(outputs), "HI\n");
+
+        let script = test_config("echo \"$1-$2\"", &["a", "b"]).through_shell("/bin/sh");
+        let (outputs, _) = run_to_completion(script).await;
+        assert_eq!(stdout(outputs), "a-b\n");
+    }
+
+    #[tokio::test]
+    async fn test_session_env_is_inherited_by_later_commands() {
+        async fn stdout(executor: &mut Executor, config: ExecConfig) -> String {
+            let mut rx = executor.exec("env", config, false).await.unwrap();
+            let mut stdout = String::new();
+            while let Some(output) = rx.recv().await {
+                if let ProcessOutput::Stdout { chunk, .. } = output {
+                    stdout.push_str(&chunk);
+                }
+            }
+            stdout
+        }
+        let script = test_config("sh", &["-c", "echo \"${FOO:-unset} ${BAR:-unset}\""]);
+
+        let mut executor = Executor::new();
+        let vars = HashMap::from([("FOO".to_string(), "bar".to_string()), ("BAR".to_string(), "1".to_string())]);
+        executor.set_env(vars).unwrap();
+        assert_eq!(stdout(&mut executor, script.clone()).await, "bar 1\n");
+
+        // The command's own env wins, and the session's counts as inherited
+        let config = ExecConfig {
+            env: HashMap::from([("FOO".to_string(), "mine".to_string())]),
+            env_remove: vec!["BAR".to_string()],
+            ..script.clone()
+        };
+        assert_eq!(stdout(&mut executor, config).await, "mine unset\n");
+
+        executor.unset_env(&["FOO".to_string()]);
+        assert_eq!(stdout(&mut executor, script).await, "unset 1\n");
+
+        let invalid = HashMap::from([("A=B".to_string(), "1".to_string()), ("OK".to_string(), "1".to_string())]);
+        assert!(executor.set_env(invalid).is_err());
+        assert!(!executor.session_env().contains_key("OK"));
+    }
+
+    #[tokio::test]
+    async fn test_expand_env_substitutes_variables_in_args() {
+        let mut executor = Executor::new();
+        executor.set_env(HashMap::from([("MODEL".to_string(), "small".to_string())])).unwrap();
+        let config = ExecConfig {
+            env: HashMap::from([("RUN".to_string(), "7".to_string())]),
+            expand_env: Some(UndefinedVars::Empty),
+            ..test_config("echo", &["${MODEL}/run-${RUN}", "[${MISSING}]", "$${RUN} costs $5"])
+        };
+        let mut rx = executor.exec("expand", config.clone(), false).await.unwrap();
+        let mut stdout = String::new();
+        while let Some(output) = rx.recv().await {
+            if let ProcessOutput::Stdout { chunk, .. } = output {
+                stdout.push_str(&chunk);
+            }
+        }
+        assert_eq!(stdout, "small/run-7 [] ${RUN} costs $5\n");
+
+        // Left alone unless asked for
+        let literal = ExecConfig { expand_env: None, ..config.clone() };
+        let mut rx = executor.exec("literal", literal, false).await.unwrap();
+        let mut stdout = String::new();
+        while let Some(output) = rx.recv().await {
+            if let ProcessOutput::Stdout { chunk, .. } = output {
+                stdout.push_str(&chunk);
+            }
+        }
+        assert_eq!(stdout, "${MODEL}/run-${RUN} [${MISSING}] $${RUN} costs $5\n");
+
+        let strict = ExecConfig {
+            expand_env: Some(UndefinedVars::Error),
+            args: vec!["${MISSING}".to_string(), "${MODEL}".to_string(), "${OTHER}-${MISSING}".to_string()],
+            ..config
+        };
+        let err = executor.exec("strict", strict.clone(), false).await.unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(SpawnError::UndefinedEnv { names }) if names == &["MISSING", "OTHER"]),
+            "{:#}",
+            err
+        );
+        let failures = validate(&strict, executor.session_env()).await;
+        assert!(matches!(failures.as_slice(), [SpawnError::UndefinedEnv { .. }]), "{:?}", failures);
+    }
+
+    #[test]
+    fn test_expand_vars_leaves_other_dollars_alone() {
+        let env = HashMap::from([("A".to_string(), "1".to_string())]);
+        let mut undefined = Vec::new();
+        assert_eq!(expand_vars("$A ${A} ${ A} ${} ${A", &env, &mut undefined), "$A 1 ${ A} ${} ${A");
+        assert_eq!(expand_vars("$$$$ ${A}$$", &env, &mut undefined), "$$ 1$");
+        assert!(undefined.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stdin_can_come_from_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("big.txt");
+        std::fs::write(&input, "pear\napple\nfig\n").unwrap();
+        let config = ExecConfig {
+            stdin_file: Some(input),
+            ..test_config("sort", &[])
+        };
+        let (outputs, completion) = run_to_completion(config).await;
+        let stdout: String = outputs
+            .iter()
+            .filter_map(|output| match output {
+                ProcessOutput::Stdout { chunk, .. } => Some(chunk.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(stdout, "apple\nfig\npear\n");
+        assert!(matches!(completion, Some(ProcessOutput::Exit { code: 0, .. })));
+
+        let config = ExecConfig {
+            stdin_file: Some(dir.path().join("missing.txt")),
+            ..test_config("sort", &[])
+        };
+        let err = Executor::new().exec("missing", config, false).await.unwrap_err();
+        assert!(err.to_string().contains("Failed to open stdin file"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_exit_reports_resource_usage() {
+        // Pure shell arithmetic, so the time is spent in the process itself
+        let script = "i=0; while [ $i -lt 200000 ]; do i=$((i + 1)); done";
+        let (outputs, completion) = run_to_completion(test_config("/bin/sh", &["-c", script])).await;
+        for exit in [outputs.last().cloned(), completion] {
+            match exit {
+                Some(ProcessOutput::Exit { code: 0, usage: Some(usage), .. }) => {
+                    assert!(usage.cpu_user_ms > 0, "{:?}", usage);
+                    assert!(usage.max_rss_kb > 0, "{:?}", usage);
+                }
+                other => panic!("expected exit with usage, got {:?}", other),
+            }
+        }
+    }
+
+    /// A fresh directory in the cgroup v2 hierarchy this test runs in, if
+    /// one can be created here.
+    fn test_cgroup_dir() -> Option<PathBuf> {
+        let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+        let mount = mounts.lines().find_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            (fields.get(2) == Some(&"cgroup2")).then(|| PathBuf::from(fields[1]))
+        })?;
+        let own = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+        let own = own.lines().find_map(|line| line.strip_prefix("0::"))?;
+        let dir = mount.join(own.trim_start_matches('/')).join(format!("boxed-test-{}", std::process::id()));
+        std::fs::create_dir(&dir).ok()?;
+        Some(dir)
+    }
+
+    #[tokio::test]
+    async fn test_commands_run_in_a_cgroup_of_their_own() {
+        let Some(dir) = test_cgroup_dir() else {
+            return; // no writable cgroup v2 hierarchy on this host
+        };
+        let parent = cgroup::Parent::open(dir.clone()).unwrap();
+        let memory_controller = std::fs::read_to_string(dir.join("cgroup.subtree_control")).unwrap().contains("memory");
+        let mut executor = Executor::new().with_cgroup_parent(Some(parent));
+
+        // The busy loop is never waited for, so only the cgroup sees its time
+        let script = "sh -c 'while :; do :; done' >/dev/null 2>&1 & sleep 0.5; ulimit -v; cat /proc/self/cgroup";
+        let config = ExecConfig {
+            memory_limit_bytes: Some(256 * 1024 * 1024),
+            ..test_config("sh", &["-c", script])
+        };
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+        let mut stdout = String::new();
+        let mut exit = None;
+        while let Some(output) = rx.recv().await {
+            match output {
+                ProcessOutput::Stdout { chunk, .. } => stdout.push_str(&chunk),
+                ProcessOutput::Exit { code, usage, .. } => exit = Some((code, usage)),
+                _ => {}
+            }
+        }
+        // The cgroup went with the process, taking the busy loop with it
+        let leftover: Vec<_> = std::fs::read_dir(&dir).unwrap().flatten().filter(|e| e.path().is_dir()).collect();
+        std::fs::remove_dir(&dir).unwrap();
+        assert!(leftover.is_empty(), "{:?}", leftover);
+
+        let name = dir.file_name().unwrap().to_string_lossy();
+        assert!(stdout.contains(&format!("/{}/boxed-test-0\n", name)), "{}", stdout);
+        // memory.max replaces RLIMIT_AS when the controller is there
+        let rlimit = if memory_controller { "unlimited" } else { "262144" };
+        assert!(stdout.starts_with(&format!("{}\n", rlimit)), "{}", stdout);
+        let (code, usage) = exit.expect("process exited");
+        assert_eq!(code, 0);
+        let usage = usage.expect("usage was measured");
+        assert!(usage.cpu_user_ms + usage.cpu_sys_ms >= 200, "{:?}", usage);
+    }
+
+    #[tokio::test]
+    async fn test_crashed_process_restarts_until_the_cap() {
+        let mut executor = Executor::new();
+        let config = test_config("/bin/sh", &["-c", "echo up; exit 3"]);
+        let policy = RestartPolicy {
+            max_restarts: 2,
+            window: Duration::from_secs(60),
+        };
+        let mut rx = executor.exec("repl", config.clone(), true).await.unwrap();
+        executor.enable_restart("repl", policy, config, true);
+
+        for expected in 1..=2 {
+            while rx.recv().await.is_some() {}
+            match executor.restart_crashed("repl", 3).await.unwrap() {
+                Restart::Restarted { output, attempt } => {
+                    assert_eq!(attempt, expected);
+                    rx = output;
+                }
+                other => panic!("expected restart, got {:?}", other),
+            }
+        }
+        let mut outputs = Vec::new();
+        while let Some(output) = rx.recv().await {
+            outputs.push(output);
+        }
+        assert_eq!(stdout_of(&outputs), "up\n");
+        assert!(matches!(
+            executor.restart_crashed("repl", 3).await.unwrap(),
+            Restart::GaveUp { restarts: 2, .. }
+        ));
+        // Once given up, later exits are left alone
+        assert!(matches!(executor.restart_crashed("repl", 3).await.unwrap(), Restart::NotNeeded));
+    }
+
+    #[tokio::test]
+    async fn test_clean_exit_or_kill_is_not_restarted() {
+        let mut executor = Executor::new();
+        let policy = RestartPolicy {
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+        };
+        let config = test_config("true", &[]);
+        let _rx = executor.exec("clean", config.clone(), true).await.unwrap();
+        executor.enable_restart("clean", policy, config, true);
+        assert!(matches!(executor.restart_crashed("clean", 0).await.unwrap(), Restart::NotNeeded));
+
+        let config = test_config("sleep", &["30"]);
+        let _rx = executor.exec("killed", config.clone(), true).await.unwrap();
+        executor.enable_restart("killed", policy, config, true);
+        executor.kill(Some("killed")).unwrap();
+        assert!(matches!(executor.restart_crashed("killed", 143).await.unwrap(), Restart::NotNeeded));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_completion_reports_success() {
+        let (outputs, completion) = run_to_completion(test_config("echo", &["hello"])).await;
+        assert!(matches!(outputs.first(), Some(ProcessOutput::Stdout { chunk, .. }) if chunk == "hello\n"));
+        assert!(matches!(completion, Some(ProcessOutput::Exit { code: 0, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_completion_reports_failure() {
+        let (_, completion) = run_to_completion(test_config("sh", &["-c", "exit 3"])).await;
+        assert!(matches!(completion, Some(ProcessOutput::Exit { code: 3, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_long_line_is_forwarded_in_bounded_chunks() {
+        const LINE: u64 = 50 * 1024 * 1024;
+        let mut executor = Executor::with_max_chunk_bytes(4096);
+        let script = format!("head -c {} /dev/zero | tr '\\0' a", LINE);
+        let mut rx = executor.exec("long", test_config("sh", &["-c", &script]), false).await.unwrap();
+        let (mut total, mut largest) = (0, 0);
+        while let Some(output) = rx.recv().await {
+            if let ProcessOutput::Stdout { chunk, .. } = output {
+                total += chunk.len() as u64;
+                largest = largest.max(chunk.len());
+            }
+        }
+        assert_eq!(total, LINE);
+        assert!(largest <= 4096, "chunk of {} bytes", largest);
+    }
+
+    #[tokio::test]
+    async fn test_chatty_output_is_coalesced_within_the_window() {
+        // Twenty separate writes spread over a couple of hundred milliseconds
+        let script = "for i in 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20; do echo $i; sleep 0.01; done";
+        let mut counts = Vec::new();
+        for window in [Duration::ZERO, Duration::from_secs(5)] {
+            let mut executor = Executor::new().with_output_window(window);
+            let mut rx = executor.exec("chatty", test_config("sh", &["-c", script]), false).await.unwrap();
+            let (mut events, mut stdout) = (0, String::new());
+            while let Some(output) = rx.recv().await {
+                if let ProcessOutput::Stdout { chunk, .. } = output {
+                    events += 1;
+                    stdout.push_str(&chunk);
+                }
+            }
+            assert_eq!(stdout.lines().count(), 20);
+            assert!(stdout.starts_with("1\n2\n") && stdout.ends_with("19\n20\n"));
+            counts.push(events);
+        }
+        assert!(counts[0] >= 10, "uncoalesced output arrived in {} events", counts[0]);
+        // Flushed once, when the pipe closed
+        assert_eq!(counts[1], 1);
+    }
+
+    #[tokio::test]
+    async fn test_abort_all_closes_output_held_open_by_grandchildren() {
+        let mut executor = Executor::new();
+        // The shell exits at once, but the background sleep keeps stdout open
+        let config = test_config("sh", &["-c", "sleep 3 & echo started"]);
+        let mut rx = executor.exec("held", config, false).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(ProcessOutput::Stdout { chunk, .. }) if chunk == "started\n"));
+
+        executor.abort_all();
+        let closed = tokio::time::timeout(Duration::from_secs(1), async {
+            while rx.recv().await.is_some() {}
+        })
+        .await;
+        assert!(closed.is_ok(), "output channel still open after abort");
+        assert!(executor.processes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unterminated_output_is_flushed_before_exit() {
+        // A crash mid-line must not lose what was already written
+        let config = test_config("sh", &["-c", "printf 'no newline'; kill -ABRT $$"]);
+        let (outputs, _) = run_to_completion(config).await;
+        match &outputs[..] {
+            [ProcessOutput::Stdout { chunk, .. }, ProcessOutput::Exit { signal, .. }] => {
+                assert_eq!(chunk, "no newline");
+                assert_eq!(*signal, Some(libc::SIGABRT));
+            }
+            other => panic!("expected the partial line then exit, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exit_reports_terminating_signal() {
+        let (_, completion) = run_to_completion(test_config("sh", &["-c", "kill -SEGV $$"])).await;
+        assert!(matches!(completion, Some(ProcessOutput::Exit { code: 139, signal: Some(libc::SIGSEGV), .. })));
+
+        let (_, completion) = run_to_completion(test_config("sh", &["-c", "exit 137"])).await;
+        assert!(matches!(completion, Some(ProcessOutput::Exit { code: 137, signal: None, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_crash_cores_are_captured_as_artifacts() {
+        // Cores piped to a helper, or forbidden outright, never reach a file
+        let pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern").unwrap_or_default();
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        // SAFETY: getrlimit only writes the struct we pass it
+        unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) };
+        if pattern.is_empty() || pattern.starts_with('|') || limit.rlim_max == 0 {
+            return;
+        }
+
+        let work = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let (watcher, mut artifacts) = crate::fs_watcher::FsWatcher::new(output.path()).await.unwrap();
+        let mut executor = Executor::new().with_core_dir(Some(output.path().join("cores")));
+        let config = ExecConfig {
+            cwd: work.path().to_string_lossy().into_owned(),
+            capture_core: true,
+            ..test_config("sh", &["-c", "kill -SEGV $$"])
+        };
+        let mut rx = executor.exec("crash", config, false).await.unwrap();
+        while rx.recv().await.is_some() {}
+
+        let cores: Vec<_> = std::fs::read_dir(output.path().join("cores")).unwrap().collect();
+        assert_eq!(cores.len(), 1);
+        let reported = loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), artifacts.recv())
+                .await
+                .expect("core artifact")
+                .unwrap();
+            match event {
+                crate::fs_watcher::WatchEvent::Artifact(artifact) => break artifact.path,
+                crate::fs_watcher::WatchEvent::ArtifactStart { path, .. } => break path,
+                _ => {}
+            }
+        };
+        assert!(reported.starts_with("cores/core.crash."), "{}", reported);
+        // Nothing is left behind where the kernel wrote it
+        assert_eq!(std::fs::read_dir(work.path()).unwrap().count(), 0);
+        watcher.stop();
+    }
+
+    #[tokio::test]
+    async fn test_stderr_is_delivered() {
+        let (outputs, _) = run_to_completion(test_config("sh", &["-c", "echo oops >&2"])).await;
+        let stderr: String = outputs
+            .iter()
+            .filter_map(|output| match output {
+                ProcessOutput::Stderr { chunk, .. } => Some(chunk.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(stderr, "oops\n");
+        assert!(!outputs.iter().any(|output| matches!(output, ProcessOutput::Stdout { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_combined_stderr_preserves_order() {
+        let config = ExecConfig {
+            combine_stderr: true,
+            ..test_config(
+                "sh",
+                &["-c", "for i in 1 2 3; do echo out$i; echo err$i >&2; done"],
+            )
+        };
+        let (outputs, completion) = run_to_completion(config).await;
+
+        let mut combined = String::new();
+        for output in &outputs {
+            match output {
+                ProcessOutput::Stdout { chunk, .. } => combined.push_str(chunk),
+                ProcessOutput::Stderr { .. } => panic!("stderr should be merged into stdout"),
+                _ => {}
+            }
+        }
+        assert_eq!(combined, "out1\nerr1\nout2\nerr2\nout3\nerr3\n");
+        assert!(matches!(completion, Some(ProcessOutput::Exit { code: 0, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_output_cap_truncates_and_kills() {
+        let config = ExecConfig {
+            max_output_bytes: Some(1000),
+            timeout: Some(Duration::from_secs(10)),
+            ..test_config("sh", &["-c", "yes | cat; yes >&2"])
+        };
+        let (outputs, completion) = run_to_completion(config).await;
+
+        let forwarded: usize = outputs
+            .iter()
+            .map(|output| match output {
+                ProcessOutput::Stdout { chunk, .. } | ProcessOutput::Stderr { chunk, .. } => chunk.len(),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(forwarded, 1000);
+        assert!(!outputs.iter().any(|o| matches!(o, ProcessOutput::Timeout(_))));
+        match completion {
+            Some(ProcessOutput::Exit { code, stdout_bytes, stderr_bytes, truncated, .. }) => {
+                assert_eq!(code, 137);
+                assert_eq!(stdout_bytes + stderr_bytes, 1000);
+                assert!(truncated);
+            }
+            other => panic!("expected exit, got {:?}", other),
+        }
+    }
+
+    /// Concatenated stdout of a finished command.
+    fn stdout_of(outputs: &[ProcessOutput]) -> String {
+        outputs
+            .iter()
+            .filter_map(|output| match output {
+                ProcessOutput::Stdout { chunk, .. } => Some(chunk.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_clear_env_hides_inherited_variables() {
+        // HOME is always inherited from the test runner; PATH is not a good
+        // probe since sh supplies a default when it is missing
+        let script = "echo \"home=${HOME:-unset} kept=${KEPT:-unset}\"";
+        let config = ExecConfig {
+            clear_env: true,
+            env: HashMap::from([("KEPT".to_string(), "yes".to_string())]),
+            ..test_config("/bin/sh", &["-c", script])
+        };
+        let (outputs, _) = run_to_completion(config).await;
+        assert_eq!(stdout_of(&outputs), "home=unset kept=yes\n");
+
+        let (outputs, _) = run_to_completion(test_config("/bin/sh", &["-c", script])).await;
+        assert!(!stdout_of(&outputs).starts_with("home=unset"));
+    }
+
+    #[tokio::test]
+    async fn test_env_remove_unsets_one_variable() {
+        let config = ExecConfig {
+            env_remove: vec!["HOME".to_string()],
+            ..test_config("sh", &["-c", "echo \"home=${HOME:-unset} path=${PATH:+set}\""])
+        };
+        let (outputs, _) = run_to_completion(config).await;
+        assert_eq!(stdout_of(&outputs), "home=unset path=set\n");
+    }
+
+    #[tokio::test]
+    async fn test_kill_all_terminates_every_process() {
+        let mut executor = Executor::new();
+        let mut first = executor.exec("a", test_config("sleep", &["30"]), false).await.unwrap();
+        let mut second = executor.exec("b", test_config("sleep", &["30"]), false).await.unwrap();
+        let mut done = executor.exec("c", test_config("true", &[]), false).await.unwrap();
+        while done.recv().await.is_some() {}
+
+        assert_eq!(executor.kill_all(), 2);
+        for rx in [&mut first, &mut second] {
+            let mut last = None;
+            while let Some(output) = rx.recv().await {
+                last = Some(output);
+            }
+            assert!(matches!(last, Some(ProcessOutput::Exit { code: 143, .. })));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_binary_stdin_passes_through_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            cwd: dir.path().to_string_lossy().into_owned(),
+            ..test_config("sh", &["-c", "cat > out.bin"])
+        };
+        let mut rx = executor.exec("test", config, true).await.unwrap();
+
+        let data: Vec<u8> = (0..=255u8).chain([0xff, 0xfe, 0x00, 0xc3]).collect();
+        executor.write_stdin(None, &data).await.unwrap();
+        executor.close_stdin(None).unwrap();
+        while rx.recv().await.is_some() {}
+
+        assert_eq!(std::fs::read(dir.path().join("out.bin")).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_stdin_writes_report_every_byte_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            cwd: dir.path().to_string_lossy().into_owned(),
+            ..test_config("sh", &["-c", "cat > out.bin"])
+        };
+        let mut rx = executor.exec("test", config, true).await.unwrap();
+
+        // Bigger than a pipe buffer, so it only goes through in parts
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        assert_eq!(executor.write_stdin(None, &data).await.unwrap(), data.len());
+        assert_eq!(executor.write_stdin(None, b"end\n").await.unwrap(), 4);
+        executor.close_stdin(None).unwrap();
+        while rx.recv().await.is_some() {}
+
+        let written = std::fs::read(dir.path().join("out.bin")).unwrap();
+        assert_eq!(written.len(), data.len() + 4);
+        assert!(written.starts_with(&data));
+    }
+
+    #[tokio::test]
+    async fn test_exit_event_reports_real_code() {
+        let mut executor = Executor::new();
+        let config = test_config("sh", &["-c", "kill -9 $$"]);
+
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+
+        let mut last = None;
+        while let Some(output) = rx.recv().await {
+            last = Some(output);
+        }
+        assert!(matches!(last, Some(ProcessOutput::Exit { code: 137, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_process() {
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            timeout: Some(Duration::from_millis(100)),
+            ..test_config("sleep", &["10"])
+        };
+
+        let started = std::time::Instant::now();
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+
+        let mut outputs = Vec::new();
+        while let Some(output) = rx.recv().await {
+            outputs.push(output);
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(outputs.first(), Some(ProcessOutput::Timeout(_))));
+        assert!(matches!(outputs.last(), Some(ProcessOutput::Exit { code: 137, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_kill_terminates_process() {
+        let mut executor = Executor::new();
+        let mut rx = executor.exec("test", test_config("sleep", &["10"]), false).await.unwrap();
+
+        executor.kill(None).unwrap();
+
+        let mut last = None;
+        while let Some(output) = rx.recv().await {
+            last = Some(output);
+        }
+        // 128 + SIGTERM
+        assert!(matches!(last, Some(ProcessOutput::Exit { code: 143, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_kill_escalates_to_sigkill() {
+        let mut executor = Executor::new();
+        let config = test_config("sh", &["-c", "trap '' TERM; sleep 10"]);
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+
+        // Give the shell time to install its trap
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        executor.kill(None).unwrap();
+
+        let mut last = None;
+        while let Some(output) = rx.recv().await {
+            last = Some(output);
+        }
+        // 128 + SIGKILL
+        assert!(matches!(last, Some(ProcessOutput::Exit { code: 137, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_kill_without_process_fails() {
+        let mut executor = Executor::new();
+        assert!(executor.kill(None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_commands_are_independent() {
+        let mut executor = Executor::new();
+        let mut slow = executor.exec("slow", test_config("sleep", &["10"]), false).await.unwrap();
+        let mut fast = executor.exec("fast", test_config("echo", &["done"]), false).await.unwrap();
+
+        let mut last = None;
+        while let Some(output) = fast.recv().await {
+            last = Some(output);
+        }
+        assert!(matches!(last, Some(ProcessOutput::Exit { code: 0, .. })));
+
+        // The fast command finishing must not disturb the slow one
+        executor.kill(Some("slow")).unwrap();
+        let mut last = None;
+        while let Some(output) = slow.recv().await {
+            last = Some(output);
+        }
+        assert!(matches!(last, Some(ProcessOutput::Exit { code: 143, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_output_is_transcoded_from_the_declared_encoding() {
+        // "Grüße aus Köln" and a Windows-1252 euro sign, one byte per character
+        let script = r"printf 'Gr\374\337e aus K\366ln \200\n'; printf '\351t\351' >&2";
+        let config = ExecConfig {
+            output_encoding: Some("windows-1252".to_string()),
+            ..test_config("sh", &["-c", script])
+        };
+        let (outputs, _) = run_to_completion(config).await;
+        let stream = |stderr: bool| -> String {
+            outputs
+                .iter()
+                .filter_map(|output| match output {
+                    ProcessOutput::Stdout { chunk, .. } if !stderr => Some(chunk.as_str()),
+                    ProcessOutput::Stderr { chunk, .. } if stderr => Some(chunk.as_str()),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(stream(false), "Grüße aus Köln €\n");
+        assert_eq!(stream(true), "été");
+
+        let config = ExecConfig {
+            output_encoding: Some("ebcdic".to_string()),
+            ..test_config("true", &[])
+        };
+        let err = Executor::new().exec("bad", config, false).await.unwrap_err();
+        assert!(err.to_string().contains("Unsupported output encoding 'ebcdic'"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_detached_process_runs_alongside_later_commands() {
+        let mut executor = Executor::new().with_max_concurrency(Some(1));
+        let server = ExecConfig { detach: true, ..test_config("sleep", &["30"]) };
+        let mut server_rx = executor.exec("server", server, false).await.unwrap();
+        let pid = executor.pid("server").expect("detached process is running");
+        assert!(executor.status()[0].detached);
+
+        // The detached process holds no slot, and is not the default target
+        let mut rx = executor.exec("build", test_config("echo", &["built"]), false).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(ProcessOutput::Stdout { chunk, .. }) if chunk == "built\n"));
+        assert!(matches!(rx.recv().await, Some(ProcessOutput::Exit { code: 0, .. })));
+        assert!(executor.kill(None).is_err());
+        assert_eq!(executor.pid("server"), Some(pid));
+
+        executor.kill(Some("server")).unwrap();
+        let exit = tokio::time::timeout(Duration::from_secs(5), server_rx.recv()).await.unwrap();
+        assert!(matches!(exit, Some(ProcessOutput::Exit { signal: Some(_), .. })), "{:?}", exit);
+        assert!(executor.status().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_commands_beyond_the_limit_wait_their_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let mut executor = Executor::new().with_max_concurrency(Some(2));
+        let slot_freed = executor.slot_freed();
+        let mut receivers = Vec::new();
+        for n in 1..=5 {
+            let script = format!("echo start {n} >> '{0}'; sleep 0.2; echo end {n} >> '{0}'", log.display());
+            let rx = executor.exec(&format!("job-{}", n), test_config("sh", &["-c", &script]), false).await.unwrap();
+            receivers.push(rx);
+        }
+        // A queued command holds its id, and can be cancelled before it starts
+        assert!(executor.exec("job-5", test_config("true", &[]), false).await.is_err());
+        let mut cancelled = executor.exec("cancelled", test_config("true", &[]), false).await.unwrap();
+        executor.kill(Some("cancelled")).unwrap();
+        assert!(matches!(cancelled.recv().await, Some(ProcessOutput::Queued(4))));
+        assert!(matches!(cancelled.recv().await, Some(ProcessOutput::Exit { code: -1, .. })));
+
+        let collectors: Vec<_> = receivers
+            .into_iter()
+            .map(|mut rx| {
+                tokio::spawn(async move {
+                    let mut outputs = Vec::new();
+                    while let Some(output) = rx.recv().await {
+                        outputs.push(output);
+                    }
+                    outputs
+                })
+            })
+            .collect();
+        while executor.has_queued() {
+            slot_freed.notified().await;
+            executor.start_queued();
+        }
+        for (n, collector) in (1..).zip(collectors) {
+            let outputs = collector.await.unwrap();
+            match n {
+                1 | 2 => assert!(matches!(outputs[..], [ProcessOutput::Exit { code: 0, .. }]), "{:?}", outputs),
+                _ => assert!(
+                    matches!(outputs[..], [ProcessOutput::Queued(position), ProcessOutput::Started, ProcessOutput::Exit { code: 0, .. }] if position == n - 2),
+                    "{:?}",
+                    outputs
+                ),
+            }
+        }
+
+        // Never more than two at once, in submission order up to the pairs
+        // spawned together
+        let mut running = 0;
+        let mut starts = Vec::new();
+        for line in std::fs::read_to_string(&log).unwrap().lines() {
+            match line.split_once(' ').unwrap() {
+                ("start", n) => {
+                    running += 1;
+                    starts.push(n.to_string());
+                }
+                _ => running -= 1,
+            }
+            assert!(running <= 2, "{} running", running);
+        }
+        starts[..2].sort();
+        starts[2..4].sort();
+        assert_eq!(starts, ["1", "2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn test_output_ring_evicts_the_oldest_chunks() {
+        let stdout = |seq, chunk| ProcessOutput::Stdout { seq, chunk };
+        let mut ring = OutputRing::new(8);
+        for chunk in ["aaa", "bbb", "ccc"] {
+            ring.push(stdout, chunk.to_string());
+        }
+        // "aaa" no longer fits alongside the other two
+        let (chunks, complete) = ring.since(0);
+        assert!(!complete);
+        assert!(matches!(&chunks[..], [
+            ProcessOutput::Stdout { seq: 2, chunk: b },
+            ProcessOutput::Stdout { seq: 3, chunk: c },
+        ] if b == "bbb" && c == "ccc"));
+        let (chunks, complete) = ring.since(2);
+        assert!(complete && chunks.len() == 1);
+        let (chunks, complete) = ring.since(3);
+        assert!(complete && chunks.is_empty());
+
+        // An oversized chunk is kept on its own
+        ring.push(stdout, "x".repeat(20));
+        let (chunks, complete) = ring.since(3);
+        assert!(complete);
+        assert!(matches!(&chunks[..], [ProcessOutput::Stdout { seq: 4, chunk }] if chunk.len() == 20));
+        assert_eq!(ring.bytes, 20);
+    }
+
+    #[tokio::test]
+    async fn test_replay_resends_output_after_a_sequence_number() {
+        let mut executor = Executor::new();
+        let script = "echo one; sleep 0.1; echo two >&2; sleep 0.1; echo three";
+        let mut rx = executor.exec("job", test_config("sh", &["-c", script]), false).await.unwrap();
+        let mut seqs = Vec::new();
+        while let Some(output) = rx.recv().await {
+            match output {
+                ProcessOutput::Stdout { seq, .. } | ProcessOutput::Stderr { seq, .. } => seqs.push(seq),
+                ProcessOutput::Exit { .. } => break,
+                _ => {}
+            }
+        }
+        assert_eq!(seqs, [1, 2, 3]);
+
+        let replay = executor.replay(Some("job"), 1).unwrap();
+        assert!(replay.complete);
+        assert!(matches!(&replay.chunks[..], [
+            ProcessOutput::Stderr { seq: 2, chunk: two },
+            ProcessOutput::Stdout { seq: 3, chunk: three },
+        ] if two == "two\n" && three == "three\n"));
+        assert_eq!(executor.replay(None, 0).unwrap().chunks.len(), 3);
+        assert!(executor.replay(Some("other"), 0).is_err());
+    }
+
+    #[test]
+    fn test_which_searches_the_inherited_path() {
+        let mut executor = Executor::new();
+        assert!(executor.which("sh").is_some_and(|path| path.is_absolute() && path.ends_with("sh")));
+        assert_eq!(executor.which("definitely-not-a-command"), None);
+
+        // A session PATH replaces the agent's own
+        let dir = tempfile::tempdir().unwrap();
+        let tool = dir.path().join("tool");
+        std::fs::write(&tool, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(executor.which("tool"), None);
+        let path = dir.path().to_string_lossy().into_owned();
+        executor.set_env(HashMap::from([("PATH".to_string(), path)])).unwrap();
+        assert_eq!(executor.which("tool"), Some(tool));
+        assert_eq!(executor.which("sh"), None);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_exec_id_rejected() {
+        let mut executor = Executor::new();
+        let _rx = executor.exec("dup", test_config("sleep", &["10"]), false).await.unwrap();
+        assert!(executor.exec("dup", test_config("true", &[]), false).await.is_err());
+        executor.kill(Some("dup")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exec_in_custom_cwd() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            cwd: dir.path().to_string_lossy().into_owned(),
+            ..test_config("pwd", &[])
+        };
+
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+        match rx.recv().await {
+            Some(ProcessOutput::Stdout { chunk: line, .. }) => {
+                assert_eq!(Path::new(line.trim_end()).canonicalize().unwrap(), dir.path().canonicalize().unwrap());
+            }
+            other => panic!("expected stdout, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_cwd_rejected() {
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            cwd: "/nonexistent/boxed-test".to_string(),
+            ..test_config("true", &[])
+        };
+
+        let err = executor.exec("test", config, false).await.unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_without_newline_forwarded() {
+        let mut executor = Executor::new();
+        let config = test_config("sh", &["-c", "printf 'Name: '; sleep 10"]);
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+
+        let output = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("prompt should arrive before the process exits");
+        assert!(matches!(output, Some(ProcessOutput::Stdout { ref chunk, .. }) if chunk == "Name: "));
+
+        executor.kill(None).unwrap();
+    }
+
+    #[test]
+    fn test_utf8_decoder_holds_split_characters() {
+        let mut decoder = Utf8Decoder::default();
+        let bytes = "héllo €".as_bytes();
+        // Split inside the two-byte 'é' and the three-byte '€'
+        assert_eq!(decoder.decode(&bytes[..2]), "h");
+        assert_eq!(decoder.decode(&bytes[2..8]), "éllo ");
+        assert_eq!(decoder.decode(&bytes[8..]), "€");
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn test_utf8_decoder_replaces_invalid_bytes() {
+        let mut decoder = Utf8Decoder::default();
+        assert_eq!(decoder.decode(b"ok\xffok"), "ok\u{FFFD}ok");
+        // A dangling lead byte at end of stream is flushed lossily
+        assert_eq!(decoder.decode(b"\xe2"), "");
+        assert_eq!(decoder.finish(), "\u{FFFD}");
+    }
+
+    #[tokio::test]
+    async fn test_signal_delivered_to_process() {
+        let mut executor = Executor::new();
+        let config = test_config("sh", &["-c", "trap 'echo hup; exit 0' HUP; while true; do sleep 0.05; done"]);
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+
+        // Give the shell time to install its trap
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        executor.signal(None, parse_signal_name("SIGHUP").unwrap()).unwrap();
+
+        let mut outputs = Vec::new();
+        while let Some(output) = rx.recv().await {
+            outputs.push(output);
+        }
+        assert!(outputs.iter().any(|o| matches!(o, ProcessOutput::Stdout { chunk: c, .. } if c == "hup\n")));
+        assert!(matches!(outputs.last(), Some(ProcessOutput::Exit { code: 0, .. })));
+    }
+
+    #[test]
+    fn test_parse_signal_name() {
+        assert_eq!(parse_signal_name("SIGHUP").unwrap(), libc::SIGHUP);
+        assert_eq!(parse_signal_name("usr1").unwrap(), libc::SIGUSR1);
+        assert!(parse_signal_name("SIGBOGUS").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_signal_without_process_fails() {
+        let mut executor = Executor::new();
+        assert!(executor.signal(None, libc::SIGINT).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stdin_writes_time_out_when_the_process_never_reads() {
+        let mut executor = Executor::new().with_stdin_timeout(Duration::from_millis(200));
+        let _rx = executor.exec("stuck", test_config("sleep", &["30"]), true).await.unwrap();
+
+        let err = executor.write_stdin(None, &vec![b'x'; 1024 * 1024]).await.unwrap_err();
+        let timeout = err.downcast_ref::<StdinTimeout>().unwrap_or_else(|| panic!("{:#}", err));
+        assert_eq!(timeout.limit, Duration::from_millis(200));
+        // The pipe buffer took what it could before the write stalled
+        assert!(timeout.bytes_written < 1024 * 1024, "{:#}", err);
+        // Later writes are still tried rather than stuck behind the first
+        let err = executor.write_stdin(None, b"more").await.unwrap_err();
+        assert!(err.downcast_ref::<StdinTimeout>().is_some(), "{:#}", err);
+        executor.kill(None).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writing_to_a_dead_repl_reports_that_it_exited() {
+        let mut executor = Executor::new();
+        let _rx = executor.exec("repl", test_config("cat", &[]), true).await.unwrap();
+        executor.write_stdin(None, b"alive\n").await.unwrap();
+
+        // Killed but possibly not yet reaped, so the write may hit the dead pipe
+        executor.kill(None).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let err = executor.write_stdin(None, b"anyone?\n").await.unwrap_err();
+        assert!(err.to_string().contains("has exited"), "{:#}", err);
+        let err = executor.write_stdin(None, b"anyone?\n").await.unwrap_err();
+        assert!(err.to_string().contains("has exited"), "{:#}", err);
+        assert!(executor.processes["repl"].stdin.is_none());
+
+        // A process that closes its stdin breaks the pipe while still running
+        let _rx = executor
+            .exec("deaf", test_config("sh", &["-c", "exec 0<&-; sleep 30"]), true)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let err = executor.write_stdin(Some("deaf"), b"hello\n").await.unwrap_err();
+        assert!(err.to_string().contains("has exited"), "{:#}", err);
+        assert!(executor.write_stdin(Some("deaf"), b"hello\n").await.is_err());
+        assert!(executor.processes["deaf"].stdin.is_none());
+        executor.kill(Some("deaf")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_stdin_sends_eof() {
+        let mut executor = Executor::new();
+        let mut rx = executor.exec("test", test_config("sort", &[]), true).await.unwrap();
+
+        executor.write_stdin(None, b"b\na\n").await.unwrap();
+        executor.close_stdin(None).unwrap();
+        // Closing twice is harmless
+        executor.close_stdin(None).unwrap();
+
+        let mut stdout = String::new();
+        let mut last = None;
+        while let Some(output) = rx.recv().await {
+            if let ProcessOutput::Stdout { chunk, .. } = &output {
+                stdout.push_str(chunk);
+            }
+            last = Some(output);
+        }
+        assert_eq!(stdout, "a\nb\n");
+        assert!(matches!(last, Some(ProcessOutput::Exit { code: 0, .. })));
+        assert!(executor.write_stdin(None, b"c\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_status_lists_running_commands() {
+        let mut executor = Executor::new();
+        assert!(executor.status().is_empty());
+
+        let config = test_config("sh", &["-c", "echo ready; sleep 10"]);
+        let mut rx = executor.exec("busy", config, false).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(ProcessOutput::Stdout { chunk, .. }) if chunk == "ready\n"));
+        let status = executor.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].exec_id, "busy");
+        assert_eq!(status[0].cmd, "sh");
+        assert_eq!(status[0].args, ["-c", "echo ready; sleep 10"]);
+        assert!(status[0].pid.is_some());
+        assert_eq!(status[0].stdout_bytes, 6);
+
+        executor.kill(Some("busy")).unwrap();
+        while rx.recv().await.is_some() {}
+        assert!(executor.status().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_exit_reports_output_byte_counts() {
+        let mut executor = Executor::new();
+        let config = test_config("sh", &["-c", "printf hello; printf 'héllo\\n' >&2"]);
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+
+        let mut last = None;
+        while let Some(output) = rx.recv().await {
+            last = Some(output);
+        }
+        match last {
+            Some(ProcessOutput::Exit { code, stdout_bytes, stderr_bytes, .. }) => {
+                assert_eq!(code, 0);
+                assert_eq!(stdout_bytes, 5);
+                assert_eq!(stderr_bytes, 7);
+            }
+            other => panic!("expected exit, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exit_reports_spawn_latency_and_first_output() {
+        let config = ExecConfig {
+            requested: Some(Instant::now() - Duration::from_millis(100)),
+            ..test_config("sh", &["-c", "sleep 0.2; echo late"])
+        };
+        let (_, completion) = run_to_completion(config).await;
+        let Some(ProcessOutput::Exit { timing, .. }) = completion else {
+            panic!("expected exit, got {:?}", completion);
+        };
+        assert!(timing.spawn_latency >= Duration::from_millis(100), "{:?}", timing);
+        let first_output = timing.first_output.expect("output was written");
+        assert!(first_output >= Duration::from_millis(200), "{:?}", timing);
+
+        let (_, completion) = run_to_completion(test_config("true", &[])).await;
+        assert!(matches!(completion, Some(ProcessOutput::Exit { timing: Timing { first_output: None, .. }, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_sampling_stops_after_duration_or_exit() {
+        if !procfs::SUPPORTED {
+            return;
+        }
+        let mut executor = Executor::new();
+        let _rx = executor.exec("sleeper", test_config("sleep", &["5"]), false).await.unwrap();
+        let (id, mut samples) = executor
+            .sample(None, Duration::from_millis(50), Duration::from_millis(220))
+            .unwrap();
+        assert_eq!(id, "sleeper");
+        let mut received = Vec::new();
+        while let Some(sample) = samples.recv().await {
+            received.push(sample);
+        }
+        assert!((3..=6).contains(&received.len()), "{:?}", received);
+        let (elapsed, sample) = received[0];
+        assert!(elapsed < Duration::from_millis(50), "{:?}", elapsed);
+        assert!(sample.rss_kb.is_some() && sample.fd_count.is_some(), "{:?}", sample);
+        assert_eq!(sample.threads, Some(1));
+
+        // An exit ends sampling long before the duration is up
+        let (_, mut samples) = executor.sample(Some("sleeper"), Duration::from_millis(20), Duration::from_secs(60)).unwrap();
+        samples.recv().await.unwrap();
+        executor.kill(Some("sleeper")).unwrap();
+        tokio::time::timeout(Duration::from_secs(3), async { while samples.recv().await.is_some() {} })
+            .await
+            .expect("sampling ended with the process");
+
+        assert!(executor.sample(Some("sleeper"), Duration::from_millis(50), Duration::from_secs(1)).is_err());
+        assert!(executor.sample(None, Duration::from_millis(1), Duration::from_secs(1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_nice_sets_child_priority() {
+        let config = ExecConfig { nice: Some(10), ..test_config("nice", &[]) };
+        let (outputs, completion) = run_to_completion(config).await;
+        assert!(matches!(outputs.first(), Some(ProcessOutput::Stdout { chunk, .. }) if chunk == "10\n"), "{:?}", outputs);
+        assert!(matches!(completion, Some(ProcessOutput::Exit { code: 0, .. })));
+
+        let mut executor = Executor::new();
+        for nice in [-21, 20] {
+            let config = ExecConfig { nice: Some(nice), ..test_config("true", &[]) };
+            let err = executor.exec("nice", config, false).await.unwrap_err();
+            assert!(err.to_string().contains("between -20 and 19"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_umask_applies_to_created_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ExecConfig {
+            umask: Some(0o077),
+            cwd: dir.path().to_string_lossy().into_owned(),
+            ..test_config("sh", &["-c", "touch file && mkdir dir"])
+        };
+        let (_, completion) = run_to_completion(config).await;
+        assert!(matches!(completion, Some(ProcessOutput::Exit { code: 0, .. })));
+        let mode = |name: &str| std::fs::metadata(dir.path().join(name)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("file"), 0o600);
+        assert_eq!(mode("dir"), 0o700);
+
+        let config = ExecConfig { umask: Some(0o1000), ..test_config("true", &[]) };
+        assert!(Executor::new().exec("umask", config, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit_terminates_allocation() {
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            memory_limit_bytes: Some(256 * 1024 * 1024),
+            ..test_config("python3", &["-c", "x = bytearray(1024 * 1024 * 1024)"])
+        };
+
+        let mut rx = match executor.exec("test", config, false).await {
+            Ok(rx) => rx,
+            Err(_) => return, // python3 is not available on this host
+        };
+
+        let mut last = None;
+        while let Some(output) = rx.recv().await {
+            last = Some(output);
+        }
+        match last {
+            Some(ProcessOutput::Exit { code, .. }) => assert_ne!(code, 0),
+            other => panic!("expected exit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_abort_under_memory_limit_is_reported() {
+        let aborted = ExitStatus::from_raw(libc::SIGABRT);
+        assert_eq!(
+            exceeded_limit(aborted, Some(1024), None),
+            Some(ResourceLimit::Memory(1024))
+        );
+        assert_eq!(exceeded_limit(aborted, None, None), None);
+        assert_eq!(exceeded_limit(ExitStatus::from_raw(0), Some(1024), None), None);
+    }
+
+    #[tokio::test]
+    async fn test_cpu_limit_stops_busy_loop() {
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            cpu_seconds: Some(1),
+            timeout: Some(Duration::from_secs(10)),
+            ..test_config("sh", &["-c", "while :; do :; done"])
+        };
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+
+        let mut limit = None;
+        let mut code = None;
+        while let Some(output) = rx.recv().await {
+            match output {
+                ProcessOutput::LimitExceeded(l) => limit = Some(l),
+                ProcessOutput::Exit { code: c, .. } => code = Some(c),
+                ProcessOutput::Timeout(_) => panic!("CPU limit should fire before the timeout"),
+                _ => {}
+            }
+        }
+        assert_eq!(limit, Some(ResourceLimit::Cpu(1)));
+        assert_eq!(code, Some(128 + libc::SIGXCPU));
+    }
+
+    #[tokio::test]
+    async fn test_tty_mode_runs_on_a_terminal() {
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            tty: Some(WindowSize::default()),
+            ..test_config("sh", &["-c", "test -t 0 && test -t 1 && test -t 2 && echo on-tty"])
+        };
+        let mut rx = executor.exec("test", config, true).await.unwrap();
+
+        let mut stdout = String::new();
+        let mut code = None;
+        while let Some(output) = rx.recv().await {
+            match output {
+                ProcessOutput::Stdout { chunk, .. } => stdout.push_str(&chunk),
+                ProcessOutput::Exit { code: c, .. } => code = Some(c),
+                _ => {}
+            }
+        }
+        assert_eq!(code, Some(0));
+        assert!(stdout.contains("on-tty"), "got {:?}", stdout);
+    }
+
+    #[tokio::test]
+    async fn test_tty_mode_describes_the_terminal() {
+        let run = |env: &[(&str, &str)]| {
+            let config = ExecConfig {
+                tty: Some(WindowSize { rows: 30, cols: 120 }),
+                env: env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+                ..test_config("sh", &["-c", "echo \"$TERM $COLUMNS $LINES\""])
+            };
+            async move {
+                let (outputs, _) = run_to_completion(config).await;
+                outputs
+                    .iter()
+                    .filter_map(|output| match output {
+                        ProcessOutput::Stdout { chunk, .. } => Some(chunk.as_str()),
+                        _ => None,
+                    })
+                    .collect::<String>()
+            }
+        };
+        assert_eq!(run(&[]).await.trim_end(), "xterm-256color 120 30");
+        assert_eq!(run(&[("TERM", "dumb")]).await.trim_end(), "dumb 120 30");
+
+        // Plain pipes get no terminal variables of their own
+        let config = ExecConfig {
+            clear_env: true,
+            ..test_config("sh", &["-c", "echo \"[$TERM]\""])
+        };
+        let (outputs, _) = run_to_completion(config).await;
+        assert!(matches!(&outputs[..], [ProcessOutput::Stdout { chunk, .. }, ..] if chunk == "[]\n"), "{:?}", outputs);
+    }
+
+    #[tokio::test]
+    async fn test_tty_resize_and_input() {
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            tty: Some(WindowSize::default()),
+            ..test_config("sh", &["-c", "read line; stty size; echo \"got $line\""])
+        };
+        let mut rx = executor.exec("test", config, true).await.unwrap();
+
+        executor
+            .resize(None, WindowSize { rows: 40, cols: 100 })
+            .unwrap();
+        executor.write_stdin(None, b"hi\n").await.unwrap();
+
+        let mut stdout = String::new();
+        while let Some(output) = rx.recv().await {
+            if let ProcessOutput::Stdout { chunk, .. } = output {
+                stdout.push_str(&chunk);
+            }
+        }
+        assert!(stdout.contains("40 100"), "got {:?}", stdout);
+        assert!(stdout.contains("got hi"), "got {:?}", stdout);
+    }
+
+    #[tokio::test]
+    async fn test_resize_without_tty_fails() {
+        let mut executor = Executor::new();
+        let _rx = executor
+            .exec("test", test_config("sleep", &["5"]), true)
+            .await
+            .unwrap();
+        assert!(executor.resize(None, WindowSize::default()).is_err());
+        executor.kill(None).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_runs_as_requested_uid() {
+        // SAFETY: geteuid has no preconditions
+        if unsafe { libc::geteuid() } != 0 {
+            return; // Only root can switch users
+        }
+
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            uid: Some(65534),
+            gid: Some(65534),
+            ..test_config("sh", &["-c", "echo $(id -u):$(id -g):$(id -G)"])
+        };
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+
+        let mut stdout = String::new();
+        while let Some(output) = rx.recv().await {
+            if let ProcessOutput::Stdout { chunk, .. } = output {
+                stdout.push_str(&chunk);
+            }
+        }
+        assert_eq!(stdout.trim(), "65534:65534:65534");
+    }
+
+    #[test]
+    fn test_resolve_cwd() {
+        assert_eq!(resolve_cwd(None), "/workspace");
+        assert_eq!(resolve_cwd(Some("packages/foo")), "/workspace/packages/foo");
+        assert_eq!(resolve_cwd(Some("/tmp")), "/tmp");
+    }
+
+    #[tokio::test]
+    async fn test_timer_cancelled_on_normal_exit() {
+        let mut executor = Executor::new();
+        let config = ExecConfig {
+            timeout: Some(Duration::from_secs(10)),
+            ..test_config("true", &[])
+        };
+
+        let mut rx = executor.exec("test", config, false).await.unwrap();
+
+        let mut outputs = Vec::new();
+        while let Some(output) = rx.recv().await {
+            outputs.push(output);
         }
+        assert!(!outputs.iter().any(|o| matches!(o, ProcessOutput::Timeout(_))));
+        assert!(matches!(outputs.last(), Some(ProcessOutput::Exit { code: 0, .. })));
     }
 }
@@ -0,0 +1,326 @@
+//! Direct access to the sandbox filesystem for `fs.list`, `fs.read`,
+//! `fs.write`, `fs.chmod` and `fs.chown`.
+//!
+//! Paths are confined to a set of sandbox roots: anything that resolves
+//! outside them, whether through `..` or a symlink, is rejected before it is
+//! read or written. Symlinks found while walking are listed but never
+//! descended into.
+
+use anyhow::{Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+
+/// Most entries a single listing returns, whatever the caller asks for
+pub const MAX_ENTRIES: usize = 10_000;
+
+/// Largest file `fs.read` returns when the caller sets no limit
+pub const DEFAULT_MAX_READ_BYTES: u64 = 10 * 1024 * 1024; // 10 MB
+
+/// One file or directory in a listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Path relative to the listed directory
+    pub name: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Permission bits, e.g. `0o755`
+    pub mode: u32,
+    /// Modification time in milliseconds since the Unix epoch
+    pub mtime_ms: u64,
+    pub is_dir: bool,
+}
+
+/// Result of listing a path.
+#[derive(Debug, Clone, Default)]
+pub struct Listing {
+    pub entries: Vec<Entry>,
+    /// The entry limit was reached before the walk finished
+    pub truncated: bool,
+}
+
+/// Resolve `path` and check it stays inside one of `roots`.
+///
+/// The path must exist, since only a canonical path can be checked.
+pub async fn confine(path: &Path, roots: &[PathBuf]) -> Result<PathBuf> {
+    let resolved = fs::canonicalize(path)
+        .await
+        .with_context(|| format!("Cannot access '{}'", path.display()))?;
+    for root in roots {
+        if let Ok(root) = fs::canonicalize(root).await {
+            if resolved.starts_with(&root) {
+                return Ok(resolved);
+            }
+        }
+    }
+    anyhow::bail!("Path '{}' is outside the sandbox", path.display())
+}
+
+/// Read a whole file, failing if it is larger than `max_bytes`.
+pub async fn read(path: &Path, roots: &[PathBuf], max_bytes: u64) -> Result<Vec<u8>> {
+    let resolved = confine(path, roots).await?;
+    let size = fs::metadata(&resolved).await?.len();
+    if size > max_bytes {
+        anyhow::bail!("File is {} bytes, over the {} byte limit", size, max_bytes);
+    }
+    let data = fs::read(&resolved).await?;
+    // The file may have grown between the size check and the read
+    if data.len() as u64 > max_bytes {
+        anyhow::bail!("File is over the {} byte limit", max_bytes);
+    }
+    Ok(data)
+}
+
+/// Write a file, creating missing parent directories and applying `mode`.
+///
+/// `..` is rejected outright since the path may not exist yet to be
+/// canonicalized; the parent is checked against the roots once created.
+pub async fn write(path: &Path, roots: &[PathBuf], data: &[u8], mode: Option<u32>) -> Result<()> {
+    if path.components().any(|c| c == Component::ParentDir) {
+        anyhow::bail!("Path '{}' must not contain '..'", path.display());
+    }
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _ => anyhow::bail!("Path '{}' does not name a file", path.display()),
+    };
+
+    // Check the deepest existing ancestor first so nothing is created outside
+    let mut existing = parent;
+    while fs::symlink_metadata(existing).await.is_err() {
+        existing = existing.parent().unwrap_or(Path::new("/"));
+    }
+    confine(existing, roots).await?;
+    fs::create_dir_all(parent)
+        .await
+        .with_context(|| format!("Failed to create '{}'", parent.display()))?;
+    let target = confine(parent, roots).await?.join(name);
+
+    // An existing symlink must not redirect the write out of the sandbox
+    if fs::symlink_metadata(&target).await.is_ok_and(|m| m.file_type().is_symlink()) {
+        confine(&target, roots).await?;
+    }
+
+    fs::write(&target, data)
+        .await
+        .with_context(|| format!("Failed to write '{}'", target.display()))?;
+    if let Some(mode) = mode {
+        fs::set_permissions(&target, std::fs::Permissions::from_mode(mode)).await?;
+    }
+    Ok(())
+}
+
+/// Set a file's permission bits, as `chmod` would.
+///
+/// A symlink is resolved first, so only targets inside the sandbox change.
+pub async fn chmod(path: &Path, roots: &[PathBuf], mode: u32) -> Result<()> {
+    if mode > 0o7777 {
+        anyhow::bail!("Invalid mode {:#o}: must be at most 0o7777", mode);
+    }
+    let target = confine(path, roots).await?;
+    fs::set_permissions(&target, std::fs::Permissions::from_mode(mode))
+        .await
+        .with_context(|| format!("Failed to change the mode of '{}'", target.display()))
+}
+
+/// Change a file's owner and group; `None` leaves that one unchanged.
+///
+/// A symlink is resolved first, as with [`chmod`].
+pub async fn chown(path: &Path, roots: &[PathBuf], uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    if uid.is_none() && gid.is_none() {
+        anyhow::bail!("Nothing to change: give a uid, a gid or both");
+    }
+    let target = confine(path, roots).await?;
+    let result = {
+        let target = target.clone();
+        tokio::task::spawn_blocking(move || std::os::unix::fs::chown(target, uid, gid)).await?
+    };
+    result.map_err(|e| {
+        let hint = match e.raw_os_error() {
+            // Only root may give a file away, or pick a group it is not in
+            Some(libc::EPERM) => "; the agent needs root to change ownership",
+            _ => "",
+        };
+        anyhow::Error::new(e).context(format!("Failed to change the owner of '{}'{}", target.display(), hint))
+    })
+}
+
+/// List a directory (or describe a single file), up to `limit` entries.
+///
+/// Entries are sorted by name within each directory; with `recursive`, every
+/// directory's contents follow the entries of its parent.
+pub async fn list(path: &Path, roots: &[PathBuf], recursive: bool, limit: usize) -> Result<Listing> {
+    let base = confine(path, roots).await?;
+    let limit = limit.min(MAX_ENTRIES);
+    let metadata = fs::metadata(&base).await?;
+    if !metadata.is_dir() {
+        let name = base
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        return Ok(Listing {
+            entries: vec![entry(name, &metadata)],
+            truncated: false,
+        });
+    }
+
+    let mut listing = Listing::default();
+    let mut pending = vec![base.clone()];
+    while let Some(dir) = pending.pop() {
+        let mut children = Vec::new();
+        let mut read_dir = fs::read_dir(&dir).await?;
+        while let Some(child) = read_dir.next_entry().await? {
+            // Entry metadata does not follow symlinks, so links are reported as themselves
+            let metadata = match child.metadata().await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            children.push((child.path(), metadata));
+        }
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut subdirs = Vec::new();
+        for (child, metadata) in children {
+            if listing.entries.len() >= limit {
+                listing.truncated = true;
+                return Ok(listing);
+            }
+            let name = child.strip_prefix(&base).unwrap_or(&child).to_string_lossy().into_owned();
+            listing.entries.push(entry(name, &metadata));
+            if recursive && metadata.is_dir() {
+                subdirs.push(child);
+            }
+        }
+        // Reversed so the stack yields subdirectories in name order
+        pending.extend(subdirs.into_iter().rev());
+    }
+    Ok(listing)
+}
+
+fn entry(name: String, metadata: &std::fs::Metadata) -> Entry {
+    let mtime_ms = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_millis() as u64);
+    Entry {
+        name,
+        size: metadata.len(),
+        mode: metadata.permissions().mode() & 0o7777,
+        mtime_ms,
+        is_dir: metadata.is_dir(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn names(listing: &Listing) -> Vec<&str> {
+        listing.entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_lists_directories_flat_and_recursive() {
+        let dir = tempdir().unwrap();
+        let roots = vec![dir.path().to_path_buf()];
+        std::fs::create_dir_all(dir.path().join("src/bin")).unwrap();
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::write(dir.path().join("src/bin/tool.rs"), "").unwrap();
+        std::fs::write(dir.path().join("README"), "hi").unwrap();
+
+        let flat = list(dir.path(), &roots, false, MAX_ENTRIES).await.unwrap();
+        assert_eq!(names(&flat), ["README", "src"]);
+        assert_eq!(flat.entries[0].size, 2);
+        assert!(flat.entries[1].is_dir);
+
+        let all = list(dir.path(), &roots, true, MAX_ENTRIES).await.unwrap();
+        assert_eq!(names(&all), ["README", "src", "src/bin", "src/main.rs", "src/bin/tool.rs"]);
+        assert!(!all.truncated);
+
+        let capped = list(dir.path(), &roots, true, 2).await.unwrap();
+        assert_eq!(capped.entries.len(), 2);
+        assert!(capped.truncated);
+    }
+
+    #[tokio::test]
+    async fn test_write_then_read_round_trips() {
+        let dir = tempdir().unwrap();
+        let roots = vec![dir.path().to_path_buf()];
+        let path = dir.path().join("inputs/nested/run.sh");
+
+        write(&path, &roots, b"#!/bin/sh\necho hi\n", Some(0o750)).await.unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o750);
+        assert_eq!(read(&path, &roots, DEFAULT_MAX_READ_BYTES).await.unwrap(), b"#!/bin/sh\necho hi\n");
+
+        let err = read(&path, &roots, 4).await.unwrap_err();
+        assert!(err.to_string().contains("over the 4 byte limit"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_write_stays_inside_the_sandbox() {
+        let dir = tempdir().unwrap();
+        let inner = dir.path().join("inner");
+        std::fs::create_dir(&inner).unwrap();
+        std::os::unix::fs::symlink(dir.path(), inner.join("up")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("outside.txt"), inner.join("link.txt")).unwrap();
+        let roots = vec![inner.clone()];
+
+        assert!(write(&inner.join("../escape.txt"), &roots, b"x", None).await.is_err());
+        assert!(write(&inner.join("up/escape.txt"), &roots, b"x", None).await.is_err());
+        assert!(write(&inner.join("link.txt"), &roots, b"x", None).await.is_err());
+        assert!(write(&dir.path().join("new/dir/file"), &roots, b"x", None).await.is_err());
+        assert!(!dir.path().join("escape.txt").exists());
+        assert!(!dir.path().join("outside.txt").exists());
+        assert!(!dir.path().join("new").exists());
+    }
+
+    #[tokio::test]
+    async fn test_chmod_and_chown_stay_inside_the_sandbox() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = tempdir().unwrap();
+        let inner = dir.path().join("inner");
+        std::fs::create_dir(&inner).unwrap();
+        let outside = dir.path().join("outside.sh");
+        std::fs::write(&outside, "").unwrap();
+        std::os::unix::fs::symlink(&outside, inner.join("link.sh")).unwrap();
+        let script = inner.join("run.sh");
+        std::fs::write(&script, "").unwrap();
+        let roots = vec![inner.clone()];
+
+        chmod(&script, &roots, 0o750).await.unwrap();
+        assert_eq!(std::fs::metadata(&script).unwrap().permissions().mode() & 0o7777, 0o750);
+        let err = chmod(&script, &roots, 0o17777).await.unwrap_err();
+        assert_eq!(err.to_string(), "Invalid mode 0o17777: must be at most 0o7777");
+        assert!(chmod(&inner.join("link.sh"), &roots, 0o777).await.is_err());
+        assert!(chmod(&inner.join("missing"), &roots, 0o644).await.is_err());
+        assert_ne!(std::fs::metadata(&outside).unwrap().permissions().mode() & 0o777, 0o777);
+
+        // Giving a file to its current owner is allowed without privileges
+        let metadata = std::fs::metadata(&script).unwrap();
+        chown(&script, &roots, Some(metadata.uid()), None).await.unwrap();
+        chown(&script, &roots, None, Some(metadata.gid())).await.unwrap();
+        assert!(chown(&script, &roots, None, None).await.is_err());
+        assert!(chown(&inner.join("link.sh"), &roots, Some(metadata.uid()), None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_paths_outside_the_sandbox() {
+        let dir = tempdir().unwrap();
+        let inner = dir.path().join("inner");
+        std::fs::create_dir(&inner).unwrap();
+        std::os::unix::fs::symlink("/etc", inner.join("etc")).unwrap();
+        let roots = vec![inner.clone()];
+
+        assert!(list(&inner.join(".."), &roots, false, MAX_ENTRIES).await.is_err());
+        assert!(list(&inner.join("etc"), &roots, false, MAX_ENTRIES).await.is_err());
+        assert!(list(Path::new("/etc/passwd"), &roots, false, MAX_ENTRIES).await.is_err());
+
+        // The link itself is listed but not followed
+        let listing = list(&inner, &roots, true, MAX_ENTRIES).await.unwrap();
+        assert_eq!(names(&listing), ["etc"]);
+        assert!(!listing.entries[0].is_dir);
+    }
+}
@@ -1,191 +1,1435 @@
 //! Filesystem watcher for artifact detection.
 //!
-//! This module monitors the /output directory for new files and streams them
-//! back to the Control Plane as base64-encoded artifacts.
+//! This module monitors the /output directory (or any configured set of
+//! directories) for new files and streams them
+//! back to the Control Plane as base64-encoded artifacts. Files too large to
+//! send in one message are uploaded when an upload target is configured, or
+//! streamed as a start/chunk/end sequence otherwise.
 
 use anyhow::{Context, Result};
 use base64::Engine;
+use notify::event::CreateKind;
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
 use tokio::fs;
-use tokio::sync::mpsc;
-use tracing::{debug, error, info, warn};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{mpsc, oneshot, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::gzip;
+use crate::ignore::IgnoreSet;
+use crate::manifest::{self, Manifest};
+use crate::sha256::{self, Sha256};
+use crate::sniff;
+use crate::upload::UploadTarget;
 
 /// An artifact detected in the watched directory.
 #[derive(Debug, Clone)]
 pub struct Artifact {
-    /// Path relative to the watched directory
+    /// The watched directory the file is in
+    pub root: String,
+    /// Path relative to the watched directory, under the configured prefix
     pub path: String,
     /// MIME type of the file
     pub mime: String,
-    /// Base64-encoded file contents
+    /// Base64-encoded file contents, compressed as `compression` says; empty
+    /// when the file was uploaded to `url` instead
     pub data_base64: String,
+    /// How the contents were compressed before encoding
+    pub compression: Compression,
+    /// Lowercase hex SHA-256 of the raw contents
+    pub sha256: String,
+    /// Size of the raw contents in bytes
+    pub size: u64,
+    /// For a followed symlink, its target relative to the watched directory
+    pub link_target: Option<String>,
+    /// Where an oversized file was uploaded
+    pub url: Option<String>,
+}
+
+/// Compression applied to an inline artifact's contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+impl Compression {
+    /// Name of the encoding as reported to the Control Plane.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+        }
+    }
+}
+
+/// Why a file was noticed but not reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Bigger than the configured artifact size limit
+    TooLarge,
+    /// Could not be opened, e.g. for lack of permission
+    Unreadable,
+    /// Not matched by the allowlist
+    Filtered,
+}
+
+impl SkipReason {
+    /// Name of the reason as reported to the Control Plane.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::TooLarge => "too_large",
+            SkipReason::Unreadable => "unreadable",
+            SkipReason::Filtered => "filtered",
+        }
+    }
+}
+
+/// How a watched directory's file contents reach the Control Plane.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StreamMode {
+    /// Inline up to the inline limit; larger files are uploaded when a
+    /// target is configured and sent in chunks otherwise
+    #[default]
+    Auto,
+    /// Every file is uploaded when a target is configured, whatever its size
+    Upload,
+    /// Every file is sent as a start/chunk/end sequence, however small
+    Chunked,
+}
+
+impl StreamMode {
+    /// Look up a mode by the name used in configuration.
+    pub fn for_name(name: &str) -> Result<Self> {
+        match name {
+            "auto" => Ok(Self::Auto),
+            "upload" => Ok(Self::Upload),
+            "chunked" => Ok(Self::Chunked),
+            _ => anyhow::bail!("Unknown streaming mode '{}': expected auto, upload or chunked", name),
+        }
+    }
+}
+
+/// Rules for one watched directory, in place of the watcher-wide
+/// [`WatchOptions`] where set.
+#[derive(Debug, Clone, Default)]
+pub struct WatcherPolicy {
+    /// Largest file reported at all
+    pub max_artifact_bytes: Option<u64>,
+    /// Largest file sent in a single message; unaffected by
+    /// [`FsWatcher::set_max_inline_bytes`]
+    pub max_inline_bytes: Option<u64>,
+    /// Paths ignored on top of the shared rules, which they can re-include
+    /// with `!pattern`
+    pub ignore: IgnoreSet,
+    /// How file contents are sent
+    pub mode: StreamMode,
+}
+
+/// Settings for what the watcher reports and how.
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Paths (relative to their watch dir) that are never read
+    pub ignore: IgnoreSet,
+    /// Gzip text artifacts, and any artifact at least this many bytes;
+    /// `None` sends everything uncompressed
+    pub compress_threshold: Option<u64>,
+    /// Report symlinks as their target's contents instead of skipping them;
+    /// targets outside the watched directory are still never read
+    pub follow_symlinks: bool,
+    /// Forced MIME types by pattern; the first matching pattern wins over
+    /// the extension-based guess
+    pub mime_overrides: Vec<(IgnoreSet, String)>,
+    /// When set, only files matching these patterns are reported
+    pub allow: Option<IgnoreSet>,
+    /// Prepended to every reported path, e.g. a session id to namespace
+    /// shared storage; already normalized by [`normalize_prefix`]
+    pub path_prefix: Option<String>,
+    /// Most artifacts read and encoded at the same time
+    pub max_concurrent_reads: usize,
+    /// Largest file sent in a single message; can be changed at runtime
+    /// with [`FsWatcher::set_max_inline_bytes`]
+    pub max_inline_bytes: u64,
+    /// Largest file reported at all; bigger ones are announced as skipped
+    /// instead of uploaded or streamed
+    pub max_artifact_bytes: Option<u64>,
+    /// How file contents are sent
+    pub mode: StreamMode,
+    /// Most distinct files reported in a session; once reached, one
+    /// [`WatchEvent::ArtifactLimitReached`] is sent and new files are only
+    /// counted, while those already reported keep being updated
+    pub max_artifacts: Option<usize>,
+    /// Where hashes of streamed artifacts are kept across agent restarts, so
+    /// files unchanged since a previous session are not streamed again
+    pub manifest: Option<PathBuf>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            ignore: IgnoreSet::default(),
+            compress_threshold: None,
+            follow_symlinks: false,
+            mime_overrides: Vec::new(),
+            allow: None,
+            path_prefix: None,
+            max_concurrent_reads: DEFAULT_CONCURRENT_READS,
+            max_inline_bytes: DEFAULT_MAX_INLINE_BYTES,
+            max_artifact_bytes: None,
+            mode: StreamMode::Auto,
+            max_artifacts: None,
+            manifest: None,
+        }
+    }
+}
+
+impl WatchOptions {
+    /// The options for a directory with its own policy.
+    fn with_policy(&self, policy: &WatcherPolicy) -> Self {
+        Self {
+            ignore: self.ignore.and(&policy.ignore),
+            max_inline_bytes: policy.max_inline_bytes.unwrap_or(self.max_inline_bytes),
+            max_artifact_bytes: policy.max_artifact_bytes.or(self.max_artifact_bytes),
+            mode: policy.mode,
+            ..self.clone()
+        }
+    }
+
+    /// MIME type reported for a file, honouring overrides.
+    ///
+    /// When the name says nothing, the start of `source` is sniffed.
+    async fn mime_for(&self, path: &Path, source: &Path, watch_dir: &Path) -> String {
+        let relative = path.strip_prefix(watch_dir).unwrap_or(path);
+        if let Some((_, mime)) = self.mime_overrides.iter().find(|(pattern, _)| pattern.matches(relative)) {
+            return mime.clone();
+        }
+        let mime = guess_mime(path);
+        if mime != "application/octet-stream" {
+            return mime;
+        }
+        match sniff_mime(source).await {
+            Some(sniffed) => sniffed.to_string(),
+            None => mime,
+        }
+    }
+
+    /// Whether a file passes the allowlist, if there is one.
+    fn is_allowed(&self, path: &Path, watch_dir: &Path) -> bool {
+        let relative = path.strip_prefix(watch_dir).unwrap_or(path);
+        self.allow.as_ref().is_none_or(|allow| allow.matches(relative))
+    }
+
+    /// Path reported for a file relative to its watch directory.
+    fn reported_path(&self, relative: String) -> String {
+        match &self.path_prefix {
+            Some(prefix) => format!("{}/{}", prefix, relative),
+            None => relative,
+        }
+    }
+}
+
+/// Normalize an artifact path prefix to `/`-separated segments.
+///
+/// Either separator is accepted and empty or `.` segments are dropped, so
+/// the prefix joins cleanly on any platform; `..` is rejected since it would
+/// let paths escape their namespace. An empty prefix means none.
+pub fn normalize_prefix(prefix: &str) -> Result<Option<String>> {
+    let mut segments = Vec::new();
+    for segment in prefix.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => anyhow::bail!("Invalid path prefix '{}': must not contain '..'", prefix),
+            segment => segments.push(segment),
+        }
+    }
+    Ok((!segments.is_empty()).then(|| segments.join("/")))
+}
+
+/// The watched directories, each with the options its policy yields.
+#[derive(Debug, Clone)]
+struct Roots {
+    dirs: Vec<PathBuf>,
+    policies: Vec<WatcherPolicy>,
+    options: Vec<Arc<WatchOptions>>,
+    /// The watcher-wide options the policies are applied to
+    shared: Arc<WatchOptions>,
+}
+
+impl Roots {
+    fn new(roots: Vec<(PathBuf, WatcherPolicy)>, shared: WatchOptions) -> Self {
+        let (dirs, policies): (Vec<_>, Vec<_>) = roots.into_iter().unzip();
+        let options = policies.iter().map(|policy| Arc::new(shared.with_policy(policy))).collect();
+        Self { dirs, policies, options, shared: Arc::new(shared) }
+    }
+
+    /// The same roots under a new watcher-wide inline limit.
+    fn with_max_inline_bytes(&self, max_inline_bytes: u64) -> Self {
+        let shared = WatchOptions { max_inline_bytes, ..(*self.shared).clone() };
+        Self::new(self.dirs.iter().cloned().zip(self.policies.iter().cloned()).collect(), shared)
+    }
+
+    /// The watched directory containing a path, and the options for it.
+    fn find(&self, path: &Path) -> (&Path, &Arc<WatchOptions>) {
+        let dir = root_for(path, &self.dirs);
+        match self.dirs.iter().position(|root| root == dir) {
+            Some(index) => (dir, &self.options[index]),
+            None => (dir, &self.shared),
+        }
+    }
+}
+
+/// Event produced by the watcher for the Control Plane.
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    /// A file small enough to send in a single message
+    Artifact(Artifact),
+    /// A large file is about to be streamed in chunks
+    ArtifactStart {
+        root: String,
+        path: String,
+        mime: String,
+        total_size: u64,
+    },
+    /// One chunk of a large file, numbered from zero
+    ArtifactChunk {
+        path: String,
+        seq: u64,
+        data_base64: String,
+    },
+    /// All chunks of a large file have been sent
+    ArtifactEnd { path: String, sha256: String },
+    /// A previously reported file was deleted or moved away
+    ArtifactRemoved { root: String, path: String },
+    /// A file exists but its contents will not be sent
+    ArtifactSkipped {
+        root: String,
+        path: String,
+        size: u64,
+        reason: SkipReason,
+    },
+    /// The consumer has not drained events for this long; artifacts are
+    /// delayed, not dropped
+    Throttled { waited: Duration },
+    /// `max_artifacts` distinct files have been reported; further new
+    /// files are held back
+    ArtifactLimitReached { limit: usize },
+    /// The notify backend reported a failure, and whether watching resumed
+    WatcherError { message: String },
 }
 
-/// Maximum file size to stream inline (files larger than this should use upload)
-const MAX_INLINE_SIZE: u64 = 10 * 1024 * 1024; // 10 MB
+/// Largest file sent in a single message unless configured otherwise;
+/// larger files are uploaded or sent in chunks
+pub const DEFAULT_MAX_INLINE_BYTES: u64 = 10 * 1024 * 1024; // 10 MB
+
+/// Size of each chunk when streaming a large artifact
+const CHUNK_SIZE: usize = 4 * 1024 * 1024; // 4 MB
+
+/// How long a path must go without new events before it is read
+const DEBOUNCE_WINDOW: Duration = Duration::from_millis(200);
+
+/// Files read at once unless configured otherwise
+pub const DEFAULT_CONCURRENT_READS: usize = 4;
+
+/// Most settled paths waiting for a read slot; further paths stay with the
+/// debouncer, which keeps coalescing their events meanwhile
+const READ_QUEUE_CAPACITY: usize = 1024;
+
+/// How long a send may stall on a full channel before a throttling notice is queued
+const THROTTLE_NOTICE_AFTER: Duration = Duration::from_secs(5);
 
 /// Filesystem watcher for artifact detection.
 pub struct FsWatcher {
-    /// The directory being watched
-    watch_dir: PathBuf,
-    /// The underlying file watcher
-    _watcher: RecommendedWatcher,
+    /// The directories being watched
+    watch_dirs: Vec<PathBuf>,
+    /// The underlying file watcher, also re-armed by the event task
+    _watcher: Arc<Mutex<RecommendedWatcher>>,
+    /// Where oversized artifacts go, shared with the event task
+    upload_tx: watch::Sender<Option<Arc<UploadTarget>>>,
+    /// Inline size limit, shared with the event task
+    inline_tx: watch::Sender<u64>,
+    /// Requests to be told once everything seen so far has been reported
+    flush_tx: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    /// What has been reported, shared with the event task
+    last_seen: Arc<LastSeen>,
+    /// Task turning file events into artifacts
+    task: tokio::task::JoinHandle<()>,
+    /// Task queueing the files present at startup
+    scan: tokio::task::JoinHandle<()>,
 }
 
 impl FsWatcher {
     /// Create a new filesystem watcher for the given directory.
     ///
     /// Returns a receiver channel that will emit detected artifacts.
-    pub async fn new(watch_dir: impl AsRef<Path>) -> Result<(Self, mpsc::Receiver<Artifact>)> {
-        let watch_dir = watch_dir.as_ref().to_path_buf();
+    #[cfg(test)]
+    pub async fn new(watch_dir: impl AsRef<Path>) -> Result<(Self, mpsc::Receiver<WatchEvent>)> {
+        Self::with_dirs(vec![watch_dir.as_ref().to_path_buf()], WatchOptions::default()).await
+    }
 
-        // Create the output directory if it doesn't exist
-        fs::create_dir_all(&watch_dir)
-            .await
-            .context("Failed to create watch directory")?;
+    /// Create a watcher over several directories feeding one channel.
+    ///
+    /// Artifact paths are relative to whichever directory contains them, and
+    /// paths matching the ignore rules (relative to that directory) are never read.
+    #[cfg(test)]
+    pub async fn with_dirs(
+        watch_dirs: Vec<PathBuf>,
+        options: WatchOptions,
+    ) -> Result<(Self, mpsc::Receiver<WatchEvent>)> {
+        let roots = watch_dirs.into_iter().map(|dir| (dir, WatcherPolicy::default())).collect();
+        Self::with_roots(roots, options).await
+    }
+
+    /// Create a watcher over several directories, each with its own policy
+    /// applied on top of `options`.
+    ///
+    /// Events are tagged with the directory they came from; a path inside
+    /// two of them belongs to the more specific one.
+    pub async fn with_roots(
+        roots: Vec<(PathBuf, WatcherPolicy)>,
+        options: WatchOptions,
+    ) -> Result<(Self, mpsc::Receiver<WatchEvent>)> {
+        let roots = Arc::new(Roots::new(roots, options));
+        let watch_dirs = roots.dirs.clone();
+        // Create the output directories if they don't exist
+        for watch_dir in &watch_dirs {
+            fs::create_dir_all(watch_dir)
+                .await
+                .with_context(|| format!("Failed to create watch directory {}", watch_dir.display()))?;
+        }
 
         let (artifact_tx, artifact_rx) = mpsc::channel(100);
-        let (event_tx, mut event_rx) = mpsc::channel(100);
+        let (event_tx, mut event_rx) = mpsc::channel::<notify::Result<Event>>(100);
 
-        // Create the file watcher
+        // Create the file watcher; errors go through the same channel as events
         let tx = event_tx.clone();
         let watcher = RecommendedWatcher::new(
-            move |res: Result<Event, notify::Error>| {
-                if let Ok(event) = res {
-                    let _ = tx.blocking_send(event);
-                }
+            move |res: notify::Result<Event>| {
+                let _ = tx.blocking_send(res);
             },
             Config::default(),
         )?;
+        let watcher = Arc::new(Mutex::new(watcher));
+        // Weak, so dropping the FsWatcher still closes the channel and ends the task
+        let (rearm, rescan_tx) = (Arc::downgrade(&watcher), event_tx.downgrade());
+
+        let (upload_tx, upload_rx) = watch::channel(None);
+        let (inline_tx, mut inline_rx) = watch::channel(roots.shared.max_inline_bytes);
+        let (flush_tx, mut flush_rx) = mpsc::unbounded_channel::<oneshot::Sender<()>>();
+        let manifest = match &roots.shared.manifest {
+            Some(path) => Some(Arc::new(Mutex::new(Manifest::load(path).await))),
+            None => None,
+        };
 
-        // Process file events in a background task
-        let artifact_tx_clone = artifact_tx.clone();
-        let watch_dir_clone = watch_dir.clone();
-        tokio::spawn(async move {
-            while let Some(event) = event_rx.recv().await {
-                if let Err(e) =
-                    process_event(event, &watch_dir_clone, &artifact_tx_clone).await
-                {
-                    error!(error = %e, "Failed to process file event");
+        // Process file events in a background task, once each path settles
+        let sender = EventSender::new(artifact_tx, THROTTLE_NOTICE_AFTER);
+        let mut task_roots = roots.clone();
+        let last_seen = Arc::new(LastSeen::default());
+        let task_last_seen = last_seen.clone();
+        let task = tokio::spawn(async move {
+            let last_seen = task_last_seen;
+            let mut debouncer = Debouncer::new(DEBOUNCE_WINDOW);
+            let mut pool = ReadPool::new(task_roots.shared.max_concurrent_reads);
+            // Answered once the debouncer and the read pool are both empty
+            let mut flushes: Vec<oneshot::Sender<()>> = Vec::new();
+            loop {
+                let upload = upload_rx.borrow().clone();
+                // Reads already under way keep the limit they started with
+                if inline_rx.has_changed().unwrap_or(false) {
+                    let max_inline_bytes = *inline_rx.borrow_and_update();
+                    task_roots = Arc::new(task_roots.with_max_inline_bytes(max_inline_bytes));
+                }
+                let roots = &task_roots;
+                pool.start(|path| {
+                    let (roots, last_seen) = (roots.clone(), last_seen.clone());
+                    let (upload, sender, manifest) = (upload.clone(), sender.clone(), manifest.clone());
+                    async move {
+                        let (watch_dir, options) = roots.find(&path);
+                        let (upload, manifest) = (upload.as_deref(), manifest.as_deref());
+                        if let Err(e) = emit_artifact(&path, watch_dir, options, upload, &last_seen, manifest, &sender).await {
+                            warn!(path = %path.display(), error = %e, "Failed to read artifact");
+                        }
+                    }
+                });
+                if !flushes.is_empty() && debouncer.is_empty() && pool.is_idle() {
+                    for flushed in flushes.drain(..) {
+                        let _ = flushed.send(());
+                    }
+                }
+
+                // While the queue is full, settled paths wait (and coalesce) in the debouncer
+                let deadline = debouncer.next_deadline().filter(|_| pool.room() > 0);
+                tokio::select! {
+                    event = event_rx.recv() => match event {
+                        Some(Ok(event)) => {
+                            // The backend dropped events, e.g. on an inotify queue overflow
+                            if event.need_rescan() {
+                                warn!("Filesystem watcher lost events, rescanning");
+                                rescan(&rescan_tx, roots);
+                            }
+                            if event.kind == EventKind::Create(CreateKind::Folder) {
+                                scan_created(&rescan_tx, roots, event.paths.clone());
+                            }
+                            for path in event_paths(event) {
+                                let (watch_dir, options) = roots.find(&path);
+                                if let Ok(relative) = path.strip_prefix(watch_dir) {
+                                    if options.ignore.is_ignored(relative) {
+                                        debug!(path = %path.display(), "Path ignored");
+                                        continue;
+                                    }
+                                }
+                                debouncer.push(path, Instant::now());
+                            }
+                        }
+                        Some(Err(error)) => {
+                            recover(error, &rearm, &roots.dirs, &sender).await;
+                            rescan(&rescan_tx, roots);
+                        }
+                        None => break,
+                    },
+                    _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                        for path in debouncer.take_settled(Instant::now(), pool.room()) {
+                            pool.push(path);
+                        }
+                    }
+                    Some(_) = pool.join_next(), if pool.is_reading() => {}
+                    Some(flushed) = flush_rx.recv() => {
+                        // Whoever flushes is done writing, so nothing needs to
+                        // settle, nor to wait for its events to arrive
+                        for path in debouncer.take_settled(Instant::now() + DEBOUNCE_WINDOW, pool.room()) {
+                            pool.push(path);
+                        }
+                        for path in unreported(roots, &last_seen).await {
+                            pool.push(path);
+                        }
+                        flushes.push(flushed);
+                    }
                 }
             }
+            // Let reads already under way finish before the channel closes
+            while pool.join_next().await.is_some() {}
         });
 
-        let mut fs_watcher = Self {
-            watch_dir,
+        // Start watching before scanning, so nothing written in between is missed
+        for watch_dir in &watch_dirs {
+            watcher
+                .lock()
+                .unwrap()
+                .watch(watch_dir, RecursiveMode::Recursive)
+                .with_context(|| format!("Failed to watch directory {}", watch_dir.display()))?;
+        }
+        let scan = tokio::spawn(scan_existing(roots, event_tx));
+
+        let fs_watcher = Self {
+            watch_dirs,
             _watcher: watcher,
+            upload_tx,
+            inline_tx,
+            flush_tx,
+            last_seen,
+            task,
+            scan,
         };
 
-        // Start watching the directory
-        fs_watcher.start_watching()?;
-
-        info!(dir = %fs_watcher.watch_dir.display(), "Filesystem watcher started");
+        info!(dirs = ?fs_watcher.watch_dirs, "Filesystem watcher started");
 
         Ok((fs_watcher, artifact_rx))
     }
 
-    /// Start watching the output directory.
-    fn start_watching(&mut self) -> Result<()> {
-        self._watcher
-            .watch(&self.watch_dir, RecursiveMode::Recursive)
-            .context("Failed to watch directory")?;
-        Ok(())
+    /// Upload oversized artifacts to `target` from now on, or stop with `None`.
+    pub fn set_upload(&self, target: Option<UploadTarget>) {
+        self.upload_tx.send_replace(target.map(Arc::new));
+    }
+
+    /// Send files up to `bytes` inline from now on; larger ones are uploaded
+    /// or streamed in chunks.
+    pub fn set_max_inline_bytes(&self, bytes: u64) {
+        self.inline_tx.send_replace(bytes);
+    }
+
+    /// New files not reported because `max_artifacts` was reached.
+    pub fn withheld_artifacts(&self) -> usize {
+        self.last_seen.counts.lock().unwrap().withheld.len()
+    }
+
+    /// Resolves once every file changed before the call has been read and
+    /// its events queued on the artifact channel.
+    ///
+    /// The caller is taken to have finished writing, so files are read
+    /// straight away instead of once they settle, found by a scan in case
+    /// their filesystem events are still in flight. If the watcher stops
+    /// first, this resolves when it does.
+    pub fn flush(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.flush_tx.send(tx);
+        async move {
+            let _ = rx.await;
+        }
+    }
+
+    /// Stop watching and cancel the background task.
+    ///
+    /// Paths still waiting to settle are never read, and the artifact
+    /// channel closes once the task is gone.
+    pub fn stop(self) {
+        self.scan.abort();
+        self.task.abort();
+        info!(dirs = ?self.watch_dirs, "Filesystem watcher stopped");
     }
 }
 
-/// Process a filesystem event and potentially emit an artifact.
-async fn process_event(
-    event: Event,
-    watch_dir: &Path,
-    artifact_tx: &mpsc::Sender<Artifact>,
-) -> Result<()> {
-    // We only care about file creation and modification
-    match event.kind {
-        EventKind::Create(_) | EventKind::Modify(_) => {}
-        _ => return Ok(()),
+/// Report a watcher failure and try to watch the directories afresh.
+///
+/// A watch directory that was deleted is recreated first. Running out of
+/// inotify watches is not retried, since watching again would only hit the
+/// same limit.
+async fn recover(
+    error: notify::Error,
+    watcher: &Weak<Mutex<RecommendedWatcher>>,
+    watch_dirs: &[PathBuf],
+    sender: &EventSender,
+) {
+    warn!(error = %error, paths = ?error.paths, "Filesystem watcher error");
+    let mut message = error.to_string();
+    match (&error.kind, watcher.upgrade()) {
+        (notify::ErrorKind::MaxFilesWatch, _) => {
+            message.push_str("; raise fs.inotify.max_user_watches to watch more files");
+        }
+        (_, Some(watcher)) => match rewatch(&watcher, watch_dirs).await {
+            Ok(()) => {
+                info!(dirs = ?watch_dirs, "Filesystem watch re-established");
+                message.push_str("; watch re-established");
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to re-establish filesystem watch");
+                message = format!("{}; failed to re-establish the watch: {:#}", message, e);
+            }
+        },
+        (_, None) => return,
     }
+    let _ = sender.send(WatchEvent::WatcherError { message }).await;
+}
 
-    for path in event.paths {
-        // Skip directories
-        if path.is_dir() {
-            continue;
+/// Watch every directory again, recreating any that have gone.
+async fn rewatch(watcher: &Mutex<RecommendedWatcher>, watch_dirs: &[PathBuf]) -> Result<()> {
+    for watch_dir in watch_dirs {
+        fs::create_dir_all(watch_dir)
+            .await
+            .with_context(|| format!("Failed to create watch directory {}", watch_dir.display()))?;
+    }
+    let mut watcher = watcher.lock().unwrap();
+    for watch_dir in watch_dirs {
+        // Drop whatever is left of the old watch so events are not doubled
+        let _ = watcher.unwatch(watch_dir);
+        watcher
+            .watch(watch_dir, RecursiveMode::Recursive)
+            .with_context(|| format!("Failed to watch directory {}", watch_dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Queue every existing file again after events may have been missed.
+///
+/// Files that have not changed since they were streamed are skipped when
+/// read, so only what was missed is reported.
+fn rescan(tx: &mpsc::WeakSender<notify::Result<Event>>, roots: &Arc<Roots>) {
+    if let Some(tx) = tx.upgrade() {
+        tokio::spawn(scan_existing(roots.clone(), tx));
+    }
+}
+
+/// Queue every file already under the watch directories, as if just created.
+///
+/// Files left by an earlier run or baked into a snapshot would otherwise
+/// never be reported. They are debounced like live events, so a file that is
+/// also modified right after startup is still read once, after it settles.
+async fn scan_existing(roots: Arc<Roots>, tx: mpsc::Sender<notify::Result<Event>>) {
+    for path in existing_files(&roots).await {
+        let event = Event::new(EventKind::Create(CreateKind::File)).add_path(path);
+        if tx.send(Ok(event)).await.is_err() {
+            return;
         }
+    }
+    debug!(dirs = ?roots.dirs, "Initial artifact scan finished");
+}
+
+/// Queue the files already inside newly created directories.
+///
+/// A new directory is only watched once its creation has been seen, so
+/// files written or moved into it before then raise no event of their own.
+fn scan_created(tx: &mpsc::WeakSender<notify::Result<Event>>, roots: &Arc<Roots>, mut dirs: Vec<PathBuf>) {
+    let Some(tx) = tx.upgrade() else {
+        return;
+    };
+    dirs.retain(|dir| {
+        let (root, options) = roots.find(dir);
+        !options.ignore.is_ignored(dir.strip_prefix(root).unwrap_or(dir))
+    });
+    let roots = roots.clone();
+    tokio::spawn(async move {
+        for path in files_under(&roots, dirs).await {
+            let event = Event::new(EventKind::Create(CreateKind::File)).add_path(path);
+            if tx.send(Ok(event)).await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Every file under the watch directories that is not ignored by the rules
+/// of the directory it belongs to.
+///
+/// Symlinks are listed as themselves and never descended into.
+async fn existing_files(roots: &Roots) -> Vec<PathBuf> {
+    files_under(roots, roots.dirs.clone()).await
+}
+
+/// Every file under `dirs` that is not ignored, as for [`existing_files`].
+async fn files_under(roots: &Roots, dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for top in dirs {
+        let mut pending = vec![top];
+        while let Some(dir) = pending.pop() {
+            let Ok(mut entries) = fs::read_dir(&dir).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                let (root, options) = roots.find(&path);
+                if options.ignore.is_ignored(path.strip_prefix(root).unwrap_or(&path)) {
+                    continue;
+                }
+                match entry.file_type().await {
+                    Ok(file_type) if file_type.is_dir() => pending.push(path),
+                    Ok(_) => files.push(path),
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+    files
+}
 
-        // Skip hidden files
-        if path
-            .file_name()
-            .map(|n| n.to_string_lossy().starts_with('.'))
-            .unwrap_or(false)
-        {
+/// Allowed files that changed since they were last reported, or never were.
+///
+/// A flush reads these directly, since their filesystem events may not
+/// have reached the watcher yet.
+async fn unreported(roots: &Roots, last_seen: &LastSeen) -> Vec<PathBuf> {
+    let mut changed = Vec::new();
+    for path in existing_files(roots).await {
+        let (root, options) = roots.find(&path);
+        if !options.is_allowed(&path, root) {
             continue;
         }
+        let Ok(metadata) = fs::metadata(&path).await else {
+            continue;
+        };
+        let signature = FileSignature {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        };
+        if last_seen.signatures.lock().unwrap().get(&path) != Some(&signature) {
+            changed.push(path);
+        }
+    }
+    changed
+}
+
+/// Size and modification time of a file when it was last streamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileSignature {
+    size: u64,
+    modified: Option<SystemTime>,
+}
 
-        debug!(path = %path.display(), "File event detected");
+/// What has been streamed so far, shared by concurrent reads.
+#[derive(Debug, Default)]
+struct LastSeen {
+    /// Signature of every file as it was last streamed
+    signatures: Mutex<HashMap<PathBuf, FileSignature>>,
+    /// Paths counted against `max_artifacts`
+    counts: Mutex<ArtifactCounts>,
+}
+
+/// Distinct paths reported in a session, deleted ones included.
+#[derive(Debug, Default)]
+struct ArtifactCounts {
+    reported: HashSet<PathBuf>,
+    /// New paths held back once the limit was reached
+    withheld: HashSet<PathBuf>,
+}
+
+/// Whether a file may be reported under `max_artifacts`.
+#[derive(Debug, PartialEq, Eq)]
+enum Admission {
+    Report,
+    /// The first file over the limit, which is held back like the rest
+    LimitReached,
+    Withheld,
+}
 
-        // Read and encode the file
-        match read_artifact(&path, watch_dir).await {
-            Ok(Some(artifact)) => {
-                info!(
-                    path = %artifact.path,
-                    mime = %artifact.mime,
-                    size = artifact.data_base64.len(),
-                    "Artifact detected"
-                );
-                if artifact_tx.send(artifact).await.is_err() {
-                    warn!("Artifact receiver dropped");
+impl ArtifactCounts {
+    fn admit(&mut self, path: &Path, limit: Option<usize>) -> Admission {
+        if self.reported.contains(path) {
+            return Admission::Report;
+        }
+        match limit {
+            Some(limit) if self.reported.len() >= limit => {
+                let first = self.withheld.is_empty();
+                self.withheld.insert(path.to_path_buf());
+                if first {
+                    Admission::LimitReached
+                } else {
+                    Admission::Withheld
                 }
             }
-            Ok(None) => {
-                // File too large or unreadable
+            _ => {
+                self.reported.insert(path.to_path_buf());
+                Admission::Report
             }
-            Err(e) => {
-                warn!(path = %path.display(), error = %e, "Failed to read artifact");
+        }
+    }
+}
+
+/// Sends watcher events with explicit backpressure.
+///
+/// Events are never dropped: when the channel is full the watcher waits for
+/// the Control Plane to catch up, which in turn stalls the notify callback.
+/// Capacity is reserved before a file is read, so a slow consumer never makes
+/// the agent hold file contents it cannot send yet. If one wait exceeds
+/// `notice_after`, a single `Throttled` event is queued ahead of the delayed
+/// one; the next notice is only sent once the channel has drained. Clones
+/// share that state, so concurrent reads produce a single notice.
+#[derive(Clone)]
+struct EventSender {
+    tx: mpsc::Sender<WatchEvent>,
+    notice_after: Duration,
+    throttled: Arc<AtomicBool>,
+}
+
+impl EventSender {
+    fn new(tx: mpsc::Sender<WatchEvent>, notice_after: Duration) -> Self {
+        Self {
+            tx,
+            notice_after,
+            throttled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Wait for room for one event.
+    async fn reserve(&self) -> Result<mpsc::Permit<'_, WatchEvent>> {
+        match self.tx.try_reserve() {
+            Ok(permit) => {
+                self.throttled.store(false, Ordering::Relaxed);
+                return Ok(permit);
             }
+            Err(mpsc::error::TrySendError::Closed(())) => anyhow::bail!("Artifact receiver dropped"),
+            Err(mpsc::error::TrySendError::Full(())) => {}
         }
+
+        if !self.throttled.load(Ordering::Relaxed) {
+            if let Ok(permit) = tokio::time::timeout(self.notice_after, self.tx.reserve()).await {
+                return permit.map_err(|_| anyhow::anyhow!("Artifact receiver dropped"));
+            }
+            // Only the first reader to give up waiting sends the notice
+            if self.throttled.swap(true, Ordering::Relaxed) {
+                return self
+                    .tx
+                    .reserve()
+                    .await
+                    .map_err(|_| anyhow::anyhow!("Artifact receiver dropped"));
+            }
+            warn!(waited_ms = self.notice_after.as_millis() as u64, "Artifact delivery throttled");
+            let notice = self
+                .tx
+                .reserve()
+                .await
+                .map_err(|_| anyhow::anyhow!("Artifact receiver dropped"))?;
+            notice.send(WatchEvent::Throttled {
+                waited: self.notice_after,
+            });
+        }
+
+        self.tx
+            .reserve()
+            .await
+            .map_err(|_| anyhow::anyhow!("Artifact receiver dropped"))
     }
 
-    Ok(())
+    /// Send one event, waiting for room as long as necessary.
+    async fn send(&self, event: WatchEvent) -> Result<()> {
+        self.reserve().await?.send(event);
+        Ok(())
+    }
+}
+
+/// Coalesces bursts of events for the same path until the file settles.
+///
+/// Each new event pushes the path's deadline back by the debounce window, so
+/// a file is only read once writes to it have paused.
+struct Debouncer {
+    window: Duration,
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl Debouncer {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Record an event for a path at the given time.
+    fn push(&mut self, path: PathBuf, now: Instant) {
+        self.pending.insert(path, now + self.window);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// The earliest time at which a pending path will have settled.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().min().copied()
+    }
+
+    /// Remove and return up to `max` paths whose deadline has passed.
+    fn take_settled(&mut self, now: Instant, max: usize) -> Vec<PathBuf> {
+        let settled: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(path, _)| path.clone())
+            .take(max)
+            .collect();
+        for path in &settled {
+            self.pending.remove(path);
+        }
+        settled
+    }
 }
 
-/// Read a file and convert it to an artifact.
-async fn read_artifact(path: &Path, watch_dir: &Path) -> Result<Option<Artifact>> {
-    // Get file metadata
-    let metadata = fs::metadata(path).await?;
+/// Reads settled paths with a bounded number of files open at once.
+///
+/// Each path is queued at most once and never read by two tasks at the same
+/// time: one that settles again while it is being read is read once more
+/// afterwards. Dropping the pool cancels every read still running.
+struct ReadPool {
+    slots: Arc<Semaphore>,
+    reads: JoinSet<()>,
+    /// Path handled by each running read
+    tasks: HashMap<tokio::task::Id, PathBuf>,
+    queue: VecDeque<PathBuf>,
+    queued: HashSet<PathBuf>,
+    reading: HashSet<PathBuf>,
+    /// Paths that settled again mid-read
+    again: HashSet<PathBuf>,
+}
+
+impl ReadPool {
+    fn new(max_concurrent: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_concurrent.max(1))),
+            reads: JoinSet::new(),
+            tasks: HashMap::new(),
+            queue: VecDeque::new(),
+            queued: HashSet::new(),
+            reading: HashSet::new(),
+            again: HashSet::new(),
+        }
+    }
+
+    /// Queue a settled path, coalescing it with any pending read of the same path.
+    fn push(&mut self, path: PathBuf) {
+        if self.reading.contains(&path) {
+            self.again.insert(path);
+        } else if self.queued.insert(path.clone()) {
+            self.queue.push_back(path);
+        }
+    }
+
+    /// How many more paths the queue accepts.
+    fn room(&self) -> usize {
+        READ_QUEUE_CAPACITY.saturating_sub(self.queue.len())
+    }
+
+    fn is_reading(&self) -> bool {
+        !self.reads.is_empty()
+    }
+
+    /// Whether nothing is being read or waiting to be.
+    fn is_idle(&self) -> bool {
+        self.queue.is_empty() && !self.is_reading()
+    }
+
+    /// Start a read for queued paths while slots are free.
+    fn start<F, Fut>(&mut self, read: F)
+    where
+        F: Fn(PathBuf) -> Fut,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        while !self.queue.is_empty() {
+            let Ok(permit) = self.slots.clone().try_acquire_owned() else {
+                break;
+            };
+            let path = self.queue.pop_front().expect("queue is not empty");
+            self.queued.remove(&path);
+            self.reading.insert(path.clone());
+            let read = read(path.clone());
+            let handle = self.reads.spawn(async move {
+                read.await;
+                drop(permit);
+            });
+            self.tasks.insert(handle.id(), path);
+        }
+    }
 
-    // Skip files that are too large for inline streaming
-    if metadata.len() > MAX_INLINE_SIZE {
-        warn!(
+    /// Wait for a read to finish, requeueing its path if it changed meanwhile.
+    async fn join_next(&mut self) -> Option<PathBuf> {
+        let id = match self.reads.join_next_with_id().await? {
+            Ok((id, ())) => id,
+            Err(e) => e.id(),
+        };
+        let path = self.tasks.remove(&id)?;
+        self.reading.remove(&path);
+        if self.again.remove(&path) {
+            self.push(path.clone());
+        }
+        Some(path)
+    }
+}
+
+/// Extract the paths of a filesystem event that may produce artifacts.
+fn event_paths(event: Event) -> Vec<PathBuf> {
+    // Removals are resolved once the path settles, like any other change
+    match event.kind {
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {}
+        _ => return Vec::new(),
+    }
+
+    event
+        .paths
+        .into_iter()
+        .filter(|path| {
+            // Skip hidden files
+            !path
+                .file_name()
+                .map(|n| n.to_string_lossy().starts_with('.'))
+                .unwrap_or(false)
+        })
+        .inspect(|path| debug!(path = %path.display(), "File event detected"))
+        .collect()
+}
+
+/// What kind of special file `metadata` describes, for logging.
+fn special_kind(metadata: &std::fs::Metadata) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+    let file_type = metadata.file_type();
+    if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        "device"
+    } else {
+        "unknown"
+    }
+}
+
+/// Send a file as a single artifact, or as a chunked stream if it is large.
+///
+/// Files whose size and mtime match what was last streamed are skipped, as
+/// are files the manifest shows a previous session already streamed with the
+/// same contents, and a path that no longer exists is reported as removed. Symlinks are skipped
+/// unless `follow_symlinks` is set, and even then only read when they resolve
+/// inside the watched directory, so a link cannot exfiltrate other files.
+/// Large files go to `upload` when set, falling back to chunks if that fails;
+/// the `mode` option sends every file one of those ways instead.
+/// Files outside the allowlist, over `max_artifact_bytes` or that cannot be
+/// opened are reported as skipped, so the Control Plane knows they exist.
+/// New files past `max_artifacts` are not reported at all, only counted.
+async fn emit_artifact(
+    path: &Path,
+    watch_dir: &Path,
+    options: &WatchOptions,
+    upload: Option<&UploadTarget>,
+    last_seen: &LastSeen,
+    manifest: Option<&Mutex<Manifest>>,
+    sender: &EventSender,
+) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return emit_removed(path, watch_dir, options, last_seen, manifest, sender).await;
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let (source, link_target, metadata) = if metadata.file_type().is_symlink() {
+        if !options.follow_symlinks {
+            debug!(path = %path.display(), "Symlink skipped");
+            return Ok(());
+        }
+        match resolve_symlink(path, watch_dir).await? {
+            Some((target, relative)) => {
+                let metadata = fs::metadata(&target).await?;
+                (target, Some(options.reported_path(relative)), metadata)
+            }
+            None => {
+                warn!(path = %path.display(), "Symlink target is outside the watch directory, skipping");
+                return Ok(());
+            }
+        }
+    } else {
+        (path.to_path_buf(), None, metadata)
+    };
+
+    // Skip directories
+    if metadata.is_dir() {
+        return Ok(());
+    }
+    // Reading a FIFO would block until a writer turns up, and sockets or
+    // devices have no contents worth reporting
+    if !metadata.is_file() {
+        warn!(path = %path.display(), kind = special_kind(&metadata), "Not a regular file, skipping");
+        return Ok(());
+    }
+    // Checked here rather than with the ignore rules, so removing a whole
+    // directory still reports the allowed files inside it
+    let relative = options.reported_path(relative_path(path, watch_dir));
+    let root = watch_dir.display().to_string();
+    let skipped = |reason| WatchEvent::ArtifactSkipped {
+        root: root.clone(),
+        path: relative.clone(),
+        size: metadata.len(),
+        reason,
+    };
+    if !options.is_allowed(path, watch_dir) {
+        debug!(path = %path.display(), "Path not in allowlist");
+        return sender.send(skipped(SkipReason::Filtered)).await;
+    }
+    let signature = FileSignature {
+        size: metadata.len(),
+        modified: metadata.modified().ok(),
+    };
+    let admission = {
+        let mut signatures = last_seen.signatures.lock().unwrap();
+        if signatures.get(path) == Some(&signature) {
+            debug!(path = %path.display(), "Artifact unchanged, skipping");
+            return Ok(());
+        }
+        let admission = last_seen.counts.lock().unwrap().admit(path, options.max_artifacts);
+        if admission == Admission::Report {
+            signatures.insert(path.to_path_buf(), signature);
+        }
+        admission
+    };
+    match admission {
+        Admission::Report => {}
+        Admission::LimitReached => {
+            let limit = options.max_artifacts.unwrap_or_default();
+            warn!(path = %path.display(), limit, "Artifact limit reached, new artifacts are no longer reported");
+            return sender.send(WatchEvent::ArtifactLimitReached { limit }).await;
+        }
+        Admission::Withheld => {
+            debug!(path = %path.display(), "Artifact limit reached, skipping");
+            return Ok(());
+        }
+    }
+    if options.max_artifact_bytes.is_some_and(|limit| metadata.len() > limit) {
+        warn!(path = %path.display(), size = metadata.len(), "Artifact exceeds the size limit, skipping");
+        return sender.send(skipped(SkipReason::TooLarge)).await;
+    }
+    if let Err(e) = fs::File::open(&source).await {
+        warn!(path = %path.display(), error = %e, "Artifact is unreadable, skipping");
+        // Forgotten so the next event for it, such as a chmod, tries again
+        last_seen.signatures.lock().unwrap().remove(path);
+        return sender.send(skipped(SkipReason::Unreadable)).await;
+    }
+    let mime = options.mime_for(path, &source, watch_dir).await;
+    if let Some(manifest) = manifest {
+        let previous = manifest.lock().unwrap().take_previous(path);
+        if let Some(previous) = previous {
+            if manifest::hash_file(&source).await.is_ok_and(|sha256| sha256 == previous) {
+                debug!(path = %path.display(), "Artifact streamed by a previous session, skipping");
+                manifest.lock().unwrap().record(path, previous);
+                return Ok(());
+            }
+        }
+    }
+    let record = |sha256: &str| {
+        if let Some(manifest) = manifest {
+            manifest.lock().unwrap().record(path, sha256.to_string());
+        }
+    };
+
+    let oversized = metadata.len() > options.max_inline_bytes;
+    let upload = upload.filter(|_| oversized || options.mode == StreamMode::Upload);
+    if oversized || upload.is_some() || options.mode == StreamMode::Chunked {
+        if let Some(upload) = upload {
+            match upload.put_file(&relative, &source, metadata.len(), &mime).await {
+                Ok(uploaded) => {
+                    info!(path = %relative, url = %uploaded.url, size = metadata.len(), "Artifact uploaded");
+                    let artifact = Artifact {
+                        root,
+                        path: relative.clone(),
+                        mime: mime.clone(),
+                        data_base64: String::new(),
+                        compression: Compression::None,
+                        sha256: uploaded.sha256.clone(),
+                        size: metadata.len(),
+                        link_target,
+                        url: Some(uploaded.url),
+                    };
+                    sender.send(WatchEvent::Artifact(artifact)).await?;
+                    record(&uploaded.sha256);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(path = %relative, error = %format!("{:#}", e), "Artifact upload failed, streaming in chunks");
+                }
+            }
+        }
+        info!(
             path = %path.display(),
             size = metadata.len(),
-            "File too large for inline streaming"
+            "Streaming artifact in chunks"
         );
+        let sha256 = stream_artifact(root, relative.clone(), &source, mime, metadata.len(), CHUNK_SIZE, sender).await?;
+        record(&sha256);
+        return Ok(());
+    }
+
+    let permit = sender.reserve().await?;
+    let mut artifact = read_artifact(relative.clone(), &source, mime, options.compress_threshold).await?;
+    artifact.root = root;
+    artifact.link_target = link_target;
+    info!(
+        path = %artifact.path,
+        mime = %artifact.mime,
+        size = artifact.size,
+        compression = artifact.compression.as_str(),
+        "Artifact detected"
+    );
+    let sha256 = artifact.sha256.clone();
+    permit.send(WatchEvent::Artifact(artifact));
+    record(&sha256);
+    Ok(())
+}
+
+/// Resolve a symlink, returning its target and the target's path relative to
+/// the watch directory, or `None` if it is dangling or points outside it.
+async fn resolve_symlink(path: &Path, watch_dir: &Path) -> Result<Option<(PathBuf, String)>> {
+    let target = match fs::canonicalize(path).await {
+        Ok(target) => target,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let root = fs::canonicalize(watch_dir).await?;
+    if !target.starts_with(&root) {
         return Ok(None);
     }
+    let relative = relative_path(&target, &root);
+    Ok(Some((target, relative)))
+}
 
-    // Read file contents
-    let data = fs::read(path).await?;
+/// Report every previously streamed file at or below a path that is gone.
+///
+/// Files created and deleted before they ever settled were never reported,
+/// so they produce no event either.
+async fn emit_removed(
+    path: &Path,
+    watch_dir: &Path,
+    options: &WatchOptions,
+    last_seen: &LastSeen,
+    manifest: Option<&Mutex<Manifest>>,
+    sender: &EventSender,
+) -> Result<()> {
+    let removed: Vec<PathBuf> = {
+        let mut signatures = last_seen.signatures.lock().unwrap();
+        let removed: Vec<PathBuf> = signatures
+            .keys()
+            .filter(|seen| seen.starts_with(path))
+            .cloned()
+            .collect();
+        for seen in &removed {
+            signatures.remove(seen);
+        }
+        removed
+    };
+    for seen in removed {
+        if let Some(manifest) = manifest {
+            manifest.lock().unwrap().forget(&seen);
+        }
+        let relative = options.reported_path(relative_path(&seen, watch_dir));
+        info!(path = %relative, "Artifact removed");
+        let root = watch_dir.display().to_string();
+        sender.send(WatchEvent::ArtifactRemoved { root, path: relative }).await?;
+    }
+    Ok(())
+}
 
-    // Detect MIME type
-    let mime = mime_guess::from_path(path)
-        .first_or_octet_stream()
-        .to_string();
+/// Read a file and convert it to an artifact reported at `relative`.
+///
+/// The contents come from `source`, which is the target for a followed
+/// symlink. With a compression threshold set, text files and files at least
+/// that large are gzipped first, unless that would not make them smaller.
+async fn read_artifact(
+    relative: String,
+    source: &Path,
+    mime: String,
+    compress_threshold: Option<u64>,
+) -> Result<Artifact> {
+    // Read file contents
+    let data = fs::read(source).await?;
 
-    // Create relative path
-    let relative_path = path
-        .strip_prefix(watch_dir)
-        .unwrap_or(path)
-        .to_string_lossy()
-        .to_string();
+    let compressed = compress_threshold
+        .filter(|&threshold| is_text_mime(&mime) || data.len() as u64 >= threshold)
+        .map(|_| gzip::compress(&data))
+        .filter(|compressed| compressed.len() < data.len());
+    let (compression, encoded) = match &compressed {
+        Some(compressed) => (Compression::Gzip, compressed.as_slice()),
+        None => (Compression::None, data.as_slice()),
+    };
 
     // Base64 encode
-    let data_base64 = base64::engine::general_purpose::STANDARD.encode(&data);
+    let data_base64 = base64::engine::general_purpose::STANDARD.encode(encoded);
 
-    Ok(Some(Artifact {
-        path: relative_path,
+    Ok(Artifact {
+        root: String::new(),
+        path: relative,
         mime,
         data_base64,
-    }))
+        compression,
+        sha256: sha256::digest_hex(&data),
+        size: data.len() as u64,
+        link_target: None,
+        url: None,
+    })
+}
+
+/// Whether a MIME type is textual and therefore worth compressing at any size.
+fn is_text_mime(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || ["json", "xml", "javascript", "yaml", "csv"]
+            .iter()
+            .any(|kind| mime.ends_with(kind))
+}
+
+/// Stream a file as start, chunk and end events without loading it whole.
+///
+/// Only one chunk is held in memory at a time, and the end event carries the
+/// SHA-256 of the full contents so the Control Plane can verify reassembly.
+/// Returns that hash.
+async fn stream_artifact(
+    root: String,
+    relative: String,
+    source: &Path,
+    mime: String,
+    total_size: u64,
+    chunk_size: usize,
+    sender: &EventSender,
+) -> Result<String> {
+    let mut file = fs::File::open(source).await?;
+
+    let start = WatchEvent::ArtifactStart {
+        root,
+        path: relative.clone(),
+        mime,
+        total_size,
+    };
+    sender.send(start).await?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; chunk_size];
+    let mut seq = 0;
+    loop {
+        // Fill the buffer completely so every chunk but the last is full-sized
+        let mut filled = 0;
+        while filled < chunk_size {
+            let n = file.read(&mut buf[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            break;
+        }
+        let permit = sender.reserve().await?;
+
+        hasher.update(&buf[..filled]);
+        let chunk = WatchEvent::ArtifactChunk {
+            path: relative.clone(),
+            seq,
+            data_base64: base64::engine::general_purpose::STANDARD.encode(&buf[..filled]),
+        };
+        permit.send(chunk);
+        seq += 1;
+    }
+
+    let sha256 = hasher.finalize_hex();
+    let end = WatchEvent::ArtifactEnd {
+        path: relative,
+        sha256: sha256.clone(),
+    };
+    sender.send(end).await?;
+    Ok(sha256)
+}
+
+/// Detect the MIME type of a file from its extension.
+fn guess_mime(path: &Path) -> String {
+    mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .to_string()
+}
+
+/// Detect the MIME type of a file from its first bytes.
+async fn sniff_mime(source: &Path) -> Option<&'static str> {
+    let file = fs::File::open(source).await.ok()?;
+    let mut head = Vec::with_capacity(sniff::SNIFF_BYTES);
+    file.take(sniff::SNIFF_BYTES as u64).read_to_end(&mut head).await.ok()?;
+    sniff::sniff(&head)
+}
+
+/// The watched directory containing a path.
+///
+/// The most specific root wins, so `/workspace/dist` takes precedence over
+/// `/workspace` when both are watched.
+fn root_for<'a>(path: &Path, watch_dirs: &'a [PathBuf]) -> &'a Path {
+    watch_dirs
+        .iter()
+        .filter(|dir| path.starts_with(dir))
+        .max_by_key(|dir| dir.components().count())
+        .or(watch_dirs.first())
+        .map(PathBuf::as_path)
+        .unwrap_or(Path::new(""))
+}
+
+/// Path of a file relative to the watched directory.
+///
+/// Linux file names need not be UTF-8, so bytes that are not valid UTF-8
+/// are percent-encoded, and so is `%` itself, which keeps every name
+/// recoverable byte for byte. Ordinary names are reported unchanged unless
+/// they contain a `%`.
+fn relative_path(path: &Path, watch_dir: &Path) -> String {
+    let relative = path.strip_prefix(watch_dir).unwrap_or(path);
+    let mut encoded = String::new();
+    for chunk in relative.as_os_str().as_bytes().utf8_chunks() {
+        encoded.push_str(&chunk.valid().replace('%', "%25"));
+        for byte in chunk.invalid() {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
 }
 
 #[cfg(test)]
@@ -202,10 +1446,836 @@ mod tests {
         assert_eq!(mime, "image/png");
     }
 
+    #[tokio::test]
+    async fn test_extensionless_files_are_sniffed() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("plot"), b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR").unwrap();
+        std::fs::write(dir.path().join("README"), "Run `make` to build.\n").unwrap();
+        std::fs::write(dir.path().join("blob"), [0u8, 1, 2, 3]).unwrap();
+        let options = WatchOptions::default();
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let tx = EventSender::new(tx, THROTTLE_NOTICE_AFTER);
+        let last_seen = LastSeen::default();
+        for name in ["plot", "README", "blob"] {
+            emit_artifact(&dir.path().join(name), dir.path(), &options, None, &last_seen, None, &tx)
+                .await
+                .unwrap();
+        }
+        drop(tx);
+
+        let mut mimes = Vec::new();
+        while let Some(event) = rx.recv().await {
+            match event {
+                WatchEvent::Artifact(artifact) => mimes.push((artifact.path, artifact.mime)),
+                other => panic!("expected artifact, got {:?}", other),
+            }
+        }
+        assert_eq!(
+            mimes,
+            vec![
+                ("plot".to_string(), "image/png".to_string()),
+                ("README".to_string(), "text/plain".to_string()),
+                ("blob".to_string(), "application/octet-stream".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compressible_artifact_is_gzipped() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("results.csv");
+        let contents = "step,loss\n".to_string() + &"100,0.25\n".repeat(5000);
+        std::fs::write(&path, &contents).unwrap();
+
+        let artifact = read_artifact(relative_path(&path, dir.path()), &path, guess_mime(&path), Some(1024 * 1024)).await.unwrap();
+        assert_eq!(artifact.compression, Compression::Gzip);
+        assert_eq!(artifact.size, contents.len() as u64);
+        let gzipped = base64::engine::general_purpose::STANDARD
+            .decode(&artifact.data_base64)
+            .unwrap();
+        assert!(gzipped.len() < contents.len() / 10, "compressed to {} bytes", gzipped.len());
+
+        let mut child = std::process::Command::new("gzip")
+            .arg("-dc")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut child.stdin.take().unwrap(), &gzipped).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), contents);
+        assert_eq!(sha256::digest_hex(contents.as_bytes()), artifact.sha256);
+
+        // Without a threshold, nothing is compressed
+        let artifact = read_artifact(relative_path(&path, dir.path()), &path, guess_mime(&path), None).await.unwrap();
+        assert_eq!(artifact.compression, Compression::None);
+    }
+
+    #[tokio::test]
+    async fn test_mime_overrides_and_allowlist() {
+        let dir = tempdir().unwrap();
+        for name in ["data.parquet", "notes.txt", "scratch.log"] {
+            std::fs::write(dir.path().join(name), name).unwrap();
+        }
+        let options = WatchOptions {
+            mime_overrides: vec![(IgnoreSet::new(["*.parquet"]), "application/vnd.apache.parquet".to_string())],
+            allow: Some(IgnoreSet::new(["*.parquet", "*.txt"])),
+            ..Default::default()
+        };
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let tx = EventSender::new(tx, THROTTLE_NOTICE_AFTER);
+        let last_seen = LastSeen::default();
+        for name in ["data.parquet", "notes.txt", "scratch.log"] {
+            emit_artifact(&dir.path().join(name), dir.path(), &options, None, &last_seen, None, &tx)
+                .await
+                .unwrap();
+        }
+        drop(tx);
+
+        let (mut mimes, mut skipped) = (Vec::new(), Vec::new());
+        while let Some(event) = rx.recv().await {
+            match event {
+                WatchEvent::Artifact(artifact) => mimes.push((artifact.path, artifact.mime)),
+                WatchEvent::ArtifactSkipped { path, reason, .. } => skipped.push((path, reason)),
+                other => panic!("expected artifact, got {:?}", other),
+            }
+        }
+        // The override wins, others keep the default guess, and the log is filtered out
+        assert_eq!(
+            mimes,
+            vec![
+                ("data.parquet".to_string(), "application/vnd.apache.parquet".to_string()),
+                ("notes.txt".to_string(), "text/plain".to_string()),
+            ]
+        );
+        assert_eq!(skipped, vec![("scratch.log".to_string(), SkipReason::Filtered)]);
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_names_round_trip() {
+        use std::ffi::OsStr;
+
+        let dir = tempdir().unwrap();
+        let name = b"caf\xe9 100%.txt";
+        let path = dir.path().join(OsStr::from_bytes(name));
+        std::fs::write(&path, "data").unwrap();
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let tx = EventSender::new(tx, THROTTLE_NOTICE_AFTER);
+        emit_artifact(&path, dir.path(), &WatchOptions::default(), None, &LastSeen::default(), None, &tx)
+            .await
+            .unwrap();
+        let reported = match rx.try_recv() {
+            Ok(WatchEvent::Artifact(artifact)) => artifact.path,
+            other => panic!("expected artifact, got {:?}", other),
+        };
+        assert_eq!(reported, "caf%E9 100%25.txt");
+
+        // What a consumer does to get the file name back
+        let mut decoded = Vec::new();
+        let mut bytes = reported.bytes();
+        while let Some(byte) = bytes.next() {
+            if byte == b'%' {
+                let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                decoded.push(u8::from_str_radix(&hex, 16).unwrap());
+            } else {
+                decoded.push(byte);
+            }
+        }
+        assert_eq!(decoded, name);
+        assert_eq!(relative_path(&dir.path().join("plots/fig.png"), dir.path()), "plots/fig.png");
+    }
+
+    #[tokio::test]
+    async fn test_path_prefix_is_prepended_to_reported_paths() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("plots")).unwrap();
+        let path = dir.path().join("plots/fig.png");
+        std::fs::write(&path, "png").unwrap();
+
+        let mut paths = Vec::new();
+        for prefix in [None, Some("sessions/abc")] {
+            let options = WatchOptions {
+                path_prefix: prefix.map(str::to_string),
+                ..Default::default()
+            };
+            let (tx, mut rx) = mpsc::channel(16);
+            let tx = EventSender::new(tx, THROTTLE_NOTICE_AFTER);
+            let last_seen = LastSeen::default();
+            emit_artifact(&path, dir.path(), &options, None, &last_seen, None, &tx).await.unwrap();
+            match rx.try_recv() {
+                Ok(WatchEvent::Artifact(artifact)) => paths.push(artifact.path),
+                other => panic!("expected artifact, got {:?}", other),
+            }
+        }
+        assert_eq!(paths, ["plots/fig.png", "sessions/abc/plots/fig.png"]);
+
+        assert_eq!(normalize_prefix(r"\sessions\.\abc\").unwrap().as_deref(), Some("sessions/abc"));
+        assert_eq!(normalize_prefix("/").unwrap(), None);
+        assert!(normalize_prefix("abc/..").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_pool_bounds_concurrency_and_coalesces() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Mutex;
+
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let read = Arc::new(Mutex::new(Vec::new()));
+        let reader = |path: PathBuf| {
+            let (active, peak, read) = (active.clone(), peak.clone(), read.clone());
+            async move {
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(2)).await;
+                read.lock().unwrap().push(path);
+                active.fetch_sub(1, Ordering::SeqCst);
+            }
+        };
+
+        let mut pool = ReadPool::new(4);
+        for i in 0..200 {
+            pool.push(PathBuf::from(format!("file-{}", i)));
+            // Repeats of a queued path collapse into one read
+            pool.push(PathBuf::from(format!("file-{}", i)));
+        }
+        pool.start(reader);
+        // A path that settles again mid-read is read once more afterwards
+        pool.push(PathBuf::from("file-0"));
+        pool.push(PathBuf::from("file-0"));
+        while pool.join_next().await.is_some() {
+            pool.start(reader);
+        }
+
+        assert_eq!(peak.load(Ordering::SeqCst), 4);
+        let mut read = read.lock().unwrap().clone();
+        assert_eq!(read.len(), 201);
+        read.sort();
+        read.dedup();
+        assert_eq!(read.len(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_burst_of_files_is_emitted_once_each() {
+        let dir = tempdir().unwrap();
+        let options = WatchOptions {
+            max_concurrent_reads: 4,
+            ..Default::default()
+        };
+        let (_watcher, mut rx) = FsWatcher::with_dirs(vec![dir.path().to_path_buf()], options).await.unwrap();
+        for i in 0..200 {
+            std::fs::write(dir.path().join(format!("part-{:03}.txt", i)), i.to_string()).unwrap();
+        }
+
+        let mut seen = HashMap::new();
+        while seen.len() < 200 {
+            match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await {
+                Ok(Some(WatchEvent::Artifact(artifact))) => *seen.entry(artifact.path).or_insert(0) += 1,
+                Ok(Some(other)) => panic!("expected artifact, got {:?}", other),
+                _ => panic!("only {} of 200 artifacts arrived", seen.len()),
+            }
+        }
+        // Nothing is emitted twice, even after the burst has been drained
+        assert!(tokio::time::timeout(Duration::from_millis(500), rx.recv()).await.is_err());
+        assert!(seen.values().all(|&count| count == 1));
+    }
+
+    #[tokio::test]
+    async fn test_existing_files_are_emitted_at_startup() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("plots")).unwrap();
+        std::fs::create_dir_all(dir.path().join("cache")).unwrap();
+        std::fs::write(dir.path().join("plots/fig.png"), "png").unwrap();
+        std::fs::write(dir.path().join("cache/skip.bin"), "x").unwrap();
+        std::fs::write(dir.path().join("report.txt"), "old").unwrap();
+
+        let options = WatchOptions {
+            ignore: IgnoreSet::new(["cache/"]),
+            ..Default::default()
+        };
+        let (_watcher, mut rx) = FsWatcher::with_dirs(vec![dir.path().to_path_buf()], options).await.unwrap();
+        // Modified right after startup: still a single, up-to-date artifact
+        std::fs::write(dir.path().join("report.txt"), "new").unwrap();
+
+        let mut artifacts = HashMap::new();
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(800), rx.recv()).await {
+            match event {
+                WatchEvent::Artifact(artifact) => {
+                    assert!(artifacts.insert(artifact.path.clone(), artifact.data_base64).is_none(), "{} emitted twice", artifact.path);
+                }
+                other => panic!("expected artifact, got {:?}", other),
+            }
+        }
+        let mut paths: Vec<&String> = artifacts.keys().collect();
+        paths.sort();
+        assert_eq!(paths, ["plots/fig.png", "report.txt"]);
+        assert_eq!(artifacts["report.txt"], base64::engine::general_purpose::STANDARD.encode("new"));
+    }
+
+    #[tokio::test]
+    async fn test_files_moved_into_a_new_directory_are_emitted() {
+        let dir = tempdir().unwrap();
+        let elsewhere = tempdir().unwrap();
+        let (_watcher, mut rx) = FsWatcher::new(dir.path()).await.unwrap();
+        // Past the startup scan, which would otherwise find the file itself
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // Both levels exist, and the file is in place, before either is watched
+        std::fs::write(elsewhere.path().join("core"), "dump").unwrap();
+        std::fs::create_dir_all(dir.path().join("cores/run-1")).unwrap();
+        std::fs::rename(elsewhere.path().join("core"), dir.path().join("cores/run-1/core")).unwrap();
+
+        let mut paths = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(800), rx.recv()).await {
+            match event {
+                WatchEvent::Artifact(artifact) => paths.push(artifact.path),
+                other => panic!("expected artifact, got {:?}", other),
+            }
+        }
+        assert_eq!(paths, ["cores/run-1/core"]);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_stops_at_the_artifact_limit() {
+        let dir = tempdir().unwrap();
+        let options = WatchOptions { max_artifacts: Some(3), ..Default::default() };
+        let (watcher, mut rx) = FsWatcher::with_dirs(vec![dir.path().to_path_buf()], options).await.unwrap();
+        for i in 0..10 {
+            std::fs::write(dir.path().join(format!("step-{}.json", i)), "{}").unwrap();
+        }
+
+        let (mut artifacts, mut limits) = (Vec::new(), Vec::new());
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(800), rx.recv()).await {
+            match event {
+                WatchEvent::Artifact(artifact) => artifacts.push(artifact.path),
+                WatchEvent::ArtifactLimitReached { limit } => limits.push(limit),
+                other => panic!("expected artifact, got {:?}", other),
+            }
+        }
+        assert_eq!(artifacts.len(), 3, "{:?}", artifacts);
+        assert_eq!(limits, vec![3]);
+        assert_eq!(watcher.withheld_artifacts(), 7);
+
+        // Files already reported are still updated, and withheld ones stay counted once
+        std::fs::write(dir.path().join(&artifacts[0]), "{\"done\": true}").unwrap();
+        std::fs::write(dir.path().join("step-extra.json"), "{}").unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        match event {
+            WatchEvent::Artifact(artifact) => assert_eq!(artifact.path, artifacts[0]),
+            other => panic!("expected artifact, got {:?}", other),
+        }
+        assert!(tokio::time::timeout(Duration::from_millis(800), rx.recv()).await.is_err());
+        assert_eq!(watcher.withheld_artifacts(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_manifest_skips_files_unchanged_since_last_session() {
+        let dir = tempdir().unwrap();
+        let state = tempdir().unwrap();
+        std::fs::write(dir.path().join("kept.txt"), "same").unwrap();
+        std::fs::write(dir.path().join("edited.txt"), "before").unwrap();
+        let options = WatchOptions {
+            manifest: Some(state.path().join("manifest.json")),
+            ..Default::default()
+        };
+
+        // Drain a whole session, returning the paths it reported
+        let session = |options: WatchOptions| {
+            let dir = dir.path().to_path_buf();
+            async move {
+                let (watcher, mut rx) = FsWatcher::with_dirs(vec![dir], options).await.unwrap();
+                let mut paths = Vec::new();
+                while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(800), rx.recv()).await {
+                    if let WatchEvent::Artifact(artifact) = event {
+                        paths.push(artifact.path);
+                    }
+                }
+                watcher.stop();
+                paths.sort();
+                paths
+            }
+        };
+
+        assert_eq!(session(options.clone()).await, ["edited.txt", "kept.txt"]);
+        // Touched but not changed: the hash still matches
+        std::fs::write(dir.path().join("kept.txt"), "same").unwrap();
+        std::fs::write(dir.path().join("edited.txt"), "after").unwrap();
+        assert_eq!(session(options.clone()).await, ["edited.txt"]);
+        assert!(session(options).await.is_empty());
+
+        // Stateless sessions stream everything
+        assert_eq!(session(WatchOptions::default()).await, ["edited.txt", "kept.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_watcher_error_is_reported_and_watch_restored() {
+        let root = tempdir().unwrap();
+        let dir = root.path().join("output");
+        let (watcher, mut rx) = FsWatcher::new(dir.clone()).await.unwrap();
+        // The backend loses the watch along with the directory
+        std::fs::remove_dir(&dir).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (tx, mut errors) = mpsc::channel(4);
+        let error = notify::Error::path_not_found().add_path(dir.clone());
+        let rearm = Arc::downgrade(&watcher._watcher);
+        recover(error, &rearm, std::slice::from_ref(&dir), &EventSender::new(tx, THROTTLE_NOTICE_AFTER)).await;
+        match errors.recv().await {
+            Some(WatchEvent::WatcherError { message }) => {
+                assert!(message.contains("watch re-established"), "{}", message);
+            }
+            other => panic!("expected a watcher error, got {:?}", other),
+        }
+        assert!(dir.is_dir());
+
+        std::fs::write(dir.join("after.txt"), "back").unwrap();
+        let artifact = tokio::time::timeout(Duration::from_secs(3), async {
+            loop {
+                if let Some(WatchEvent::Artifact(artifact)) = rx.recv().await {
+                    return artifact;
+                }
+            }
+        })
+        .await
+        .expect("no artifact after the watch was restored");
+        assert_eq!(artifact.path, "after.txt");
+    }
+
+    #[tokio::test]
+    async fn test_files_over_a_lowered_inline_limit_are_chunked() {
+        let dir = tempdir().unwrap();
+        let (watcher, mut rx) = FsWatcher::new(dir.path().to_path_buf()).await.unwrap();
+        watcher.set_max_inline_bytes(64);
+
+        std::fs::write(dir.path().join("small.txt"), "tiny").unwrap();
+        std::fs::write(dir.path().join("medium.csv"), "x".repeat(1000)).unwrap();
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(800), rx.recv()).await {
+            events.push(event);
+        }
+        assert!(events.iter().any(|e| matches!(e, WatchEvent::Artifact(a) if a.path == "small.txt")));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            WatchEvent::ArtifactStart { path, total_size: 1000, .. } if path == "medium.csv"
+        )));
+        assert!(!events.iter().any(|e| matches!(e, WatchEvent::Artifact(a) if a.path == "medium.csv")));
+    }
+
+    #[tokio::test]
+    async fn test_files_over_the_artifact_limit_are_reported_as_skipped() {
+        let dir = tempdir().unwrap();
+        let options = WatchOptions { max_artifact_bytes: Some(1024), ..Default::default() };
+        let (_watcher, mut rx) = FsWatcher::with_dirs(vec![dir.path().to_path_buf()], options).await.unwrap();
+
+        std::fs::write(dir.path().join("small.txt"), "tiny").unwrap();
+        std::fs::write(dir.path().join("huge.bin"), vec![0u8; 4096]).unwrap();
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(800), rx.recv()).await {
+            events.push(event);
+        }
+        assert!(events.iter().any(|e| matches!(e, WatchEvent::Artifact(a) if a.path == "small.txt")));
+        let huge: Vec<_> = events
+            .iter()
+            .filter(|e| match e {
+                WatchEvent::Artifact(a) => a.path == "huge.bin",
+                WatchEvent::ArtifactStart { path, .. } | WatchEvent::ArtifactSkipped { path, .. } => path == "huge.bin",
+                _ => false,
+            })
+            .collect();
+        assert!(matches!(huge[..], [WatchEvent::ArtifactSkipped {
+            size: 4096,
+            reason: SkipReason::TooLarge,
+            ..
+        }]), "{:?}", huge);
+    }
+
+    #[tokio::test]
+    async fn test_fifos_are_skipped_without_blocking() {
+        let dir = tempdir().unwrap();
+        // A single reader, so a read stuck on the FIFO would hold up everything
+        let options = WatchOptions { max_concurrent_reads: 1, ..Default::default() };
+        let (_watcher, mut rx) = FsWatcher::with_dirs(vec![dir.path().to_path_buf()], options).await.unwrap();
+        let fifo = dir.path().join("pipe");
+        let status = std::process::Command::new("mkfifo").arg(&fifo).status().unwrap();
+        assert!(status.success());
+        // Let the FIFO settle and be looked at before anything else arrives
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        std::fs::write(dir.path().join("after.txt"), "still here").unwrap();
+
+        let mut paths = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(800), rx.recv()).await {
+            match event {
+                WatchEvent::Artifact(artifact) => paths.push(artifact.path),
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+        assert_eq!(paths, ["after.txt"]);
+    }
+
     #[tokio::test]
     async fn test_watcher_creation() {
         let dir = tempdir().unwrap();
         let result = FsWatcher::new(dir.path()).await;
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_rapid_writes_produce_one_artifact() {
+        let dir = tempdir().unwrap();
+        let (_watcher, mut rx) = FsWatcher::new(dir.path()).await.unwrap();
+
+        let path = dir.path().join("report.txt");
+        std::fs::write(&path, "first").unwrap();
+        std::fs::write(&path, "second").unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("artifact should be emitted")
+            .unwrap();
+        match event {
+            WatchEvent::Artifact(artifact) => {
+                assert_eq!(artifact.path, "report.txt");
+                let data = base64::engine::general_purpose::STANDARD
+                    .decode(&artifact.data_base64)
+                    .unwrap();
+                assert_eq!(data, b"second");
+                assert_eq!(artifact.size, 6);
+                assert_eq!(artifact.sha256, sha256::digest_hex(b"second"));
+            }
+            other => panic!("expected artifact, got {:?}", other),
+        }
+
+        // No duplicate should follow once the file has settled
+        let extra = tokio::time::timeout(DEBOUNCE_WINDOW * 3, rx.recv()).await;
+        assert!(extra.is_err(), "unexpected extra event: {:?}", extra);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_file_not_re_emitted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.csv");
+        std::fs::write(&path, "a,b").unwrap();
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let tx = EventSender::new(tx, THROTTLE_NOTICE_AFTER);
+        let last_seen = LastSeen::default();
+        emit_artifact(&path, dir.path(), &WatchOptions::default(), None, &last_seen, None, &tx).await.unwrap();
+        emit_artifact(&path, dir.path(), &WatchOptions::default(), None, &last_seen, None, &tx).await.unwrap();
+        drop(tx);
+
+        let mut count = 0;
+        while rx.recv().await.is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_ignored_paths_are_not_emitted() {
+        let dir = tempdir().unwrap();
+        let options = WatchOptions {
+            ignore: IgnoreSet::new(["*.tmp", "**/cache/**"]),
+            ..Default::default()
+        };
+        let (_watcher, mut rx) = FsWatcher::with_dirs(vec![dir.path().to_path_buf()], options)
+            .await
+            .unwrap();
+
+        std::fs::create_dir(dir.path().join("cache")).unwrap();
+        std::fs::write(dir.path().join("cache").join("blob.bin"), "x").unwrap();
+        std::fs::write(dir.path().join("partial.tmp"), "x").unwrap();
+        std::fs::write(dir.path().join("result.txt"), "done").unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("artifact should be emitted")
+            .unwrap();
+        match event {
+            WatchEvent::Artifact(artifact) => assert_eq!(artifact.path, "result.txt"),
+            other => panic!("expected artifact, got {:?}", other),
+        }
+        let extra = tokio::time::timeout(DEBOUNCE_WINDOW * 3, rx.recv()).await;
+        assert!(extra.is_err(), "unexpected extra event: {:?}", extra);
+    }
+
+    #[tokio::test]
+    async fn test_full_channel_throttles_without_losing_artifacts() {
+        let dir = tempdir().unwrap();
+        let names = ["a.txt", "b.txt", "c.txt"];
+        for name in names {
+            std::fs::write(dir.path().join(name), name).unwrap();
+        }
+
+        let (tx, mut rx) = mpsc::channel(1);
+        tx.send(WatchEvent::ArtifactRemoved { root: String::new(), path: "filler".to_string() })
+            .await
+            .unwrap();
+        let sender = EventSender::new(tx, Duration::from_millis(50));
+
+        let root = dir.path().to_path_buf();
+        let emitter = tokio::spawn(async move {
+            let last_seen = LastSeen::default();
+            for name in names {
+                emit_artifact(&root.join(name), &root, &WatchOptions::default(), None, &last_seen, None, &sender)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        // Leave the channel full long enough to trip the notice
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        emitter.await.unwrap();
+
+        assert!(matches!(events[0], WatchEvent::ArtifactRemoved { .. }));
+        assert!(matches!(events[1], WatchEvent::Throttled { .. }));
+        let delivered: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match event {
+                WatchEvent::Artifact(artifact) => Some(artifact.path.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(delivered, names);
+        assert_eq!(
+            events.iter().filter(|e| matches!(e, WatchEvent::Throttled { .. })).count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deleted_artifact_is_reported_once() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("tmp.log");
+        std::fs::write(&path, "scratch").unwrap();
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let tx = EventSender::new(tx, THROTTLE_NOTICE_AFTER);
+        let last_seen = LastSeen::default();
+        emit_artifact(&path, dir.path(), &WatchOptions::default(), None, &last_seen, None, &tx).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(WatchEvent::Artifact(_))));
+
+        std::fs::remove_file(&path).unwrap();
+        emit_artifact(&path, dir.path(), &WatchOptions::default(), None, &last_seen, None, &tx).await.unwrap();
+        match rx.recv().await {
+            Some(WatchEvent::ArtifactRemoved { path, .. }) => assert_eq!(path, "tmp.log"),
+            other => panic!("expected removal, got {:?}", other),
+        }
+
+        // A second settle of the same missing path says nothing new
+        emit_artifact(&path, dir.path(), &WatchOptions::default(), None, &last_seen, None, &tx).await.unwrap();
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_symlinks_never_leak_outside_the_watch_dir() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("real.txt"), "inside").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.path().join("passwd")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("real.txt"), dir.path().join("alias.txt")).unwrap();
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let tx = EventSender::new(tx, THROTTLE_NOTICE_AFTER);
+        let last_seen = LastSeen::default();
+        let passwd = dir.path().join("passwd");
+        let alias = dir.path().join("alias.txt");
+
+        // Not following: no symlink is read at all
+        let options = WatchOptions::default();
+        emit_artifact(&passwd, dir.path(), &options, None, &last_seen, None, &tx).await.unwrap();
+        emit_artifact(&alias, dir.path(), &options, None, &last_seen, None, &tx).await.unwrap();
+        assert!(rx.try_recv().is_err());
+
+        // Following: only targets inside the watch dir are read
+        let options = WatchOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        emit_artifact(&passwd, dir.path(), &options, None, &last_seen, None, &tx).await.unwrap();
+        assert!(rx.try_recv().is_err());
+        emit_artifact(&alias, dir.path(), &options, None, &last_seen, None, &tx).await.unwrap();
+        match rx.try_recv() {
+            Ok(WatchEvent::Artifact(artifact)) => {
+                assert_eq!(artifact.path, "alias.txt");
+                assert_eq!(artifact.link_target.as_deref(), Some("real.txt"));
+                assert_eq!(artifact.size, 6);
+            }
+            other => panic!("expected artifact, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_removed_directory_reports_its_files() {
+        let dir = tempdir().unwrap();
+        let sub = dir.path().join("plots");
+        std::fs::create_dir(&sub).unwrap();
+        std::fs::write(sub.join("a.png"), "a").unwrap();
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let tx = EventSender::new(tx, THROTTLE_NOTICE_AFTER);
+        let last_seen = LastSeen::default();
+        emit_artifact(&sub.join("a.png"), dir.path(), &WatchOptions::default(), None, &last_seen, None, &tx).await.unwrap();
+        rx.recv().await.unwrap();
+
+        std::fs::remove_dir_all(&sub).unwrap();
+        emit_artifact(&sub, dir.path(), &WatchOptions::default(), None, &last_seen, None, &tx).await.unwrap();
+        match rx.recv().await {
+            Some(WatchEvent::ArtifactRemoved { path, .. }) => assert_eq!(path, "plots/a.png"),
+            other => panic!("expected removal, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiple_dirs_share_one_channel() {
+        let output = tempdir().unwrap();
+        let dist = tempdir().unwrap();
+        let dirs = vec![output.path().to_path_buf(), dist.path().to_path_buf()];
+        let (_watcher, mut rx) = FsWatcher::with_dirs(dirs, WatchOptions::default()).await.unwrap();
+
+        std::fs::write(output.path().join("a.txt"), "a").unwrap();
+        std::fs::write(dist.path().join("b.js"), "b").unwrap();
+
+        let mut paths = Vec::new();
+        for _ in 0..2 {
+            let event = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("artifact should be emitted")
+                .unwrap();
+            if let WatchEvent::Artifact(artifact) = event {
+                paths.push(artifact.path);
+            }
+        }
+        paths.sort();
+        assert_eq!(paths, vec!["a.txt", "b.js"]);
+    }
+
+    #[tokio::test]
+    async fn test_each_root_applies_its_own_policy() {
+        let small = tempdir().unwrap();
+        let large = tempdir().unwrap();
+        let roots = vec![
+            (small.path().to_path_buf(), WatcherPolicy {
+                max_artifact_bytes: Some(1024),
+                ignore: IgnoreSet::new(["*.log"]),
+                ..Default::default()
+            }),
+            (large.path().to_path_buf(), WatcherPolicy {
+                max_artifact_bytes: Some(8192),
+                mode: StreamMode::Chunked,
+                ..Default::default()
+            }),
+        ];
+        let (watcher, mut rx) = FsWatcher::with_roots(roots, WatchOptions::default()).await.unwrap();
+
+        for dir in [small.path(), large.path()] {
+            std::fs::write(dir.join("report.bin"), vec![7u8; 2048]).unwrap();
+            std::fs::write(dir.join("debug.log"), "trace").unwrap();
+        }
+        watcher.flush().await;
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+
+        let (small_root, large_root) = (small.path().display().to_string(), large.path().display().to_string());
+        let skipped: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                WatchEvent::ArtifactSkipped { root, path, reason, .. } => Some((root, path.as_str(), *reason)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(skipped, [(&small_root, "report.bin", SkipReason::TooLarge)]);
+        let mut started: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                WatchEvent::ArtifactStart { root, path, total_size, .. } => Some((root, path.as_str(), *total_size)),
+                _ => None,
+            })
+            .collect();
+        started.sort();
+        assert_eq!(started, [(&large_root, "debug.log", 5), (&large_root, "report.bin", 2048)]);
+        assert!(!events.iter().any(|e| matches!(e, WatchEvent::Artifact(_))), "{:?}", events);
+    }
+
+    #[test]
+    fn test_root_for_prefers_most_specific_dir() {
+        let dirs = vec![PathBuf::from("/workspace"), PathBuf::from("/workspace/dist")];
+        assert_eq!(
+            root_for(Path::new("/workspace/dist/app.js"), &dirs),
+            Path::new("/workspace/dist")
+        );
+        assert_eq!(
+            root_for(Path::new("/workspace/src/main.rs"), &dirs),
+            Path::new("/workspace")
+        );
+    }
+
+    #[test]
+    fn test_debouncer_coalesces_events() {
+        let mut debouncer = Debouncer::new(Duration::from_millis(200));
+        let start = Instant::now();
+        let path = PathBuf::from("/output/a.txt");
+
+        debouncer.push(path.clone(), start);
+        debouncer.push(path.clone(), start + Duration::from_millis(150));
+
+        // The second event pushed the deadline back
+        assert!(debouncer.take_settled(start + Duration::from_millis(250), usize::MAX).is_empty());
+        assert_eq!(debouncer.take_settled(start + Duration::from_millis(350), usize::MAX), vec![path]);
+        assert!(debouncer.next_deadline().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_artifact_in_chunks() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("model.bin");
+        let data: Vec<u8> = (0..10u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let tx = EventSender::new(tx, THROTTLE_NOTICE_AFTER);
+        stream_artifact(String::new(), relative_path(&path, dir.path()), &path, guess_mime(&path), data.len() as u64, 4, &tx)
+            .await
+            .unwrap();
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+
+        assert!(matches!(
+            &events[0],
+            WatchEvent::ArtifactStart { path, total_size: 10, .. } if path == "model.bin"
+        ));
+        let mut reassembled = Vec::new();
+        for (expected_seq, event) in events[1..4].iter().enumerate() {
+            match event {
+                WatchEvent::ArtifactChunk { seq, data_base64, .. } => {
+                    assert_eq!(*seq, expected_seq as u64);
+                    reassembled.extend(
+                        base64::engine::general_purpose::STANDARD
+                            .decode(data_base64)
+                            .unwrap(),
+                    );
+                }
+                other => panic!("expected chunk, got {:?}", other),
+            }
+        }
+        assert_eq!(reassembled, data);
+        match &events[4] {
+            WatchEvent::ArtifactEnd { sha256, .. } => {
+                assert_eq!(*sha256, sha256::digest_hex(&data));
+            }
+            other => panic!("expected end, got {:?}", other),
+        }
+        assert_eq!(events.len(), 5);
+    }
 }
@@ -0,0 +1,262 @@
+//! Minimal gzip encoder (RFC 1951 / RFC 1952).
+//!
+//! Artifacts only ever need to be compressed, never decompressed, so this
+//! greedy LZ77 matcher with the fixed Huffman code is kept in-tree rather
+//! than pulling in a compression crate. It trails zlib by a few percent on
+//! text, which is what it is used for.
+
+/// Shortest back-reference deflate can express
+const MIN_MATCH: usize = 3;
+
+/// Longest back-reference deflate can express
+const MAX_MATCH: usize = 258;
+
+/// Furthest back a match may reach
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// Candidates examined per position; bounds the time spent on repetitive input
+const MAX_CHAIN: usize = 64;
+
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Gzip-compress a buffer into a complete single-member `.gz` stream.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no mtime, no extra flags, unknown OS
+    let header = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    let mut bits = BitWriter::new(Vec::with_capacity(data.len() / 2 + 32));
+    bits.out.extend_from_slice(&header);
+    deflate(data, &mut bits);
+
+    let mut out = bits.finish();
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    // ISIZE is the input length modulo 2^32
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Encode `data` as one final deflate block using the fixed Huffman code.
+fn deflate(data: &[u8], bits: &mut BitWriter) {
+    // BFINAL = 1, BTYPE = 01 (fixed Huffman)
+    bits.write(1, 1);
+    bits.write(1, 2);
+
+    let mut matcher = Matcher::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (length, distance) = matcher.longest_match(data, pos);
+        if length >= MIN_MATCH {
+            write_length(bits, length);
+            write_distance(bits, distance);
+            for p in pos..pos + length {
+                matcher.insert(data, p);
+            }
+            pos += length;
+        } else {
+            write_literal(bits, data[pos] as u16);
+            matcher.insert(data, pos);
+            pos += 1;
+        }
+    }
+    write_literal(bits, 256);
+}
+
+/// Hash chains over the last window of input.
+struct Matcher {
+    /// Most recent position for each 3-byte hash
+    head: Vec<usize>,
+    /// Previous position with the same hash, indexed by position in the window
+    prev: Vec<usize>,
+}
+
+impl Matcher {
+    fn new() -> Self {
+        Self {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; WINDOW_SIZE],
+        }
+    }
+
+    fn hash(data: &[u8], pos: usize) -> usize {
+        let key = (data[pos] as usize) << 16 | (data[pos + 1] as usize) << 8 | data[pos + 2] as usize;
+        (key.wrapping_mul(2654435761) >> 8) & ((1 << HASH_BITS) - 1)
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH > data.len() {
+            return;
+        }
+        let hash = Self::hash(data, pos);
+        self.prev[pos % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = pos;
+    }
+
+    /// The longest earlier match for the bytes at `pos`, as (length, distance).
+    fn longest_match(&self, data: &[u8], pos: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let max_len = MAX_MATCH.min(data.len() - pos);
+        let mut best = (0, 0);
+        let mut candidate = self.head[Self::hash(data, pos)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || pos - candidate > WINDOW_SIZE {
+                break;
+            }
+            let length = data[candidate..]
+                .iter()
+                .zip(&data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, pos - candidate);
+                if length == max_len {
+                    break;
+                }
+            }
+            let next = self.prev[candidate % WINDOW_SIZE];
+            // Chains only go backwards; anything else is a recycled slot
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        best
+    }
+}
+
+/// Emit a literal/length symbol (0..=287) with the fixed Huffman code.
+fn write_literal(bits: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    };
+    bits.write_code(code as u32, len);
+}
+
+fn write_length(bits: &mut BitWriter, length: usize) {
+    let index = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap_or(0);
+    write_literal(bits, 257 + index as u16);
+    bits.write((length - LENGTH_BASE[index] as usize) as u32, LENGTH_EXTRA[index] as u32);
+}
+
+fn write_distance(bits: &mut BitWriter, distance: usize) {
+    let index = DIST_BASE.iter().rposition(|&base| base as usize <= distance).unwrap_or(0);
+    // Fixed distance codes are plain 5-bit numbers
+    bits.write_code(index as u32, 5);
+    bits.write((distance - DIST_BASE[index] as usize) as u32, DIST_EXTRA[index] as u32);
+}
+
+/// Packs values least-significant bit first, as deflate requires.
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new(out: Vec<u8>) -> Self {
+        Self { out, acc: 0, count: 0 }
+    }
+
+    /// Write the low `count` bits of `value`.
+    fn write(&mut self, value: u32, count: u32) {
+        self.acc |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Write a Huffman code, which deflate stores most-significant bit first.
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    /// Flush the final partial byte.
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 (IEEE) as used in the gzip trailer.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    /// Decompress with the system gzip, the same decoder a client would use.
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        let mut child = Command::new("gzip")
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(data).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "gzip rejected the stream");
+        output.stdout
+    }
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_round_trips_through_gzip() {
+        let repetitive = "timestamp,level,message\n".repeat(2000).into_bytes();
+        let binary: Vec<u8> = (0..50_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        for data in [Vec::new(), b"a".to_vec(), repetitive, binary] {
+            assert_eq!(gunzip(&compress(&data)), data);
+        }
+    }
+}
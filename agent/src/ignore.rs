@@ -0,0 +1,254 @@
+//! Gitignore-style path patterns for the artifact watcher.
+//!
+//! Supports the subset of gitignore syntax that matters for build output:
+//! `*`, `?`, `[...]` classes, `**` across directories, trailing `/` for
+//! directories, leading `/` to anchor at the watch root, and `!` to
+//! re-include. The last matching pattern wins.
+
+use std::path::{Component, Path};
+
+/// One parsed pattern line.
+#[derive(Debug, Clone)]
+struct Rule {
+    /// `!pattern`: re-include paths an earlier rule ignored
+    negated: bool,
+    /// `pattern/`: only matches directories
+    dir_only: bool,
+    /// Pattern contained a slash, so it is matched from the watch root
+    anchored: bool,
+    /// Pattern split on `/`
+    segments: Vec<String>,
+}
+
+/// A set of ignore patterns matched against watch-dir-relative paths.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreSet {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreSet {
+    /// Build a set from pattern lines; blank lines and `#` comments are skipped.
+    pub fn new<S: AsRef<str>>(patterns: impl IntoIterator<Item = S>) -> Self {
+        let rules = patterns
+            .into_iter()
+            .filter_map(|line| parse_rule(line.as_ref()))
+            .collect();
+        Self { rules }
+    }
+
+    /// This set's rules followed by another's, which win where both match.
+    pub fn and(&self, other: &IgnoreSet) -> Self {
+        let rules = self.rules.iter().chain(&other.rules).cloned().collect();
+        Self { rules }
+    }
+
+    /// Whether a file at this relative path should be ignored.
+    ///
+    /// A file is also ignored when any of its parent directories is.
+    pub fn is_ignored(&self, relative: &Path) -> bool {
+        self.matches(relative)
+    }
+
+    /// Whether the patterns select this path, for sets that are not ignore
+    /// lists (allowlists, MIME overrides).
+    pub fn matches(&self, relative: &Path) -> bool {
+        let components: Vec<String> = relative
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        if components.is_empty() {
+            return false;
+        }
+
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.negated == ignored && rule_matches(rule, &components) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+fn parse_rule(line: &str) -> Option<Rule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let (negated, line) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (dir_only, line) = match line.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let anchored = line.contains('/');
+    let line = line.trim_start_matches('/');
+    if line.is_empty() {
+        return None;
+    }
+
+    Some(Rule {
+        negated,
+        dir_only,
+        anchored,
+        segments: line.split('/').map(str::to_string).collect(),
+    })
+}
+
+/// Match a rule against a file path, or against any directory containing it.
+fn rule_matches(rule: &Rule, components: &[String]) -> bool {
+    // Directories are every proper prefix; the file itself is the full path
+    let candidates = (1..=components.len()).filter(|&len| !rule.dir_only || len < components.len());
+    for len in candidates {
+        let prefix = &components[..len];
+        let matched = if rule.anchored {
+            segments_match(&rule.segments, prefix)
+        } else {
+            // A slash-free pattern matches a name at any depth
+            wildcard_match(&rule.segments[0], &prefix[len - 1])
+        };
+        if matched {
+            return true;
+        }
+    }
+    false
+}
+
+/// Match pattern segments against path segments, with `**` spanning any
+/// number of directories (at least one when it ends the pattern).
+fn segments_match(pattern: &[String], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            let min = usize::from(rest.is_empty());
+            (min..=path.len()).any(|skip| segments_match(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path_rest)) => wildcard_match(first, name) && segments_match(rest, path_rest),
+            None => false,
+        },
+    }
+}
+
+/// Match a single path segment against `*`, `?` and `[...]` wildcards.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    wildcard_match_at(&pattern, &name)
+}
+
+fn wildcard_match_at(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| wildcard_match_at(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && wildcard_match_at(&pattern[1..], &name[1..]),
+        Some('[') => match (name.first(), class_end(pattern)) {
+            (Some(&c), Some(end)) => {
+                class_matches(&pattern[1..end], c) && wildcard_match_at(&pattern[end + 1..], &name[1..])
+            }
+            // An unterminated class is a literal '['
+            (Some('['), None) => wildcard_match_at(&pattern[1..], &name[1..]),
+            _ => false,
+        },
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && wildcard_match_at(&pattern[2..], &name[1..])
+        }
+        Some(&literal) => name.first() == Some(&literal) && wildcard_match_at(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Index of the `]` closing a class that starts at `pattern[0]`.
+fn class_end(pattern: &[char]) -> Option<usize> {
+    let mut i = 1;
+    if matches!(pattern.get(i), Some('!' | '^')) {
+        i += 1;
+    }
+    // A leading ']' is part of the class
+    if pattern.get(i) == Some(&']') {
+        i += 1;
+    }
+    pattern[i..].iter().position(|&c| c == ']').map(|pos| i + pos)
+}
+
+/// Whether `c` belongs to a class body such as `a-z0-9` or `!abc`.
+fn class_matches(body: &[char], c: char) -> bool {
+    let (negated, body) = match body.first() {
+        Some('!' | '^') => (true, &body[1..]),
+        _ => (false, body),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < body.len() {
+        if i + 2 < body.len() && body[i + 1] == '-' {
+            found |= (body[i]..=body[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= body[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(patterns: &[&str], path: &str) -> bool {
+        IgnoreSet::new(patterns).is_ignored(Path::new(path))
+    }
+
+    #[test]
+    fn test_extension_patterns_match_at_any_depth() {
+        assert!(ignored(&["*.tmp"], "scratch.tmp"));
+        assert!(ignored(&["*.tmp"], "deep/nested/scratch.tmp"));
+        assert!(!ignored(&["*.tmp"], "report.txt"));
+        assert!(!ignored(&["*.tmp"], "scratch.tmp.txt"));
+    }
+
+    #[test]
+    fn test_double_star_spans_directories() {
+        let patterns = ["**/cache/**"];
+        assert!(ignored(&patterns, "cache/blob.bin"));
+        assert!(ignored(&patterns, "a/b/cache/c/blob.bin"));
+        assert!(!ignored(&patterns, "cache"));
+        assert!(!ignored(&patterns, "cached/blob.bin"));
+    }
+
+    #[test]
+    fn test_directory_names_ignore_their_contents() {
+        assert!(ignored(&["node_modules"], "web/node_modules/left-pad/index.js"));
+        assert!(ignored(&["__pycache__/"], "pkg/__pycache__/mod.pyc"));
+        // Directory-only patterns do not match a file with that name
+        assert!(!ignored(&["build/"], "build"));
+    }
+
+    #[test]
+    fn test_anchored_patterns_match_from_the_root() {
+        assert!(ignored(&["/logs"], "logs/run.txt"));
+        assert!(!ignored(&["/logs"], "app/logs/run.txt"));
+        assert!(ignored(&["plots/*.png"], "plots/fig.png"));
+        assert!(!ignored(&["plots/*.png"], "old/plots/fig.png"));
+    }
+
+    #[test]
+    fn test_negation_and_comments() {
+        let patterns = ["# scratch files", "", "*.log", "!keep.log"];
+        assert!(ignored(&patterns, "debug.log"));
+        assert!(!ignored(&patterns, "keep.log"));
+    }
+
+    #[test]
+    fn test_wildcard_classes() {
+        assert!(wildcard_match("file[0-9].csv", "file7.csv"));
+        assert!(!wildcard_match("file[!0-9].csv", "file7.csv"));
+        assert!(wildcard_match("?.txt", "a.txt"));
+        assert!(!wildcard_match("?.txt", "ab.txt"));
+    }
+}
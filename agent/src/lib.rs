@@ -0,0 +1,9 @@
+//! Protocol types and a client for talking to a Boxed agent.
+//!
+//! The agent binary and its callers share [`rpc`], so a change to a method's
+//! params or an event's fields shows up as a compile error on both sides
+//! rather than as a malformed message at runtime. [`client`] speaks the
+//! protocol over any pair of async streams, such as a sandbox's stdio.
+
+pub mod client;
+pub mod rpc;
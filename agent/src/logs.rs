@@ -0,0 +1,140 @@
+//! Recent agent log records, kept in memory for `logs.tail`.
+//!
+//! The agent logs JSON to stderr, which a vsock setup that only forwards
+//! stdin/stdout never shows the Control Plane. [`LogBuffer`] is a second
+//! writer for the same subscriber that keeps the last few records, so they
+//! can be fetched over the RPC channel instead.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Records kept when nothing else is configured
+pub const DEFAULT_LOG_BUFFER_LINES: usize = 1000;
+
+/// The newest log records, oldest first, up to a fixed count.
+#[derive(Debug, Clone)]
+pub struct LogBuffer {
+    inner: Arc<Mutex<Records>>,
+}
+
+#[derive(Debug)]
+struct Records {
+    lines: VecDeque<String>,
+    capacity: usize,
+    /// Records pushed out to make room since startup
+    dropped: u64,
+}
+
+impl LogBuffer {
+    /// Keep at most `capacity` records.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Records {
+                lines: VecDeque::with_capacity(capacity.min(DEFAULT_LOG_BUFFER_LINES)),
+                capacity,
+                dropped: 0,
+            })),
+        }
+    }
+
+    fn push(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line).trim_end().to_string();
+        if line.is_empty() {
+            return;
+        }
+        let mut records = self.inner.lock().unwrap();
+        if records.lines.len() == records.capacity {
+            records.lines.pop_front();
+            records.dropped += 1;
+        }
+        records.lines.push_back(line);
+    }
+
+    /// The newest `max_lines` records (all of them when `None`), oldest
+    /// first, and how many older ones no longer fit.
+    ///
+    /// Records are the JSON objects the subscriber wrote; a line that is
+    /// not JSON comes back as a string.
+    pub fn tail(&self, max_lines: Option<usize>) -> (Vec<serde_json::Value>, u64) {
+        let records = self.inner.lock().unwrap();
+        let skip = records.lines.len().saturating_sub(max_lines.unwrap_or(usize::MAX));
+        let lines = records
+            .lines
+            .iter()
+            .skip(skip)
+            .map(|line| serde_json::from_str(line).unwrap_or_else(|_| serde_json::Value::String(line.clone())))
+            .collect();
+        (lines, records.dropped)
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogBuffer {
+    type Writer = LogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogWriter {
+            buffer: self,
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// Splits what one event writes into records.
+pub struct LogWriter<'a> {
+    buffer: &'a LogBuffer,
+    /// A line still waiting for its newline
+    pending: Vec<u8>,
+}
+
+impl io::Write for LogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            self.buffer.push(&line);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter<'_> {
+    fn drop(&mut self) {
+        if !self.pending.is_empty() {
+            self.buffer.push(&self.pending);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_the_newest_records_as_json() {
+        let buffer = LogBuffer::new(2);
+        let subscriber = tracing_subscriber::fmt().json().with_writer(buffer.clone()).finish();
+        tracing::subscriber::with_default(subscriber, || {
+            for n in 0..3 {
+                tracing::info!(n, "Record");
+            }
+            tracing::warn!(path = "/output/x.bin", "Artifact is unreadable, skipping");
+        });
+
+        let (records, dropped) = buffer.tail(None);
+        assert_eq!(dropped, 2);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["fields"]["n"], 2);
+        assert_eq!(records[1]["level"], "WARN");
+        assert_eq!(records[1]["fields"]["path"], "/output/x.bin");
+
+        let (records, _) = buffer.tail(Some(1));
+        assert_eq!(records[0]["fields"]["message"], "Artifact is unreadable, skipping");
+        assert!(buffer.tail(Some(0)).0.is_empty());
+    }
+}
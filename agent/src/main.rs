@@ -5,33 +5,78 @@
 //! - Streaming stdout/stderr in real-time
 //! - Watching for artifacts (files in /output) and streaming them back
 //!
+//! Set `BOXED_OUTPUT_DIR` (colon-separated) or pass `--output-dir` one or
+//! more times to watch other directories. Paths matching `--ignore` patterns
+//! (gitignore syntax, also read from `--ignore-file` or the comma-separated
+//! `BOXED_IGNORE`) are never reported as artifacts.
+//!
+//! Messages are newline-delimited by default. `--framing content-length`
+//! (or `BOXED_FRAMING`) selects LSP-style `Content-Length` headers instead,
+//! and a client may also switch through the `init` handshake.
+//!
 //! # Architecture
 //!
 //! The agent is designed to never panic. If user code crashes, the agent
 //! handles the error gracefully and remains alive for subsequent commands.
 
 use anyhow::Result;
-use tracing::{error, info};
+use base64::Engine;
+use futures::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use tracing_subscriber::fmt::writer::MakeWriterExt;
 use tracing_subscriber::EnvFilter;
 
+use boxed_agent::rpc;
+
+mod cgroup;
+mod config;
+mod coredump;
+mod diagnostics;
+mod dotenv;
+mod encoding;
 mod executor;
+mod files;
 mod fs_watcher;
-mod rpc;
+mod gzip;
+mod ignore;
+mod logs;
+mod manifest;
+mod plugins;
+mod procfs;
+mod pty;
+mod sha256;
+mod sniff;
+mod tail;
+mod upload;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize structured JSON logging
+    let started = Instant::now();
+    let config = config::AgentConfig::load()?;
+
+    // Initialize structured JSON logging, on stderr so stdout carries only
+    // JSON-RPC, and kept in memory for `logs.tail`
+    let logs = logs::LogBuffer::new(config.log_buffer_lines);
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env().add_directive("boxed_agent=info".parse()?))
         .json()
+        .with_writer(std::io::stderr.and(logs.clone()))
         .init();
 
     info!(version = env!("CARGO_PKG_VERSION"), "🗳️ Boxed Agent starting");
 
+    // Methods answered outside the built-in dispatch
+    let mut plugins = plugins::Registry::default();
+    plugins.register("plugin.echo", plugins::Echo)?;
+
     // The agent communicates over stdin/stdout for maximum compatibility
     // Docker: Attaches via exec
     // Firecracker: Connects via vsock, forwarded to stdin/stdout
-    if let Err(e) = run_agent().await {
+    let rpc = rpc::RpcHandler::new(tokio::io::stdin(), tokio::io::stdout());
+    if let Err(e) = run_agent(config, started, logs, plugins, rpc).await {
         error!(error = %e, "Agent encountered fatal error");
         std::process::exit(1);
     }
@@ -40,23 +85,101 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn run_agent() -> Result<()> {
-    // Initialize RPC listener
-    let stdin = tokio::io::stdin();
-    let stdout = tokio::io::stdout();
-    let mut rpc = rpc::RpcHandler::new(stdin, stdout);
+async fn run_agent<R, W>(
+    config: config::AgentConfig,
+    started: Instant,
+    logs: logs::LogBuffer,
+    plugins: plugins::Registry,
+    mut rpc: rpc::RpcHandler<R, W>,
+) -> Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    rpc.set_framing(config.framing);
+
+    // Without a usable cgroup parent, commands still run under rlimits
+    let cgroup_parent = config.cgroup_parent.clone().and_then(|dir| match cgroup::Parent::open(dir) {
+        Ok(parent) => Some(parent),
+        Err(e) => {
+            warn!(error = %format!("{:#}", e), "Failed to set up cgroups, continuing without them");
+            None
+        }
+    });
 
     // Initialize executor
-    let mut executor = executor::Executor::new();
+    let mut executor = executor::Executor::with_max_chunk_bytes(config.max_chunk_bytes)
+        .with_output_window(config.output_window)
+        .with_max_concurrency(config.max_concurrent_processes)
+        .with_core_dir(config.output_dirs.first().map(|dir| dir.join(CORE_DIR)))
+        .with_stdin_timeout(config.stdin_timeout)
+        .with_cgroup_parent(cgroup_parent);
+    let slot_freed = executor.slot_freed();
 
-    // Initialize FS watcher
-    let (_watcher, mut artifact_rx) = fs_watcher::FsWatcher::new("/output").await?;
+    // Initialize FS watcher. Without one, e.g. on a read-only rootfs,
+    // commands still run; only artifacts go unreported
+    let (watcher, mut artifact_rx, watcher_error) =
+        match fs_watcher::FsWatcher::with_roots(config.watch_roots(), config.watch_options()).await {
+            Ok((watcher, artifact_rx)) => (Some(watcher), artifact_rx, None),
+            Err(e) => {
+                let message = format!("{:#}", e);
+                error!(error = %message, "Failed to start artifact watcher, continuing without artifacts");
+                let (_, artifact_rx) = tokio::sync::mpsc::channel(1);
+                (None, artifact_rx, Some(message))
+            }
+        };
     
     // Channel for events (Stdout, Stderr, Exit, Artifact, Error)
     let (event_tx, mut event_rx) = tokio::sync::mpsc::channel::<rpc::StreamEvent>(100);
 
+    // Responses produced after their request was handled (exec.sync)
+    let (response_tx, mut response_rx) = tokio::sync::mpsc::channel::<rpc::Response>(100);
+
+    // Exits of auto-restarting REPLs, as (exec id, exit code)
+    let (restart_tx, mut restart_rx) = tokio::sync::mpsc::channel::<(String, i32)>(100);
+
+    // Exits of `exec.sequence` steps, as (exec id, exit code)
+    let (step_tx, mut step_rx) = tokio::sync::mpsc::channel::<(String, i32)>(100);
+    let mut sequences = HashMap::new();
+
+    // Files followed with `fs.tail`
+    let mut tails = tail::Tails::new(&config.fs_roots, tail::POLL_INTERVAL);
+
+    // The open `artifact.batch_begin` batch, and word that the watcher has
+    // caught up with one being ended
+    let mut batch: Option<ArtifactBatch> = None;
+    let mut batches_started = 0;
+    let (batch_flushed_tx, mut batch_flushed_rx) = tokio::sync::mpsc::channel::<()>(1);
+
+    // Written from this loop like every other message, so it never splits one
+    let mut keepalive = config.keepalive.map(|period| {
+        let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        timer
+    });
+
+    // Pushed back by every request and process event
+    let idle_deadline = || config.idle_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let mut idle = idle_deadline();
+
+    // Everything above is live, so commands sent from here on are not raced
+    rpc.send_event(rpc::StreamEvent::Ready {
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        pid: std::process::id(),
+    })
+    .await?;
+    if let Some(message) = watcher_error {
+        rpc.send_event(rpc::StreamEvent::Error {
+            exec_id: None,
+            message: format!("Artifact watcher unavailable, artifacts will not be reported: {}", message),
+        })
+        .await?;
+    }
     info!("Ready to accept commands");
 
+    // Set by a `shutdown` request, answered once everything has drained
+    let mut shutdown_id = None;
+
     loop {
         tokio::select! {
             // Read next request (handles EOF)
@@ -69,133 +192,1889 @@ async fn run_agent() -> Result<()> {
                     }
                     Err(e) => {
                         error!("Failed to read request: {}", e);
+                        if let Some(response) = e
+                            .downcast_ref::<rpc::RejectedMessage>()
+                            .and_then(rpc::RejectedMessage::response)
+                        {
+                            rpc.send_response(response).await?;
+                        }
                         continue;
                     }
                 };
 
-                match request.method.as_str() {
-                    "exec" => {
-                        let params: rpc::ExecParams = serde_json::from_value(request.params.clone())?;
-                        let config = executor::ExecConfig {
-                            cmd: params.cmd,
-                            args: params.args,
-                            env: params.env,
-                            cwd: "/workspace".to_string(),
-                        };
-                        
-                        if let Some(id) = request.id {
-                            rpc.send_response(rpc::Response::success(id, serde_json::Value::Null)).await?;
-                        }
-
-                        // Start execution and spawn monitoring task
-                        match executor.exec(config, false).await {
-                            Ok(mut output_rx) => {
-                                let tx = event_tx.clone();
-                                tokio::spawn(async move {
-                                    while let Some(output) = output_rx.recv().await {
-                                        match output {
-                                            executor::ProcessOutput::Stdout(line) => {
-                                                let _ = tx.send(rpc::StreamEvent::Stdout { chunk: line + "\n" }).await;
-                                            }
-                                            executor::ProcessOutput::Stderr(line) => {
-                                                let _ = tx.send(rpc::StreamEvent::Stderr { chunk: line + "\n" }).await;
-                                            }
-                                            executor::ProcessOutput::Error(e) => {
-                                                let _ = tx.send(rpc::StreamEvent::Error { message: e }).await;
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                    // Note: In this simple implementation, we don't handle wait_for_completion 
-                                    // inside the monitoring task because it needs &mut self.
-                                    // We will improve this in the next iteration.
-                                    let _ = tx.send(rpc::StreamEvent::Exit { code: 0 }).await;
-                                });
-                            }
-                            Err(e) => {
-                                let _ = event_tx.send(rpc::StreamEvent::Error { message: e.to_string() }).await;
-                            }
-                        }
+                idle = idle_deadline();
+                let id = request.id.clone();
+                if request.method == "shutdown" {
+                    info!("Shutdown requested");
+                    shutdown_id = id;
+                    break;
+                }
+                let result = if request.method == "exec.sync" {
+                    // Answered from a background task once the command finishes,
+                    // so the loop keeps serving other requests meanwhile
+                    match start_sync_exec(&request, &config, &mut executor, &response_tx).await {
+                        Ok(()) => continue,
+                        Err(e) => Err(e),
                     }
-                    "repl.start" => {
-                        let params: rpc::ReplStartParams = serde_json::from_value(request.params.clone())?;
-                        let config = executor::ExecConfig {
-                            cmd: params.cmd,
-                            args: params.args,
-                            env: params.env,
-                            cwd: "/workspace".to_string(),
-                        };
-
-                        if let Some(id) = request.id {
-                            rpc.send_response(rpc::Response::success(id, serde_json::Value::Null)).await?;
-                        }
-
-                        match executor.exec(config, true).await {
-                            Ok(mut output_rx) => {
-                                let tx = event_tx.clone();
-                                tokio::spawn(async move {
-                                    while let Some(output) = output_rx.recv().await {
-                                        match output {
-                                            executor::ProcessOutput::Stdout(line) => {
-                                                let _ = tx.send(rpc::StreamEvent::Stdout { chunk: line + "\n" }).await;
-                                            }
-                                            executor::ProcessOutput::Stderr(line) => {
-                                                let _ = tx.send(rpc::StreamEvent::Stderr { chunk: line + "\n" }).await;
-                                            }
-                                            executor::ProcessOutput::Error(e) => {
-                                                let _ = tx.send(rpc::StreamEvent::Error { message: e }).await;
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                    let _ = tx.send(rpc::StreamEvent::Exit { code: 0 }).await;
-                                });
-                            }
-                            Err(e) => {
-                                let _ = event_tx.send(rpc::StreamEvent::Error { message: e.to_string() }).await;
-                            }
-                        }
+                } else if request.method == "exec.sequence" {
+                    // Answered once the last step to run has exited
+                    match start_sequence(&request, &config, &mut executor, &event_tx, &step_tx, &mut sequences).await {
+                        Ok(()) => continue,
+                        Err(e) => Err(e),
                     }
-                    "repl.input" => {
-                        let params: rpc::ReplInputParams = serde_json::from_value(request.params.clone())?;
-                        match executor.write_stdin(&params.data).await {
-                            Ok(_) => {
-                                if let Some(id) = request.id {
-                                    rpc.send_response(rpc::Response::success(id, serde_json::Value::Null)).await?;
-                                }
-                            }
-                            Err(e) => {
-                                if let Some(id) = request.id {
-                                    rpc.send_response(rpc::Response::error(id, rpc::INVALID_PARAMS, &e.to_string())).await?;
-                                }
-                            }
-                        }
+                } else if request.method == "repl.input" || request.method == "repl.input_line" {
+                    // Answered once the process takes the input, so one that
+                    // never reads it cannot hold up other requests
+                    match start_stdin_write(&request, &mut executor, &event_tx, &response_tx) {
+                        Ok(()) => continue,
+                        Err(e) => Err(e),
                     }
-                    _ => {
-                        if let Some(id) = request.id {
-                            rpc.send_response(rpc::Response::error(id, rpc::METHOD_NOT_FOUND, "Method not found")).await?;
-                        }
+                } else if request.method == "fs.tail" || request.method == "fs.tail.stop" {
+                    handle_tail(&request, &mut tails, &event_tx).await
+                } else if request.method == "logs.tail" {
+                    logs_tail(&request, &logs)
+                } else if request.method == "artifact.batch_begin" || request.method == "artifact.batch_end" {
+                    // Ending a batch is answered once its artifacts have been sent
+                    match handle_batch(&request, watcher.as_ref(), &mut batch, &mut batches_started, &batch_flushed_tx) {
+                        Ok(Some(result)) => Ok(result),
+                        Ok(None) => continue,
+                        Err(e) => Err(e),
                     }
+                } else if let Some(handler) = plugins.find(&request.method) {
+                    // Built-ins never get here, as plugins live in their own namespace
+                    start_plugin_call(&request, handler, &event_tx, &response_tx);
+                    continue;
+                } else {
+                    dispatch(&request, &config, &mut executor, watcher.as_ref(), &event_tx, &restart_tx, started).await
+                };
+                let next_framing = negotiated_framing(&request, &result);
+                if let Some(id) = id {
+                    rpc.send_response(rpc::Response::from_result(id, result)).await?;
+                } else if let Err(e) = result {
+                    // Notifications get no response, so surface failures as events
+                    let exec_id = request.params.get("exec_id").and_then(|v| v.as_str());
+                    rpc.send_event(rpc::StreamEvent::Error {
+                        exec_id: exec_id.map(str::to_string),
+                        message: e.message,
+                    })
+                    .await?;
+                }
+                // The handshake response still goes out in the old framing
+                if let Some(framing) = next_framing {
+                    info!(?framing, "Switching message framing");
+                    rpc.set_framing(framing);
+                }
+            }
+            // Deliver deferred responses
+            response = response_rx.recv() => {
+                if let Some(response) = response {
+                    rpc.send_response(response).await?;
                 }
             }
             // Process events
             event = event_rx.recv() => {
                 if let Some(e) = event {
-                    rpc.send_event(e).await?;
+                    idle = idle_deadline();
+                    send_process_event(e, watcher.as_ref(), &mut artifact_rx, &mut batch, &mut rpc).await?;
+                }
+            }
+            // Respawn crashed REPLs
+            exited = restart_rx.recv() => {
+                if let Some((exec_id, code)) = exited {
+                    // The exit event was queued first; deliver it before any restart notice
+                    while let Ok(event) = event_rx.try_recv() {
+                        send_process_event(event, watcher.as_ref(), &mut artifact_rx, &mut batch, &mut rpc).await?;
+                    }
+                    if let Some(event) = restart_crashed(&mut executor, &event_tx, &restart_tx, exec_id, code).await {
+                        rpc.send_event(event).await?;
+                    }
+                }
+            }
+            // Start the next step of a sequence, or finish it
+            finished = step_rx.recv() => {
+                if let Some((exec_id, code)) = finished {
+                    // The step's exit event goes out before the next step starts
+                    while let Ok(event) = event_rx.try_recv() {
+                        send_process_event(event, watcher.as_ref(), &mut artifact_rx, &mut batch, &mut rpc).await?;
+                    }
+                    if let Some(response) = advance_sequence(&mut sequences, &mut executor, &event_tx, &step_tx, exec_id, code).await {
+                        rpc.send_response(response).await?;
+                    }
+                }
+            }
+            // Hand freed process slots to queued commands
+            _ = slot_freed.notified(), if executor.has_queued() => {
+                executor.start_queued();
+            }
+            // Process artifacts; never fires without a watcher
+            Some(artifact) = artifact_rx.recv() => {
+                if let Some(artifact) = unbatched(&mut batch, artifact) {
+                    rpc.send_event(artifact_event(artifact)).await?;
                 }
             }
-            // Process artifacts
-            artifact = artifact_rx.recv() => {
-                if let Some(a) = artifact {
-                    rpc.send_event(rpc::StreamEvent::Artifact {
-                        path: a.path,
-                        mime: a.mime,
-                        data_base64: a.data_base64
-                    }).await?;
+            // Close a batch once everything written before its end is in
+            Some(()) = batch_flushed_rx.recv() => {
+                while let Ok(artifact) = artifact_rx.try_recv() {
+                    if let Some(artifact) = unbatched(&mut batch, artifact) {
+                        rpc.send_event(artifact_event(artifact)).await?;
+                    }
+                }
+                if let Some(closed) = batch.take() {
+                    let (event, response) = closed.close();
+                    rpc.send_event(event).await?;
+                    if let Some(response) = response {
+                        rpc.send_response(response).await?;
+                    }
                 }
             }
+            // Shut down once nothing has happened for the idle timeout
+            _ = tokio::time::sleep_until(idle.unwrap_or_else(tokio::time::Instant::now)), if idle.is_some() => {
+                if executor.status().is_empty() && sequences.is_empty() {
+                    info!("Idle timeout reached, shutting down");
+                    break;
+                }
+                // A quiet but running command still counts as activity
+                idle = idle_deadline();
+            }
+            // Keep the connection warm
+            _ = async { keepalive.as_mut().unwrap().tick().await }, if keepalive.is_some() => {
+                let uptime_ms = started.elapsed().as_millis() as u64;
+                rpc.send_event(rpc::StreamEvent::Keepalive { uptime_ms }).await?;
+            }
         }
     }
-    
+
+    // Tails never end on their own, so stop them before waiting for events to drain
+    drop(tails);
+    // What an unfinished batch collected is sent rather than lost
+    if let Some(open) = batch.take() {
+        let (event, response) = open.close();
+        rpc.send_event(event).await?;
+        if let Some(response) = response {
+            rpc.send_response(response).await?;
+        }
+    }
+    drop(event_tx);
+    drop(response_tx);
+    drain_on_shutdown(&mut rpc, &mut executor, watcher, event_rx, response_rx, artifact_rx).await?;
+
+    // The acknowledgement is the last message, so the client knows nothing follows
+    if let Some(id) = shutdown_id {
+        let result = rpc::to_result(rpc::ShutdownResult { ok: true });
+        rpc.send_response(rpc::Response::from_result(id, result)).await?;
+    }
+    Ok(())
+}
+
+/// Longest a shutdown waits for processes to exit and their events to flush.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How long the watcher must stay quiet before a shutdown stops waiting for artifacts.
+const ARTIFACT_QUIET: Duration = Duration::from_millis(500);
+
+/// Longest an exit event waits for the watcher to catch up with the files
+/// written before it, so a stuck watcher cannot hold it back for good.
+const EXIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest an `fs.*` request may take, e.g. on a hung network mount.
+const FS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Subdirectory of the first output directory that captured cores are moved to
+const CORE_DIR: &str = "cores";
+
+/// Stop running processes and deliver every event still in flight.
+///
+/// Processes get SIGTERM (escalating to SIGKILL), then their remaining output
+/// and exit events are sent, along with pending `exec.sync` responses,
+/// followed by any artifacts they left behind. The watcher and any tasks
+/// still reading output are then cancelled.
+async fn drain_on_shutdown<R, W>(
+    rpc: &mut rpc::RpcHandler<R, W>,
+    executor: &mut executor::Executor,
+    watcher: Option<fs_watcher::FsWatcher>,
+    mut event_rx: tokio::sync::mpsc::Receiver<rpc::StreamEvent>,
+    mut response_rx: tokio::sync::mpsc::Receiver<rpc::Response>,
+    mut artifact_rx: tokio::sync::mpsc::Receiver<fs_watcher::WatchEvent>,
+) -> Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + SHUTDOWN_GRACE;
+    let killed = executor.kill_all();
+    if killed > 0 {
+        info!(count = killed, "Terminated running processes");
+    }
+
+    // Forwarders hold the only remaining senders, so this ends once every
+    // process has reported its exit
+    while let Ok(Some(event)) = tokio::time::timeout_at(deadline, event_rx.recv()).await {
+        rpc.send_event(event).await?;
+    }
+    while let Ok(Some(response)) = tokio::time::timeout_at(deadline, response_rx.recv()).await {
+        rpc.send_response(response).await?;
+    }
+
+    loop {
+        let quiet = (tokio::time::Instant::now() + ARTIFACT_QUIET).min(deadline);
+        match tokio::time::timeout_at(quiet, artifact_rx.recv()).await {
+            Ok(Some(artifact)) => rpc.send_event(artifact_event(artifact)).await?,
+            _ => break,
+        }
+    }
+
+    if let Some(watcher) = watcher {
+        watcher.stop();
+    }
+    executor.abort_all();
     Ok(())
 }
+
+/// Methods accepted by [`dispatch`], advertised through the `init` handshake.
+const SUPPORTED_METHODS: &[&str] = &[
+    "init",
+    "hello",
+    "ping",
+    "exec",
+    "exec.sync",
+    "exec.sequence",
+    "exec.validate",
+    "exec.kill",
+    "exec.signal",
+    "proc.sample",
+    "replay",
+    "repl.start",
+    "repl.input",
+    "repl.input_line",
+    "repl.eof",
+    "repl.resize",
+    "upload.configure",
+    "artifact.configure",
+    "artifact.batch_begin",
+    "artifact.batch_end",
+    "status",
+    "fs.list",
+    "fs.read",
+    "fs.write",
+    "fs.chmod",
+    "fs.chown",
+    "fs.tail",
+    "fs.tail.stop",
+    "env.set",
+    "env.unset",
+    "env.get",
+    "logs.tail",
+    "which",
+    "shutdown",
+];
+
+/// Framing requested by a successful `init`, if any.
+fn negotiated_framing(
+    request: &rpc::Request,
+    result: &Result<serde_json::Value, rpc::RpcError>,
+) -> Option<rpc::Framing> {
+    match (request.method.as_str(), result) {
+        ("init" | "hello", Ok(_)) => request.parse_params::<rpc::InitParams>().ok()?.framing,
+        _ => None,
+    }
+}
+
+/// Handle a single request and produce its JSON-RPC result.
+async fn dispatch(
+    request: &rpc::Request,
+    config: &config::AgentConfig,
+    executor: &mut executor::Executor,
+    watcher: Option<&fs_watcher::FsWatcher>,
+    event_tx: &tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+    restart_tx: &tokio::sync::mpsc::Sender<(String, i32)>,
+    started: Instant,
+) -> Result<serde_json::Value, rpc::RpcError> {
+    match request.method.as_str() {
+        "ping" => rpc::to_result(rpc::PingResult {
+            pong: true,
+            uptime_ms: started.elapsed().as_millis() as u64,
+        }),
+        "status" => {
+            let processes: Vec<rpc::ProcessStatus> = executor
+                .status()
+                .into_iter()
+                .map(|p| rpc::ProcessStatus {
+                    exec_id: p.exec_id,
+                    pid: p.pid,
+                    cmd: p.cmd,
+                    args: p.args,
+                    elapsed_ms: p.elapsed.as_millis() as u64,
+                    stdout_bytes: p.stdout_bytes,
+                    stderr_bytes: p.stderr_bytes,
+                    detached: p.detached,
+                })
+                .collect();
+            rpc::to_result(rpc::StatusResult {
+                running: !processes.is_empty(),
+                processes,
+                artifacts_withheld: watcher.map(|watcher| watcher.withheld_artifacts()).filter(|&count| count > 0),
+            })
+        }
+        "init" | "hello" => {
+            let params: rpc::InitParams = request.parse_params()?;
+            let protocol_version = params
+                .protocol_version
+                .map_or(rpc::PROTOCOL_VERSION, |v| v.min(rpc::PROTOCOL_VERSION));
+            let result = rpc::InitResult {
+                agent_version: env!("CARGO_PKG_VERSION").to_string(),
+                protocol_version,
+                methods: SUPPORTED_METHODS.iter().map(|m| m.to_string()).collect(),
+                framing: params.framing,
+            };
+            rpc::to_result(result)
+        }
+        "exec" => {
+            let params: rpc::ExecParams = request.parse_params()?;
+            let exec_id = params.spawn.exec_id.clone().unwrap_or_else(|| executor.next_exec_id());
+            let detach = params.detach;
+            let (exec_config, pipe_stdin) = exec_params_config(params, config).await?;
+            let exec_config = executor::ExecConfig { detach, ..exec_config };
+            let result = start_process(executor, event_tx, None, exec_id.clone(), exec_config, pipe_stdin).await?;
+            // A detached process is stopped by pid or exec id later, so both are returned
+            match executor.pid(&exec_id).filter(|_| detach) {
+                Some(pid) => Ok(serde_json::json!({ "exec_id": exec_id, "pid": pid })),
+                None => Ok(result),
+            }
+        }
+        "exec.validate" => {
+            let (exec_config, _) = exec_params_config(request.parse_params()?, config).await?;
+            validate_exec(&exec_config, executor.session_env()).await
+        }
+        "env.set" => {
+            let params: rpc::EnvSetParams = request.parse_params()?;
+            executor.set_env(params.vars).map_err(spawn_error)?;
+            Ok(serde_json::Value::Null)
+        }
+        "env.unset" => {
+            let params: rpc::EnvUnsetParams = request.parse_params()?;
+            executor.unset_env(&params.keys);
+            Ok(serde_json::Value::Null)
+        }
+        "env.get" => rpc::to_result(rpc::EnvGetResult {
+            vars: executor.session_env().clone(),
+        }),
+        "which" => {
+            let params: rpc::WhichParams = request.parse_params()?;
+            let which = |name: &str| executor.which(name).map(|path| path.to_string_lossy().into_owned());
+            match (params.name, params.names) {
+                (Some(name), None) => rpc::to_result(rpc::WhichResult { path: which(&name) }),
+                (None, Some(names)) => rpc::to_result(rpc::WhichBatchResult {
+                    paths: names
+                        .into_iter()
+                        .map(|name| {
+                            let path = which(&name);
+                            (name, path)
+                        })
+                        .collect(),
+                }),
+                _ => Err(rpc::RpcError::new(rpc::INVALID_PARAMS, "Expected exactly one of 'name' or 'names'")),
+            }
+        }
+        "repl.start" => {
+            let params: rpc::ReplStartParams = request.parse_params()?;
+            let exec_id = params.spawn.exec_id.clone().unwrap_or_else(|| executor.next_exec_id());
+            let default_size = pty::WindowSize::default();
+            let tty = params.tty.then(|| pty::WindowSize {
+                rows: params.rows.unwrap_or(default_size.rows),
+                cols: params.cols.unwrap_or(default_size.cols),
+            });
+            let config = executor::ExecConfig {
+                tty,
+                ..exec_config(params.spawn)
+            };
+            if !params.auto_restart {
+                return start_process(executor, event_tx, None, exec_id, config, true).await;
+            }
+            let policy = executor::RestartPolicy {
+                max_restarts: params.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS),
+                window: RESTART_WINDOW,
+            };
+            let result = start_process(executor, event_tx, Some(restart_tx), exec_id.clone(), config.clone(), true).await?;
+            executor.enable_restart(&exec_id, policy, config, true);
+            Ok(result)
+        }
+        "repl.resize" => {
+            let params: rpc::ReplResizeParams = request.parse_params()?;
+            let size = pty::WindowSize {
+                rows: params.rows,
+                cols: params.cols,
+            };
+            executor
+                .resize(params.exec_id.as_deref(), size)
+                .map_err(|e| rpc::RpcError::new(rpc::INVALID_PARAMS, format!("{:#}", e)))?;
+            Ok(serde_json::Value::Null)
+        }
+        "repl.eof" => {
+            let params: rpc::ExecTargetParams = request.parse_params()?;
+            executor
+                .close_stdin(params.exec_id.as_deref())
+                .map_err(|e| rpc::RpcError::new(rpc::INVALID_PARAMS, e.to_string()))?;
+            Ok(serde_json::Value::Null)
+        }
+        "exec.kill" => {
+            let params: rpc::ExecTargetParams = request.parse_params()?;
+            executor
+                .kill(params.exec_id.as_deref())
+                .map_err(|e| rpc::RpcError::new(rpc::INVALID_PARAMS, e.to_string()))?;
+            Ok(serde_json::Value::Null)
+        }
+        "exec.signal" => {
+            let params: rpc::ExecSignalParams = request.parse_params()?;
+            let signal = match params.signal {
+                rpc::SignalSpec::Number(number) => number,
+                rpc::SignalSpec::Name(name) => executor::parse_signal_name(&name)
+                    .map_err(|e| rpc::RpcError::new(rpc::INVALID_PARAMS, e.to_string()))?,
+            };
+            executor
+                .signal(params.exec_id.as_deref(), signal)
+                .map_err(|e| rpc::RpcError::new(rpc::INVALID_PARAMS, format!("{:#}", e)))?;
+            Ok(serde_json::Value::Null)
+        }
+        "proc.sample" => {
+            let params: rpc::ProcSampleParams = request.parse_params()?;
+            let (exec_id, mut samples) = executor
+                .sample(
+                    params.exec_id.as_deref(),
+                    Duration::from_millis(params.interval_ms),
+                    Duration::from_millis(params.duration_ms),
+                )
+                .map_err(|e| rpc::RpcError::new(rpc::INVALID_PARAMS, format!("{:#}", e)))?;
+            let (tx, id) = (event_tx.clone(), exec_id.clone());
+            tokio::spawn(async move {
+                while let Some((elapsed, sample)) = samples.recv().await {
+                    let event = rpc::StreamEvent::ProcSample {
+                        exec_id: id.clone(),
+                        elapsed_ms: elapsed.as_millis() as u64,
+                        rss_kb: sample.rss_kb,
+                        fd_count: sample.fd_count,
+                        threads: sample.threads,
+                    };
+                    if tx.send(event).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(serde_json::json!({ "exec_id": exec_id }))
+        }
+        "replay" => {
+            let params: rpc::ReplayParams = request.parse_params()?;
+            let replay = executor
+                .replay(params.exec_id.as_deref(), params.since_seq)
+                .map_err(|e| rpc::RpcError::new(rpc::INVALID_PARAMS, format!("{:#}", e)))?;
+            let result = rpc::ReplayResult {
+                exec_id: replay.exec_id.clone(),
+                replayed: replay.chunks.len(),
+                complete: replay.complete,
+            };
+            let tx = event_tx.clone();
+            tokio::spawn(async move {
+                for output in replay.chunks {
+                    if tx.send(output_event(replay.exec_id.clone(), output)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            rpc::to_result(result)
+        }
+        "fs.list" => {
+            let params: rpc::FsListParams = request.parse_params()?;
+            let path = PathBuf::from(executor::resolve_cwd(params.path.as_deref()));
+            let limit = params.limit.unwrap_or(files::MAX_ENTRIES);
+            let listing = fs_call(&path, files::list(&path, &config.fs_roots, params.recursive, limit)).await?;
+            let entries = listing
+                .entries
+                .into_iter()
+                .map(|e| rpc::FsEntry {
+                    name: e.name,
+                    size: e.size,
+                    mode: e.mode,
+                    mtime_ms: e.mtime_ms,
+                    is_dir: e.is_dir,
+                })
+                .collect();
+            rpc::to_result(rpc::FsListResult {
+                entries,
+                truncated: listing.truncated,
+            })
+        }
+        "fs.read" => {
+            let params: rpc::FsReadParams = request.parse_params()?;
+            let path = PathBuf::from(executor::resolve_cwd(Some(&params.path)));
+            let max_bytes = params.max_bytes.unwrap_or(files::DEFAULT_MAX_READ_BYTES);
+            let data = fs_call(&path, files::read(&path, &config.fs_roots, max_bytes)).await?;
+            rpc::to_result(rpc::FsReadResult {
+                data_base64: base64::engine::general_purpose::STANDARD.encode(&data),
+                mime: mime_guess::from_path(&path).first_or_octet_stream().to_string(),
+                size: data.len() as u64,
+            })
+        }
+        "fs.write" => {
+            let params: rpc::FsWriteParams = request.parse_params()?;
+            let path = PathBuf::from(executor::resolve_cwd(Some(&params.path)));
+            let data = base64::engine::general_purpose::STANDARD
+                .decode(&params.data_base64)
+                .map_err(|e| rpc::RpcError::new(rpc::INVALID_PARAMS, format!("Invalid base64 data: {}", e)))?;
+            fs_call(&path, files::write(&path, &config.fs_roots, &data, params.mode)).await?;
+            Ok(serde_json::Value::Null)
+        }
+        "fs.chmod" => {
+            let params: rpc::FsChmodParams = request.parse_params()?;
+            let path = PathBuf::from(executor::resolve_cwd(Some(&params.path)));
+            fs_call(&path, files::chmod(&path, &config.fs_roots, params.mode)).await?;
+            Ok(serde_json::Value::Null)
+        }
+        "fs.chown" => {
+            let params: rpc::FsChownParams = request.parse_params()?;
+            let path = PathBuf::from(executor::resolve_cwd(Some(&params.path)));
+            fs_call(&path, files::chown(&path, &config.fs_roots, params.uid, params.gid)).await?;
+            Ok(serde_json::Value::Null)
+        }
+        "upload.configure" => {
+            let params: rpc::UploadConfigureParams = request.parse_params()?;
+            let target = match &params.base_url {
+                Some(base_url) => Some(upload::UploadTarget::new(base_url, params.headers).map_err(|e| {
+                    rpc::RpcError::new(rpc::INVALID_PARAMS, format!("{:#}", e))
+                        .with_data(serde_json::json!({ "base_url": base_url }))
+                })?),
+                None => None,
+            };
+            artifact_watcher(watcher)?.set_upload(target);
+            Ok(serde_json::Value::Null)
+        }
+        "artifact.configure" => {
+            let params: rpc::ArtifactConfigureParams = request.parse_params()?;
+            artifact_watcher(watcher)?.set_max_inline_bytes(params.max_inline_bytes);
+            Ok(serde_json::Value::Null)
+        }
+        _ => Err(rpc::RpcError::new(rpc::METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+
+/// The artifact watcher, for methods that configure it.
+fn artifact_watcher(watcher: Option<&fs_watcher::FsWatcher>) -> Result<&fs_watcher::FsWatcher, rpc::RpcError> {
+    watcher.ok_or_else(|| rpc::RpcError::new(rpc::INTERNAL_ERROR, "Artifact watcher is not running"))
+}
+
+/// Artifacts held back between `artifact.batch_begin` and `artifact.batch_end`.
+struct ArtifactBatch {
+    id: String,
+    artifacts: Vec<rpc::ArtifactParams>,
+    /// Files sent in chunks meanwhile, too large to hold back
+    streamed: Vec<String>,
+    /// Set by `artifact.batch_end`
+    closing: bool,
+    /// The `artifact.batch_end` request to answer once the batch is sent
+    end_id: Option<serde_json::Value>,
+}
+
+impl ArtifactBatch {
+    /// The batch's notification, and the response to `artifact.batch_end`
+    /// if it asked for one.
+    fn close(self) -> (rpc::StreamEvent, Option<rpc::Response>) {
+        let result = rpc::ArtifactBatchResult {
+            batch_id: self.id.clone(),
+            files: Some(self.artifacts.len() + self.streamed.len()),
+        };
+        let response = self.end_id.map(|id| rpc::Response::from_result(id, rpc::to_result(result)));
+        let event = rpc::StreamEvent::ArtifactBatch {
+            batch_id: self.id,
+            artifacts: self.artifacts,
+            streamed: self.streamed,
+        };
+        (event, response)
+    }
+}
+
+/// Hold a watcher event back in the open batch, if there is one and the
+/// event belongs there, or hand it back to be sent now.
+fn unbatched(batch: &mut Option<ArtifactBatch>, event: fs_watcher::WatchEvent) -> Option<fs_watcher::WatchEvent> {
+    let Some(batch) = batch else {
+        return Some(event);
+    };
+    match event {
+        fs_watcher::WatchEvent::Artifact(a) => {
+            batch.artifacts.push(artifact_params(a));
+            None
+        }
+        fs_watcher::WatchEvent::ArtifactEnd { ref path, .. } => {
+            batch.streamed.push(path.clone());
+            Some(event)
+        }
+        event => Some(event),
+    }
+}
+
+/// Send a process event, making sure an exit never overtakes the artifacts
+/// written before it.
+///
+/// For an exit, the watcher first reads every file changed so far, whether
+/// or not it has settled or its filesystem event has arrived, and what it
+/// finds goes out ahead. Other requests wait meanwhile, so a process can
+/// never be seen to exit before its outputs are in.
+async fn send_process_event<R, W>(
+    event: rpc::StreamEvent,
+    watcher: Option<&fs_watcher::FsWatcher>,
+    artifact_rx: &mut tokio::sync::mpsc::Receiver<fs_watcher::WatchEvent>,
+    batch: &mut Option<ArtifactBatch>,
+    rpc: &mut rpc::RpcHandler<R, W>,
+) -> Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    if let (rpc::StreamEvent::Exit { exec_id, .. }, Some(watcher)) = (&event, watcher) {
+        let flushed = tokio::time::timeout(EXIT_FLUSH_TIMEOUT, watcher.flush());
+        tokio::pin!(flushed);
+        loop {
+            tokio::select! {
+                result = &mut flushed => {
+                    if result.is_err() {
+                        warn!(exec_id = %exec_id, "Artifact watcher did not catch up, sending exit anyway");
+                    }
+                    break;
+                }
+                // Drained while it catches up, so a full channel cannot stall it
+                Some(artifact) = artifact_rx.recv() => {
+                    if let Some(artifact) = unbatched(batch, artifact) {
+                        rpc.send_event(artifact_event(artifact)).await?;
+                    }
+                }
+            }
+        }
+        while let Ok(artifact) = artifact_rx.try_recv() {
+            if let Some(artifact) = unbatched(batch, artifact) {
+                rpc.send_event(artifact_event(artifact)).await?;
+            }
+        }
+    }
+    rpc.send_event(event).await
+}
+
+/// Open or end an artifact batch for `artifact.batch_begin` and
+/// `artifact.batch_end`.
+///
+/// Ending one asks the watcher to catch up with everything written so far;
+/// the batch is sent, and the request answered, once `flushed_tx` reports
+/// that it has. Returns `None` for that deferred answer.
+fn handle_batch(
+    request: &rpc::Request,
+    watcher: Option<&fs_watcher::FsWatcher>,
+    batch: &mut Option<ArtifactBatch>,
+    batches_started: &mut u64,
+    flushed_tx: &tokio::sync::mpsc::Sender<()>,
+) -> Result<Option<serde_json::Value>, rpc::RpcError> {
+    let watcher = artifact_watcher(watcher)?;
+    if request.method == "artifact.batch_begin" {
+        let params: rpc::ArtifactBatchParams = request.parse_params()?;
+        if let Some(open) = batch {
+            return Err(rpc::RpcError::new(
+                rpc::INVALID_REQUEST,
+                format!("Artifact batch '{}' is already open", open.id),
+            ));
+        }
+        *batches_started += 1;
+        let id = params.batch_id.unwrap_or_else(|| format!("batch-{}", batches_started));
+        info!(batch_id = %id, "Artifact batch opened");
+        *batch = Some(ArtifactBatch {
+            id: id.clone(),
+            artifacts: Vec::new(),
+            streamed: Vec::new(),
+            closing: false,
+            end_id: None,
+        });
+        return rpc::to_result(rpc::ArtifactBatchResult { batch_id: id, files: None }).map(Some);
+    }
+
+    let open = match batch {
+        Some(open) if !open.closing => open,
+        Some(open) => {
+            return Err(rpc::RpcError::new(
+                rpc::INVALID_REQUEST,
+                format!("Artifact batch '{}' is already ending", open.id),
+            ))
+        }
+        None => return Err(rpc::RpcError::new(rpc::INVALID_REQUEST, "No artifact batch is open")),
+    };
+    open.closing = true;
+    open.end_id = request.id.clone();
+    let (flushed, flushed_tx) = (watcher.flush(), flushed_tx.clone());
+    tokio::spawn(async move {
+        flushed.await;
+        let _ = flushed_tx.send(()).await;
+    });
+    Ok(None)
+}
+
+/// Answer a plugin method from a background task, then forward whatever it
+/// streams.
+fn start_plugin_call(
+    request: &rpc::Request,
+    handler: std::sync::Arc<dyn plugins::MethodHandler>,
+    event_tx: &tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+    response_tx: &tokio::sync::mpsc::Sender<rpc::Response>,
+) {
+    let (id, method) = (request.id.clone(), request.method.clone());
+    let call = handler.call(&method, request.params.clone());
+    let (event_tx, response_tx) = (event_tx.clone(), response_tx.clone());
+    tokio::spawn(async move {
+        let (result, events) = match call.await {
+            Ok(plugins::Reply::Value(value)) => (Ok(value), None),
+            Ok(plugins::Reply::Stream { result, events }) => (Ok(result), Some(events)),
+            Err(e) => (Err(e), None),
+        };
+        match (&id, result) {
+            (Some(id), result) => {
+                let _ = response_tx.send(rpc::Response::from_result(id.clone(), result)).await;
+            }
+            // Notifications get no response, so surface failures as events
+            (None, Err(e)) => {
+                let _ = event_tx.send(rpc::StreamEvent::Error { exec_id: None, message: e.message }).await;
+            }
+            (None, Ok(_)) => {}
+        }
+        let Some(mut events) = events else {
+            return;
+        };
+        while let Some(data) = events.next().await {
+            let event = rpc::StreamEvent::PluginEvent {
+                method: method.clone(),
+                request_id: id.clone(),
+                data,
+            };
+            if event_tx.send(event).await.is_err() {
+                return;
+            }
+        }
+        let _ = event_tx.send(rpc::StreamEvent::PluginEnd { method, request_id: id }).await;
+    });
+}
+
+/// The agent's most recent log records, for `logs.tail`.
+fn logs_tail(request: &rpc::Request, logs: &logs::LogBuffer) -> Result<serde_json::Value, rpc::RpcError> {
+    let params: rpc::LogsTailParams = request.parse_params()?;
+    let (records, dropped) = logs.tail(params.max_lines);
+    rpc::to_result(rpc::LogsTailResult { records, dropped })
+}
+
+/// Start or stop following a file for `fs.tail` and `fs.tail.stop`.
+async fn handle_tail(
+    request: &rpc::Request,
+    tails: &mut tail::Tails,
+    event_tx: &tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+) -> Result<serde_json::Value, rpc::RpcError> {
+    if request.method == "fs.tail.stop" {
+        let params: rpc::FsTailStopParams = request.parse_params()?;
+        let path = PathBuf::from(executor::resolve_cwd(Some(&params.path)));
+        if !tails.stop(&path) {
+            return Err(fs_error(anyhow::anyhow!("Not tailing '{}'", path.display()), &path));
+        }
+        return Ok(serde_json::Value::Null);
+    }
+
+    let params: rpc::FsTailParams = request.parse_params()?;
+    // Keyed by the path as given, since a rotated file may not resolve later
+    let path = PathBuf::from(executor::resolve_cwd(Some(&params.path)));
+    let mut tail_rx = fs_call(&path, tails.start(path.clone(), params.from_end)).await?;
+
+    let tag = path.display().to_string();
+    let event_tx = event_tx.clone();
+    let result = rpc::FsTailResult { path: tag.clone() };
+    tokio::spawn(async move {
+        while let Some(event) = tail_rx.recv().await {
+            let path = tag.clone();
+            let event = match event {
+                tail::TailEvent::Data(chunk) => rpc::StreamEvent::TailData { path, chunk },
+                tail::TailEvent::Rotated => rpc::StreamEvent::TailRotated { path },
+            };
+            if event_tx.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+    rpc::to_result(result)
+}
+
+/// Map a failed filesystem request to its error object, naming the path.
+fn fs_error(err: anyhow::Error, path: &std::path::Path) -> rpc::RpcError {
+    rpc::RpcError::new(rpc::INVALID_PARAMS, format!("{:#}", err)).with_data(serde_json::json!({ "path": path }))
+}
+
+/// Run a filesystem operation for a request, giving up after [`FS_TIMEOUT`].
+///
+/// The operation itself may carry on in the background, but the request
+/// loop is free again.
+async fn fs_call<T>(
+    path: &std::path::Path,
+    operation: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> Result<T, rpc::RpcError> {
+    match tokio::time::timeout(FS_TIMEOUT, operation).await {
+        Ok(result) => result.map_err(|e| fs_error(e, path)),
+        Err(_) => {
+            let timeout_ms = FS_TIMEOUT.as_millis() as u64;
+            let message = format!("Timed out after {}ms on '{}'", timeout_ms, path.display());
+            Err(rpc::RpcError::new(rpc::TIMEOUT, message)
+                .with_data(serde_json::json!({ "path": path, "timeout_ms": timeout_ms })))
+        }
+    }
+}
+
+/// Queue the input of `repl.input` or `repl.input_line`, answering from a
+/// background task once the process has taken it or the write timed out.
+fn start_stdin_write(
+    request: &rpc::Request,
+    executor: &mut executor::Executor,
+    event_tx: &tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+    response_tx: &tokio::sync::mpsc::Sender<rpc::Response>,
+) -> Result<(), rpc::RpcError> {
+    let mut params: rpc::ReplInputParams = request.parse_params()?;
+    params.append_newline |= request.method == "repl.input_line";
+    let written = executor
+        .queue_stdin(params.exec_id.as_deref(), params.bytes()?)
+        .map_err(|e| rpc::RpcError::new(rpc::INVALID_PARAMS, e.to_string()))?;
+
+    let id = request.id.clone();
+    let (event_tx, response_tx) = (event_tx.clone(), response_tx.clone());
+    tokio::spawn(async move {
+        let result = match written.await {
+            Ok(bytes_written) => rpc::to_result(rpc::ReplInputResult { bytes_written }),
+            Err(e) => Err(match e.downcast_ref::<executor::StdinTimeout>() {
+                Some(timeout) => rpc::RpcError::new(rpc::TIMEOUT, e.to_string()).with_data(serde_json::json!({
+                    "timeout_ms": timeout.limit.as_millis() as u64,
+                    "bytes_written": timeout.bytes_written,
+                })),
+                None => rpc::RpcError::new(rpc::INVALID_PARAMS, format!("{:#}", e)),
+            }),
+        };
+        match (id, result) {
+            (Some(id), result) => {
+                let _ = response_tx.send(rpc::Response::from_result(id, result)).await;
+            }
+            // Notifications get no response, so surface failures as events
+            (None, Err(e)) => {
+                let _ = event_tx
+                    .send(rpc::StreamEvent::Error {
+                        exec_id: params.exec_id,
+                        message: e.message,
+                    })
+                    .await;
+            }
+            (None, Ok(_)) => {}
+        }
+    });
+    Ok(())
+}
+
+/// Translate spawn options from the wire into executor configuration.
+fn exec_config(spawn: rpc::SpawnParams) -> executor::ExecConfig {
+    executor::ExecConfig {
+        cmd: spawn.cmd,
+        args: spawn.args,
+        env: spawn.env,
+        cwd: executor::resolve_cwd(spawn.cwd.as_deref()),
+        timeout: spawn.timeout_ms.map(Duration::from_millis),
+        memory_limit_bytes: spawn.memory_limit_bytes,
+        cpu_seconds: spawn.cpu_seconds,
+        cpu_millicores: spawn.cpu_millicores,
+        nice: spawn.nice,
+        umask: spawn.umask,
+        tty: None,
+        uid: spawn.uid,
+        gid: spawn.gid,
+        supplementary_groups: spawn.supplementary_groups,
+        combine_stderr: spawn.combine_stderr,
+        max_output_bytes: spawn.max_output_bytes,
+        discard_stdout: false,
+        discard_stderr: false,
+        output_encoding: spawn.output_encoding,
+        diagnostics: None,
+        clear_env: spawn.clear_env,
+        env_remove: spawn.env_remove,
+        path_prepend: spawn.path_prepend,
+        path_append: spawn.path_append,
+        expand_env: None,
+        capture_core: spawn.capture_core,
+        stdin_file: None,
+        requested: Some(Instant::now()),
+        detach: false,
+    }
+}
+
+/// Executor configuration for `exec` and its variants, with `env_file`
+/// merged in and a stdin file resolved, both confined to the sandbox roots,
+/// and whether stdin is to be a pipe.
+async fn exec_params_config(
+    mut params: rpc::ExecParams,
+    config: &config::AgentConfig,
+) -> Result<(executor::ExecConfig, bool), rpc::RpcError> {
+    if let Some(path) = &params.env_file {
+        let path = PathBuf::from(executor::resolve_cwd(Some(path)));
+        fs_call(&path, dotenv::load_into(&path, &config.fs_roots, &mut params.spawn.env)).await?;
+    }
+    let stdin = match (params.stdin, params.stdin_file) {
+        (stdin, None) => stdin,
+        (rpc::StdinSource::Null, Some(path)) => rpc::StdinSource::File(path),
+        (_, Some(_)) => {
+            return Err(rpc::RpcError::new(rpc::INVALID_PARAMS, "Expected only one of 'stdin' and 'stdin_file'"));
+        }
+    };
+    let pipe_stdin = stdin == rpc::StdinSource::Pipe;
+    let stdin_file = match stdin {
+        rpc::StdinSource::File(path) => {
+            let path = PathBuf::from(executor::resolve_cwd(Some(&path)));
+            let resolved = fs_call(&path, files::confine(&path, &config.fs_roots)).await?;
+            if !resolved.is_file() {
+                return Err(fs_error(anyhow::anyhow!("'{}' is not a file", path.display()), &path));
+            }
+            Some(resolved)
+        }
+        rpc::StdinSource::Null | rpc::StdinSource::Pipe => None,
+    };
+    let diagnostics = params
+        .diagnostics_format
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(|e: anyhow::Error| rpc::RpcError::new(rpc::INVALID_PARAMS, e.to_string()))?;
+    let streams = params.streams.unwrap_or_else(|| vec![rpc::OutputStream::Stdout, rpc::OutputStream::Stderr]);
+    let exec_config = executor::ExecConfig {
+        stdin_file,
+        diagnostics,
+        discard_stdout: !streams.contains(&rpc::OutputStream::Stdout),
+        discard_stderr: !streams.contains(&rpc::OutputStream::Stderr),
+        expand_env: params.expand_env.then_some(match params.undefined_vars {
+            rpc::UndefinedVars::Empty => executor::UndefinedVars::Empty,
+            rpc::UndefinedVars::Error => executor::UndefinedVars::Error,
+        }),
+        ..exec_config(params.spawn)
+    };
+    let exec_config = if params.shell {
+        exec_config.through_shell(&config.shell)
+    } else {
+        exec_config
+    };
+    Ok((exec_config, pipe_stdin))
+}
+
+/// Restarts a REPL may use within [`RESTART_WINDOW`] unless the caller says otherwise.
+const DEFAULT_MAX_RESTARTS: u32 = 3;
+
+/// Span over which REPL restarts are counted against the cap.
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+/// Spawn a process and forward its output, returning the exec id to the caller.
+///
+/// With `restart_tx`, the exit code is also reported there so a crash can be
+/// answered with a restart.
+async fn start_process(
+    executor: &mut executor::Executor,
+    event_tx: &tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+    restart_tx: Option<&tokio::sync::mpsc::Sender<(String, i32)>>,
+    exec_id: String,
+    config: executor::ExecConfig,
+    pipe_stdin: bool,
+) -> Result<serde_json::Value, rpc::RpcError> {
+    let output_rx = executor
+        .exec(&exec_id, config, pipe_stdin)
+        .await
+        .map_err(spawn_error)?;
+    forward_output(exec_id.clone(), output_rx, event_tx.clone(), restart_tx.cloned());
+    Ok(serde_json::json!({ "exec_id": exec_id }))
+}
+
+/// Restart a REPL after it exited, if its policy calls for it.
+///
+/// Returns the notification to send: a restart notice, or an error once the
+/// process has crashed too often to be restarted again.
+async fn restart_crashed(
+    executor: &mut executor::Executor,
+    event_tx: &tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+    restart_tx: &tokio::sync::mpsc::Sender<(String, i32)>,
+    exec_id: String,
+    code: i32,
+) -> Option<rpc::StreamEvent> {
+    match executor.restart_crashed(&exec_id, code).await {
+        Ok(executor::Restart::Restarted { output, attempt }) => {
+            forward_output(exec_id.clone(), output, event_tx.clone(), Some(restart_tx.clone()));
+            Some(rpc::StreamEvent::ReplRestarted { exec_id, attempt })
+        }
+        Ok(executor::Restart::GaveUp { restarts, window }) => Some(rpc::StreamEvent::Error {
+            exec_id: Some(exec_id),
+            message: format!(
+                "Process crashed after {} restarts within {}s; not restarting again",
+                restarts,
+                window.as_secs()
+            ),
+        }),
+        Ok(executor::Restart::NotNeeded) => None,
+        Err(e) => Some(rpc::StreamEvent::Error {
+            exec_id: Some(exec_id),
+            message: format!("Failed to restart process: {:#}", e),
+        }),
+    }
+}
+
+/// Map a failed spawn to its error object, with dedicated codes for a
+/// missing or non-executable command and the offending command or path in
+/// `data`.
+fn spawn_error(err: anyhow::Error) -> rpc::RpcError {
+    match err.downcast_ref::<executor::SpawnError>() {
+        Some(failure) => spawn_failure(failure, format!("{:#}", err)),
+        None => rpc::RpcError::new(rpc::INVALID_PARAMS, format!("{:#}", err)),
+    }
+}
+
+/// The error object for one classified spawn failure.
+fn spawn_failure(failure: &executor::SpawnError, message: String) -> rpc::RpcError {
+    match failure {
+        executor::SpawnError::CommandNotFound { cmd, source } => rpc::RpcError::new(rpc::COMMAND_NOT_FOUND, message)
+            .with_data(serde_json::json!({ "cmd": cmd, "errno": source.raw_os_error() })),
+        executor::SpawnError::PermissionDenied { cmd, source } => rpc::RpcError::new(rpc::PERMISSION_DENIED, message)
+            .with_data(serde_json::json!({ "cmd": cmd, "errno": source.raw_os_error() })),
+        executor::SpawnError::InvalidCwd { cwd, .. } => {
+            rpc::RpcError::new(rpc::INVALID_PARAMS, message).with_data(serde_json::json!({ "cwd": cwd }))
+        }
+        executor::SpawnError::InvalidEnv { keys } => {
+            rpc::RpcError::new(rpc::INVALID_PARAMS, message).with_data(serde_json::json!({ "keys": keys }))
+        }
+        executor::SpawnError::UndefinedEnv { names } => {
+            rpc::RpcError::new(rpc::INVALID_PARAMS, message).with_data(serde_json::json!({ "names": names }))
+        }
+        executor::SpawnError::ArgumentListTooLong { cmd, args, bytes, source } => {
+            rpc::RpcError::new(rpc::INVALID_PARAMS, message).with_data(serde_json::json!({
+                "cmd": cmd,
+                "args": args,
+                "bytes": bytes,
+                "errno": source.raw_os_error(),
+            }))
+        }
+    }
+}
+
+/// Answer `exec.validate`: `{ ok: true }`, or an error whose data lists every
+/// check that failed. The error takes the code of the first failure.
+async fn validate_exec(
+    config: &executor::ExecConfig,
+    session_env: &std::collections::HashMap<String, String>,
+) -> Result<serde_json::Value, rpc::RpcError> {
+    let failures: Vec<rpc::RpcError> = executor::validate(config, session_env)
+        .await
+        .iter()
+        .map(|failure| spawn_failure(failure, failure.to_string()))
+        .collect();
+    let Some(first) = failures.first() else {
+        return rpc::to_result(rpc::ExecValidateResult { ok: true });
+    };
+    let message = failures.iter().map(|f| f.message.as_str()).collect::<Vec<_>>().join("; ");
+    Err(rpc::RpcError::new(first.code, message).with_data(serde_json::json!({ "failures": failures })))
+}
+
+/// Output buffered per stream by `exec.sync` when the caller sets no cap.
+const SYNC_OUTPUT_LIMIT: u64 = 1024 * 1024;
+
+/// Run a command for `exec.sync`, answering with its buffered output.
+///
+/// Output is capped (killing the command, as with `max_output_bytes`) so a
+/// chatty command cannot grow the response without bound.
+async fn start_sync_exec(
+    request: &rpc::Request,
+    agent_config: &config::AgentConfig,
+    executor: &mut executor::Executor,
+    response_tx: &tokio::sync::mpsc::Sender<rpc::Response>,
+) -> Result<(), rpc::RpcError> {
+    let params: rpc::ExecParams = request.parse_params()?;
+    let exec_id = params.spawn.exec_id.clone().unwrap_or_else(|| executor.next_exec_id());
+    let (mut config, pipe_stdin) = exec_params_config(params, agent_config).await?;
+    config.max_output_bytes = Some(config.max_output_bytes.unwrap_or(SYNC_OUTPUT_LIMIT));
+
+    let mut output_rx = executor
+        .exec(&exec_id, config, pipe_stdin)
+        .await
+        .map_err(spawn_error)?;
+
+    let id = request.id.clone();
+    let response_tx = response_tx.clone();
+    tokio::spawn(async move {
+        let mut result = rpc::ExecSyncResult {
+            exec_id,
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: -1,
+            truncated: false,
+        };
+        while let Some(output) = output_rx.recv().await {
+            match output {
+                executor::ProcessOutput::Stdout { chunk, .. } => result.stdout.push_str(&chunk),
+                executor::ProcessOutput::Stderr { chunk, .. } => result.stderr.push_str(&chunk),
+                executor::ProcessOutput::Exit { code, truncated, .. } => {
+                    result.exit_code = code;
+                    result.truncated = truncated;
+                }
+                _ => {}
+            }
+        }
+        // A notification still runs the command, but nobody awaits the result
+        if let Some(id) = id {
+            let _ = response_tx
+                .send(rpc::Response::from_result(id, rpc::to_result(result)))
+                .await;
+        }
+    });
+    Ok(())
+}
+
+/// An `exec.sequence` whose steps are still running.
+struct Sequence {
+    /// Request to answer once the sequence ends
+    id: Option<serde_json::Value>,
+    /// Steps not yet started, with whether each pipes its stdin
+    steps: VecDeque<(executor::ExecConfig, bool)>,
+    stop_on_failure: bool,
+    exit_codes: Vec<i32>,
+    failed_step: Option<usize>,
+}
+
+/// Start the first step of an `exec.sequence`.
+///
+/// Every step is checked before any runs, so a bad step fails the request
+/// instead of surfacing halfway through.
+async fn start_sequence(
+    request: &rpc::Request,
+    agent_config: &config::AgentConfig,
+    executor: &mut executor::Executor,
+    event_tx: &tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+    step_tx: &tokio::sync::mpsc::Sender<(String, i32)>,
+    sequences: &mut HashMap<String, Sequence>,
+) -> Result<(), rpc::RpcError> {
+    let params: rpc::ExecSequenceParams = request.parse_params()?;
+    let exec_id = params.exec_id.unwrap_or_else(|| executor.next_exec_id());
+    if sequences.contains_key(&exec_id) {
+        return Err(rpc::RpcError::new(
+            rpc::INVALID_PARAMS,
+            format!("Sequence '{}' is already running", exec_id),
+        ));
+    }
+    let mut steps = VecDeque::new();
+    for step in params.steps {
+        let (config, pipe_stdin) = exec_params_config(step, agent_config).await?;
+        if let Some(failure) = executor::validate(&config, executor.session_env()).await.first() {
+            return Err(spawn_failure(failure, format!("Step {}: {}", steps.len(), failure)));
+        }
+        steps.push_back((config, pipe_stdin));
+    }
+    let Some((first, pipe_stdin)) = steps.pop_front() else {
+        return Err(rpc::RpcError::new(rpc::INVALID_PARAMS, "Sequence has no steps"));
+    };
+
+    start_step(executor, event_tx, step_tx, &exec_id, 0, first, pipe_stdin).await?;
+    sequences.insert(
+        exec_id,
+        Sequence {
+            id: request.id.clone(),
+            steps,
+            stop_on_failure: params.stop_on_failure,
+            exit_codes: Vec::new(),
+            failed_step: None,
+        },
+    );
+    Ok(())
+}
+
+/// Announce a sequence step and spawn it under the sequence's exec id.
+async fn start_step(
+    executor: &mut executor::Executor,
+    event_tx: &tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+    step_tx: &tokio::sync::mpsc::Sender<(String, i32)>,
+    exec_id: &str,
+    step: usize,
+    mut config: executor::ExecConfig,
+    pipe_stdin: bool,
+) -> Result<(), rpc::RpcError> {
+    // Later steps were parsed with the request, but only wait on their predecessors
+    if step > 0 {
+        config.requested = None;
+    }
+    let _ = event_tx
+        .send(rpc::StreamEvent::SequenceStep {
+            exec_id: exec_id.to_string(),
+            step,
+            cmd: config.cmd.clone(),
+        })
+        .await;
+    start_process(executor, event_tx, Some(step_tx), exec_id.to_string(), config, pipe_stdin).await?;
+    Ok(())
+}
+
+/// Record a finished step and start the next one, if the sequence goes on.
+///
+/// Returns the request's response once the sequence has ended. A step that
+/// fails to spawn is reported as an error event and counts as exit code -1.
+async fn advance_sequence(
+    sequences: &mut HashMap<String, Sequence>,
+    executor: &mut executor::Executor,
+    event_tx: &tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+    step_tx: &tokio::sync::mpsc::Sender<(String, i32)>,
+    exec_id: String,
+    mut code: i32,
+) -> Option<rpc::Response> {
+    let sequence = sequences.get_mut(&exec_id)?;
+    loop {
+        let step = sequence.exit_codes.len();
+        sequence.exit_codes.push(code);
+        if code != 0 && sequence.failed_step.is_none() {
+            sequence.failed_step = Some(step);
+        }
+        if code != 0 && sequence.stop_on_failure {
+            break;
+        }
+        let Some((config, pipe_stdin)) = sequence.steps.pop_front() else {
+            break;
+        };
+        match start_step(executor, event_tx, step_tx, &exec_id, step + 1, config, pipe_stdin).await {
+            Ok(()) => return None,
+            Err(e) => {
+                let _ = event_tx
+                    .send(rpc::StreamEvent::Error {
+                        exec_id: Some(exec_id.clone()),
+                        message: e.message,
+                    })
+                    .await;
+                code = -1;
+            }
+        }
+    }
+
+    let sequence = sequences.remove(&exec_id)?;
+    let result = rpc::ExecSequenceResult {
+        exec_id,
+        exit_codes: sequence.exit_codes,
+        failed_step: sequence.failed_step,
+    };
+    // A notification still runs the steps, but nobody awaits the result
+    sequence.id.map(|id| rpc::Response::from_result(id, rpc::to_result(result)))
+}
+
+/// The notification fields of a file sent inline or uploaded.
+fn artifact_params(a: fs_watcher::Artifact) -> rpc::ArtifactParams {
+    rpc::ArtifactParams {
+        root: a.root,
+        path: a.path,
+        mime: a.mime,
+        data_base64: a.data_base64,
+        compression: a.compression.as_str().to_string(),
+        sha256: a.sha256,
+        size: a.size,
+        link_target: a.link_target,
+        url: a.url,
+    }
+}
+
+/// Convert a watcher event into its notification.
+fn artifact_event(event: fs_watcher::WatchEvent) -> rpc::StreamEvent {
+    match event {
+        fs_watcher::WatchEvent::Artifact(a) => rpc::StreamEvent::Artifact(artifact_params(a)),
+        fs_watcher::WatchEvent::ArtifactStart { root, path, mime, total_size } => {
+            rpc::StreamEvent::ArtifactStart { root, path, mime, total_size }
+        }
+        fs_watcher::WatchEvent::ArtifactChunk { path, seq, data_base64 } => {
+            rpc::StreamEvent::ArtifactChunk { path, seq, data_base64 }
+        }
+        fs_watcher::WatchEvent::ArtifactEnd { path, sha256 } => {
+            rpc::StreamEvent::ArtifactEnd { path, sha256 }
+        }
+        fs_watcher::WatchEvent::ArtifactRemoved { root, path } => {
+            rpc::StreamEvent::ArtifactRemoved { root, path }
+        }
+        fs_watcher::WatchEvent::ArtifactSkipped { root, path, size, reason } => rpc::StreamEvent::ArtifactSkipped {
+            root,
+            path,
+            size,
+            reason: reason.as_str().to_string(),
+        },
+        fs_watcher::WatchEvent::ArtifactLimitReached { limit } => rpc::StreamEvent::ArtifactLimitReached { limit },
+        fs_watcher::WatchEvent::Throttled { waited } => rpc::StreamEvent::Error {
+            exec_id: None,
+            message: format!(
+                "Artifact delivery throttled: events have not been drained for {}ms; \
+                 artifacts are delayed, not dropped",
+                waited.as_millis()
+            ),
+        },
+        fs_watcher::WatchEvent::WatcherError { message } => rpc::StreamEvent::Error {
+            exec_id: None,
+            message: format!("Artifact watcher error: {}", message),
+        },
+    }
+}
+
+/// Spawn a task that forwards process output to the event channel.
+///
+/// Every event, errors included, is tagged with `exec_id` so clients can
+/// keep separate output buffers per command. The executor always finishes
+/// with `ProcessOutput::Exit`, so the real exit code reaches the Control
+/// Plane after all output has been sent.
+fn forward_output(
+    exec_id: String,
+    mut output_rx: tokio::sync::mpsc::Receiver<executor::ProcessOutput>,
+    tx: tokio::sync::mpsc::Sender<rpc::StreamEvent>,
+    restart_tx: Option<tokio::sync::mpsc::Sender<(String, i32)>>,
+) {
+    tokio::spawn(async move {
+        let mut exit_code = None;
+        while let Some(output) = output_rx.recv().await {
+            if let executor::ProcessOutput::Exit { code, .. } = output {
+                exit_code = Some(code);
+            }
+            let event = output_event(exec_id.clone(), output);
+            if tx.send(event).await.is_err() {
+                break;
+            }
+        }
+        if let (Some(restart_tx), Some(code)) = (restart_tx, exit_code) {
+            let _ = restart_tx.send((exec_id, code)).await;
+        }
+    });
+}
+
+/// The notification for one piece of a command's output.
+fn output_event(exec_id: String, output: executor::ProcessOutput) -> rpc::StreamEvent {
+    match output {
+        executor::ProcessOutput::Stdout { seq, chunk } => rpc::StreamEvent::Stdout { exec_id, seq, chunk },
+        executor::ProcessOutput::Stderr { seq, chunk } => rpc::StreamEvent::Stderr { exec_id, seq, chunk },
+        executor::ProcessOutput::Diagnostic(diagnostic) => rpc::StreamEvent::Diagnostic {
+            exec_id,
+            file: diagnostic.file,
+            line: diagnostic.line,
+            col: diagnostic.col,
+            severity: diagnostic.severity,
+            message: diagnostic.message,
+        },
+        executor::ProcessOutput::Exit {
+            code,
+            signal,
+            stdout_bytes,
+            stderr_bytes,
+            truncated,
+            usage,
+            timing,
+        } => rpc::StreamEvent::Exit {
+            exec_id,
+            code,
+            signal,
+            stdout_bytes: Some(stdout_bytes),
+            stderr_bytes: Some(stderr_bytes),
+            truncated,
+            cpu_user_ms: usage.map(|u| u.cpu_user_ms),
+            cpu_sys_ms: usage.map(|u| u.cpu_sys_ms),
+            max_rss_kb: usage.map(|u| u.max_rss_kb),
+            spawn_latency_ms: Some(timing.spawn_latency.as_millis() as u64),
+            time_to_first_output_ms: timing.first_output.map(|d| d.as_millis() as u64),
+        },
+        executor::ProcessOutput::Timeout(limit) => rpc::StreamEvent::Timeout {
+            exec_id,
+            timeout_ms: limit.as_millis() as u64,
+        },
+        executor::ProcessOutput::LimitExceeded(limit) => rpc::StreamEvent::LimitExceeded {
+            exec_id,
+            resource: limit.resource().to_string(),
+            limit: limit.value(),
+        },
+        executor::ProcessOutput::Error(e) => rpc::StreamEvent::Error {
+            exec_id: Some(exec_id),
+            message: e,
+        },
+        executor::ProcessOutput::Queued(position) => rpc::StreamEvent::Queued { exec_id, position },
+        executor::ProcessOutput::Started => rpc::StreamEvent::Started { exec_id },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines, ReadHalf, WriteHalf};
+
+    type AgentLines = Lines<BufReader<ReadHalf<DuplexStream>>>;
+
+    /// Run an agent parsed from `args` over an in-memory pipe, returning the
+    /// client's ends of it and the agent's task.
+    fn spawn_test_agent(
+        args: &[&str],
+        plugins: plugins::Registry,
+    ) -> (AgentLines, WriteHalf<DuplexStream>, tokio::task::JoinHandle<Result<()>>) {
+        spawn_test_agent_with_logs(args, logs::LogBuffer::new(16), plugins)
+    }
+
+    fn spawn_test_agent_with_logs(
+        args: &[&str],
+        logs: logs::LogBuffer,
+        plugins: plugins::Registry,
+    ) -> (AgentLines, WriteHalf<DuplexStream>, tokio::task::JoinHandle<Result<()>>) {
+        let config = config::AgentConfig::parse(args.iter().map(|arg| arg.to_string()), |_| None).unwrap();
+        let (client, agent) = tokio::io::duplex(64 * 1024);
+        let (agent_read, agent_write) = tokio::io::split(agent);
+        let (client_read, client_write) = tokio::io::split(client);
+        let agent = tokio::spawn(run_agent(config, Instant::now(), logs, plugins, rpc::RpcHandler::new(agent_read, agent_write)));
+        (BufReader::new(client_read).lines(), client_write, agent)
+    }
+
+    /// The agent's next message, failing the test if none comes within 10s.
+    async fn next_message(lines: &mut AgentLines) -> serde_json::Value {
+        let line = tokio::time::timeout(Duration::from_secs(10), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .expect("agent output");
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_idle_agent_shuts_itself_down() {
+        let output = tempfile::tempdir().unwrap();
+        let args = [
+            "--output-dir",
+            output.path().to_str().unwrap(),
+            "--no-artifact-manifest",
+            "--idle-timeout-secs",
+            "1",
+        ];
+        let started = Instant::now();
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins::Registry::default());
+
+        // A command outlasting the timeout keeps the agent up until it exits
+        let request = r#"{"jsonrpc":"2.0","id":1,"method":"exec","params":{"cmd":"sleep","args":["2"]}}"#;
+        client_write.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+        assert_eq!(next_message(&mut lines).await["method"], "ready");
+        let mut exited_at = None;
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if line.contains(r#""method":"exit""#) {
+                exited_at = Some(started.elapsed());
+            }
+        }
+        let exited_at = exited_at.expect("exit event before shutdown");
+        assert!(exited_at >= Duration::from_secs(2), "{:?}", exited_at);
+
+        // Then one quiet timeout later the loop ends, with the input still open
+        tokio::time::timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+        let stopped_at = started.elapsed();
+        assert!(stopped_at >= exited_at + Duration::from_secs(1), "{:?} after exit at {:?}", stopped_at, exited_at);
+        drop(client_write);
+    }
+
+    #[tokio::test]
+    async fn test_commands_run_without_an_artifact_watcher() {
+        // Nothing, not even root, can create a directory under a regular file
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let output = dir.path().join("file/output");
+        let args = ["--output-dir", output.to_str().unwrap(), "--no-artifact-manifest"];
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins::Registry::default());
+
+        assert_eq!(next_message(&mut lines).await["method"], "ready");
+        let error = next_message(&mut lines).await;
+        assert_eq!(error["method"], "error");
+        assert!(error["params"]["message"].as_str().unwrap().contains("Artifact watcher unavailable"), "{}", error);
+
+        let request = r#"{"jsonrpc":"2.0","id":1,"method":"exec","params":{"cmd":"echo","args":["still working"]}}"#;
+        client_write.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+        let mut stdout = String::new();
+        let exit = loop {
+            let message = next_message(&mut lines).await;
+            match message["method"].as_str() {
+                Some("stdout") => stdout.push_str(message["params"]["chunk"].as_str().unwrap()),
+                Some("exit") => break message,
+                _ => {}
+            }
+        };
+        assert_eq!(stdout, "still working\n");
+        assert_eq!(exit["params"]["code"], 0);
+
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exit_waits_for_artifacts_written_just_before_it() {
+        let output = tempfile::tempdir().unwrap();
+        let args = ["--output-dir", output.path().to_str().unwrap(), "--no-artifact-manifest"];
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins::Registry::default());
+        assert_eq!(next_message(&mut lines).await["method"], "ready");
+
+        let script = "printf done > \"$1/result.txt\"; seq 3 > \"$1/metrics.csv\"";
+        let exec = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "exec",
+            "params": { "cmd": "sh", "args": ["-c", script, "sh", output.path()] },
+        });
+        client_write.write_all(format!("{}\n", exec).as_bytes()).await.unwrap();
+        let mut artifacts = Vec::new();
+        loop {
+            let message = next_message(&mut lines).await;
+            match message["method"].as_str() {
+                Some("artifact") => artifacts.push(message["params"]["path"].as_str().unwrap().to_string()),
+                Some("exit") => break,
+                _ => {}
+            }
+        }
+        artifacts.sort();
+        assert_eq!(artifacts, ["metrics.csv", "result.txt"]);
+
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), agent).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_plugin_methods_are_dispatched_to_the_registry() {
+        let output = tempfile::tempdir().unwrap();
+        let args = ["--output-dir", output.path().to_str().unwrap(), "--no-artifact-manifest"];
+        let mut plugins = plugins::Registry::default();
+        plugins.register("plugin.echo", plugins::Echo).unwrap();
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins);
+        assert_eq!(next_message(&mut lines).await["method"], "ready");
+
+        let say = r#"{"jsonrpc":"2.0","id":1,"method":"plugin.echo.say","params":{"hello":"world"}}"#;
+        client_write.write_all(format!("{}\n", say).as_bytes()).await.unwrap();
+        assert_eq!(next_message(&mut lines).await["result"], serde_json::json!({ "hello": "world" }));
+
+        let repeat = r#"{"jsonrpc":"2.0","id":2,"method":"plugin.echo.repeat","params":{"text":"hi","times":2}}"#;
+        client_write.write_all(format!("{}\n", repeat).as_bytes()).await.unwrap();
+        let mut messages = Vec::new();
+        while messages.last().is_none_or(|m: &serde_json::Value| m["method"] != "plugin.end") {
+            messages.push(next_message(&mut lines).await);
+        }
+        let response = messages.iter().find(|m| m["id"] == 2).expect("response");
+        assert_eq!(response["result"]["times"], 2);
+        let events: Vec<_> = messages
+            .iter()
+            .filter(|m| m["method"] == "plugin.event")
+            .map(|m| (m["params"]["request_id"].clone(), m["params"]["data"]["seq"].clone()))
+            .collect();
+        assert_eq!(events, [(2.into(), 0.into()), (2.into(), 1.into())]);
+
+        // Unclaimed names, and methods a plugin does not know, are not found
+        for (id, method) in [(3, "plugin.other.say"), (4, "plugin.echo.shout")] {
+            let request = serde_json::json!({ "jsonrpc": "2.0", "id": id, "method": method });
+            client_write.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+            assert_eq!(next_message(&mut lines).await["error"]["code"], rpc::METHOD_NOT_FOUND);
+        }
+
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), agent).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_logs_tail_returns_recent_agent_logs() {
+        let output = tempfile::tempdir().unwrap();
+        let args = ["--output-dir", output.path().to_str().unwrap(), "--no-artifact-manifest"];
+        let logs = logs::LogBuffer::new(16);
+        let subscriber = tracing_subscriber::fmt().json().with_writer(logs.clone()).finish();
+        tracing::subscriber::with_default(subscriber, || {
+            info!(exec_id = "exec-1", "Process started");
+            error!(exec_id = "exec-1", error = "No such file or directory", "Failed to spawn process");
+        });
+        let (mut lines, mut client_write, agent) = spawn_test_agent_with_logs(&args, logs, plugins::Registry::default());
+        assert_eq!(next_message(&mut lines).await["method"], "ready");
+
+        let tail = r#"{"jsonrpc":"2.0","id":1,"method":"logs.tail","params":{"max_lines":1}}"#;
+        client_write.write_all(format!("{}\n", tail).as_bytes()).await.unwrap();
+        let response = next_message(&mut lines).await;
+        let records = response["result"]["records"].as_array().unwrap();
+        assert_eq!(records.len(), 1, "{}", response);
+        assert_eq!(records[0]["level"], "ERROR");
+        assert_eq!(records[0]["fields"]["error"], "No such file or directory");
+        assert_eq!(response["result"]["dropped"], 0);
+
+        let tail = r#"{"jsonrpc":"2.0","id":2,"method":"logs.tail"}"#;
+        client_write.write_all(format!("{}\n", tail).as_bytes()).await.unwrap();
+        assert_eq!(next_message(&mut lines).await["result"]["records"].as_array().unwrap().len(), 2);
+
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), agent).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_input_to_a_process_that_never_reads_times_out() {
+        let output = tempfile::tempdir().unwrap();
+        let args = ["--output-dir", output.path().to_str().unwrap(), "--no-artifact-manifest", "--stdin-timeout-ms", "500"];
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins::Registry::default());
+        assert_eq!(next_message(&mut lines).await["method"], "ready");
+
+        let start = r#"{"jsonrpc":"2.0","id":1,"method":"repl.start","params":{"cmd":"sleep","args":["30"],"exec_id":"stuck"}}"#;
+        client_write.write_all(format!("{}\n", start).as_bytes()).await.unwrap();
+        let started = loop {
+            let message = next_message(&mut lines).await;
+            if message["id"] == 1 {
+                break message;
+            }
+        };
+        assert!(started.get("error").is_none(), "{}", started);
+
+        // Far more than a pipe buffers, so the write can never finish
+        let input = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "repl.input",
+            "params": { "exec_id": "stuck", "data": "x".repeat(256 * 1024) },
+        });
+        client_write.write_all(format!("{}\n", input).as_bytes()).await.unwrap();
+        let sent = Instant::now();
+        let ping = r#"{"jsonrpc":"2.0","id":3,"method":"ping"}"#;
+        client_write.write_all(format!("{}\n", ping).as_bytes()).await.unwrap();
+
+        let mut responses = Vec::new();
+        while responses.len() < 2 {
+            let message = next_message(&mut lines).await;
+            if !message["id"].is_null() {
+                responses.push(message);
+            }
+        }
+        // The loop answered the ping while the write was still stuck
+        assert_eq!(responses[0]["id"], 3);
+        assert_eq!(responses[0]["result"]["pong"], true);
+        assert_eq!(responses[1]["id"], 2);
+        assert_eq!(responses[1]["error"]["code"], rpc::TIMEOUT, "{}", responses[1]);
+        assert_eq!(responses[1]["error"]["data"]["timeout_ms"], 500);
+        assert!(responses[1]["error"]["data"]["bytes_written"].as_u64().unwrap() < 256 * 1024);
+        assert!(sent.elapsed() >= Duration::from_millis(500));
+
+        let kill = r#"{"jsonrpc":"2.0","id":4,"method":"exec.kill","params":{"exec_id":"stuck"}}"#;
+        client_write.write_all(format!("{}\n", kill).as_bytes()).await.unwrap();
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), agent).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batched_artifacts_arrive_as_one_event() {
+        let output = tempfile::tempdir().unwrap();
+        let args = ["--output-dir", output.path().to_str().unwrap(), "--no-artifact-manifest"];
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins::Registry::default());
+        assert_eq!(next_message(&mut lines).await["method"], "ready");
+
+        let begin = r#"{"jsonrpc":"2.0","id":1,"method":"artifact.batch_begin","params":{"batch_id":"report"}}"#;
+        client_write.write_all(format!("{}\n", begin).as_bytes()).await.unwrap();
+        assert_eq!(next_message(&mut lines).await["result"]["batch_id"], "report");
+        for name in ["report.html", "chart-1.png", "chart-2.png"] {
+            std::fs::write(output.path().join(name), name).unwrap();
+        }
+        // Ended before any of them has settled, so none has been read yet
+        let end = r#"{"jsonrpc":"2.0","id":2,"method":"artifact.batch_end"}"#;
+        client_write.write_all(format!("{}\n", end).as_bytes()).await.unwrap();
+
+        let batch = next_message(&mut lines).await;
+        assert_eq!(batch["method"], "artifact.batch", "{}", batch);
+        assert_eq!(batch["params"]["batch_id"], "report");
+        let mut paths: Vec<&str> = batch["params"]["artifacts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|artifact| artifact["path"].as_str().unwrap())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["chart-1.png", "chart-2.png", "report.html"]);
+        let response = next_message(&mut lines).await;
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["result"]["files"], 3);
+
+        // Ending again, with nothing open, is refused
+        client_write.write_all(format!("{}\n", end).as_bytes()).await.unwrap();
+        assert_eq!(next_message(&mut lines).await["error"]["message"], "No artifact batch is open");
+
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribed_streams_are_drained_but_not_sent() {
+        let output = tempfile::tempdir().unwrap();
+        let args = ["--output-dir", output.path().to_str().unwrap(), "--no-artifact-manifest"];
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins::Registry::default());
+
+        // Far more stdout than a pipe holds, which would block an undrained writer
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "exec",
+            "params": {
+                "cmd": "sh",
+                "args": ["-c", "head -c 1000000 /dev/zero; echo failed >&2"],
+                "streams": ["stderr"],
+            },
+        });
+        client_write.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+        let mut stderr = String::new();
+        let exit = loop {
+            let message = next_message(&mut lines).await;
+            match message["method"].as_str() {
+                Some("stdout") => panic!("unsubscribed stdout was sent: {}", message),
+                Some("stderr") => stderr.push_str(message["params"]["chunk"].as_str().unwrap()),
+                Some("exit") => break message,
+                _ => {}
+            }
+        };
+        assert_eq!(stderr, "failed\n");
+        assert_eq!(exit["params"]["code"], 0);
+        assert_eq!(exit["params"]["stdout_bytes"], 1_000_000);
+
+        // End of input shuts the agent down
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rustc_diagnostics_follow_their_stderr() {
+        let output = tempfile::tempdir().unwrap();
+        let args = ["--output-dir", output.path().to_str().unwrap(), "--no-artifact-manifest"];
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins::Registry::default());
+
+        let diagnostic = r#"{"$message_type":"diagnostic","message":"unused variable: `x`","level":"warning","spans":[{"file_name":"src/lib.rs","line_start":3,"column_start":9,"is_primary":true}],"children":[]}"#;
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "exec",
+            "params": {
+                "cmd": "sh",
+                "args": ["-c", "printf '%s\\n' \"$1\" >&2", "sh", diagnostic],
+                "diagnostics_format": "rustc",
+            },
+        });
+        client_write.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+        let mut events = Vec::new();
+        loop {
+            let message = next_message(&mut lines).await;
+            match message["method"].as_str() {
+                Some("exit") => break,
+                Some(method @ ("stderr" | "diagnostic")) => events.push((method.to_string(), message["params"].clone())),
+                _ => {}
+            }
+        }
+        // Raw stderr still flows, and comes first
+        assert_eq!(events.len(), 2, "{:?}", events);
+        assert_eq!(events[0].0, "stderr");
+        assert_eq!(events[0].1["chunk"], format!("{}\n", diagnostic));
+        assert_eq!(events[1].0, "diagnostic");
+        let parsed = &events[1].1;
+        assert_eq!(parsed["file"], "src/lib.rs");
+        assert_eq!((parsed["line"].as_u64(), parsed["col"].as_u64()), (Some(3), Some(9)));
+        assert_eq!(parsed["severity"], "warning");
+        assert_eq!(parsed["message"], "unused variable: `x`");
+
+        let request = r#"{"jsonrpc":"2.0","id":2,"method":"exec","params":{"cmd":"true","diagnostics_format":"msvc"}}"#;
+        client_write.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+        let response = loop {
+            let message = next_message(&mut lines).await;
+            if message["id"] == 2 {
+                break message;
+            }
+        };
+        assert_eq!(response["error"]["code"], rpc::INVALID_PARAMS, "{}", response);
+
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exec_with_piped_stdin_takes_input() {
+        let output = tempfile::tempdir().unwrap();
+        let args = ["--output-dir", output.path().to_str().unwrap(), "--no-artifact-manifest"];
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins::Registry::default());
+
+        let requests = [
+            r#"{"jsonrpc":"2.0","id":1,"method":"exec","params":{"cmd":"cat","exec_id":"cat","stdin":"pipe"}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"repl.input","params":{"exec_id":"cat","data":"secret\n"}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"repl.eof","params":{"exec_id":"cat"}}"#,
+        ];
+        for request in requests {
+            client_write.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+        }
+        let mut stdout = String::new();
+        let mut responses = HashMap::new();
+        let exit = loop {
+            let message = next_message(&mut lines).await;
+            match message["method"].as_str() {
+                Some("stdout") => stdout.push_str(message["params"]["chunk"].as_str().unwrap()),
+                Some("exit") => break message,
+                Some(_) => {}
+                None => {
+                    responses.insert(message["id"].as_u64().unwrap(), message);
+                }
+            }
+        };
+        for id in 1..=3 {
+            assert!(responses[&id].get("error").is_none(), "{}", responses[&id]);
+        }
+        assert_eq!(responses[&2]["result"]["bytes_written"], 7);
+        // cat saw the end of its input, so it exited on its own
+        assert_eq!(stdout, "secret\n");
+        assert_eq!(exit["params"]["code"], 0);
+
+        let both = r#"{"jsonrpc":"2.0","id":4,"method":"exec","params":{"cmd":"cat","stdin":"pipe","stdin_file":"in.txt"}}"#;
+        client_write.write_all(format!("{}\n", both).as_bytes()).await.unwrap();
+        let response = loop {
+            let message = next_message(&mut lines).await;
+            if message["id"] == 4 {
+                break message;
+            }
+        };
+        assert_eq!(response["error"]["code"], rpc::INVALID_PARAMS, "{}", response);
+
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sequence_stops_at_its_first_failing_step() {
+        let output = tempfile::tempdir().unwrap();
+        let args = ["--output-dir", output.path().to_str().unwrap(), "--no-artifact-manifest"];
+        let (mut lines, mut client_write, agent) = spawn_test_agent(&args, plugins::Registry::default());
+        assert_eq!(next_message(&mut lines).await["method"], "ready");
+
+        let sequence = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "exec.sequence",
+            "params": {
+                "exec_id": "build",
+                "steps": [{ "cmd": "true" }, { "cmd": "false" }, { "cmd": "echo", "args": ["skipped"] }],
+                "stop_on_failure": true,
+            },
+        });
+        client_write.write_all(format!("{}\n", sequence).as_bytes()).await.unwrap();
+        let mut steps = Vec::new();
+        let response = loop {
+            let message = next_message(&mut lines).await;
+            match message["method"].as_str() {
+                Some("sequence.step") => steps.push((message["params"]["step"].clone(), message["params"]["cmd"].clone())),
+                Some("stdout") => panic!("a step after the failure ran: {}", message),
+                Some(_) => {}
+                None => break message,
+            }
+        };
+        assert_eq!(steps, [(0.into(), "true".into()), (1.into(), "false".into())]);
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["exec_id"], "build");
+        assert_eq!(response["result"]["exit_codes"], serde_json::json!([0, 1]));
+        assert_eq!(response["result"]["failed_step"], 1);
+
+        // A step that could never start fails the request before any runs
+        let sequence = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "exec.sequence",
+            "params": {
+                "steps": [{ "cmd": "true" }, { "cmd": "no-such-command-boxed" }],
+                "stop_on_failure": false,
+            },
+        });
+        client_write.write_all(format!("{}\n", sequence).as_bytes()).await.unwrap();
+        let response = next_message(&mut lines).await;
+        assert_eq!(response["error"]["code"], rpc::COMMAND_NOT_FOUND, "{}", response);
+        assert!(response["error"]["message"].as_str().unwrap().starts_with("Step 1: "), "{}", response);
+
+        client_write.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), agent).await.unwrap().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+//! Record of streamed artifacts that outlives the agent process.
+//!
+//! When a new agent session attaches to the same rootfs, the startup scan
+//! would otherwise stream every file in the output directories again. The
+//! manifest maps each streamed file to the SHA-256 of what was sent, so a
+//! file whose contents still hash the same is skipped, once, on its first
+//! sighting after startup. It is stored as a JSON object keyed by absolute
+//! path and rewritten after every change.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use tracing::warn;
+
+use crate::sha256::Sha256;
+
+/// Hashes of streamed artifacts, persisted at `path`.
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    /// Loaded from a previous session and not yet checked against the file
+    previous: HashMap<String, String>,
+    /// Streamed, or confirmed unchanged, by this session
+    current: HashMap<String, String>,
+}
+
+impl Manifest {
+    /// Load the manifest at `path`.
+    ///
+    /// A missing or unreadable manifest starts empty, so at worst files are
+    /// streamed again.
+    pub async fn load(path: &Path) -> Self {
+        let previous = match tokio::fs::read(path).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!(path = %path.display(), error = %e, "Ignoring corrupt artifact manifest");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            path: path.to_path_buf(),
+            previous,
+            current: HashMap::new(),
+        }
+    }
+
+    /// The hash recorded for `file` by a previous session, if not yet taken.
+    pub fn take_previous(&mut self, file: &Path) -> Option<String> {
+        self.previous.remove(&*file.to_string_lossy())
+    }
+
+    /// Note that `file` was sent with contents hashing to `sha256`.
+    pub fn record(&mut self, file: &Path, sha256: String) {
+        self.current.insert(file.to_string_lossy().into_owned(), sha256);
+        self.save();
+    }
+
+    /// Drop `file` from the manifest after it was removed.
+    pub fn forget(&mut self, file: &Path) {
+        if self.current.remove(&*file.to_string_lossy()).is_some() {
+            self.save();
+        }
+    }
+
+    /// Persist the current entries; failures are logged, since streaming
+    /// itself still works without a manifest.
+    fn save(&self) {
+        if let Err(e) = self.write() {
+            warn!(path = %self.path.display(), error = %format!("{:#}", e), "Failed to write artifact manifest");
+        }
+    }
+
+    /// Write through a temporary file, so a crash never leaves half a manifest.
+    fn write(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        std::fs::write(&temp, serde_json::to_vec(&self.current)?)
+            .with_context(|| format!("Failed to write {}", PathBuf::from(&temp).display()))?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// SHA-256 of a file's contents, read a piece at a time.
+pub async fn hash_file(path: &Path) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(hasher.finalize_hex());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
@@ -0,0 +1,156 @@
+//! Methods added to the agent without touching its dispatch.
+//!
+//! A [`MethodHandler`] registered at startup under a prefix such as
+//! `plugin.echo` answers every method in that namespace (`plugin.echo.say`,
+//! `plugin.echo.repeat`, ...). Built-in methods are matched first, and
+//! prefixes must start with `plugin.` so a plugin can never shadow one, now
+//! or after a built-in is added.
+
+use crate::rpc::{self, RpcError};
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt};
+use std::sync::Arc;
+
+/// Namespace every plugin prefix lives under
+const NAMESPACE: &str = "plugin.";
+
+/// What a plugin method answers with.
+pub enum Reply {
+    /// A plain result
+    Value(serde_json::Value),
+    /// A result, then each item of `events` as a `plugin.event`
+    /// notification and finally `plugin.end`
+    Stream {
+        result: serde_json::Value,
+        events: BoxStream<'static, serde_json::Value>,
+    },
+}
+
+/// Handles the methods under one prefix.
+///
+/// Calls run in the background, so a slow one never holds up other
+/// requests.
+pub trait MethodHandler: Send + Sync {
+    /// Answer `method`, the full name as requested, given its params
+    /// (`null` when the request had none).
+    fn call(&self, method: &str, params: serde_json::Value) -> BoxFuture<'static, Result<Reply, RpcError>>;
+}
+
+/// The registered plugins, by prefix.
+#[derive(Clone, Default)]
+pub struct Registry {
+    handlers: Vec<(String, Arc<dyn MethodHandler>)>,
+}
+
+impl Registry {
+    /// Route methods named `prefix` or starting with `prefix.` to `handler`.
+    ///
+    /// Fails if the prefix is outside the `plugin.` namespace or already
+    /// taken. Where prefixes nest, the longer one wins.
+    pub fn register(&mut self, prefix: &str, handler: impl MethodHandler + 'static) -> Result<()> {
+        let name = prefix.strip_prefix(NAMESPACE).unwrap_or_default();
+        if name.is_empty() || name.split('.').any(str::is_empty) {
+            anyhow::bail!("Invalid plugin prefix '{}': expected plugin.NAME", prefix);
+        }
+        if self.handlers.iter().any(|(taken, _)| taken == prefix) {
+            anyhow::bail!("Plugin prefix '{}' is already registered", prefix);
+        }
+        self.handlers.push((prefix.to_string(), Arc::new(handler)));
+        Ok(())
+    }
+
+    /// The handler for a method, if a plugin claims it.
+    pub fn find(&self, method: &str) -> Option<Arc<dyn MethodHandler>> {
+        self.handlers
+            .iter()
+            .filter(|(prefix, _)| {
+                method
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, handler)| handler.clone())
+    }
+}
+
+/// Example plugin, registered as `plugin.echo`: `say` answers with its
+/// params, and `repeat { text, times }` streams `text` back `times` times.
+pub struct Echo;
+
+#[derive(serde::Deserialize)]
+struct RepeatParams {
+    text: String,
+    times: usize,
+}
+
+impl MethodHandler for Echo {
+    fn call(&self, method: &str, params: serde_json::Value) -> BoxFuture<'static, Result<Reply, RpcError>> {
+        let reply = match method.rsplit('.').next() {
+            Some("say") => Ok(Reply::Value(params)),
+            Some("repeat") => match serde_json::from_value::<RepeatParams>(params) {
+                Ok(repeat) => Ok(Reply::Stream {
+                    result: serde_json::json!({ "times": repeat.times }),
+                    events: futures::stream::iter(0..repeat.times)
+                        .map(move |seq| serde_json::json!({ "seq": seq, "text": repeat.text }))
+                        .boxed(),
+                }),
+                Err(e) => Err(RpcError::new(rpc::INVALID_PARAMS, e.to_string())),
+            },
+            _ => Err(RpcError::new(rpc::METHOD_NOT_FOUND, "Method not found")),
+        };
+        async move { reply }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_methods_route_to_the_longest_prefix() {
+        struct Named(&'static str);
+        impl MethodHandler for Named {
+            fn call(&self, _: &str, _: serde_json::Value) -> BoxFuture<'static, Result<Reply, RpcError>> {
+                let name = self.0;
+                async move { Ok(Reply::Value(name.into())) }.boxed()
+            }
+        }
+
+        let mut registry = Registry::default();
+        registry.register("plugin.db", Named("db")).unwrap();
+        registry.register("plugin.db.admin", Named("admin")).unwrap();
+        assert!(registry.register("plugin.db", Named("again")).is_err());
+        for prefix in ["exec", "plugin.", "plugin", "plugin..x", "fs.plugin.x"] {
+            assert!(registry.register(prefix, Named("bad")).is_err(), "{}", prefix);
+        }
+
+        let answer = |method: &str| {
+            let handler = registry.find(method)?;
+            match handler.call(method, serde_json::Value::Null).now_or_never()?.ok()? {
+                Reply::Value(value) => value.as_str().map(str::to_string),
+                Reply::Stream { .. } => None,
+            }
+        };
+        assert_eq!(answer("plugin.db.query").as_deref(), Some("db"));
+        assert_eq!(answer("plugin.db").as_deref(), Some("db"));
+        assert_eq!(answer("plugin.db.admin.vacuum").as_deref(), Some("admin"));
+        assert_eq!(answer("plugin.dbx.query"), None);
+        assert_eq!(answer("exec"), None);
+    }
+
+    #[tokio::test]
+    async fn test_echo_streams_repeats() {
+        let reply = Echo.call("plugin.echo.repeat", serde_json::json!({ "text": "hi", "times": 2 })).await;
+        let Ok(Reply::Stream { result, events }) = reply else {
+            panic!("expected a stream");
+        };
+        assert_eq!(result["times"], 2);
+        let events: Vec<_> = events.collect().await;
+        assert_eq!(events, [serde_json::json!({ "seq": 0, "text": "hi" }), serde_json::json!({ "seq": 1, "text": "hi" })]);
+
+        let missing = Echo.call("plugin.echo.shout", serde_json::Value::Null).await;
+        assert!(matches!(missing, Err(e) if e.code == rpc::METHOD_NOT_FOUND));
+    }
+}
@@ -0,0 +1,85 @@
+//! Point-in-time resource readings for a process, from `/proc`.
+//!
+//! Only Linux has the files read here. Elsewhere [`SUPPORTED`] is false and
+//! every reading comes back empty, so callers can refuse up front instead of
+//! streaming samples with nothing in them.
+
+use std::path::PathBuf;
+
+/// Whether this platform exposes the files samples are read from
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// What one process was using at one moment. A field is `None` when its
+/// file could not be read, e.g. because the process just exited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sample {
+    /// Resident set size in KiB (`VmRSS`)
+    pub rss_kb: Option<u64>,
+    /// Open file descriptors
+    pub fd_count: Option<u64>,
+    /// Threads in the process (`Threads`)
+    pub threads: Option<u64>,
+}
+
+impl Sample {
+    /// Whether nothing could be read, meaning the process is gone.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Read the current usage of `pid`.
+pub async fn sample(pid: u32) -> Sample {
+    let dir = PathBuf::from(format!("/proc/{}", pid));
+    let status = tokio::fs::read_to_string(dir.join("status")).await.ok();
+    let field = |name: &str| status.as_deref().and_then(|status| status_field(status, name));
+    let (rss_kb, threads) = (field("VmRSS"), field("Threads"));
+    Sample {
+        rss_kb,
+        fd_count: count_entries(dir.join("fd")).await,
+        threads,
+    }
+}
+
+/// The leading number of a `Name:   value [unit]` line in a status file.
+fn status_field(status: &str, name: &str) -> Option<u64> {
+    status.lines().find_map(|line| {
+        let value = line.strip_prefix(name)?.strip_prefix(':')?;
+        value.split_whitespace().next()?.parse().ok()
+    })
+}
+
+async fn count_entries(dir: PathBuf) -> Option<u64> {
+    let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+    let mut count = 0;
+    while let Ok(Some(_)) = entries.next_entry().await {
+        count += 1;
+    }
+    Some(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_fields_are_parsed() {
+        let status = "Name:\tpython3\nVmRSS:\t  20480 kB\nThreads:\t4\n";
+        assert_eq!(status_field(status, "VmRSS"), Some(20480));
+        assert_eq!(status_field(status, "Threads"), Some(4));
+        // Kernel threads have no memory lines at all
+        assert_eq!(status_field(status, "VmSwap"), None);
+    }
+
+    #[tokio::test]
+    async fn test_samples_this_process() {
+        if !SUPPORTED {
+            return;
+        }
+        let sample = sample(std::process::id()).await;
+        assert!(sample.rss_kb.is_some_and(|kb| kb > 0), "{:?}", sample);
+        assert!(sample.fd_count.is_some_and(|fds| fds >= 3), "{:?}", sample);
+        assert!(sample.threads.is_some_and(|threads| threads >= 1), "{:?}", sample);
+        assert!(super::sample(u32::MAX).await.is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+//! Pseudo-terminal allocation for interactive commands.
+//!
+//! Programs that check `isatty()` (shells, editors, REPLs, `top`) only behave
+//! interactively when attached to a terminal, so `repl.start` can run them on
+//! the slave side of a PTY while the agent reads and writes the master.
+
+use anyhow::Result;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/// Terminal dimensions in character cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl Default for WindowSize {
+    fn default() -> Self {
+        Self { rows: 24, cols: 80 }
+    }
+}
+
+impl WindowSize {
+    fn to_winsize(self) -> libc::winsize {
+        libc::winsize {
+            ws_row: self.rows,
+            ws_col: self.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+/// Both ends of a freshly opened pseudo-terminal.
+pub struct Pty {
+    /// Side kept by the agent
+    pub master: OwnedFd,
+    /// Side handed to the child as its stdio
+    pub slave: OwnedFd,
+}
+
+impl Pty {
+    /// Open a new PTY with the given initial size.
+    pub fn open(size: WindowSize) -> Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+        let winsize = size.to_winsize();
+        // SAFETY: openpty writes two fds into the out-params and only reads winsize
+        let rc = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &winsize,
+            )
+        };
+        if rc != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // SAFETY: openpty succeeded, so both fds are open and owned by us
+        let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+        // Keep the fds out of unrelated children; dup2 onto stdio clears the flag
+        set_cloexec(&master)?;
+        set_cloexec(&slave)?;
+
+        Ok(Self { master, slave })
+    }
+}
+
+/// Change the window size of the terminal behind `master`.
+///
+/// The kernel delivers SIGWINCH to the terminal's foreground process group.
+pub fn resize(master: &OwnedFd, size: WindowSize) -> Result<()> {
+    let winsize = size.to_winsize();
+    // SAFETY: TIOCSWINSZ only reads the winsize struct
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Make the calling process a session leader controlled by its stdin terminal.
+///
+/// Must only be called in the forked child, after stdio has been redirected
+/// to the PTY slave.
+pub fn attach_controlling_terminal() -> std::io::Result<()> {
+    // SAFETY: setsid and ioctl are async-signal-safe and take no pointers
+    unsafe {
+        if libc::setsid() < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn set_cloexec(fd: &OwnedFd) -> Result<()> {
+    // SAFETY: F_SETFD on an fd we own
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
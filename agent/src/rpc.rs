@@ -4,9 +4,11 @@
 //! and the Agent, using JSON-RPC 2.0 over raw streams.
 
 use anyhow::{Context, Result};
+use base64::Engine;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
 
 /// JSON-RPC 2.0 request structure.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,6 +22,51 @@ pub struct Request {
 }
 
 impl Request {
+    /// Parse and validate a raw message as a JSON-RPC 2.0 request.
+    pub fn parse(message: &[u8]) -> Result<Self, RejectedMessage> {
+        let value: serde_json::Value =
+            serde_json::from_slice(message).map_err(|e| RejectedMessage {
+                id: None,
+                error: RpcError::new(PARSE_ERROR, format!("Parse error: {}", e)),
+            })?;
+
+        let id = value.get("id").cloned().filter(|id| !id.is_null());
+        let invalid = |reason: &str| RejectedMessage {
+            id: id.clone(),
+            error: RpcError::new(INVALID_REQUEST, format!("Invalid Request: {}", reason)),
+        };
+
+        let Some(object) = value.as_object() else {
+            return Err(invalid("expected an object"));
+        };
+        if object.get("jsonrpc").and_then(|v| v.as_str()) != Some("2.0") {
+            return Err(invalid("jsonrpc must be \"2.0\""));
+        }
+        if !object.get("method").is_some_and(|m| m.is_string()) {
+            return Err(invalid("method must be a string"));
+        }
+        match object.get("params") {
+            None | Some(serde_json::Value::Object(_)) | Some(serde_json::Value::Array(_)) => {}
+            Some(_) => return Err(invalid("params must be an object or array")),
+        }
+
+        serde_json::from_value(value).map_err(|e| invalid(&e.to_string()))
+    }
+
+    /// Deserialize the request params, treating absent params as an empty object.
+    pub fn parse_params<T: DeserializeOwned>(&self) -> Result<T, RpcError> {
+        let params = match &self.params {
+            serde_json::Value::Null => serde_json::json!({}),
+            params => params.clone(),
+        };
+        serde_json::from_value(params).map_err(|e| {
+            RpcError::new(INVALID_PARAMS, e.to_string()).with_data(serde_json::json!({
+                "method": self.method,
+                "line": e.line(),
+                "column": e.column(),
+            }))
+        })
+    }
 
     /// Create a notification (no response expected).
     pub fn notification(method: &str, params: serde_json::Value) -> Self {
@@ -59,14 +106,31 @@ impl Response {
         Self {
             jsonrpc: "2.0".to_string(),
             result: None,
-            error: Some(RpcError {
-                code,
-                message: message.to_string(),
-                data: None,
-            }),
+            error: Some(RpcError::new(code, message)),
             id,
         }
     }
+
+    /// Create an error response carrying structured context in `data`.
+    pub fn error_with_data(id: serde_json::Value, code: i32, message: &str, data: serde_json::Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            result: None,
+            error: Some(RpcError::new(code, message).with_data(data)),
+            id,
+        }
+    }
+
+    /// Create a success or error response from a handler result.
+    ///
+    /// Errors with context, like spawn failures or invalid params, keep it.
+    pub fn from_result(id: serde_json::Value, result: Result<serde_json::Value, RpcError>) -> Self {
+        match result {
+            Ok(value) => Self::success(id, value),
+            Err(RpcError { code, message, data: Some(data) }) => Self::error_with_data(id, code, &message, data),
+            Err(RpcError { code, message, data: None }) => Self::error(id, code, &message),
+        }
+    }
 }
 
 /// JSON-RPC 2.0 error object.
@@ -78,9 +142,118 @@ pub struct RpcError {
     pub data: Option<serde_json::Value>,
 }
 
+impl RpcError {
+    /// Create an error object without additional data.
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    /// Attach structured context, such as the offending path or errno.
+    pub fn with_data(mut self, data: serde_json::Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+}
+
+/// A message that was read but could not be accepted as a request.
+#[derive(Debug, thiserror::Error)]
+#[error("{}", error.message)]
+pub struct RejectedMessage {
+    /// Id of the offending request, when one could be recovered
+    pub id: Option<serde_json::Value>,
+    pub error: RpcError,
+}
+
+impl RejectedMessage {
+    /// The error response owed to the client, if it can be addressed.
+    ///
+    /// Parse errors are always answered with a null id, as the spec requires;
+    /// invalid requests only when they carried an id.
+    pub fn response(&self) -> Option<Response> {
+        let id = match (&self.id, self.error.code) {
+            (Some(id), _) => id.clone(),
+            (None, PARSE_ERROR) => serde_json::Value::Null,
+            (None, _) => return None,
+        };
+        Some(Response::error(id, self.error.code, &self.error.message))
+    }
+}
+
+/// Version of the agent protocol, bumped whenever methods or events change
+/// incompatibly. Clients can gate optional features on the negotiated value.
+pub const PROTOCOL_VERSION: u32 = 1;
+
 // Standard JSON-RPC 2.0 error codes
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
 pub const INVALID_PARAMS: i32 = -32602;
 pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INTERNAL_ERROR: i32 = -32603;
+
+// Agent-specific server errors
+pub const COMMAND_NOT_FOUND: i32 = -32010;
+pub const PERMISSION_DENIED: i32 = -32011;
+pub const TIMEOUT: i32 = -32012;
+
+/// How messages are delimited on the wire.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Framing {
+    /// One JSON message per line
+    #[default]
+    Line,
+    /// LSP-style `Content-Length: N\r\n\r\n` header before each message
+    ContentLength,
+}
+
+impl std::str::FromStr for Framing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "line" => Ok(Framing::Line),
+            "content-length" => Ok(Framing::ContentLength),
+            _ => anyhow::bail!("Unknown framing '{}', expected 'line' or 'content-length'", s),
+        }
+    }
+}
+
+/// Largest body accepted in Content-Length framing.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Serialize a handler's typed result into a JSON-RPC result value.
+pub fn to_result<T: Serialize>(value: T) -> Result<serde_json::Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+/// One file reported by the artifact watcher.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactParams {
+    /// The watched directory the file is in
+    pub root: String,
+    /// Relative to the watched directory; bytes of the file name that
+    /// are not UTF-8, and `%`, are percent-encoded
+    pub path: String,
+    pub mime: String,
+    pub data_base64: String,
+    /// "gzip" when `data_base64` decodes to a gzip stream, else "none"
+    pub compression: String,
+    /// Lowercase hex SHA-256 of the decoded contents
+    pub sha256: String,
+    /// Decoded size in bytes
+    pub size: u64,
+    /// Set when the artifact is a symlink, to its target in the watch dir
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+    /// Set when the file was uploaded instead of inlined; `data_base64`
+    /// is then empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
 
 /// Streaming event from Agent to Control Plane.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -88,59 +261,898 @@ pub const METHOD_NOT_FOUND: i32 = -32601;
 pub enum StreamEvent {
     /// Standard output chunk
     #[serde(rename = "stdout")]
-    Stdout { chunk: String },
+    Stdout {
+        exec_id: String,
+        /// Position of this chunk in the command's output, shared with the
+        /// other stream; what `replay` takes as `since_seq`
+        #[serde(default)]
+        seq: u64,
+        chunk: String,
+    },
     
     /// Standard error chunk
     #[serde(rename = "stderr")]
-    Stderr { chunk: String },
+    Stderr {
+        exec_id: String,
+        /// Position of this chunk in the command's output, shared with the
+        /// other stream; what `replay` takes as `since_seq`
+        #[serde(default)]
+        seq: u64,
+        chunk: String,
+    },
+
+    /// A diagnostic parsed from stderr under `diagnostics_format`, sent
+    /// after the stderr chunk it came from. Location fields are omitted for
+    /// diagnostics that point at no code
+    #[serde(rename = "diagnostic")]
+    Diagnostic {
+        exec_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        file: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        line: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        col: Option<u64>,
+        /// As the toolchain reports it, e.g. "error", "warning" or "note"
+        severity: String,
+        message: String,
+    },
     
     /// Process exited
     #[serde(rename = "exit")]
-    Exit { code: i32 },
+    Exit {
+        exec_id: String,
+        code: i32,
+        /// Signal that terminated the process, e.g. 9 for SIGKILL or 11 for
+        /// SIGSEGV; omitted when it exited normally. `code` is then `128 + signal`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signal: Option<i32>,
+        /// Total bytes written to stdout; omitted when unknown
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stdout_bytes: Option<u64>,
+        /// Total bytes written to stderr; omitted when unknown
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stderr_bytes: Option<u64>,
+        /// Set when output hit the command's cap and the rest was discarded
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        truncated: bool,
+        /// User CPU time in milliseconds; omitted when the platform cannot tell
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cpu_user_ms: Option<u64>,
+        /// System CPU time in milliseconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cpu_sys_ms: Option<u64>,
+        /// Peak resident set size in KiB
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_rss_kb: Option<u64>,
+        /// Milliseconds from the request arriving until the process was
+        /// spawned, which is time spent in the agent and the kernel
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        spawn_latency_ms: Option<u64>,
+        /// Milliseconds from spawning until the first output; omitted when
+        /// the process wrote nothing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        time_to_first_output_ms: Option<u64>,
+    },
+
+    /// Process exceeded its wall-clock limit and was killed
+    #[serde(rename = "timeout")]
+    Timeout { exec_id: String, timeout_ms: u64 },
+
+    /// A crashed REPL was respawned under the same exec id
+    #[serde(rename = "repl.restarted")]
+    ReplRestarted {
+        exec_id: String,
+        /// Restarts within the current window, including this one
+        attempt: u32,
+    },
+
+    /// An `exec.sequence` step is starting; its output follows under the
+    /// sequence's exec id
+    #[serde(rename = "sequence.step")]
+    SequenceStep {
+        exec_id: String,
+        /// Zero-based position of the step in the sequence
+        step: usize,
+        cmd: String,
+    },
+
+    /// Process was terminated after hitting a resource limit
+    #[serde(rename = "limit_exceeded")]
+    LimitExceeded {
+        exec_id: String,
+        /// Which limit was hit ("memory" or "cpu")
+        resource: String,
+        limit: u64,
+    },
     
     /// Artifact detected
     #[serde(rename = "artifact")]
-    Artifact {
+    Artifact(ArtifactParams),
+
+    /// The artifacts of an `artifact.batch_begin` batch, sent together once
+    /// the batch ends
+    #[serde(rename = "artifact.batch")]
+    ArtifactBatch {
+        batch_id: String,
+        artifacts: Vec<ArtifactParams>,
+        /// Files of the batch too large to hold back, which were streamed in
+        /// chunks as they appeared
+        streamed: Vec<String>,
+    },
+
+    /// Start of a chunked artifact too large to send inline
+    #[serde(rename = "artifact.start")]
+    ArtifactStart {
+        root: String,
         path: String,
         mime: String,
+        total_size: u64,
+    },
+
+    /// One chunk of a chunked artifact
+    #[serde(rename = "artifact.chunk")]
+    ArtifactChunk {
+        path: String,
+        seq: u64,
         data_base64: String,
     },
+
+    /// End of a chunked artifact, with the SHA-256 of the whole file
+    #[serde(rename = "artifact.end")]
+    ArtifactEnd { path: String, sha256: String },
+
+    /// A previously reported artifact was deleted
+    #[serde(rename = "artifact.removed")]
+    ArtifactRemoved { root: String, path: String },
+
+    /// A file was noticed but its contents will not be sent
+    #[serde(rename = "artifact.skipped")]
+    ArtifactSkipped {
+        root: String,
+        path: String,
+        size: u64,
+        /// "too_large", "unreadable" or "filtered"
+        reason: String,
+    },
+
+    /// The session's artifact limit was reached; new files are no longer
+    /// reported, while those already reported still are when they change
+    #[serde(rename = "artifact.limit_reached")]
+    ArtifactLimitReached { limit: usize },
     
+    /// Content appended to a file followed with `fs.tail`
+    #[serde(rename = "fs.tail.data")]
+    TailData {
+        /// The tailed file, as returned by `fs.tail`
+        path: String,
+        chunk: String,
+    },
+
+    /// A tailed file was truncated or replaced; data that follows comes
+    /// from its start
+    #[serde(rename = "fs.tail.rotated")]
+    TailRotated { path: String },
+
+    /// One item streamed by a plugin method. Tagged with the request it
+    /// answers, since it may arrive before the response does
+    #[serde(rename = "plugin.event")]
+    PluginEvent {
+        method: String,
+        request_id: Option<serde_json::Value>,
+        data: serde_json::Value,
+    },
+
+    /// A plugin method's stream ended; nothing more follows for the request
+    #[serde(rename = "plugin.end")]
+    PluginEnd {
+        method: String,
+        request_id: Option<serde_json::Value>,
+    },
+
+    /// One reading taken for `proc.sample`; fields the platform could not
+    /// read are omitted
+    #[serde(rename = "proc.sample")]
+    ProcSample {
+        exec_id: String,
+        /// Milliseconds since sampling began
+        elapsed_ms: u64,
+        /// Resident memory in KiB
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rss_kb: Option<u64>,
+        /// Open file descriptors
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fd_count: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        threads: Option<u64>,
+    },
+
+    /// The agent is running as many processes as it may; the command was
+    /// accepted and waits at `position` (from 1) for a slot
+    #[serde(rename = "queued")]
+    Queued { exec_id: String, position: usize },
+
+    /// A queued command got a slot and was spawned
+    #[serde(rename = "started")]
+    Started { exec_id: String },
+
+    /// Sent once at startup, before any other message, when the agent is
+    /// ready for commands
+    #[serde(rename = "ready")]
+    Ready {
+        agent_version: String,
+        pid: u32,
+    },
+
+    /// Sent periodically when configured, so idle connections stay open
+    /// and a silent agent can be told apart from an idle one
+    #[serde(rename = "keepalive")]
+    Keepalive {
+        /// Milliseconds since the agent started
+        uptime_ms: u64,
+    },
+
     /// Error occurred
     #[serde(rename = "error")]
-    Error { message: String },
+    Error {
+        /// Command the error relates to, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exec_id: Option<String>,
+        message: String,
+    },
 }
 
-/// Parameters for the "exec" method.
-#[derive(Debug, Clone, Deserialize)]
-pub struct ExecParams {
+impl StreamEvent {
+    /// The command this event belongs to; `None` for artifact events.
+    pub fn exec_id(&self) -> Option<&str> {
+        match self {
+            Self::Stdout { exec_id, .. }
+            | Self::Stderr { exec_id, .. }
+            | Self::Diagnostic { exec_id, .. }
+            | Self::Exit { exec_id, .. }
+            | Self::Timeout { exec_id, .. }
+            | Self::ReplRestarted { exec_id, .. }
+            | Self::SequenceStep { exec_id, .. }
+            | Self::ProcSample { exec_id, .. }
+            | Self::Queued { exec_id, .. }
+            | Self::Started { exec_id }
+            | Self::LimitExceeded { exec_id, .. } => Some(exec_id),
+            Self::Error { exec_id, .. } => exec_id.as_deref(),
+            Self::Artifact(_)
+            | Self::ArtifactBatch { .. }
+            | Self::ArtifactStart { .. }
+            | Self::ArtifactChunk { .. }
+            | Self::ArtifactEnd { .. }
+            | Self::ArtifactRemoved { .. }
+            | Self::ArtifactSkipped { .. }
+            | Self::ArtifactLimitReached { .. }
+            | Self::TailData { .. }
+            | Self::TailRotated { .. }
+            | Self::PluginEvent { .. }
+            | Self::PluginEnd { .. }
+            | Self::Ready { .. }
+            | Self::Keepalive { .. } => None,
+        }
+    }
+}
+
+/// Options shared by every method that spawns a process.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SpawnParams {
+    /// Caller-chosen id used to tag events and target follow-up requests
+    #[serde(default)]
+    pub exec_id: Option<String>,
     pub cmd: String,
     #[serde(default)]
     pub args: Vec<String>,
     #[serde(default)]
     pub env: HashMap<String, String>,
+    /// Working directory, absolute or relative to /workspace
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Wall-clock limit in milliseconds after which the process is killed
+    #[serde(default, alias = "timeout")]
+    pub timeout_ms: Option<u64>,
+    /// Address-space limit (RLIMIT_AS) in bytes, or `memory.max` when the
+    /// agent runs commands in cgroups
+    #[serde(default)]
+    pub memory_limit_bytes: Option<u64>,
+    /// CPU time limit (RLIMIT_CPU) in seconds
+    #[serde(default)]
+    pub cpu_seconds: Option<u64>,
+    /// CPU bandwidth cap in thousandths of a CPU; needs the agent to run
+    /// commands in cgroups
+    #[serde(default)]
+    pub cpu_millicores: Option<u32>,
+    /// Scheduling niceness, from -20 (highest priority) to 19 (lowest)
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// File mode creation mask, e.g. 63 for 0o077
+    #[serde(default)]
+    pub umask: Option<u32>,
+    /// User id to run as
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// Group id to run as
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Supplementary group ids for the process
+    #[serde(default)]
+    pub supplementary_groups: Option<Vec<u32>>,
+    /// Deliver stderr interleaved with stdout, in write order
+    #[serde(default)]
+    pub combine_stderr: bool,
+    /// Cap on stdout and stderr bytes combined; the process is killed past it
+    #[serde(default)]
+    pub max_output_bytes: Option<u64>,
+    /// Encoding the process writes in ("utf-8", "iso-8859-1" or
+    /// "windows-1252"); output is always sent as UTF-8
+    #[serde(default)]
+    pub output_encoding: Option<String>,
+    /// Start from an empty environment instead of inheriting the agent's
+    #[serde(default)]
+    pub clear_env: bool,
+    /// Inherited variables to unset
+    #[serde(default)]
+    pub env_remove: Vec<String>,
+    /// Directories to search before the inherited `PATH`, such as a venv's `bin`
+    #[serde(default)]
+    pub path_prepend: Vec<String>,
+    /// Directories to search after the inherited `PATH`
+    #[serde(default)]
+    pub path_append: Vec<String>,
+    /// Keep the core if the process crashes, reported as an artifact
+    /// under `cores/`
+    #[serde(default)]
+    pub capture_core: bool,
+}
+
+/// Parameters for the "exec" method.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecParams {
+    #[serde(flatten)]
+    pub spawn: SpawnParams,
+    /// Where stdin comes from: "null" (the default), "pipe" to feed it with
+    /// `repl.input` and close it with `repl.eof`, or `{ "file": path }`
+    #[serde(default)]
+    pub stdin: StdinSource,
+    /// Same as `stdin: { "file": path }`, which came later
+    #[serde(default)]
+    pub stdin_file: Option<String>,
+    /// Dotenv file inside the sandbox whose variables are added to `env`;
+    /// entries in `env` win over the file's
+    #[serde(default)]
+    pub env_file: Option<String>,
+    /// Run `cmd` as a script for the agent's login shell, with `args` as its
+    /// positional parameters. The shell interprets the whole string, so
+    /// callers must not splice untrusted input into it.
+    #[serde(default)]
+    pub shell: bool,
+    /// Substitute `${NAME}` in `cmd` and `args` from the command's
+    /// environment, without the injection risks of `shell`; `$$` is a
+    /// literal `$`
+    #[serde(default)]
+    pub expand_env: bool,
+    /// What an `expand_env` variable that is not set becomes
+    #[serde(default)]
+    pub undefined_vars: UndefinedVars,
+    /// Output streams to send as notifications; both when unset. The
+    /// others are still read, so the process never blocks writing them.
+    #[serde(default)]
+    pub streams: Option<Vec<OutputStream>>,
+    /// Keep the process running in the background, e.g. a dev server, and
+    /// answer with its `pid` as well. It takes no concurrency slot, and only
+    /// requests naming its exec id reach it, so `exec.kill` without one
+    /// leaves it alone. Honoured by `exec` only
+    #[serde(default)]
+    pub detach: bool,
+    /// Also parse stderr as this toolchain's diagnostics and send each as
+    /// a `diagnostic` notification; raw stderr still flows. "rustc" reads
+    /// `--error-format=json` output
+    #[serde(default)]
+    pub diagnostics_format: Option<String>,
+}
+
+/// Where a command's stdin comes from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StdinSource {
+    /// Nothing: reads see the end of input straight away
+    #[default]
+    Null,
+    /// A pipe written to with `repl.input`, like a REPL's
+    Pipe,
+    /// A file inside the sandbox, absolute or relative to /workspace, so
+    /// large inputs need not travel over RPC
+    File(String),
+}
+
+/// Handling of unset variables under `expand_env`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UndefinedVars {
+    /// Replaced with an empty string
+    #[default]
+    Empty,
+    /// The command is rejected, naming the variables
+    Error,
+}
+
+/// One of a process's output streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Parameters for the "exec.sequence" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExecSequenceParams {
+    /// Shared by every step; each step's own `exec_id` is ignored
+    #[serde(default)]
+    pub exec_id: Option<String>,
+    /// Commands to run one after another
+    pub steps: Vec<ExecParams>,
+    /// Skip the remaining steps once one exits non-zero, like `&&`;
+    /// otherwise every step runs, like `;`
+    pub stop_on_failure: bool,
 }
 
 /// Parameters for the "repl.start" method.
 #[derive(Debug, Clone, Deserialize)]
 pub struct ReplStartParams {
-    pub cmd: String,
+    #[serde(flatten)]
+    pub spawn: SpawnParams,
+    /// Run on a pseudo-terminal instead of pipes
     #[serde(default)]
-    pub args: Vec<String>,
+    pub tty: bool,
+    /// Initial terminal height, when `tty` is set
     #[serde(default)]
-    pub env: HashMap<String, String>,
+    pub rows: Option<u16>,
+    /// Initial terminal width, when `tty` is set
+    #[serde(default)]
+    pub cols: Option<u16>,
+    /// Respawn the process with the same settings when it exits non-zero
+    #[serde(default)]
+    pub auto_restart: bool,
+    /// Restarts allowed per minute before a crash loop is given up on
+    #[serde(default)]
+    pub max_restarts: Option<u32>,
 }
 
-/// Parameters for the "repl.input" method.
+/// Parameters for the "repl.resize" method.
 #[derive(Debug, Clone, Deserialize)]
+pub struct ReplResizeParams {
+    /// Target command; defaults to the most recently started one
+    #[serde(default)]
+    pub exec_id: Option<String>,
+    pub rows: u16,
+    pub cols: u16,
+}
+
+/// Parameters for the "env.set" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EnvSetParams {
+    /// Variables to add to the session, overwriting existing ones
+    pub vars: HashMap<String, String>,
+}
+
+/// Parameters for the "env.unset" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EnvUnsetParams {
+    pub keys: Vec<String>,
+}
+
+/// Result of the "env.get" method.
+#[derive(Debug, Clone, Serialize)]
+pub struct EnvGetResult {
+    /// Every variable set for the session
+    pub vars: HashMap<String, String>,
+}
+
+/// Parameters for the "which" method: one `name`, or a batch of `names`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WhichParams {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub names: Option<Vec<String>>,
+}
+
+/// Result of the "which" method for a single `name`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WhichResult {
+    /// Absolute path of the executable, or null when it is not on `PATH`
+    pub path: Option<String>,
+}
+
+/// Result of the "which" method for a batch of `names`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WhichBatchResult {
+    /// Each requested name's path, or null when it was not found
+    pub paths: HashMap<String, Option<String>>,
+}
+
+/// Parameters for the "replay" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplayParams {
+    /// Command whose output to resend; the only running one when omitted
+    #[serde(default)]
+    pub exec_id: Option<String>,
+    /// Resend chunks numbered after this one; 0 resends everything kept
+    #[serde(default)]
+    pub since_seq: u64,
+}
+
+/// Result of the "replay" method. The chunks themselves are resent as
+/// ordinary stdout/stderr notifications, with their original `seq`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayResult {
+    pub exec_id: String,
+    /// Chunks about to be resent
+    pub replayed: usize,
+    /// False when chunks after `since_seq` were already evicted from the buffer
+    pub complete: bool,
+}
+
+/// Parameters for the "logs.tail" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogsTailParams {
+    /// Newest records to return; everything kept when omitted
+    #[serde(default)]
+    pub max_lines: Option<usize>,
+}
+
+/// Result of the "logs.tail" method.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogsTailResult {
+    /// The agent's own log records, oldest first, as the JSON objects it
+    /// writes to stderr
+    pub records: Vec<serde_json::Value>,
+    /// Older records that no longer fit in the buffer
+    pub dropped: u64,
+}
+
+/// Parameters for the "repl.input" and "repl.input_line" methods.
+///
+/// `repl.input` writes exactly the bytes given, adding no terminator unless
+/// `append_newline` is set; `repl.input_line` always ends them with `\n`,
+/// as a line typed at a prompt would be.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReplInputParams {
+    /// Target command; defaults to the most recently started one
+    #[serde(default)]
+    pub exec_id: Option<String>,
     pub data: String,
+    /// How `data` is encoded; base64 allows arbitrary bytes
+    #[serde(default)]
+    pub encoding: InputEncoding,
+    /// Write a `\n` after the decoded data
+    #[serde(default)]
+    pub append_newline: bool,
+}
+
+/// Encoding of data sent to a process's stdin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputEncoding {
+    /// Text written as its UTF-8 bytes
+    #[default]
+    Utf8,
+    /// Base64 decoded before writing
+    Base64,
+}
+
+impl ReplInputParams {
+    /// The raw bytes to write to stdin, terminator included.
+    pub fn bytes(&self) -> Result<Vec<u8>, RpcError> {
+        let mut bytes = match self.encoding {
+            InputEncoding::Utf8 => self.data.as_bytes().to_vec(),
+            InputEncoding::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(&self.data)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid base64 data: {}", e)))?,
+        };
+        if self.append_newline {
+            bytes.push(b'\n');
+        }
+        Ok(bytes)
+    }
+}
+
+/// Result of the "repl.input" and "repl.input_line" methods.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplInputResult {
+    /// Bytes written to stdin, terminator included
+    pub bytes_written: usize,
+}
+
+/// Parameters for the "proc.sample" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProcSampleParams {
+    /// Target command; defaults to the most recently started one
+    #[serde(default)]
+    pub exec_id: Option<String>,
+    /// Milliseconds between samples
+    pub interval_ms: u64,
+    /// Milliseconds to keep sampling, unless the process exits first
+    pub duration_ms: u64,
+}
+
+/// Parameters for the "exec.signal" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExecSignalParams {
+    /// Target command; defaults to the most recently started one
+    #[serde(default)]
+    pub exec_id: Option<String>,
+    pub signal: SignalSpec,
+}
+
+/// A signal given either by name ("SIGHUP", "HUP") or by number.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SignalSpec {
+    Number(i32),
+    Name(String),
+}
+
+/// Parameters for the "init" handshake.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InitParams {
+    /// Highest protocol version the client understands
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
+    /// Framing to switch to once the handshake response has been sent
+    #[serde(default)]
+    pub framing: Option<Framing>,
+}
+
+/// Result of the "init" handshake.
+#[derive(Debug, Clone, Serialize)]
+pub struct InitResult {
+    /// Agent crate version
+    pub agent_version: String,
+    /// Protocol version both sides will speak
+    pub protocol_version: u32,
+    /// Methods this agent accepts
+    pub methods: Vec<String>,
+    /// Framing used after this response, echoed when the client asked to switch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub framing: Option<Framing>,
+}
+
+/// Parameters for the "fs.list" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsListParams {
+    /// Directory or file to inspect, relative to /workspace unless absolute
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Include the contents of subdirectories
+    #[serde(default)]
+    pub recursive: bool,
+    /// Stop after this many entries (the agent caps it regardless)
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// One entry in an "fs.list" result.
+#[derive(Debug, Clone, Serialize)]
+pub struct FsEntry {
+    /// Path relative to the listed directory
+    pub name: String,
+    pub size: u64,
+    /// Permission bits
+    pub mode: u32,
+    /// Modification time in milliseconds since the Unix epoch
+    pub mtime_ms: u64,
+    pub is_dir: bool,
+}
+
+/// Result of the "fs.list" method.
+#[derive(Debug, Clone, Serialize)]
+pub struct FsListResult {
+    pub entries: Vec<FsEntry>,
+    /// More entries exist than were returned
+    pub truncated: bool,
+}
+
+/// Parameters for the "fs.read" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsReadParams {
+    /// File to read, relative to /workspace unless absolute
+    pub path: String,
+    /// Fail instead of returning a file larger than this
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+/// Parameters for the "fs.tail" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsTailParams {
+    /// File to follow, relative to /workspace unless absolute
+    pub path: String,
+    /// Skip what the file already holds and send only new content
+    #[serde(default)]
+    pub from_end: bool,
+}
+
+/// Result of the "fs.tail" method.
+#[derive(Debug, Clone, Serialize)]
+pub struct FsTailResult {
+    /// Absolute path the tail's notifications are tagged with
+    pub path: String,
+}
+
+/// Parameters for the "fs.tail.stop" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsTailStopParams {
+    pub path: String,
+}
+
+/// Result of the "fs.read" method.
+#[derive(Debug, Clone, Serialize)]
+pub struct FsReadResult {
+    pub data_base64: String,
+    pub mime: String,
+    pub size: u64,
+}
+
+/// Parameters for the "fs.write" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsWriteParams {
+    /// File to write, relative to /workspace unless absolute
+    pub path: String,
+    pub data_base64: String,
+    /// Permission bits to set, e.g. 493 for 0o755
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+/// Parameters for the "fs.chmod" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsChmodParams {
+    /// File or directory, relative to /workspace unless absolute
+    pub path: String,
+    /// Permission bits to set, e.g. 493 for 0o755
+    pub mode: u32,
+}
+
+/// Parameters for the "fs.chown" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsChownParams {
+    /// File or directory, relative to /workspace unless absolute
+    pub path: String,
+    /// New owner; omitted to keep the current one
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// New group; omitted to keep the current one
+    #[serde(default)]
+    pub gid: Option<u32>,
+}
+
+/// Parameters for the "upload.configure" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadConfigureParams {
+    /// `http://` or `https://` prefix that oversized artifacts are PUT under;
+    /// null disables uploads
+    pub base_url: Option<String>,
+    /// Headers sent with every upload, e.g. authorization
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// Parameters for the "artifact.configure" method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArtifactConfigureParams {
+    /// Largest artifact sent in a single message; bigger ones are uploaded
+    /// or streamed in chunks
+    pub max_inline_bytes: u64,
+}
+
+/// Parameters for the "artifact.batch_begin" method.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ArtifactBatchParams {
+    /// Tags the batch's event; generated when omitted
+    #[serde(default)]
+    pub batch_id: Option<String>,
+}
+
+/// Result of the "artifact.batch_begin" and "artifact.batch_end" methods.
+#[derive(Debug, Clone, Serialize)]
+pub struct ArtifactBatchResult {
+    pub batch_id: String,
+    /// For `artifact.batch_end`, how many files the batch reported, inline
+    /// or streamed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<usize>,
+}
+
+/// Result of the "exec.sync" method.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecSyncResult {
+    pub exec_id: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    /// Output hit the cap and the command was killed
+    pub truncated: bool,
+}
+
+/// Result of the "exec.sequence" method, sent once the sequence ends.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecSequenceResult {
+    pub exec_id: String,
+    /// Exit codes of the steps that ran, in order; a step that could not be
+    /// started counts as -1
+    pub exit_codes: Vec<i32>,
+    /// First step that exited non-zero, if any
+    pub failed_step: Option<usize>,
+}
+
+/// Result of the "ping" liveness check.
+#[derive(Debug, Clone, Serialize)]
+pub struct PingResult {
+    pub pong: bool,
+    /// Milliseconds since the agent started
+    pub uptime_ms: u64,
+}
+
+/// Result of the "status" method.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusResult {
+    /// Whether any command is still running
+    pub running: bool,
+    /// Running commands, oldest first; omitted when idle
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub processes: Vec<ProcessStatus>,
+    /// New artifacts not reported since the artifact limit was reached;
+    /// omitted while under the limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifacts_withheld: Option<usize>,
+}
+
+/// One running command in a "status" result.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessStatus {
+    pub exec_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    pub cmd: String,
+    pub args: Vec<String>,
+    /// Milliseconds since the process was spawned
+    pub elapsed_ms: u64,
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub detached: bool,
+}
+
+/// Result of "exec.validate" when every pre-spawn check passes.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecValidateResult {
+    pub ok: bool,
+}
+
+/// Result of "shutdown", sent once every pending event has been delivered.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShutdownResult {
+    pub ok: bool,
+}
+
+/// Parameters for methods that only name a target command
+/// ("exec.kill", "repl.eof").
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExecTargetParams {
+    /// Target command; defaults to the most recently started one
+    #[serde(default)]
+    pub exec_id: Option<String>,
 }
 
 /// RPC handler that processes incoming requests.
 pub struct RpcHandler<R, W> {
     reader: BufReader<R>,
     writer: BufWriter<W>,
+    framing: Framing,
 }
 
 impl<R, W> RpcHandler<R, W>
@@ -153,69 +1165,127 @@ where
         Self {
             reader: BufReader::new(reader),
             writer: BufWriter::new(writer),
+            framing: Framing::Line,
         }
     }
 
+    /// Use the given framing for all subsequent reads and writes.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
     /// Read the next request from the stream.
     pub async fn read_request(&mut self) -> Result<Option<Request>> {
-        let mut line = String::new();
-        let bytes_read = self
-            .reader
-            .read_line(&mut line)
-            .await
-            .context("Failed to read from stream")?;
-
-        if bytes_read == 0 {
+        let message = match self.framing {
+            Framing::Line => self.read_line_message().await?,
+            Framing::ContentLength => self.read_framed_message().await?,
+        };
+        let Some(message) = message else {
             return Ok(None); // EOF
+        };
+
+        Ok(Some(Request::parse(&message)?))
+    }
+
+    /// Read one newline-terminated message, skipping blank lines.
+    async fn read_line_message(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let mut line = Vec::new();
+            let bytes_read = self
+                .reader
+                .read_until(b'\n', &mut line)
+                .await
+                .context("Failed to read from stream")?;
+
+            if bytes_read == 0 {
+                return Ok(None);
+            }
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                return Ok(Some(line));
+            }
         }
+    }
 
-        let request: Request =
-            serde_json::from_str(&line).context("Failed to parse JSON-RPC request")?;
+    /// Read one message preceded by `Content-Length` headers.
+    async fn read_framed_message(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut length = None;
+        let mut in_headers = false;
+        loop {
+            let mut header = String::new();
+            let bytes_read = self
+                .reader
+                .read_line(&mut header)
+                .await
+                .context("Failed to read from stream")?;
+            if bytes_read == 0 {
+                if in_headers {
+                    anyhow::bail!("Stream ended inside message headers");
+                }
+                return Ok(None); // EOF
+            }
+
+            let header = header.trim_end_matches(['\r', '\n']);
+            if header.is_empty() {
+                // Tolerate stray blank lines between messages
+                if !in_headers {
+                    continue;
+                }
+                break;
+            }
+            in_headers = true;
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Length") {
+                    let value: usize = value
+                        .trim()
+                        .parse()
+                        .context("Invalid Content-Length header")?;
+                    length = Some(value);
+                }
+            }
+        }
 
-        Ok(Some(request))
+        let length = length.context("Message is missing a Content-Length header")?;
+        if length > MAX_FRAME_SIZE {
+            anyhow::bail!("Message of {} bytes exceeds the {} byte limit", length, MAX_FRAME_SIZE);
+        }
+        let mut body = vec![0u8; length];
+        self.reader
+            .read_exact(&mut body)
+            .await
+            .context("Stream ended inside message body")?;
+        Ok(Some(body))
     }
 
     /// Send a response to the stream.
     pub async fn send_response(&mut self, response: Response) -> Result<()> {
         let json = serde_json::to_string(&response)?;
-        self.writer.write_all(json.as_bytes()).await?;
-        self.writer.write_all(b"\n").await?;
-        self.writer.flush().await?;
-        Ok(())
+        self.write_message(&json).await
     }
 
     /// Send a streaming event (notification) to the stream.
     pub async fn send_event(&mut self, event: StreamEvent) -> Result<()> {
-        let notification = match &event {
-            StreamEvent::Stdout { chunk } => {
-                Request::notification("stdout", serde_json::json!({ "chunk": chunk }))
-            }
-            StreamEvent::Stderr { chunk } => {
-                Request::notification("stderr", serde_json::json!({ "chunk": chunk }))
-            }
-            StreamEvent::Exit { code } => {
-                Request::notification("exit", serde_json::json!({ "code": code }))
-            }
-            StreamEvent::Artifact {
-                path,
-                mime,
-                data_base64,
-            } => Request::notification(
-                "artifact",
-                serde_json::json!({
-                    "path": path,
-                    "mime": mime,
-                    "data_base64": data_base64
-                }),
-            ),
-            StreamEvent::Error { message } => {
-                Request::notification("error", serde_json::json!({ "message": message }))
-            }
-        };
+        // The serde representation of StreamEvent is already `{ method, params }`
+        let mut value = serde_json::to_value(&event)?;
+        let method = value["method"].as_str().unwrap_or_default().to_string();
+        let notification = Request::notification(&method, value["params"].take());
 
         let json = serde_json::to_string(&notification)?;
-        self.writer.write_all(json.as_bytes()).await?;
-        self.writer.write_all(b"\n").await?;
+        self.write_message(&json).await
+    }
+
+    /// Write a single serialized message using the current framing.
+    async fn write_message(&mut self, json: &str) -> Result<()> {
+        match self.framing {
+            Framing::Line => {
+                self.writer.write_all(json.as_bytes()).await?;
+                self.writer.write_all(b"\n").await?;
+            }
+            Framing::ContentLength => {
+                let header = format!("Content-Length: {}\r\n\r\n", json.len());
+                self.writer.write_all(header.as_bytes()).await?;
+                self.writer.write_all(json.as_bytes()).await?;
+            }
+        }
         self.writer.flush().await?;
         Ok(())
     }
@@ -242,6 +1312,36 @@ mod tests {
         assert!(json.contains("\"method\":\"exec\""));
     }
 
+    #[test]
+    fn test_error_data_round_trips() {
+        let data = serde_json::json!({ "cmd": "pyhton3", "errno": 2 });
+        let response = Response::error_with_data(serde_json::json!(7), COMMAND_NOT_FOUND, "Command not found", data.clone());
+        let json = serde_json::to_string(&response).unwrap();
+        let parsed: Response = serde_json::from_str(&json).unwrap();
+        let error = parsed.error.unwrap();
+        assert_eq!(error.code, COMMAND_NOT_FOUND);
+        assert_eq!(error.data, Some(data));
+
+        // Handler errors with context, like invalid params, come out the same way
+        let request = Request::parse(br#"{"jsonrpc":"2.0","method":"exec","params":{"cmd":7},"id":9}"#).unwrap();
+        let error = request.parse_params::<ExecParams>().unwrap_err();
+        let expected = Response::error_with_data(serde_json::json!(9), error.code, &error.message, error.data.clone().unwrap());
+        let response = Response::from_result(serde_json::json!(9), Err(error));
+        assert_eq!(serde_json::to_value(response).unwrap(), serde_json::to_value(expected).unwrap());
+
+        // Plain errors leave the field out entirely
+        let json = serde_json::to_value(Response::error(serde_json::json!(8), INTERNAL_ERROR, "boom")).unwrap();
+        assert!(json["error"].get("data").is_none());
+    }
+
+    #[test]
+    fn test_invalid_params_carry_method() {
+        let request = Request::parse(br#"{"jsonrpc":"2.0","method":"exec","params":{"cmd":7},"id":1}"#).unwrap();
+        let error = request.parse_params::<ExecParams>().unwrap_err();
+        assert_eq!(error.code, INVALID_PARAMS);
+        assert_eq!(error.data.unwrap()["method"], "exec");
+    }
+
     #[test]
     fn test_response_success() {
         let response = Response::success(
@@ -265,4 +1365,224 @@ mod tests {
         assert!(response.error.is_some());
         assert_eq!(response.error.unwrap().code, METHOD_NOT_FOUND);
     }
+
+    #[test]
+    fn test_parse_params_accepts_missing_params() {
+        let request = Request::notification("exec.kill", serde_json::Value::Null);
+        let params: ExecTargetParams = request.parse_params().unwrap();
+        assert!(params.exec_id.is_none());
+    }
+
+    #[test]
+    fn test_parse_params_rejects_bad_params() {
+        let request = Request::notification("exec", serde_json::json!({ "args": [] }));
+        let err = request.parse_params::<ExecParams>().unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_exec_stdin_is_null_pipe_or_a_file() {
+        let parse = |params: serde_json::Value| Request::notification("exec", params).parse_params::<ExecParams>();
+        assert_eq!(parse(serde_json::json!({ "cmd": "cat" })).unwrap().stdin, StdinSource::Null);
+        assert_eq!(parse(serde_json::json!({ "cmd": "cat", "stdin": "pipe" })).unwrap().stdin, StdinSource::Pipe);
+        let params = parse(serde_json::json!({ "cmd": "cat", "stdin": { "file": "in.txt" } })).unwrap();
+        assert_eq!(params.stdin, StdinSource::File("in.txt".to_string()));
+        assert!(parse(serde_json::json!({ "cmd": "cat", "stdin": "tty" })).is_err());
+    }
+
+    #[test]
+    fn test_sequence_params_require_stop_on_failure() {
+        let steps = serde_json::json!([{ "cmd": "make" }, { "cmd": "make", "args": ["test"], "exec_id": "ignored" }]);
+        let request = Request::notification(
+            "exec.sequence",
+            serde_json::json!({ "steps": steps, "stop_on_failure": true }),
+        );
+        let params: ExecSequenceParams = request.parse_params().unwrap();
+        assert!(params.stop_on_failure);
+        assert_eq!(params.steps[1].spawn.args, vec!["test"]);
+
+        let request = Request::notification("exec.sequence", serde_json::json!({ "steps": steps }));
+        let err = request.parse_params::<ExecSequenceParams>().unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn test_events_carry_exec_id() {
+        let (client, agent) = tokio::io::duplex(4096);
+        let mut rpc = RpcHandler::new(tokio::io::empty(), agent);
+        rpc.send_event(StreamEvent::Stdout {
+            exec_id: "build".to_string(),
+            seq: 0,
+            chunk: "ok\n".to_string(),
+        })
+        .await
+        .unwrap();
+
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).await.unwrap();
+        let notification: Request = serde_json::from_str(&line).unwrap();
+        assert_eq!(notification.method, "stdout");
+        assert!(notification.id.is_none());
+        assert_eq!(notification.params["exec_id"], "build");
+        assert_eq!(notification.params["chunk"], "ok\n");
+    }
+
+    #[test]
+    fn test_exit_byte_counts_are_optional() {
+        let event = StreamEvent::Exit {
+            exec_id: "build".to_string(),
+            code: 0,
+            signal: None,
+            stdout_bytes: Some(12),
+            stderr_bytes: None,
+            truncated: false,
+            cpu_user_ms: None,
+            cpu_sys_ms: None,
+            max_rss_kb: None,
+            spawn_latency_ms: Some(3),
+            time_to_first_output_ms: None,
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["params"]["spawn_latency_ms"], 3);
+        assert!(json["params"].get("time_to_first_output_ms").is_none());
+        assert_eq!(json["params"]["stdout_bytes"], 12);
+        assert!(json["params"].get("stderr_bytes").is_none());
+
+        let old: StreamEvent = serde_json::from_value(serde_json::json!({
+            "method": "exit",
+            "params": { "exec_id": "build", "code": 1 }
+        }))
+        .unwrap();
+        assert!(matches!(old, StreamEvent::Exit { stdout_bytes: None, spawn_latency_ms: None, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_content_length_framing_round_trip() {
+        let body = r#"{"jsonrpc":"2.0","method":"repl.input","params":{"data":"a\nb"},
+"id":7}"#;
+        let input = format!(
+            "Content-Length: {}\r\nContent-Type: application/json\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let (client, agent) = tokio::io::duplex(4096);
+        let mut rpc = RpcHandler::new(input.as_bytes(), agent);
+        rpc.set_framing(Framing::ContentLength);
+
+        let request = rpc.read_request().await.unwrap().unwrap();
+        assert_eq!(request.method, "repl.input");
+        assert_eq!(request.params["data"], "a\nb");
+        assert!(rpc.read_request().await.unwrap().is_none());
+
+        rpc.send_response(Response::success(serde_json::json!(7), serde_json::Value::Null))
+            .await
+            .unwrap();
+        drop(rpc);
+        let mut written = String::new();
+        BufReader::new(client).read_to_string(&mut written).await.unwrap();
+        let (header, json) = written.split_once("\r\n\r\n").unwrap();
+        assert_eq!(header, format!("Content-Length: {}", json.len()));
+        let response: Response = serde_json::from_str(json).unwrap();
+        assert_eq!(response.id, 7);
+    }
+
+    #[tokio::test]
+    async fn test_content_length_framing_rejects_missing_length() {
+        let input = "Content-Type: application/json\r\n\r\n{}";
+        let mut rpc = RpcHandler::new(input.as_bytes(), tokio::io::sink());
+        rpc.set_framing(Framing::ContentLength);
+        assert!(rpc.read_request().await.is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_json() {
+        let rejected = Request::parse(b"{not json").unwrap_err();
+        assert_eq!(rejected.error.code, PARSE_ERROR);
+        let response = rejected.response().unwrap();
+        assert_eq!(response.id, serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_parse_rejects_wrong_version() {
+        let rejected =
+            Request::parse(br#"{"jsonrpc":"1.0","method":"exec","id":3}"#).unwrap_err();
+        assert_eq!(rejected.error.code, INVALID_REQUEST);
+        assert_eq!(rejected.response().unwrap().id, 3);
+    }
+
+    #[test]
+    fn test_parse_rejects_missing_method() {
+        let rejected = Request::parse(br#"{"jsonrpc":"2.0","id":4}"#).unwrap_err();
+        assert_eq!(rejected.error.code, INVALID_REQUEST);
+        assert_eq!(rejected.response().unwrap().id, 4);
+
+        // Without an id there is nobody to answer
+        let rejected = Request::parse(br#"{"jsonrpc":"2.0"}"#).unwrap_err();
+        assert!(rejected.response().is_none());
+    }
+
+    #[test]
+    fn test_parse_rejects_non_object_params() {
+        let rejected =
+            Request::parse(br#"{"jsonrpc":"2.0","method":"exec","params":42,"id":5}"#)
+                .unwrap_err();
+        assert_eq!(rejected.error.code, INVALID_REQUEST);
+
+        let request =
+            Request::parse(br#"{"jsonrpc":"2.0","method":"exec","params":{"cmd":"ls"}}"#)
+                .unwrap();
+        assert_eq!(request.params["cmd"], "ls");
+    }
+
+    #[test]
+    fn test_error_event_exec_id_is_optional() {
+        let event = StreamEvent::Error {
+            exec_id: Some("build".to_string()),
+            message: "boom".to_string(),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["params"]["exec_id"], "build");
+
+        let event = StreamEvent::Error {
+            exec_id: None,
+            message: "boom".to_string(),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert!(json["params"].get("exec_id").is_none());
+    }
+
+    #[test]
+    fn test_repl_input_decodes_base64() {
+        let request = Request::notification(
+            "repl.input",
+            serde_json::json!({ "data": "AP8K", "encoding": "base64" }),
+        );
+        let params: ReplInputParams = request.parse_params().unwrap();
+        assert_eq!(params.bytes().unwrap(), vec![0x00, 0xff, 0x0a]);
+
+        let request = Request::notification("repl.input", serde_json::json!({ "data": "hi" }));
+        let params: ReplInputParams = request.parse_params().unwrap();
+        assert_eq!(params.bytes().unwrap(), b"hi");
+
+        let request = Request::notification(
+            "repl.input",
+            serde_json::json!({ "data": "not base64!", "encoding": "base64" }),
+        );
+        let params: ReplInputParams = request.parse_params().unwrap();
+        assert_eq!(params.bytes().unwrap_err().code, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_repl_input_adds_a_newline_only_when_asked() {
+        let request = Request::notification("repl.input", serde_json::json!({ "data": "look\r" }));
+        let params: ReplInputParams = request.parse_params().unwrap();
+        assert_eq!(params.bytes().unwrap(), b"look\r");
+
+        let request = Request::notification(
+            "repl.input",
+            serde_json::json!({ "data": "bG9vaw==", "encoding": "base64", "append_newline": true }),
+        );
+        let params: ReplInputParams = request.parse_params().unwrap();
+        assert_eq!(params.bytes().unwrap(), b"look\n");
+    }
 }
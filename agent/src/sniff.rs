@@ -0,0 +1,108 @@
+//! Content sniffing for files whose name says nothing about their type.
+//!
+//! The extension-based guess falls back to `application/octet-stream` for
+//! names like `Dockerfile` or a freshly built binary. A short table of magic
+//! numbers covers the formats sandboxes commonly produce, with a plain-text
+//! check behind it; anything else stays octet-stream. This is kept in-tree
+//! rather than pulling in a sniffing crate for a few dozen signatures.
+
+/// How much of a file is looked at
+pub const SNIFF_BYTES: usize = 8 * 1024;
+
+/// Bytes at an offset marking a format, tried in order
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (257, b"ustar", "application/x-tar"),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"\x00asm", "application/wasm"),
+    (0, b"SQLite format 3\x00", "application/vnd.sqlite3"),
+    (0, b"PAR1", "application/vnd.apache.parquet"),
+    (0, b"\x93NUMPY", "application/x-npy"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"\x1aE\xdf\xa3", "video/webm"),
+];
+
+/// Guess a MIME type from the first bytes of a file, up to [`SNIFF_BYTES`].
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    if let Some((_, _, mime)) = SIGNATURES
+        .iter()
+        .find(|(offset, magic, _)| head.get(*offset..).is_some_and(|rest| rest.starts_with(magic)))
+    {
+        return Some(mime);
+    }
+    // RIFF containers carry their type after the length
+    if head.starts_with(b"RIFF") && head.len() >= 12 {
+        match &head[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            b"AVI " => return Some("video/x-msvideo"),
+            _ => {}
+        }
+    }
+    if head.get(4..8) == Some(b"ftyp") {
+        return Some("video/mp4");
+    }
+    is_text(head).then_some("text/plain")
+}
+
+/// Whether bytes read from the start of a file look like text: UTF-8
+/// without NULs or control characters other than the usual whitespace
+/// and terminal escapes.
+fn is_text(head: &[u8]) -> bool {
+    if head.is_empty() {
+        return false;
+    }
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // Cut off mid-character by the read limit
+        Err(e) if e.error_len().is_none() && head.len() >= SNIFF_BYTES => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+    !text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magic_numbers() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"RIFF\x24\x00\x00\x00WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"\x7fELF\x02\x01\x01"), Some("application/x-executable"));
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff(&tar), Some("application/x-tar"));
+        assert_eq!(sniff(b"\x00\x01\x02\x03"), None);
+    }
+
+    #[test]
+    fn test_text_detection() {
+        assert_eq!(sniff(b"FROM alpine:3.20\nRUN apk add curl\n"), Some("text/plain"));
+        assert_eq!(sniff("\x1b[32mgrün\x1b[0m\r\n".as_bytes()), Some("text/plain"));
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"text\x00with a nul"), None);
+        assert_eq!(sniff(b"caf\xe9"), None);
+
+        // A character split by the read limit does not make it binary
+        let mut head = "a".repeat(SNIFF_BYTES - 1).into_bytes();
+        head.push(0xc3);
+        assert_eq!(sniff(&head), Some("text/plain"));
+    }
+}
@@ -0,0 +1,261 @@
+//! Following growing files for `fs.tail`.
+//!
+//! A tail polls its file rather than relying on filesystem events: polling
+//! notices an in-place truncation and a replaced file (the two ways logs are
+//! rotated) just as it notices appends, and never runs into watch limits.
+//! When the file is renamed away, whatever was still appended to it is read
+//! before following its replacement.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use crate::executor::Utf8Decoder;
+use crate::files;
+
+/// How often a tailed file is checked for new content
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Most bytes read from a file per notification
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Something that happened to a tailed file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TailEvent {
+    /// Content appended since the last event
+    Data(String),
+    /// The file was truncated or replaced; following restarts at its start
+    Rotated,
+}
+
+/// The files currently being tailed, by path.
+pub struct Tails {
+    active: HashMap<PathBuf, AbortHandle>,
+    /// Sandbox roots every followed file must stay inside
+    roots: Arc<[PathBuf]>,
+    poll_interval: Duration,
+}
+
+impl Tails {
+    pub fn new(roots: &[PathBuf], poll_interval: Duration) -> Self {
+        Self {
+            active: HashMap::new(),
+            roots: roots.into(),
+            poll_interval,
+        }
+    }
+
+    /// Start following `path`, from its end or from the beginning.
+    ///
+    /// The stream of events ends when the tail is stopped.
+    pub async fn start(&mut self, path: PathBuf, from_end: bool) -> Result<mpsc::Receiver<TailEvent>> {
+        if self.active.contains_key(&path) {
+            anyhow::bail!("Already tailing '{}'", path.display());
+        }
+        files::confine(&path, &self.roots).await?;
+        let mut file = File::open(&path)
+            .await
+            .with_context(|| format!("Failed to open '{}'", path.display()))?;
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            anyhow::bail!("'{}' is not a regular file", path.display());
+        }
+        let offset = if from_end { file.seek(SeekFrom::End(0)).await? } else { 0 };
+
+        let (tx, rx) = mpsc::channel(100);
+        let identity = (metadata.dev(), metadata.ino());
+        let task = tokio::spawn(follow(
+            path.clone(),
+            self.roots.clone(),
+            file,
+            identity,
+            offset,
+            self.poll_interval,
+            tx,
+        ));
+        self.active.insert(path, task.abort_handle());
+        Ok(rx)
+    }
+
+    /// Stop following `path`; false if it was not being tailed.
+    pub fn stop(&mut self, path: &Path) -> bool {
+        match self.active.remove(path) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Tails {
+    fn drop(&mut self) {
+        for task in self.active.values() {
+            task.abort();
+        }
+    }
+}
+
+/// Poll `path` for changes until the receiver goes away or the task is aborted.
+///
+/// A missing file is waited for, since logrotate may move the old file
+/// before creating the new one. A replacement is only followed while it
+/// stays inside `roots`.
+async fn follow(
+    path: PathBuf,
+    roots: Arc<[PathBuf]>,
+    mut file: File,
+    mut identity: (u64, u64),
+    mut offset: u64,
+    poll_interval: Duration,
+    tx: mpsc::Sender<TailEvent>,
+) {
+    let mut decoder = Utf8Decoder::default();
+    let mut buf = vec![0u8; READ_CHUNK];
+    loop {
+        if read_appended(&mut file, &mut offset, &mut buf, &mut decoder, &tx).await.is_err() {
+            return;
+        }
+
+        let rotated = match tokio::fs::metadata(&path).await {
+            Ok(metadata) if (metadata.dev(), metadata.ino()) != identity => match reopen(&path, &roots).await {
+                Some(new) => {
+                    // The old file may have grown since it was last read
+                    if read_appended(&mut file, &mut offset, &mut buf, &mut decoder, &tx).await.is_err() {
+                        return;
+                    }
+                    file = new;
+                    identity = (metadata.dev(), metadata.ino());
+                    true
+                }
+                None => false,
+            },
+            Ok(metadata) if metadata.len() < offset => file.seek(SeekFrom::Start(0)).await.is_ok(),
+            _ => false,
+        };
+        if rotated {
+            offset = 0;
+            let rest = decoder.finish();
+            if !rest.is_empty() && tx.send(TailEvent::Data(rest)).await.is_err() {
+                return;
+            }
+            if tx.send(TailEvent::Rotated).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// Open the file now at `path`, if it is still inside the sandbox.
+async fn reopen(path: &Path, roots: &[PathBuf]) -> Option<File> {
+    files::confine(path, roots).await.ok()?;
+    File::open(path).await.ok()
+}
+
+/// Send everything appended to `file` since `offset`.
+///
+/// Read errors are treated as nothing new; only a closed receiver is an error.
+async fn read_appended(
+    file: &mut File,
+    offset: &mut u64,
+    buf: &mut [u8],
+    decoder: &mut Utf8Decoder,
+    tx: &mpsc::Sender<TailEvent>,
+) -> Result<(), mpsc::error::SendError<TailEvent>> {
+    loop {
+        let n = match file.read(buf).await {
+            Ok(0) | Err(_) => return Ok(()),
+            Ok(n) => n,
+        };
+        *offset += n as u64;
+        let chunk = decoder.decode(&buf[..n]);
+        if !chunk.is_empty() {
+            tx.send(TailEvent::Data(chunk)).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    const POLL: Duration = Duration::from_millis(20);
+
+    /// Collect data until `expected` has arrived, noting rotations as "|".
+    async fn receive(rx: &mut mpsc::Receiver<TailEvent>, expected: &str) -> String {
+        let mut received = String::new();
+        while received != expected {
+            let event = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap_or_else(|_| panic!("timed out with {:?}, wanted {:?}", received, expected))
+                .expect("tail ended");
+            match event {
+                TailEvent::Data(chunk) => received.push_str(&chunk),
+                TailEvent::Rotated => received.push('|'),
+            }
+        }
+        received
+    }
+
+    fn append(path: &Path, text: &str) {
+        let mut file = std::fs::OpenOptions::new().append(true).create(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_follows_appends_from_the_end() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, "old line\n").unwrap();
+
+        let mut tails = Tails::new(&[dir.path().to_path_buf()], POLL);
+        let mut rx = tails.start(path.clone(), true).await.unwrap();
+        assert!(tails.start(path.clone(), false).await.is_err());
+        assert!(tails.start("/etc/hostname".into(), false).await.is_err());
+        append(&path, "first\n");
+        receive(&mut rx, "first\n").await;
+        append(&path, "second\n");
+        receive(&mut rx, "second\n").await;
+
+        assert!(tails.stop(&path));
+        assert!(!tails.stop(&path));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_survives_truncation_and_replacement() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, "existing\n").unwrap();
+
+        let mut tails = Tails::new(&[dir.path().to_path_buf()], POLL);
+        let mut rx = tails.start(path.clone(), false).await.unwrap();
+        receive(&mut rx, "existing\n").await;
+
+        // copytruncate-style rotation
+        std::fs::write(&path, "").unwrap();
+        receive(&mut rx, "|").await;
+        append(&path, "after truncate\n");
+        receive(&mut rx, "after truncate\n").await;
+
+        // Rename-style rotation: the old file's last lines come first
+        let rotated = dir.path().join("app.log.1");
+        std::fs::rename(&path, &rotated).unwrap();
+        append(&rotated, "late write\n");
+        append(&path, "new file\n");
+        receive(&mut rx, "late write\n|new file\n").await;
+    }
+}